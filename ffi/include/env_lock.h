@@ -0,0 +1,38 @@
+/*
+ * C bindings for env-lock. See ffi/src/lib.rs for full documentation.
+ */
+
+#ifndef ENV_LOCK_H
+#define ENV_LOCK_H
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Opaque handle to a held environment lock */
+typedef struct EnvLockGuard EnvLockGuard;
+
+/*
+ * Lock the environment, blocking until the lock is available. Returns NULL on
+ * error.
+ */
+EnvLockGuard *env_lock_acquire(void);
+
+/*
+ * Set a variable while the lock is held. Pass a NULL value to remove the
+ * variable. Returns 0 on success, or -1 if any argument is invalid.
+ */
+int env_lock_set(EnvLockGuard *guard, const char *name, const char *value);
+
+/*
+ * Restore every variable modified through the guard, then unlock. Returns 0 on
+ * success, or -1 if a check failed while releasing. The guard is released
+ * either way.
+ */
+int env_lock_release(EnvLockGuard *guard);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* ENV_LOCK_H */
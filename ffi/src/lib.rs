@@ -0,0 +1,183 @@
+//! C bindings for [env-lock](https://docs.rs/env-lock), so C/C++ test code
+//! linked into a Rust test binary coordinates on the same lock and restoration
+//! machinery as the Rust tests. The header for these functions is in
+//! `include/env_lock.h`.
+//!
+//! To share the lock with Rust tests, the C code must be linked into the
+//! *same* binary as this crate's `rlib` (e.g. by depending on this crate and
+//! compiling the C code with `cc` in a build script). Linking the `cdylib` or
+//! `staticlib` separately gives the C code its own copy of env-lock, and its
+//! own lock.
+//!
+//! ```c
+//! EnvLockGuard *guard = env_lock_acquire();
+//! env_lock_set(guard, "FOO", "bar");
+//! env_lock_set(guard, "BAZ", NULL);
+//! // ...
+//! env_lock_release(guard);
+//! ```
+
+#![deny(clippy::all)]
+
+use env_lock::EnvGuard;
+use std::{
+    ffi::{c_char, c_int, CStr},
+    iter,
+    panic::{self, AssertUnwindSafe},
+    ptr,
+};
+
+/// Opaque handle to a held environment lock
+pub struct EnvLockGuard {
+    guard: EnvGuard<'static>,
+}
+
+/// Lock the environment, blocking until the lock is available. The returned
+/// guard must be passed to [env_lock_release] to restore and unlock the
+/// environment. Returns null if locking panicked, e.g. because the current
+/// thread already holds the lock.
+#[no_mangle]
+pub extern "C" fn env_lock_acquire() -> *mut EnvLockGuard {
+    // Never unwind into C
+    panic::catch_unwind(|| {
+        let guard = env_lock::lock_env(iter::empty::<(&str, Option<&str>)>());
+        Box::into_raw(Box::new(EnvLockGuard { guard }))
+    })
+    .unwrap_or(ptr::null_mut())
+}
+
+/// Set a variable while the lock is held. Pass a null `value` to remove the
+/// variable instead. The variable's original value will be restored when the
+/// guard is released. Returns 0 on success, or -1 if the guard or name is null,
+/// either string isn't valid UTF-8, or the name is empty or contains `=`.
+///
+/// # Safety
+/// `guard` must be a pointer returned by [env_lock_acquire] that hasn't been
+/// released yet. `name` and `value` (if non-null) must be valid, nul-terminated
+/// strings.
+#[no_mangle]
+pub unsafe extern "C" fn env_lock_set(
+    guard: *mut EnvLockGuard,
+    name: *const c_char,
+    value: *const c_char,
+) -> c_int {
+    if guard.is_null() || name.is_null() {
+        return -1;
+    }
+    // SAFETY: Pointers are non-null and valid per the function contract
+    let guard = unsafe { &mut *guard };
+    let Ok(name) = unsafe { CStr::from_ptr(name) }.to_str() else {
+        return -1;
+    };
+    let value = if value.is_null() {
+        None
+    } else {
+        match unsafe { CStr::from_ptr(value) }.to_str() {
+            Ok(value) => Some(value),
+            Err(_) => return -1,
+        }
+    };
+    if name.is_empty() || name.contains('=') {
+        return -1;
+    }
+
+    // Never unwind into C
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        if let Some(value) = value {
+            guard.guard.set(name, value);
+        } else {
+            guard.guard.remove(name);
+        }
+    }));
+    if result.is_ok() {
+        0
+    } else {
+        -1
+    }
+}
+
+/// Restore every variable set through the guard, then unlock the environment.
+/// Passing null is a no-op. Returns 0 on success, or -1 if releasing panicked,
+/// e.g. because of a failed hold budget or mutation check. The guard is
+/// released either way.
+///
+/// # Safety
+/// `guard` must be null or a pointer returned by [env_lock_acquire] that hasn't
+/// been released yet. The pointer is invalid after this call.
+#[no_mangle]
+pub unsafe extern "C" fn env_lock_release(guard: *mut EnvLockGuard) -> c_int {
+    if guard.is_null() {
+        return 0;
+    }
+    // SAFETY: The pointer was created by Box::into_raw in env_lock_acquire,
+    // and the caller guarantees it hasn't been freed yet
+    let guard = unsafe { Box::from_raw(guard) };
+    // Never unwind into C. The guard is dropped while unwinding, so the
+    // environment is still restored and unlocked.
+    match panic::catch_unwind(AssertUnwindSafe(|| drop(guard))) {
+        Ok(()) => 0,
+        Err(_) => -1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use env_lock::MutationCheck;
+    use std::{env, ffi::CString, thread, time::Duration};
+
+    /// Run a closure when dropped, to put back a global setting that a test
+    /// changed, even if the test panics
+    struct Reset<F: FnMut()>(F);
+
+    impl<F: FnMut()> Drop for Reset<F> {
+        fn drop(&mut self) {
+            (self.0)();
+        }
+    }
+
+    /// Set and remove variables through the C interface
+    #[test]
+    fn set_and_release() {
+        let c = |s: &str| CString::new(s).unwrap();
+        let set = c("ENV_LOCK_FFI_TEST_SET");
+        let remove = c("ENV_LOCK_FFI_TEST_REMOVE");
+        env::set_var("ENV_LOCK_FFI_TEST_REMOVE", "existing");
+
+        // Writes go through the guard, so they aren't external mutations
+        env_lock::set_mutation_check(MutationCheck::Panic);
+        let reset = Reset(|| env_lock::set_mutation_check(MutationCheck::Off));
+        let guard = env_lock_acquire();
+        unsafe {
+            assert_eq!(env_lock_set(guard, set.as_ptr(), c("1").as_ptr()), 0);
+            assert_eq!(env_lock_set(guard, set.as_ptr(), c("2").as_ptr()), 0);
+            assert_eq!(env_lock_set(guard, remove.as_ptr(), ptr::null()), 0);
+            assert_eq!(env_lock_set(guard, c("A=B").as_ptr(), ptr::null()), -1);
+        }
+        assert_eq!(env::var("ENV_LOCK_FFI_TEST_SET").unwrap(), "2");
+        assert!(env::var("ENV_LOCK_FFI_TEST_REMOVE").is_err());
+        assert_eq!(unsafe { env_lock_release(guard) }, 0);
+        drop(reset);
+
+        assert!(env::var("ENV_LOCK_FFI_TEST_SET").is_err());
+        assert_eq!(env::var("ENV_LOCK_FFI_TEST_REMOVE").unwrap(), "existing");
+    }
+
+    /// Panics while releasing are reported as an error instead of unwinding
+    /// into C, and the guard is still released
+    #[test]
+    fn release_panic() {
+        let name = CString::new("ENV_LOCK_FFI_TEST_RELEASE_PANIC").unwrap();
+        let value = CString::new("1").unwrap();
+        let guard = env_lock_acquire();
+        assert!(!guard.is_null());
+        unsafe {
+            assert_eq!(env_lock_set(guard, name.as_ptr(), value.as_ptr()), 0);
+            (*guard).guard.assert_released_within(Duration::ZERO);
+        }
+        thread::sleep(Duration::from_millis(1));
+        assert_eq!(unsafe { env_lock_release(guard) }, -1);
+        assert!(env::var("ENV_LOCK_FFI_TEST_RELEASE_PANIC").is_err());
+        assert_eq!(unsafe { env_lock_release(env_lock_acquire()) }, 0);
+    }
+}
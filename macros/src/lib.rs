@@ -0,0 +1,397 @@
+//! Procedural macros for [env-lock](https://docs.rs/env-lock). Don't depend on
+//! this crate directly; enable the `macros` feature of env-lock instead.
+
+#![forbid(unsafe_code)]
+#![deny(clippy::all)]
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::{format_ident, quote};
+use std::collections::HashSet;
+use syn::{
+    ext::IdentExt,
+    parse::{Parse, ParseStream},
+    parse_macro_input,
+    punctuated::Punctuated,
+    Data, DeriveInput, Expr, ExprArray, Field, Fields, Ident, ItemFn, Lit,
+    LitStr, Token, Type,
+};
+
+/// Generate one test per combination of variable values. Each test locks the
+/// environment with its combination applied, then calls the annotated
+/// function. See the env-lock docs for details.
+#[proc_macro_attribute]
+pub fn env_matrix(args: TokenStream, item: TokenStream) -> TokenStream {
+    let matrix = parse_macro_input!(args as Matrix);
+    let function = parse_macro_input!(item as ItemFn);
+    expand_matrix(matrix, function).into()
+}
+
+/// Lock the environment with the given variables for the entire body of the
+/// annotated function. See the env-lock docs for details.
+#[proc_macro_attribute]
+pub fn vars(args: TokenStream, item: TokenStream) -> TokenStream {
+    let variables = parse_macro_input!(args as Variables);
+    let function = parse_macro_input!(item as ItemFn);
+    expand_vars(variables, function).into()
+}
+
+/// Implement `ToEnv` for a struct with named fields. See the env-lock docs
+/// for details.
+#[proc_macro_derive(ToEnv)]
+pub fn derive_to_env(item: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(item as DeriveInput);
+    expand_to_env(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn expand_to_env(input: DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let entries = named_fields(&input)?.into_iter().map(|field| {
+        let ident = &field.ident;
+        let name = field_name(field);
+        let value = if is_option(&field.ty) {
+            quote! {
+                ::std::option::Option::as_ref(&self.#ident)
+                    .map(::std::string::ToString::to_string)
+            }
+        } else {
+            quote! {
+                ::std::option::Option::Some(
+                    ::std::string::ToString::to_string(&self.#ident)
+                )
+            }
+        };
+        quote! { (#name, #value) }
+    });
+    let name = &input.ident;
+    let (impl_generics, type_generics, where_clause) =
+        input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::env_lock::ToEnv for #name #type_generics
+            #where_clause
+        {
+            fn to_env(
+                &self,
+            ) -> ::std::vec::Vec<(
+                &'static str,
+                ::std::option::Option<::std::string::String>,
+            )> {
+                ::std::vec![#(#entries),*]
+            }
+        }
+    })
+}
+
+/// Implement `FromEnv` for a struct with named fields. See the env-lock docs
+/// for details.
+#[proc_macro_derive(FromEnv)]
+pub fn derive_from_env(item: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(item as DeriveInput);
+    expand_from_env(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn expand_from_env(
+    input: DeriveInput,
+) -> syn::Result<proc_macro2::TokenStream> {
+    let fields = named_fields(&input)?.into_iter().map(|field| {
+        let ident = &field.ident;
+        let name = field_name(field);
+        let getter = if is_option(&field.ty) {
+            quote! { optional }
+        } else {
+            quote! { required }
+        };
+        quote! { #ident: fields.#getter(#name)? }
+    });
+    let name = &input.ident;
+    let (impl_generics, type_generics, where_clause) =
+        input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::env_lock::FromEnv for #name #type_generics
+            #where_clause
+        {
+            fn from_env(
+                fields: &::env_lock::EnvFields<'_>,
+            ) -> ::std::result::Result<Self, ::env_lock::FromEnvError> {
+                ::std::result::Result::Ok(Self { #(#fields),* })
+            }
+        }
+    })
+}
+
+/// Get the fields of a struct with named fields, or an error for anything
+/// else
+fn named_fields(input: &DeriveInput) -> syn::Result<Vec<&Field>> {
+    match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => Ok(fields.named.iter().collect()),
+            _ => Err(syn::Error::new_spanned(
+                &input.ident,
+                "Expected a struct with named fields",
+            )),
+        },
+        _ => Err(syn::Error::new_spanned(
+            &input.ident,
+            "Expected a struct with named fields",
+        )),
+    }
+}
+
+/// Get a field's name, without the `r#` of raw identifiers
+fn field_name(field: &Field) -> String {
+    field
+        .ident
+        .as_ref()
+        .map(|ident| ident.unraw().to_string())
+        .unwrap_or_default()
+}
+
+/// Is a type an `Option`? This is checked by name, because macros can't
+/// resolve types.
+fn is_option(ty: &Type) -> bool {
+    match ty {
+        Type::Path(path) => path
+            .path
+            .segments
+            .last()
+            .is_some_and(|segment| segment.ident == "Option"),
+        _ => false,
+    }
+}
+
+/// Arguments to [vars]: `VAR = value, ...`
+struct Variables {
+    variables: Vec<Variable>,
+}
+
+/// One variable and its value
+struct Variable {
+    variable: Ident,
+    /// `None` means the variable is removed
+    value: Option<LitStr>,
+}
+
+impl Parse for Variables {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let variables =
+            Punctuated::<Variable, Token![,]>::parse_terminated(input)?;
+        Ok(Self {
+            variables: variables.into_iter().collect(),
+        })
+    }
+}
+
+impl Parse for Variable {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let variable: Ident = input.parse()?;
+        input.parse::<Token![=]>()?;
+        let value = parse_value(&input.parse()?)?;
+        Ok(Self { variable, value })
+    }
+}
+
+fn expand_vars(
+    variables: Variables,
+    mut function: ItemFn,
+) -> proc_macro2::TokenStream {
+    let variables = variables
+        .variables
+        .iter()
+        .map(|Variable { variable, value }| lock_pair(variable, value));
+    let block = &function.block;
+    // Bind the guard before anything in the body runs, and drop it only after
+    // the body (including its return value) is done
+    function.block = syn::parse_quote! {{
+        let _guard = ::env_lock::lock_env([#(#variables),*]);
+        #block
+    }};
+    quote! { #function }
+}
+
+/// Generate a `(name, value)` tuple to pass to `lock_env`
+fn lock_pair(
+    variable: &Ident,
+    value: &Option<LitStr>,
+) -> proc_macro2::TokenStream {
+    let variable = variable.to_string();
+    match value {
+        Some(value) => {
+            quote! { (#variable, ::std::option::Option::Some(#value)) }
+        }
+        None => {
+            quote! { (#variable, ::std::option::Option::None::<&str>) }
+        }
+    }
+}
+
+/// Arguments to [env_matrix]: `VAR = [values...], ...`
+struct Matrix {
+    dimensions: Vec<Dimension>,
+}
+
+/// One variable in a matrix, and all its possible values
+struct Dimension {
+    variable: Ident,
+    /// `None` means the variable is removed
+    values: Vec<Option<LitStr>>,
+}
+
+impl Parse for Matrix {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let dimensions =
+            Punctuated::<Dimension, Token![,]>::parse_terminated(input)?;
+        Ok(Self {
+            dimensions: dimensions.into_iter().collect(),
+        })
+    }
+}
+
+impl Parse for Dimension {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let variable: Ident = input.parse()?;
+        input.parse::<Token![=]>()?;
+        let array: ExprArray = input.parse()?;
+        let values = array
+            .elems
+            .iter()
+            .map(parse_value)
+            .collect::<syn::Result<Vec<_>>>()?;
+        if values.is_empty() {
+            return Err(syn::Error::new_spanned(
+                array,
+                "Expected at least one value",
+            ));
+        }
+        Ok(Self { variable, values })
+    }
+}
+
+/// Parse a string literal as a value, or `None` to remove the variable
+fn parse_value(expr: &Expr) -> syn::Result<Option<LitStr>> {
+    match expr {
+        Expr::Lit(lit) => match &lit.lit {
+            Lit::Str(value) => Ok(Some(value.clone())),
+            _ => Err(syn::Error::new_spanned(lit, "Expected string literal")),
+        },
+        Expr::Path(path) if path.path.is_ident("None") => Ok(None),
+        _ => Err(syn::Error::new_spanned(
+            expr,
+            "Expected string literal or `None`",
+        )),
+    }
+}
+
+fn expand_matrix(
+    matrix: Matrix,
+    mut function: ItemFn,
+) -> proc_macro2::TokenStream {
+    let name = &function.sig.ident;
+    let output = &function.sig.output;
+    // Attributes such as #[should_panic] belong on the generated tests. Doc
+    // comments stay on the original function.
+    let (docs, test_attrs): (Vec<_>, Vec<_>) = function
+        .attrs
+        .drain(..)
+        .partition(|attr| attr.path().is_ident("doc"));
+    let test_attrs = test_attrs
+        .into_iter()
+        .filter(|attr| !attr.path().is_ident("test"));
+    let test_attrs = quote! { #(#test_attrs)* };
+    function.attrs = docs;
+
+    let mut used_names = HashSet::new();
+    let tests = combinations(&matrix.dimensions).into_iter().map(|combo| {
+        let test_name = test_name(&combo, &mut used_names);
+        let variables = combo
+            .iter()
+            .map(|(variable, value)| lock_pair(variable, value));
+        quote! {
+            #[test]
+            #test_attrs
+            fn #test_name() #output {
+                let _guard = ::env_lock::lock_env([#(#variables),*]);
+                super::#name()
+            }
+        }
+    });
+
+    quote! {
+        #function
+
+        mod #name {
+            #(#tests)*
+        }
+    }
+}
+
+/// Get the cartesian product of all dimensions
+fn combinations(
+    dimensions: &[Dimension],
+) -> Vec<Vec<(&Ident, &Option<LitStr>)>> {
+    dimensions.iter().fold(vec![vec![]], |combos, dimension| {
+        combos
+            .into_iter()
+            .flat_map(|combo| {
+                dimension.values.iter().map(move |value| {
+                    let mut combo = combo.clone();
+                    combo.push((&dimension.variable, value));
+                    combo
+                })
+            })
+            .collect()
+    })
+}
+
+/// Generate a unique test function name for a combination of values, e.g.
+/// `mode_a__tls_on`
+fn test_name(
+    combo: &[(&Ident, &Option<LitStr>)],
+    used_names: &mut HashSet<String>,
+) -> Ident {
+    let base = combo
+        .iter()
+        .map(|(variable, value)| {
+            let value = value
+                .as_ref()
+                .map(LitStr::value)
+                .unwrap_or_else(|| "none".into());
+            sanitize(&format!("{variable}_{value}"))
+        })
+        .collect::<Vec<_>>()
+        .join("__");
+    let base = if base.is_empty() {
+        "empty".into()
+    } else {
+        base
+    };
+
+    // Different values can sanitize to the same name, so dedupe
+    let mut name = base.clone();
+    let mut suffix = 1;
+    while !used_names.insert(name.clone()) {
+        suffix += 1;
+        name = format!("{base}_{suffix}");
+    }
+    format_ident!("{}", name, span = Span::call_site())
+}
+
+/// Convert a string to a lowercase identifier
+fn sanitize(s: &str) -> String {
+    let mut ident: String = s
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if ident.starts_with(|c: char| c.is_ascii_digit()) {
+        ident.insert(0, '_');
+    }
+    ident
+}
@@ -0,0 +1,193 @@
+//! Python bindings for [env-lock](https://docs.rs/env-lock), so Python tests
+//! running in the same process as Rust code (or vice versa) coordinate on the
+//! same lock as the Rust tests. Build the extension module with `maturin`.
+//!
+//! ```python
+//! import os
+//! import env_lock
+//!
+//! with env_lock.lock_env({"FOO": "bar", "BAZ": None}):
+//!     assert os.environ["FOO"] == "bar"
+//! ```
+//!
+//! To share the lock with Rust tests, Python must be embedded in the *same*
+//! binary as this crate's `rlib`. A separately built extension module has its
+//! own copy of env-lock, and its own lock.
+
+#![forbid(unsafe_code)]
+#![deny(clippy::all)]
+
+use env_lock::EnvGuard;
+use pyo3::{exceptions::PyRuntimeError, prelude::*, types::PyDict};
+use std::{
+    env,
+    ffi::{OsStr, OsString},
+    iter,
+};
+
+/// A context manager that locks the environment on enter, and restores and
+/// unlocks it on exit
+#[pyclass(module = "env_lock")]
+struct EnvLock {
+    variables: Vec<(String, Option<String>)>,
+    locked: Option<Locked>,
+}
+
+/// State of an entered [EnvLock]
+struct Locked {
+    /// Holds the lock, and restores the process environment as a backstop
+    guard: EnvGuard<'static>,
+    previous_values: Vec<(String, Option<OsString>)>,
+}
+
+#[pymethods]
+impl EnvLock {
+    fn __enter__(mut slf: PyRefMut<'_, Self>) -> PyResult<PyRefMut<'_, Self>> {
+        if slf.locked.is_some() {
+            return Err(PyRuntimeError::new_err(
+                "Environment is already locked",
+            ));
+        }
+        let py = slf.py();
+        // Release the GIL while waiting for the lock, otherwise we'd deadlock
+        // with any Python thread holding the lock
+        let mut guard = py.allow_threads(|| {
+            env_lock::lock_env(iter::empty::<(&str, Option<&str>)>())
+        });
+
+        let mut previous_values = Vec::with_capacity(slf.variables.len());
+        for (name, value) in &slf.variables {
+            previous_values.push((name.clone(), env::var_os(name)));
+            set_variable(
+                py,
+                &mut guard,
+                name,
+                value.as_deref().map(OsStr::new),
+            )?;
+        }
+        slf.locked = Some(Locked {
+            guard,
+            previous_values,
+        });
+        Ok(slf)
+    }
+
+    #[pyo3(signature = (_exc_type, _exc_value, _traceback))]
+    fn __exit__(
+        &mut self,
+        py: Python<'_>,
+        _exc_type: Option<&Bound<'_, PyAny>>,
+        _exc_value: Option<&Bound<'_, PyAny>>,
+        _traceback: Option<&Bound<'_, PyAny>>,
+    ) -> PyResult<bool> {
+        if let Some(mut locked) = self.locked.take() {
+            // Restore while the lock is held, so Python's copy of the
+            // environment is restored too
+            for (name, value) in locked.previous_values.iter().rev() {
+                set_variable(py, &mut locked.guard, name, value.as_deref())?;
+            }
+            drop(locked.guard);
+        }
+        // Never suppress exceptions
+        Ok(false)
+    }
+}
+
+/// Lock the environment and apply variables. The variables are set when the
+/// returned context manager is entered, and restored when it exits.
+#[pyfunction]
+fn lock_env(variables: &Bound<'_, PyDict>) -> PyResult<EnvLock> {
+    let variables = variables
+        .iter()
+        .map(|(name, value)| Ok((name.extract()?, value.extract()?)))
+        .collect::<PyResult<_>>()?;
+    Ok(EnvLock {
+        variables,
+        locked: None,
+    })
+}
+
+/// Set or remove a variable through the guard, then update `os.environ` to
+/// match. Python caches the environment at startup, so modifying only the
+/// process environment wouldn't be visible to Python code. Updating
+/// `os.environ` writes the same value to the process environment again.
+fn set_variable(
+    py: Python<'_>,
+    guard: &mut EnvGuard<'static>,
+    name: &str,
+    value: Option<&OsStr>,
+) -> PyResult<()> {
+    let environ = py.import_bound("os")?.getattr("environ")?;
+    if let Some(value) = value {
+        guard.set(name, value);
+        environ.set_item(name, value)?;
+    } else {
+        guard.remove(name);
+        environ.call_method1("pop", (name, py.None()))?;
+    }
+    Ok(())
+}
+
+#[pymodule]
+#[pyo3(name = "env_lock")]
+fn env_lock_module(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_class::<EnvLock>()?;
+    module.add_function(wrap_pyfunction!(lock_env, module)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pyo3::types::PyModule;
+
+    /// Lock the environment from Python
+    #[test]
+    fn lock_from_python() {
+        let var = "ENV_LOCK_PYTHON_TEST";
+        env::set_var(var, "existing");
+
+        Python::with_gil(|py| {
+            let module = PyModule::new_bound(py, "env_lock").unwrap();
+            env_lock_module(&module).unwrap();
+            let locals = PyDict::new_bound(py);
+            locals.set_item("env_lock", module).unwrap();
+            py.run_bound(
+                r#"
+import os
+with env_lock.lock_env({"ENV_LOCK_PYTHON_TEST": None}):
+    assert "ENV_LOCK_PYTHON_TEST" not in os.environ
+"#,
+                None,
+                Some(&locals),
+            )
+            .unwrap();
+        });
+
+        assert_eq!(env::var(var).unwrap(), "existing");
+    }
+
+    /// Non-UTF-8 values are restored faithfully
+    #[cfg(unix)]
+    #[test]
+    fn restore_non_utf8() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let var = "ENV_LOCK_PYTHON_TEST_NON_UTF8";
+        let existing = OsStr::from_bytes(b"\xff");
+        env::set_var(var, existing);
+
+        Python::with_gil(|py| {
+            let variables = PyDict::new_bound(py);
+            variables.set_item(var, "new").unwrap();
+            let lock = Bound::new(py, lock_env(&variables).unwrap()).unwrap();
+            lock.call_method0("__enter__").unwrap();
+            assert_eq!(env::var(var).unwrap(), "new");
+            lock.call_method1("__exit__", (py.None(), py.None(), py.None()))
+                .unwrap();
+        });
+
+        assert_eq!(env::var_os(var).unwrap(), existing);
+        env::remove_var(var);
+    }
+}
@@ -0,0 +1,75 @@
+//! A single lock shared by every version of
+//! [env-lock](https://docs.rs/env-lock) in a process. Each version of env-lock
+//! has its own statics, so if two incompatible versions end up in the same
+//! test binary (e.g. via transitive dependencies), their locks wouldn't
+//! protect each other. Cargo unifies all semver-compatible versions of this
+//! crate though, so as long as this crate never makes a breaking change, every
+//! version of env-lock will share the lock defined here.
+//!
+//! **This crate is an implementation detail of env-lock.** You probably want to
+//! use env-lock directly.
+
+#![forbid(unsafe_code)]
+#![deny(clippy::all)]
+
+use std::sync::{Condvar, Mutex, MutexGuard};
+
+static LOCKED: Mutex<bool> = Mutex::new(false);
+static RELEASED: Condvar = Condvar::new();
+
+/// Block until the process lock is available, then acquire it. The lock is
+/// released when the returned token is dropped.
+pub fn lock() -> ProcessLockToken {
+    let mut locked = state();
+    while *locked {
+        locked = RELEASED
+            .wait(locked)
+            .unwrap_or_else(|error| error.into_inner());
+    }
+    *locked = true;
+    ProcessLockToken { _private: () }
+}
+
+/// Proof that the process lock is held. The lock is released when this is
+/// dropped. This can be sent to and released from any thread.
+pub struct ProcessLockToken {
+    _private: (),
+}
+
+impl Drop for ProcessLockToken {
+    fn drop(&mut self) {
+        *state() = false;
+        RELEASED.notify_one();
+    }
+}
+
+/// Lock the internal state. It's only held for short, non-panicking critical
+/// sections, so we can ignore poisoning.
+fn state() -> MutexGuard<'static, bool> {
+    LOCKED.lock().unwrap_or_else(|error| error.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::mpsc, thread, time::Duration};
+
+    /// The lock can't be acquired until the previous token is dropped
+    #[test]
+    fn exclusive() {
+        let token = lock();
+        let (sender, receiver) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            let _token = lock();
+            sender.send(()).unwrap();
+        });
+
+        assert_eq!(
+            receiver.recv_timeout(Duration::from_millis(100)),
+            Err(mpsc::RecvTimeoutError::Timeout)
+        );
+        drop(token);
+        receiver.recv().unwrap();
+        handle.join().unwrap();
+    }
+}
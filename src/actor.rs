@@ -0,0 +1,220 @@
+//! An alternative to holding the lock directly, where all environment
+//! mutations are performed by a single dedicated manager thread. Callers
+//! request a [Lease] from an [EnvManager], and the manager applies the
+//! variables, waits for the lease to be dropped, then restores the
+//! environment. Because every mutation happens on the manager thread, the
+//! environment is only ever modified from one place, no matter which threads
+//! request and drop the leases.
+//!
+//! The manager acquires the same global lock as [lock_env], so leases and
+//! guards can be used side by side. A thread can't request a lease while it
+//! holds a guard or another lease from the same manager, because the
+//! manager would wait forever for that to be dropped.
+//!
+//! ```
+//! use env_lock::actor::EnvManager;
+//! use std::env;
+//!
+//! let var = "ENV_LOCK_ACTOR_VARIABLE";
+//! let manager = EnvManager::spawn();
+//!
+//! let lease = manager.lease([(var, Some("hello!"))]);
+//! assert_eq!(env::var(var).unwrap(), "hello!");
+//! drop(lease);
+//!
+//! assert!(env::var(var).is_err());
+//! ```
+
+use crate::{
+    expand_names, lock::ENV_LOCK, lock_env, IntoEnvValue, VariableNames,
+};
+use std::{
+    ffi::OsString,
+    panic::{self, AssertUnwindSafe, Location},
+    sync::{mpsc, Mutex},
+    thread,
+};
+
+/// A handle to a manager thread that owns all environment mutations. The
+/// thread exits once every handle to it has been dropped.
+pub struct EnvManager {
+    // Wrapped in a mutex so the manager can be shared between threads
+    sender: Mutex<mpsc::Sender<LeaseRequest>>,
+}
+
+/// A request for the manager to apply some variables, and grant a lease once
+/// they've been applied
+struct LeaseRequest {
+    variables: Vec<(String, Option<OsString>)>,
+    /// If applying the variables panicked, the panic is sent back here so it
+    /// can be propagated to the requester
+    grant: mpsc::Sender<thread::Result<Lease>>,
+}
+
+impl EnvManager {
+    /// Spawn a new manager thread
+    pub fn spawn() -> Self {
+        let (sender, receiver) = mpsc::channel::<LeaseRequest>();
+        thread::Builder::new()
+            .name("env-lock-manager".into())
+            .spawn(move || {
+                // Requests are granted one at a time, in the order received
+                for request in receiver {
+                    handle_request(request);
+                }
+            })
+            .expect("Error spawning env-lock manager thread");
+        Self {
+            sender: Mutex::new(sender),
+        }
+    }
+
+    /// Request a lease on the environment, with each given variable set to
+    /// its corresponding value. Accepts the same names and values as
+    /// [EnvGuard::child](crate::EnvGuard::child). This will block until the
+    /// manager has acquired the lock and applied the variables. The
+    /// environment will be restored and unlocked when the returned lease is
+    /// dropped.
+    ///
+    /// ## Panics
+    /// Panics if the current thread holds an [EnvGuard](crate::EnvGuard),
+    /// because the manager would wait forever for it to be dropped. Holding
+    /// another lease from this thread would also wait forever, but leases can
+    /// be sent between threads, so that isn't detected.
+    #[track_caller]
+    pub fn lease<'a>(
+        &self,
+        variables: impl IntoIterator<
+            Item = (impl VariableNames<'a>, Option<impl IntoEnvValue>),
+        >,
+    ) -> Lease {
+        ENV_LOCK.assert_not_held(Location::caller());
+        let variables = expand_names(variables)
+            .map(|(variable, value)| (variable.into_owned(), value))
+            .collect();
+        let (grant, granted) = mpsc::channel();
+        self.sender
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+            .send(LeaseRequest { variables, grant })
+            .expect("env-lock manager thread has exited");
+        match granted.recv().expect("env-lock manager thread has exited") {
+            Ok(lease) => lease,
+            Err(error) => panic::resume_unwind(error),
+        }
+    }
+}
+
+/// Apply a single request, then hold the lock until its lease is dropped
+fn handle_request(request: LeaseRequest) {
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        lock_env(
+            request
+                .variables
+                .iter()
+                .map(|(variable, value)| (variable.as_str(), value.as_deref())),
+        )
+    }));
+    match result {
+        Ok(guard) => {
+            let (release, released) = mpsc::channel();
+            let (restore, restored) = mpsc::channel();
+            let lease = Lease { release, restored };
+            if request.grant.send(Ok(lease)).is_ok() {
+                // Either a message or a disconnect means the lease was dropped
+                let _ = released.recv();
+            }
+            drop(guard);
+            let _ = restore.send(());
+        }
+        Err(error) => {
+            let _ = request.grant.send(Err(error));
+        }
+    }
+}
+
+/// Exclusive access to the environment, granted by an [EnvManager]. The
+/// environment will be restored and unlocked when this is dropped. This can
+/// be sent to and dropped on any thread.
+pub struct Lease {
+    release: mpsc::Sender<()>,
+    restored: mpsc::Receiver<()>,
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        // Wait for the manager to restore the environment, so the caller can
+        // rely on it being restored once the lease is gone
+        let _ = self.release.send(());
+        let _ = self.restored.recv();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    /// A lease can be moved to and dropped on another thread
+    #[test]
+    fn send_lease() {
+        let var = "ENV_LOCK_TEST_VARIABLE_ACTOR_SEND";
+        let manager = EnvManager::spawn();
+
+        let lease = manager.lease([(var, Some("hello!"))]);
+        thread::spawn(move || {
+            assert_eq!(env::var(var).unwrap(), "hello!");
+            drop(lease);
+        })
+        .join()
+        .unwrap();
+
+        assert!(env::var(var).is_err());
+    }
+
+    /// A panic while holding a lease restores the environment and doesn't
+    /// affect the manager
+    #[test]
+    fn panic_in_holder() {
+        let var = "ENV_LOCK_TEST_VARIABLE_ACTOR_PANIC";
+        let manager = EnvManager::spawn();
+
+        panic::catch_unwind(|| {
+            let _lease = manager.lease([(var, Some("panicked!"))]);
+            panic!("oh no!");
+        })
+        .unwrap_err();
+        assert!(env::var(var).is_err());
+
+        let _lease = manager.lease([(var, Some("very calm"))]);
+        assert_eq!(env::var(var).unwrap(), "very calm");
+    }
+
+    /// Requesting a lease while holding a guard panics instead of hanging
+    #[test]
+    #[should_panic(expected = "Deadlock")]
+    fn lease_while_locked() {
+        crate::set_lock_mode(crate::LockMode::Always);
+        let manager = EnvManager::spawn();
+        let _guard =
+            lock_env([("ENV_LOCK_TEST_VARIABLE_ACTOR_DEADLOCK", None::<&str>)]);
+        let _lease =
+            manager.lease([("ENV_LOCK_TEST_VARIABLE_ACTOR_DEADLOCK", Some(1))]);
+    }
+
+    /// Leases can be validated by the stress tester, alongside regular guards
+    #[test]
+    fn stress_lease() {
+        let manager = EnvManager::spawn();
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                crate::stress(4, 50, |variables| {
+                    manager.lease(variables.iter().copied())
+                })
+            });
+            crate::stress(4, 50, |variables| {
+                lock_env(variables.iter().copied())
+            });
+        });
+    }
+}
@@ -0,0 +1,145 @@
+use crate::{expand_names, validate, EnvGuard, IntoEnvValue, VariableNames};
+use std::{
+    borrow::Cow,
+    ffi::OsString,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, MutexGuard,
+    },
+};
+
+/// Variables applied by every guard before its own
+static BASELINE: Mutex<Vec<(String, Option<OsString>)>> =
+    Mutex::new(Vec::new());
+
+/// Number of live guards that applied the baseline. Only modified while
+/// holding exclusive access to the environment, so nested guards can tell
+/// that the baseline is already in place.
+static DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+/// Set a baseline environment for the whole process. Every guard with
+/// exclusive access to the environment (e.g. from
+/// [lock_env](crate::lock_env)) applies the baseline first, then its own
+/// variables on top, so a test suite can establish a known-good environment
+/// once instead of repeating it in each test. Baseline variables are restored
+/// along with the guard's own when it's dropped. Nested guards don't
+/// re-apply the baseline, so they keep the outer guard's changes. Guards that
+/// only lock some variables, from
+/// [lock_env_vars](crate::lock_env_vars), don't apply the baseline.
+///
+/// Each call replaces the previous baseline. Call this from one-time setup,
+/// before any guards are created.
+///
+/// ```
+/// use std::env;
+///
+/// env_lock::set_baseline([
+///     ("ENV_LOCK_BASELINE_LOG", Some("debug")),
+///     ("ENV_LOCK_BASELINE_PROXY", None),
+/// ]);
+/// let guard = env_lock::lock_env([("ENV_LOCK_BASELINE_PROXY", Some("http://proxy"))]);
+/// assert_eq!(env::var("ENV_LOCK_BASELINE_LOG").unwrap(), "debug");
+/// assert_eq!(env::var("ENV_LOCK_BASELINE_PROXY").unwrap(), "http://proxy");
+/// drop(guard);
+/// assert!(env::var("ENV_LOCK_BASELINE_LOG").is_err());
+/// # env_lock::clear_baseline();
+/// ```
+///
+/// ## Panics
+/// Panics if a name or value is invalid. See
+/// [try_lock_env_checked](crate::try_lock_env_checked).
+#[track_caller]
+pub fn set_baseline<'a>(
+    variables: impl IntoIterator<
+        Item = (impl VariableNames<'a>, Option<impl IntoEnvValue>),
+    >,
+) {
+    let variables = expand_names(variables)
+        .map(|(variable, value)| {
+            if let Err(error) = validate::check(&variable, value.as_deref()) {
+                panic!("{error}");
+            }
+            (variable.into_owned(), value)
+        })
+        .collect();
+    *baseline() = variables;
+}
+
+/// Remove the baseline environment, so guards only apply their own
+/// variables. See [set_baseline].
+pub fn clear_baseline() {
+    baseline().clear();
+}
+
+/// Apply the baseline to a new guard, unless another guard already applied
+/// it. Return whether it was applied, in which case [exit] must be called
+/// when the guard is dropped. The caller must have exclusive access.
+pub(crate) fn enter(guard: &mut EnvGuard<'_>) -> bool {
+    if DEPTH.fetch_add(1, Ordering::AcqRel) > 0 {
+        DEPTH.fetch_sub(1, Ordering::AcqRel);
+        return false;
+    }
+    let variables = baseline().clone();
+    guard.apply(
+        variables
+            .into_iter()
+            .map(|(variable, value)| (Cow::Owned(variable), value)),
+    );
+    true
+}
+
+/// Mark that a guard from [enter] has released its exclusive access, so the
+/// next guard should apply the baseline
+pub(crate) fn exit() {
+    DEPTH.fetch_sub(1, Ordering::AcqRel);
+}
+
+/// Mark that a guard from [enter] has reacquired exclusive access
+pub(crate) fn reenter() {
+    DEPTH.fetch_add(1, Ordering::AcqRel);
+}
+
+/// Forget every guard that applied the baseline, after they were restored by
+/// [force_restore_all](crate::force_restore_all)
+pub(crate) fn reset() {
+    DEPTH.store(0, Ordering::Release);
+}
+
+fn baseline() -> MutexGuard<'static, Vec<(String, Option<OsString>)>> {
+    // The baseline is only replaced whole, so it can't be left inconsistent
+    BASELINE.lock().unwrap_or_else(|error| error.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lock_env, lock_raw, set_lock_mode, LockMode};
+    use std::env;
+
+    /// The baseline is applied by the outermost guard, under its overrides
+    #[test]
+    fn baseline() {
+        let var1 = "ENV_LOCK_TEST_VARIABLE_BASELINE_1";
+        let var2 = "ENV_LOCK_TEST_VARIABLE_BASELINE_2";
+        set_lock_mode(LockMode::Always);
+        // Hold the lock, so other tests don't see the baseline
+        let lock = lock_raw();
+        set_baseline([([var1, var2], Some("baseline"))]);
+        let mut outer = lock.into_guard([(var2, Some("outer"))]);
+        clear_baseline();
+        assert_eq!(env::var(var1).unwrap(), "baseline");
+        assert_eq!(env::var(var2).unwrap(), "outer");
+
+        // Nested guards don't re-apply the baseline
+        outer.set(var1, "changed");
+        set_baseline([(var1, Some("baseline"))]);
+        let nested = lock_env([(var2, Some("nested"))]);
+        clear_baseline();
+        assert_eq!(env::var(var1).unwrap(), "changed");
+        drop(nested);
+        assert_eq!(env::var(var2).unwrap(), "outer");
+        drop(outer);
+        assert!(env::var(var1).is_err());
+        assert!(env::var(var2).is_err());
+    }
+}
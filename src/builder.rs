@@ -0,0 +1,157 @@
+use crate::{sys, EnvGuard, EnvOverlay};
+use std::{borrow::Cow, path::PathBuf};
+
+/// A builder for a set of variables to lock. This lets helper functions
+/// compose partial setups, e.g. a shared base environment plus per-test
+/// overrides, instead of each building its own array of tuples. Later values
+/// for a variable replace earlier ones. Nothing is modified until
+/// [lock](Self::lock) is called.
+///
+/// ```
+/// use env_lock::EnvLock;
+/// use std::env;
+///
+/// fn base() -> EnvLock<'static> {
+///     EnvLock::new()
+///         .set("ENV_LOCK_BUILDER_HOST", "localhost")
+///         .set("ENV_LOCK_BUILDER_PORT", "80")
+/// }
+///
+/// let _guard = base()
+///     .set("ENV_LOCK_BUILDER_PORT", "8080")
+///     .unset("ENV_LOCK_BUILDER_PROXY")
+///     .lock();
+/// assert_eq!(env::var("ENV_LOCK_BUILDER_HOST").unwrap(), "localhost");
+/// assert_eq!(env::var("ENV_LOCK_BUILDER_PORT").unwrap(), "8080");
+/// assert!(env::var("ENV_LOCK_BUILDER_PROXY").is_err());
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EnvLock<'a> {
+    /// Each variable appears at most once
+    variables: Vec<(Cow<'a, str>, Option<String>)>,
+    /// Working directory to change to while locked
+    current_dir: Option<PathBuf>,
+}
+
+impl<'a> EnvLock<'a> {
+    /// Create an empty builder
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set a variable to a value
+    pub fn set(
+        self,
+        variable: impl Into<Cow<'a, str>>,
+        value: impl Into<String>,
+    ) -> Self {
+        self.insert(variable.into(), Some(value.into()))
+    }
+
+    /// Remove a variable from the environment
+    pub fn unset(self, variable: impl Into<Cow<'a, str>>) -> Self {
+        self.insert(variable.into(), None)
+    }
+
+    /// Set every variable in a map or list of pairs
+    ///
+    /// ```
+    /// use env_lock::EnvLock;
+    /// use std::collections::HashMap;
+    ///
+    /// let variables =
+    ///     HashMap::from([("ENV_LOCK_BUILDER_ALL", "1".to_owned())]);
+    /// let _guard = EnvLock::new().set_all(variables).lock();
+    /// ```
+    pub fn set_all(
+        self,
+        variables: impl IntoIterator<
+            Item = (impl Into<Cow<'a, str>>, impl Into<String>),
+        >,
+    ) -> Self {
+        variables
+            .into_iter()
+            .fold(self, |builder, (variable, value)| {
+                builder.set(variable, value)
+            })
+    }
+
+    /// Change the current working directory while locked, and restore it
+    /// when the guard is dropped. See
+    /// [EnvGuard::set_current_dir](crate::EnvGuard::set_current_dir).
+    pub fn current_dir(mut self, path: impl Into<PathBuf>) -> Self {
+        self.current_dir = Some(path.into());
+        self
+    }
+
+    /// Add all variables from another builder, with its values taking
+    /// precedence over this one. Its working directory also takes
+    /// precedence, if it has one.
+    pub fn merge(mut self, other: EnvLock<'a>) -> Self {
+        if other.current_dir.is_some() {
+            self.current_dir = other.current_dir;
+        }
+        other
+            .variables
+            .into_iter()
+            .fold(self, |builder, (variable, value)| {
+                builder.insert(variable, value)
+            })
+    }
+
+    /// Lock the environment and apply every variable. See
+    /// [lock_env](crate::lock_env).
+    ///
+    /// ## Panics
+    /// Panics if the working directory can't be changed. See
+    /// [lock_env_with_cwd](crate::lock_env_with_cwd).
+    #[track_caller]
+    pub fn lock(self) -> EnvGuard<'a> {
+        match self.current_dir {
+            Some(path) => crate::lock_env_with_cwd(self.variables, path),
+            None => EnvGuard::new(self.variables),
+        }
+    }
+
+    /// Build an [EnvOverlay] from these variables, to apply them only to
+    /// subprocesses instead of locking the environment
+    pub fn overlay(self) -> EnvOverlay<'a> {
+        EnvOverlay::from_variables(self.variables)
+    }
+
+    fn insert(mut self, variable: Cow<'a, str>, value: Option<String>) -> Self {
+        if let Some((_, existing)) = self
+            .variables
+            .iter_mut()
+            .find(|(name, _)| sys::same_name(name, &variable))
+        {
+            *existing = value;
+        } else {
+            self.variables.push((variable, value));
+        }
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    /// Later values replace earlier ones, including across merges
+    #[test]
+    fn lock() {
+        let var1 = "ENV_LOCK_TEST_VARIABLE_BUILDER_1";
+        let var2 = "ENV_LOCK_TEST_VARIABLE_BUILDER_2";
+        env::set_var(var2, "existing");
+        let base = EnvLock::new().set(var1, "base").set(var2, "base");
+        let overrides = EnvLock::new().set(var1, "override").unset(var2);
+
+        let guard = base.merge(overrides).lock();
+        assert_eq!(env::var(var1).unwrap(), "override");
+        assert!(env::var(var2).is_err());
+        drop(guard);
+        assert!(env::var(var1).is_err());
+        assert_eq!(env::var(var2).unwrap(), "existing");
+    }
+}
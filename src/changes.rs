@@ -0,0 +1,57 @@
+use crate::sys;
+use std::{collections::BTreeMap, ffi::OsString};
+
+/// A change to a variable made behind a guard's back, e.g. by the code under
+/// test. Returned by [EnvGuard::changes](crate::EnvGuard::changes). Values
+/// that aren't valid UTF-8 are converted lossily. On Windows, where names are
+/// case-insensitive, names are uppercase.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EnvChange {
+    /// Name of the changed variable
+    pub name: String,
+    /// Value established by the guard, or `None` if it was unset
+    pub before: Option<String>,
+    /// Current value, or `None` if it's now unset
+    pub after: Option<String>,
+}
+
+/// Values of every variable, to diff against later, keyed by
+/// [name_key](crate::sys::name_key). Variables whose name isn't valid UTF-8
+/// are skipped.
+pub(crate) type Baseline = BTreeMap<String, OsString>;
+
+/// Capture the current environment as a baseline
+pub(crate) fn capture() -> Baseline {
+    sys::vars_os()
+        .filter_map(|(name, value)| {
+            let name = name.into_string().ok()?;
+            Some((sys::name_key(&name).into_owned(), value))
+        })
+        .collect()
+}
+
+/// Get every difference between a baseline and the current environment,
+/// sorted by name
+pub(crate) fn diff(baseline: &Baseline) -> Vec<EnvChange> {
+    let current = capture();
+    let lossy = |value: &OsString| value.to_string_lossy().into_owned();
+    let changed = baseline
+        .iter()
+        .filter(|(name, value)| current.get(*name) != Some(*value))
+        .map(|(name, value)| EnvChange {
+            name: name.clone(),
+            before: Some(lossy(value)),
+            after: current.get(name).map(lossy),
+        });
+    let created = current
+        .iter()
+        .filter(|(name, _)| !baseline.contains_key(*name))
+        .map(|(name, value)| EnvChange {
+            name: name.clone(),
+            before: None,
+            after: Some(lossy(value)),
+        });
+    let mut changes: Vec<EnvChange> = changed.chain(created).collect();
+    changes.sort_by(|a, b| a.name.cmp(&b.name));
+    changes
+}
@@ -0,0 +1,388 @@
+use std::{
+    any::Any,
+    fmt::Debug,
+    panic::{self, AssertUnwindSafe},
+    thread,
+};
+
+/// A resource that can be acquired, producing a guard that releases it when
+/// dropped. Implement this for your own RAII resources (temp directories, mock
+/// servers, global registries, etc.) to bundle them with environment guards
+/// in a [CombinedGuard]. Any closure returning a guard is also lockable.
+pub trait Lockable {
+    /// Guard that releases the resource on drop
+    type Guard;
+
+    /// Acquire the resource
+    fn lock(self) -> Self::Guard;
+}
+
+impl<F: FnOnce() -> G, G> Lockable for F {
+    type Guard = G;
+
+    fn lock(self) -> Self::Guard {
+        self()
+    }
+}
+
+/// Two guards bundled into one, with a defined teardown order: the inner
+/// guard is acquired first and released last. Chain [and](Self::and) to
+/// bundle more guards; each one is released before the guards acquired
+/// before it.
+///
+/// The same panic-safety rules as [EnvGuard](crate::EnvGuard) apply:
+/// - If acquiring the outer resource panics, the inner guard is released
+/// - If releasing the outer guard panics, the inner guard is still released
+///   before the panic is propagated
+///
+/// ```
+/// use env_lock::CombinedGuard;
+/// use std::{env, fs};
+///
+/// let var = "ENV_LOCK_COMBINED_DIRECTORY";
+/// let directory = env::temp_dir().join("env-lock-combined-doctest");
+/// let guard = CombinedGuard::new(
+///     || env_lock::lock_env([(var, Some(directory.to_str().unwrap()))]),
+///     || TempDir::new(&directory),
+/// );
+/// assert!(fs::metadata(env::var(var).unwrap()).unwrap().is_dir());
+/// // Directory is deleted, then the environment is restored
+/// drop(guard);
+/// assert!(env::var(var).is_err());
+///
+/// struct TempDir(std::path::PathBuf);
+///
+/// impl TempDir {
+///     fn new(path: &std::path::Path) -> Self {
+///         fs::create_dir_all(path).unwrap();
+///         Self(path.to_owned())
+///     }
+/// }
+///
+/// impl Drop for TempDir {
+///     fn drop(&mut self) {
+///         let _ = fs::remove_dir_all(&self.0);
+///     }
+/// }
+/// ```
+#[derive(Debug)]
+pub struct CombinedGuard<Inner, Outer> {
+    /// Options so we can control drop order. Always populated until drop.
+    inner: Option<Inner>,
+    outer: Option<Outer>,
+}
+
+impl<Inner, Outer> CombinedGuard<Inner, Outer> {
+    /// Acquire `inner`, then `outer`
+    pub fn new(
+        inner: impl Lockable<Guard = Inner>,
+        outer: impl Lockable<Guard = Outer>,
+    ) -> Self {
+        let inner = inner.lock();
+        // If this panics, the inner guard is dropped during unwinding
+        let outer = outer.lock();
+        Self {
+            inner: Some(inner),
+            outer: Some(outer),
+        }
+    }
+
+    /// Acquire another resource, bundling it with this guard. The new
+    /// resource will be released first.
+    pub fn and<L: Lockable>(self, next: L) -> CombinedGuard<Self, L::Guard> {
+        CombinedGuard::new(|| self, next)
+    }
+
+    /// Get the guard that was acquired first
+    pub fn inner(&self) -> &Inner {
+        self.inner.as_ref().expect("Guard is populated until drop")
+    }
+
+    /// Get the guard that was acquired first
+    pub fn inner_mut(&mut self) -> &mut Inner {
+        self.inner.as_mut().expect("Guard is populated until drop")
+    }
+
+    /// Get the guard that was acquired second
+    pub fn outer(&self) -> &Outer {
+        self.outer.as_ref().expect("Guard is populated until drop")
+    }
+
+    /// Get the guard that was acquired second
+    pub fn outer_mut(&mut self) -> &mut Outer {
+        self.outer.as_mut().expect("Guard is populated until drop")
+    }
+}
+
+impl<Inner, Outer> Drop for CombinedGuard<Inner, Outer> {
+    fn drop(&mut self) {
+        let outer = self.outer.take();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| drop(outer)));
+        // Always release the inner guard, even if the outer one panicked
+        drop(self.inner.take());
+        if let Err(payload) = result {
+            // Don't double panic, which would abort
+            if !thread::panicking() {
+                panic::resume_unwind(payload);
+            }
+        }
+    }
+}
+
+/// A set of resources to acquire in a globally consistent order. Each resource
+/// is identified by a key, and resources are always acquired in ascending key
+/// order regardless of the order they were added in, then released in reverse.
+/// If every test uses the same keys for the same resources, two tests can't
+/// deadlock by acquiring the same resources in different orders.
+///
+/// ```
+/// use env_lock::{EnvGuard, OrderedLocks};
+/// use std::sync::{Mutex, MutexGuard};
+///
+/// static DATABASE: Mutex<()> = Mutex::new(());
+///
+/// // Acquired as "database", then "env", regardless of insertion order
+/// let guard = OrderedLocks::new()
+///     .with("env", || {
+///         env_lock::lock_env([("ENV_LOCK_ORDERED_VARIABLE", Some("1"))])
+///     })
+///     .with("database", || DATABASE.lock().unwrap())
+///     .lock();
+/// assert!(guard.get::<EnvGuard>(&"env").is_some());
+/// assert!(guard.get::<MutexGuard<()>>(&"database").is_some());
+/// ```
+pub struct OrderedLocks<K> {
+    resources: Vec<(K, LockFn)>,
+}
+
+type LockFn = Box<dyn FnOnce() -> Box<dyn Any>>;
+
+impl<K: Ord + Debug> OrderedLocks<K> {
+    /// Create an empty set of resources
+    pub fn new() -> Self {
+        Self {
+            resources: Vec::new(),
+        }
+    }
+
+    /// Add a resource to be acquired
+    pub fn with<L>(mut self, key: K, resource: L) -> Self
+    where
+        L: 'static + Lockable,
+        L::Guard: 'static,
+    {
+        self.resources
+            .push((key, Box::new(move || Box::new(resource.lock()))));
+        self
+    }
+
+    /// Acquire every resource, in ascending key order. If acquiring any
+    /// resource panics, those already acquired are released in reverse order.
+    ///
+    /// ## Panics
+    /// Panics if the same key was added twice, because acquiring the same
+    /// resource twice would deadlock
+    pub fn lock(mut self) -> OrderedGuard<K> {
+        self.resources.sort_by(|(a, _), (b, _)| a.cmp(b));
+        for pair in self.resources.windows(2) {
+            if pair[0].0 == pair[1].0 {
+                panic!("Resource {:?} added multiple times", pair[0].0);
+            }
+        }
+
+        // Build the guard incrementally so it releases everything acquired so
+        // far if a later resource panics
+        let mut guard = OrderedGuard {
+            guards: Vec::with_capacity(self.resources.len()),
+        };
+        for (key, lock) in self.resources {
+            let acquired = lock();
+            guard.guards.push((key, acquired));
+        }
+        guard
+    }
+}
+
+impl<K: Ord + Debug> Default for OrderedLocks<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Guards acquired by [OrderedLocks]. Each guard is released in the reverse
+/// order of acquisition when this is dropped. A panic while releasing one
+/// guard doesn't prevent releasing the rest.
+pub struct OrderedGuard<K> {
+    /// Sorted by key, which is also the acquisition order
+    guards: Vec<(K, Box<dyn Any>)>,
+}
+
+impl<K: Ord> OrderedGuard<K> {
+    /// Get the guard for a resource. Returns `None` if the key isn't present,
+    /// or the guard isn't of type `T`.
+    pub fn get<T: 'static>(&self, key: &K) -> Option<&T> {
+        let index = self.find(key)?;
+        self.guards[index].1.downcast_ref()
+    }
+
+    /// Get the guard for a resource mutably. Returns `None` if the key isn't
+    /// present, or the guard isn't of type `T`.
+    pub fn get_mut<T: 'static>(&mut self, key: &K) -> Option<&mut T> {
+        let index = self.find(key)?;
+        self.guards[index].1.downcast_mut()
+    }
+
+    /// Iterate over the keys, in acquisition order
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.guards.iter().map(|(key, _)| key)
+    }
+
+    fn find(&self, key: &K) -> Option<usize> {
+        self.guards.binary_search_by(|(k, _)| k.cmp(key)).ok()
+    }
+}
+
+impl<K> Drop for OrderedGuard<K> {
+    fn drop(&mut self) {
+        let mut first_panic = None;
+        while let Some((_, guard)) = self.guards.pop() {
+            let result = panic::catch_unwind(AssertUnwindSafe(|| drop(guard)));
+            if let Err(payload) = result {
+                first_panic.get_or_insert(payload);
+            }
+        }
+        if let Some(payload) = first_panic {
+            // Don't double panic, which would abort
+            if !thread::panicking() {
+                panic::resume_unwind(payload);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lock_env;
+    use std::{
+        env,
+        sync::{Arc, Mutex},
+    };
+
+    /// Records its name when dropped, optionally panicking
+    struct Resource {
+        name: &'static str,
+        dropped: Arc<Mutex<Vec<&'static str>>>,
+        panic: bool,
+    }
+
+    impl Drop for Resource {
+        fn drop(&mut self) {
+            self.dropped.lock().unwrap().push(self.name);
+            if self.panic {
+                panic!("{} failed to release", self.name);
+            }
+        }
+    }
+
+    /// Guards are released in reverse order of acquisition
+    #[test]
+    fn release_order() {
+        let var = "ENV_LOCK_TEST_VARIABLE_COMBINED_ORDER";
+        let dropped = Arc::new(Mutex::new(Vec::new()));
+        let resource = |name| {
+            let dropped = Arc::clone(&dropped);
+            move || Resource {
+                name,
+                dropped,
+                panic: false,
+            }
+        };
+
+        let guard = CombinedGuard::new(resource("a"), resource("b"))
+            .and(|| lock_env([(var, Some("1"))]))
+            .and(resource("c"));
+        assert_eq!(env::var(var).unwrap(), "1");
+        assert_eq!(guard.inner().inner().outer().name, "b");
+        drop(guard);
+
+        assert_eq!(*dropped.lock().unwrap(), ["c", "b", "a"]);
+        assert!(env::var(var).is_err());
+    }
+
+    /// A panic while releasing one guard doesn't prevent releasing the rest
+    #[test]
+    fn release_panic() {
+        let var = "ENV_LOCK_TEST_VARIABLE_COMBINED_PANIC";
+        let dropped = Arc::new(Mutex::new(Vec::new()));
+        let guard = CombinedGuard::new(
+            || lock_env([(var, Some("1"))]),
+            || Resource {
+                name: "resource",
+                dropped: Arc::clone(&dropped),
+                panic: true,
+            },
+        );
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| drop(guard)));
+        assert!(result.is_err());
+        assert_eq!(*dropped.lock().unwrap(), ["resource"]);
+        assert!(env::var(var).is_err());
+    }
+
+    /// Resources are acquired in key order and released in reverse
+    #[test]
+    fn ordered_locks() {
+        let var = "ENV_LOCK_TEST_VARIABLE_ORDERED";
+        let dropped = Arc::new(Mutex::new(Vec::new()));
+        let acquired = Arc::new(Mutex::new(Vec::new()));
+        let resource = |name: &'static str| {
+            let dropped = Arc::clone(&dropped);
+            let acquired = Arc::clone(&acquired);
+            move || {
+                acquired.lock().unwrap().push(name);
+                Resource {
+                    name,
+                    dropped,
+                    panic: false,
+                }
+            }
+        };
+
+        let guard = OrderedLocks::new()
+            .with(3, resource("c"))
+            .with(1, resource("a"))
+            .with(2, move || lock_env([(var, Some("1"))]))
+            .lock();
+        assert_eq!(*acquired.lock().unwrap(), ["a", "c"]);
+        assert_eq!(guard.keys().collect::<Vec<_>>(), [&1, &2, &3]);
+        assert_eq!(guard.get::<Resource>(&1).unwrap().name, "a");
+        assert!(guard.get::<Resource>(&2).is_none());
+        assert_eq!(env::var(var).unwrap(), "1");
+        drop(guard);
+
+        assert_eq!(*dropped.lock().unwrap(), ["c", "a"]);
+        assert!(env::var(var).is_err());
+    }
+
+    /// Adding the same key twice is an error
+    #[test]
+    #[should_panic(expected = "Resource 1 added multiple times")]
+    fn ordered_locks_duplicate() {
+        OrderedLocks::new().with(1, || ()).with(1, || ()).lock();
+    }
+
+    /// A panic while acquiring releases everything already acquired
+    #[test]
+    fn acquire_panic() {
+        let var = "ENV_LOCK_TEST_VARIABLE_COMBINED_ACQUIRE";
+        let result = panic::catch_unwind(|| {
+            CombinedGuard::new(
+                || lock_env([(var, Some("1"))]),
+                || -> u32 { panic!("Failed to acquire") },
+            )
+        });
+        assert!(result.is_err());
+        assert!(env::var(var).is_err());
+    }
+}
@@ -0,0 +1,121 @@
+//! Lock the environment via the [test_context] crate. [EnvContext] acquires a
+//! guard in [setup](TestContext::setup) and restores the environment in
+//! [teardown](TestContext::teardown), so tests only need a single attribute.
+//! Declare the variables by implementing [ContextVariables] on a marker type.
+//! The `test_context` attribute only accepts a plain type name, so add an
+//! alias for the context type too.
+//!
+//! Requires the `test-context` feature.
+//!
+//! ```
+//! use env_lock::context::{ContextVariables, EnvContext};
+//! use test_context::test_context;
+//!
+//! struct Database;
+//!
+//! impl ContextVariables for Database {
+//!     const VARIABLES: &'static [(&'static str, Option<&'static str>)] = &[
+//!         ("DATABASE_HOST", Some("localhost")),
+//!         ("DATABASE_PORT", Some("5432")),
+//!     ];
+//! }
+//!
+//! type DatabaseEnv = EnvContext<Database>;
+//!
+//! #[test_context(DatabaseEnv)]
+//! #[test]
+//! fn connect(env: &mut DatabaseEnv) {
+//!     let port: u16 = env.parse("DATABASE_PORT");
+//!     assert_eq!(port, 5432);
+//! }
+//! ```
+
+use crate::{lock_env, sys, EnvGuard};
+use std::{fmt::Debug, marker::PhantomData, str::FromStr};
+use test_context::TestContext;
+
+/// Variables set by an [EnvContext]
+pub trait ContextVariables {
+    /// Each variable and the value to set it to, or `None` to unset it. See
+    /// [lock_env].
+    const VARIABLES: &'static [(&'static str, Option<&'static str>)];
+}
+
+/// A [TestContext] that holds the environment lock for the duration of a
+/// test, with the variables defined by `V`. See the [module docs](self).
+pub struct EnvContext<V> {
+    guard: EnvGuard<'static>,
+    variables: PhantomData<V>,
+}
+
+impl<V> EnvContext<V> {
+    /// Get the current value of a variable, or `None` if it's unset or not
+    /// valid unicode
+    pub fn var(&self, variable: &str) -> Option<String> {
+        sys::var(variable).ok()
+    }
+
+    /// Parse the current value of a variable
+    ///
+    /// ## Panics
+    /// Panics if the variable is unset or can't be parsed as `T`
+    #[track_caller]
+    pub fn parse<T>(&self, variable: &str) -> T
+    where
+        T: FromStr,
+        T::Err: Debug,
+    {
+        let value = self
+            .var(variable)
+            .unwrap_or_else(|| panic!("Variable `{variable}` is not set"));
+        value.parse().unwrap_or_else(|error| {
+            panic!("Error parsing variable `{variable}`: {error:?}")
+        })
+    }
+
+    /// Get the underlying guard, to modify the environment further within the
+    /// test
+    pub fn guard(&mut self) -> &mut EnvGuard<'static> {
+        &mut self.guard
+    }
+}
+
+impl<V: ContextVariables> TestContext for EnvContext<V> {
+    fn setup() -> Self {
+        Self {
+            guard: lock_env(V::VARIABLES.iter().copied()),
+            variables: PhantomData,
+        }
+    }
+
+    fn teardown(self) {
+        drop(self.guard);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_context::test_context;
+
+    const VARIABLE: &str = "ENV_LOCK_TEST_VARIABLE_CONTEXT";
+
+    struct Variables;
+
+    impl ContextVariables for Variables {
+        const VARIABLES: &'static [(&'static str, Option<&'static str>)] =
+            &[(VARIABLE, Some("3"))];
+    }
+
+    type Context = EnvContext<Variables>;
+
+    /// Variables are set for the duration of the test
+    #[test_context(Context)]
+    #[test]
+    fn context(context: &mut Context) {
+        assert_eq!(context.var(VARIABLE).as_deref(), Some("3"));
+        assert_eq!(context.parse::<u32>(VARIABLE), 3);
+        context.guard().swap(VARIABLE, Some("4"));
+        assert_eq!(context.var(VARIABLE).as_deref(), Some("4"));
+    }
+}
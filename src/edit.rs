@@ -0,0 +1,261 @@
+use crate::sys;
+use std::{
+    borrow::Cow,
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+};
+
+/// Separator between entries in a list variable such as `PATH`
+const SEPARATOR: &str = if cfg!(windows) { ";" } else { ":" };
+
+/// A change to make to a variable, for [lock_env](crate::lock_env). Besides
+/// setting and unsetting, this can set a default, or add an entry to a list
+/// variable such as `PATH`, using the platform's separator (`;` on Windows,
+/// `:` elsewhere). The entry is added to the variable's value at the time
+/// it's applied, and the whole value is restored when the guard is dropped.
+///
+/// ```
+/// use env_lock::EnvEdit;
+/// use std::env;
+///
+/// let var = "ENV_LOCK_EDIT_PATH";
+/// env::set_var(var, "/usr/bin");
+/// let guard = env_lock::lock_env([
+///     (var, EnvEdit::Prepend("/test/bin")),
+///     (var, EnvEdit::Append("/fallback/bin")),
+/// ]);
+/// let paths = env::join_paths(["/test/bin", "/usr/bin", "/fallback/bin"]);
+/// assert_eq!(env::var_os(var).unwrap(), paths.unwrap());
+/// drop(guard);
+/// assert_eq!(env::var(var).unwrap(), "/usr/bin");
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EnvEdit<T> {
+    /// Set the variable to a value
+    Set(T),
+    /// Remove the variable
+    Unset,
+    /// Add an entry to the start of the list. If the variable is unset or
+    /// empty, it's set to just the entry.
+    Prepend(T),
+    /// Add an entry to the end of the list. If the variable is unset or
+    /// empty, it's set to just the entry.
+    Append(T),
+    /// Set the variable only if it isn't already set, e.g. to default
+    /// `RUST_LOG` without overriding a value exported by the developer. A
+    /// variable set to an empty string counts as set. Either way, the
+    /// variable is restored when the guard is dropped.
+    ///
+    /// ```
+    /// use env_lock::EnvEdit;
+    /// use std::env;
+    ///
+    /// let var = "ENV_LOCK_EDIT_DEFAULT";
+    /// let guard = env_lock::lock_env([(var, EnvEdit::SetIfUnset("debug"))]);
+    /// assert_eq!(env::var(var).unwrap(), "debug");
+    /// drop(guard);
+    ///
+    /// env::set_var(var, "trace");
+    /// let _guard = env_lock::lock_env([(var, EnvEdit::SetIfUnset("debug"))]);
+    /// assert_eq!(env::var(var).unwrap(), "trace");
+    /// ```
+    SetIfUnset(T),
+}
+
+impl<T: AsRef<OsStr>> EnvEdit<T> {
+    /// Get the new value for a variable, based on its current value
+    pub(crate) fn resolve(&self, variable: &str) -> Option<OsString> {
+        let join = |first: &OsStr, second: &OsStr| {
+            let mut value = first.to_owned();
+            if !first.is_empty() && !second.is_empty() {
+                value.push(SEPARATOR);
+            }
+            value.push(second);
+            value
+        };
+        let current = || sys::var_os(variable).unwrap_or_default();
+        match self {
+            Self::Set(value) => Some(value.as_ref().to_owned()),
+            Self::Unset => None,
+            Self::Prepend(entry) => Some(join(entry.as_ref(), &current())),
+            Self::Append(entry) => Some(join(&current(), entry.as_ref())),
+            Self::SetIfUnset(value) => Some(
+                sys::var_os(variable)
+                    .unwrap_or_else(|| value.as_ref().to_owned()),
+            ),
+        }
+    }
+}
+
+/// A value that can be stored in a variable. This is implemented for strings,
+/// paths, numbers, `bool`, and `char`, so values don't need to be converted
+/// to strings first. Paths and [OsString]s are stored losslessly, even if
+/// they aren't valid UTF-8.
+///
+/// ```
+/// use std::{env, path::PathBuf};
+///
+/// let _guard = env_lock::lock_env([
+///     ("ENV_LOCK_VALUE_PORT", Some(8080)),
+/// ]);
+/// let _guard = env_lock::lock_env([
+///     ("ENV_LOCK_VALUE_CONFIG", Some(PathBuf::from("/etc/config.toml"))),
+/// ]);
+/// assert_eq!(env::var("ENV_LOCK_VALUE_PORT").unwrap(), "8080");
+/// assert_eq!(env::var("ENV_LOCK_VALUE_CONFIG").unwrap(), "/etc/config.toml");
+/// ```
+pub trait IntoEnvValue {
+    /// Convert to the value to store
+    fn into_env_value(self) -> OsString;
+}
+
+/// Implement [IntoEnvValue] for types that convert to [OsString]
+macro_rules! impl_os_string {
+    ($($t:ty),* $(,)?) => {
+        $(
+            impl IntoEnvValue for $t {
+                fn into_env_value(self) -> OsString {
+                    self.into()
+                }
+            }
+        )*
+    };
+}
+
+/// Implement [IntoEnvValue] for types formatted with [Display](std::fmt::Display)
+macro_rules! impl_display {
+    ($($t:ty),* $(,)?) => {
+        $(
+            impl IntoEnvValue for $t {
+                fn into_env_value(self) -> OsString {
+                    self.to_string().into()
+                }
+            }
+        )*
+    };
+}
+
+impl_os_string!(&str, String, &String, &OsStr, OsString, &OsString, PathBuf);
+impl_display!(
+    bool, char, i8, i16, i32, i64, i128, isize, u8, u16, u32, u64, u128, usize,
+    f32, f64,
+);
+
+impl IntoEnvValue for Cow<'_, str> {
+    fn into_env_value(self) -> OsString {
+        self.into_owned().into()
+    }
+}
+
+impl IntoEnvValue for &Path {
+    fn into_env_value(self) -> OsString {
+        self.as_os_str().to_owned()
+    }
+}
+
+impl IntoEnvValue for &PathBuf {
+    fn into_env_value(self) -> OsString {
+        self.as_os_str().to_owned()
+    }
+}
+
+/// A value for [lock_env](crate::lock_env): either an [EnvEdit], or an
+/// `Option` of an [IntoEnvValue], where `None` unsets the variable
+pub trait IntoEnvEdit {
+    /// Type of the value in the edit
+    type Value: AsRef<OsStr>;
+
+    /// Convert to an edit
+    fn into_edit(self) -> EnvEdit<Self::Value>;
+}
+
+impl<T: IntoEnvValue> IntoEnvEdit for Option<T> {
+    type Value = OsString;
+
+    fn into_edit(self) -> EnvEdit<OsString> {
+        match self {
+            Some(value) => EnvEdit::Set(value.into_env_value()),
+            None => EnvEdit::Unset,
+        }
+    }
+}
+
+impl<T: AsRef<OsStr>> IntoEnvEdit for EnvEdit<T> {
+    type Value = T;
+
+    fn into_edit(self) -> EnvEdit<T> {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lock_env;
+    use std::env;
+
+    /// Entries are joined with the separator, which is skipped if the variable
+    /// is unset or empty
+    #[test]
+    fn prepend_append() {
+        let var = "ENV_LOCK_TEST_VARIABLE_EDIT";
+        let guard = lock_env([
+            (var, EnvEdit::Append("b")),
+            (var, EnvEdit::Prepend("a")),
+            (var, EnvEdit::Append("c")),
+        ]);
+        assert_eq!(
+            env::var(var).unwrap(),
+            format!("a{SEPARATOR}b{SEPARATOR}c")
+        );
+        drop(guard);
+        assert!(env::var(var).is_err());
+
+        let guard =
+            lock_env([(var, EnvEdit::Set("")), (var, EnvEdit::Prepend("a"))]);
+        assert_eq!(env::var(var).unwrap(), "a");
+        drop(guard);
+        let _guard = lock_env([(var, EnvEdit::<&str>::Unset)]);
+        assert!(env::var(var).is_err());
+    }
+
+    /// Typed values are converted without going through a `String`
+    #[test]
+    fn values() {
+        let var = "ENV_LOCK_TEST_VARIABLE_EDIT_VALUE";
+        let guard = lock_env([(var, Some(-3))]);
+        assert_eq!(env::var(var).unwrap(), "-3");
+        drop(guard);
+        let guard = crate::lock_env_vars([(var, Some(true))]);
+        assert_eq!(env::var(var).unwrap(), "true");
+        drop(guard);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            let path = Path::new(OsStr::from_bytes(b"/tmp/\xff"));
+            let guard = lock_env([(var, Some(path.to_owned()))]);
+            assert_eq!(env::var_os(var).unwrap(), path.as_os_str());
+            drop(guard);
+        }
+        assert!(env::var(var).is_err());
+    }
+
+    /// Defaults only apply to unset variables, and are restored either way
+    #[test]
+    fn set_if_unset() {
+        let var = "ENV_LOCK_TEST_VARIABLE_EDIT_DEFAULT";
+        let guard = lock_env([(var, EnvEdit::SetIfUnset("default"))]);
+        assert_eq!(env::var(var).unwrap(), "default");
+        drop(guard);
+        assert!(env::var(var).is_err());
+
+        let guard = lock_env([
+            (var, EnvEdit::Set("")),
+            (var, EnvEdit::SetIfUnset("default")),
+        ]);
+        assert_eq!(env::var(var).unwrap(), "");
+        drop(guard);
+        assert!(env::var(var).is_err());
+    }
+}
@@ -0,0 +1,227 @@
+//! Load fixtures from encrypted files, so tests that need real-ish secrets
+//! don't have to store them in plaintext. Files are decrypted with the
+//! [age](https://age-encryption.org) or [SOPS](https://getsops.io) command
+//! line tools, which must be installed and on the `PATH`. The decrypted text
+//! is then parsed with one of the [parse](crate::parse) functions.
+//!
+//! Requires the `encrypted` feature.
+//!
+//! ```no_run
+//! use env_lock::{
+//!     encrypted::{self, AgeKey, Encryption},
+//!     parse,
+//! };
+//!
+//! let key = AgeKey::File("tests/key.txt".into());
+//! let preset = encrypted::decrypt(
+//!     "tests/secrets.env.age",
+//!     Encryption::Age,
+//!     &key,
+//!     parse::shell,
+//! )
+//! .unwrap();
+//! let _guard = preset.lock();
+//! ```
+
+use crate::{parse::ParseError, presets::Preset};
+use std::{
+    error::Error,
+    fmt::{self, Debug, Display},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+/// How a file is encrypted
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Encryption {
+    /// The whole file is encrypted with `age`, in binary or armored form
+    Age,
+    /// The file is encrypted with `sops`, using an age key. The file type is
+    /// determined by `sops` from the file extension.
+    Sops,
+}
+
+/// The age identity used to decrypt a file
+pub enum AgeKey {
+    /// Path to an identity file, as generated by `age-keygen`
+    File(PathBuf),
+    /// Callback that returns the contents of an identity file, e.g. by
+    /// reading a secret from CI. Called each time a file is decrypted.
+    Callback(Box<dyn Fn() -> String + Send + Sync>),
+}
+
+impl Debug for AgeKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::File(path) => f.debug_tuple("File").field(path).finish(),
+            Self::Callback(_) => f.debug_tuple("Callback").finish(),
+        }
+    }
+}
+
+/// An error encountered while loading an encrypted file
+#[derive(Debug)]
+pub enum DecryptError {
+    /// The decryption program couldn't be run
+    Io(io::Error),
+    /// The decryption program failed
+    Command {
+        program: &'static str,
+        stderr: String,
+    },
+    /// The decrypted file isn't valid UTF-8
+    Utf8,
+    /// The decrypted file couldn't be parsed
+    Parse(ParseError),
+}
+
+impl Display for DecryptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(error) => write!(f, "Error running decryption: {error}"),
+            Self::Command { program, stderr } => {
+                write!(f, "`{program}` failed: {}", stderr.trim())
+            }
+            Self::Utf8 => write!(f, "Decrypted file is not valid UTF-8"),
+            Self::Parse(error) => write!(f, "{error}"),
+        }
+    }
+}
+
+impl Error for DecryptError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io(error) => Some(error),
+            Self::Parse(error) => Some(error),
+            Self::Command { .. } | Self::Utf8 => None,
+        }
+    }
+}
+
+/// Decrypt a file and parse its variables. `parser` is one of the
+/// [parse](crate::parse) functions, matching the format of the decrypted
+/// file. The plaintext is never written to disk.
+pub fn decrypt(
+    path: impl AsRef<Path>,
+    encryption: Encryption,
+    key: &AgeKey,
+    parser: fn(&str) -> Result<Preset, ParseError>,
+) -> Result<Preset, DecryptError> {
+    let path = path.as_ref();
+    let (program, mut command) = match encryption {
+        Encryption::Age => {
+            let mut command = Command::new("age");
+            command.arg("--decrypt");
+            match key {
+                AgeKey::File(key_path) => {
+                    command.arg("--identity").arg(key_path)
+                }
+                // Identity is passed via stdin
+                AgeKey::Callback(_) => command.args(["--identity", "-"]),
+            };
+            ("age", command)
+        }
+        Encryption::Sops => {
+            let mut command = Command::new("sops");
+            command.arg("--decrypt");
+            match key {
+                AgeKey::File(key_path) => {
+                    command.env("SOPS_AGE_KEY_FILE", key_path)
+                }
+                AgeKey::Callback(callback) => {
+                    command.env("SOPS_AGE_KEY", callback())
+                }
+            };
+            ("sops", command)
+        }
+    };
+    command.arg(path);
+
+    let stdin = match (encryption, key) {
+        (Encryption::Age, AgeKey::Callback(callback)) => Some(callback()),
+        _ => None,
+    };
+    command
+        .stdin(if stdin.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    let mut child = command.spawn().map_err(DecryptError::Io)?;
+    if let Some(stdin) = stdin {
+        // Dropping the handle closes stdin, so age knows the key is complete
+        child
+            .stdin
+            .take()
+            .expect("stdin is piped")
+            .write_all(stdin.as_bytes())
+            .map_err(DecryptError::Io)?;
+    }
+    let output = child.wait_with_output().map_err(DecryptError::Io)?;
+
+    if !output.status.success() {
+        return Err(DecryptError::Command {
+            program,
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        });
+    }
+    let plaintext =
+        String::from_utf8(output.stdout).map_err(|_| DecryptError::Utf8)?;
+    parser(&plaintext).map_err(DecryptError::Parse)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::{lock_env, parse};
+    use std::{env, fs, os::unix::fs::PermissionsExt};
+
+    /// Decrypt via a stub `age` program that checks the key from the callback
+    #[test]
+    fn decrypt_age_callback() {
+        let directory = env::temp_dir()
+            .join(format!("env-lock-encrypted-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let program = directory.join("age");
+        fs::write(
+            &program,
+            "#!/bin/sh\nread key\n[ \"$key\" = KEY ] || exit 1\ncat \"$4\"\n",
+        )
+        .unwrap();
+        fs::set_permissions(&program, fs::Permissions::from_mode(0o755))
+            .unwrap();
+        let file = directory.join("secrets.env.age");
+        fs::write(&file, "export ENV_LOCK_TEST_VARIABLE_SECRET=hunter2\n")
+            .unwrap();
+
+        // Shadow any real age installation
+        let path = env::join_paths(
+            [directory.clone()]
+                .into_iter()
+                .chain(env::split_paths(&env::var_os("PATH").unwrap())),
+        )
+        .unwrap();
+        let _guard = lock_env([("PATH", Some(path.to_str().unwrap()))]);
+        let decrypt = |key| {
+            decrypt(
+                &file,
+                Encryption::Age,
+                &AgeKey::Callback(key),
+                parse::shell,
+            )
+        };
+        let preset = decrypt(Box::new(|| "KEY\n".into())).unwrap();
+        assert_eq!(
+            preset.into_iter().collect::<Vec<_>>(),
+            [("ENV_LOCK_TEST_VARIABLE_SECRET", Some("hunter2"))]
+        );
+        assert!(matches!(
+            decrypt(Box::new(|| "WRONG\n".into())),
+            Err(DecryptError::Command { program: "age", .. })
+        ));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,144 @@
+//! Abstraction over environment access, for dependency injection. Write code
+//! against the [Environment] trait instead of calling [std::env] directly,
+//! then unit test it with a [MemoryEnv], which never touches the process
+//! environment or the global lock. Integration tests can still pass an
+//! [EnvGuard] to run the same code against the real, locked environment.
+//!
+//! ```
+//! use env_lock::environment::{Environment, MemoryEnv};
+//!
+//! fn port(env: &impl Environment) -> u16 {
+//!     env.get("PORT").and_then(|port| port.parse().ok()).unwrap_or(80)
+//! }
+//!
+//! assert_eq!(port(&MemoryEnv::from_iter([("PORT", "8080")])), 8080);
+//! assert_eq!(port(&MemoryEnv::new()), 80);
+//! ```
+
+use crate::{sys, EnvGuard};
+use std::collections::BTreeMap;
+
+/// Read and write access to a set of environment variables. Values that
+/// aren't valid UTF-8 are treated as missing.
+pub trait Environment {
+    /// Get the value of a variable
+    fn get(&self, variable: &str) -> Option<String>;
+
+    /// Set a variable to a value
+    fn set(&mut self, variable: &str, value: &str);
+
+    /// Remove a variable
+    fn remove(&mut self, variable: &str);
+
+    /// Get every variable and its value
+    fn iter(&self) -> Box<dyn Iterator<Item = (String, String)> + '_>;
+}
+
+/// The real process environment, while it's locked. Modifications are made
+/// with [EnvGuard::set] and [EnvGuard::remove], so they're restored when the
+/// guard is dropped.
+impl<'a> Environment for EnvGuard<'a> {
+    fn get(&self, variable: &str) -> Option<String> {
+        sys::var(variable).ok()
+    }
+
+    fn set(&mut self, variable: &str, value: &str) {
+        EnvGuard::set(self, variable, value);
+    }
+
+    fn remove(&mut self, variable: &str) {
+        EnvGuard::remove(self, variable);
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (String, String)> + '_> {
+        Box::new(sys::vars_os().filter_map(|(variable, value)| {
+            Some((variable.into_string().ok()?, value.into_string().ok()?))
+        }))
+    }
+}
+
+/// An in-memory environment, for unit tests that don't need the real one.
+/// It starts empty, and is unaffected by the process environment.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MemoryEnv {
+    variables: BTreeMap<String, String>,
+}
+
+impl MemoryEnv {
+    /// Create an empty environment
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Environment for MemoryEnv {
+    fn get(&self, variable: &str) -> Option<String> {
+        self.variables.get(variable).cloned()
+    }
+
+    fn set(&mut self, variable: &str, value: &str) {
+        self.variables.insert(variable.to_owned(), value.to_owned());
+    }
+
+    fn remove(&mut self, variable: &str) {
+        self.variables.remove(variable);
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (String, String)> + '_> {
+        Box::new(
+            self.variables
+                .iter()
+                .map(|(variable, value)| (variable.clone(), value.clone())),
+        )
+    }
+}
+
+impl<K: Into<String>, V: Into<String>> FromIterator<(K, V)> for MemoryEnv {
+    fn from_iter<T: IntoIterator<Item = (K, V)>>(iter: T) -> Self {
+        Self {
+            variables: iter
+                .into_iter()
+                .map(|(variable, value)| (variable.into(), value.into()))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lock_env;
+    use std::env;
+
+    /// Exercise an environment through the trait
+    fn exercise(env: &mut dyn Environment, prefix: &str) {
+        let var1 = format!("{prefix}_1");
+        let var2 = format!("{prefix}_2");
+        env.set(&var1, "1");
+        env.set(&var2, "2");
+        env.remove(&var2);
+        assert_eq!(env.get(&var1).as_deref(), Some("1"));
+        assert_eq!(env.get(&var2), None);
+        assert!(env
+            .iter()
+            .any(|(variable, value)| variable == var1 && value == "1"));
+    }
+
+    /// Both implementations behave the same, and the guard restores its
+    /// changes
+    #[test]
+    fn environment() {
+        let prefix = "ENV_LOCK_TEST_VARIABLE_ENVIRONMENT";
+        let mut memory = MemoryEnv::new();
+        exercise(&mut memory, prefix);
+        assert_eq!(
+            memory.iter().collect::<Vec<_>>(),
+            [(format!("{prefix}_1"), "1".to_owned())]
+        );
+
+        let mut guard = lock_env([(prefix, None::<&str>)]);
+        exercise(&mut guard, prefix);
+        drop(guard);
+        assert!(env::var(format!("{prefix}_1")).is_err());
+    }
+}
@@ -0,0 +1,163 @@
+//! Extension traits for building test-support libraries on top of env-lock.
+//! These are covered by the same semver guarantees as the rest of the public
+//! API. The traits are sealed, so they can't be implemented outside this
+//! crate, which allows new methods to be added without a breaking change.
+//!
+//! Most users will want to import these via the [prelude](crate::prelude).
+
+use crate::{
+    lock_env, EnvGuard, EnvOverlay, IntoEnvEdit, IntoEnvValue, VariableNames,
+};
+use std::{
+    ops::{Deref, DerefMut},
+    process::Command,
+};
+
+/// Each extension trait needs its own sealing trait, because a blanket
+/// implementation for one would conflict with the others
+mod private {
+    pub trait SealedLock {}
+    pub trait SealedCommand {}
+    pub trait SealedFixture {}
+}
+
+/// Extension for any list of variables that can be passed to [lock_env]
+///
+/// ```
+/// use env_lock::prelude::*;
+/// use std::env;
+///
+/// let var = "ENV_LOCK_EXT_LOCK_VARIABLE";
+/// let guard = [([var, "ENV_LOCK_EXT_LOCK_OTHER"], Some("hello!"))].lock_env();
+/// assert_eq!(env::var(var).unwrap(), "hello!");
+/// assert_eq!(env::var("ENV_LOCK_EXT_LOCK_OTHER").unwrap(), "hello!");
+/// ```
+pub trait LockExt<'a>: private::SealedLock {
+    /// Lock the environment and apply these variables. See [lock_env]
+    fn lock_env(self) -> EnvGuard<'a>;
+}
+
+impl<'a, I, N, E> private::SealedLock for I
+where
+    I: IntoIterator<Item = (N, E)>,
+    N: VariableNames<'a>,
+    E: IntoEnvEdit,
+{
+}
+
+impl<'a, I, N, E> LockExt<'a> for I
+where
+    I: IntoIterator<Item = (N, E)>,
+    N: VariableNames<'a>,
+    E: IntoEnvEdit,
+{
+    #[track_caller]
+    fn lock_env(self) -> EnvGuard<'a> {
+        lock_env(self)
+    }
+}
+
+/// Extension for [Command], to apply a list of variables to a subprocess
+/// *without* modifying the environment of the current process. Shorthand for
+/// [EnvOverlay::apply].
+///
+/// ```
+/// use env_lock::prelude::*;
+/// use std::process::Command;
+///
+/// let mut command = Command::new("env");
+/// command.env_vars([("FOO", Some("bar")), ("BAZ", None)]);
+/// ```
+pub trait CommandEnvExt: private::SealedCommand {
+    /// Set each variable with a value, and remove each variable with a value
+    /// of `None`. Accepts the same variables as [EnvOverlay::new].
+    fn env_vars<'a>(
+        &mut self,
+        variables: impl IntoIterator<
+            Item = (impl VariableNames<'a>, Option<impl IntoEnvValue>),
+        >,
+    ) -> &mut Self;
+}
+
+impl private::SealedCommand for Command {}
+
+impl CommandEnvExt for Command {
+    fn env_vars<'a>(
+        &mut self,
+        variables: impl IntoIterator<
+            Item = (impl VariableNames<'a>, Option<impl IntoEnvValue>),
+        >,
+    ) -> &mut Self {
+        EnvOverlay::new(variables).apply(self)
+    }
+}
+
+/// Extension for [EnvGuard], to bundle it with other fixture data
+///
+/// ```
+/// use env_lock::prelude::*;
+/// use std::env;
+///
+/// struct Config {
+///     port: u16,
+/// }
+///
+/// fn fixture() -> Fixture<'static, Config> {
+///     [("ENV_LOCK_EXT_PORT", Some("3000"))]
+///         .lock_env()
+///         .with_fixture(Config { port: 3000 })
+/// }
+///
+/// let config = fixture();
+/// assert_eq!(config.port, 3000);
+/// assert_eq!(env::var("ENV_LOCK_EXT_PORT").unwrap(), "3000");
+/// ```
+pub trait FixtureExt<'a>: private::SealedFixture {
+    /// Attach a value to this guard. The value can be accessed via [Deref],
+    /// and the environment stays locked until the fixture is dropped.
+    fn with_fixture<T>(self, value: T) -> Fixture<'a, T>;
+}
+
+impl<'a> private::SealedFixture for EnvGuard<'a> {}
+
+impl<'a> FixtureExt<'a> for EnvGuard<'a> {
+    fn with_fixture<T>(self, value: T) -> Fixture<'a, T> {
+        Fixture { value, guard: self }
+    }
+}
+
+/// A value bundled with an [EnvGuard]. Created by
+/// [FixtureExt::with_fixture]. The environment will be restored and unlocked
+/// when this is dropped.
+pub struct Fixture<'a, T> {
+    // Field order matters: the value is dropped before the environment is
+    // restored, in case it depends on the environment
+    value: T,
+    guard: EnvGuard<'a>,
+}
+
+impl<'a, T> Fixture<'a, T> {
+    /// Get a mutable reference to the guard
+    pub fn guard(&mut self) -> &mut EnvGuard<'a> {
+        &mut self.guard
+    }
+
+    /// Split the fixture into its value and guard
+    pub fn into_parts(self) -> (T, EnvGuard<'a>) {
+        (self.value, self.guard)
+    }
+}
+
+impl<'a, T> Deref for Fixture<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.value
+    }
+}
+
+impl<'a, T> DerefMut for Fixture<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.value
+    }
+}
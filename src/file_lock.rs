@@ -0,0 +1,201 @@
+//! Serialize tests across processes, via an advisory lock on a file. Cargo
+//! runs each test binary (and each doctest) in its own process, so the
+//! in-process lock can't stop them from racing on external resources that
+//! are keyed by the environment, such as temporary directories or config
+//! files. With the `file-lock` feature, each guard also holds an advisory
+//! lock on a file that's shared by every process, so env-locked tests are
+//! serialized across binaries too.
+//!
+//! By default, each Cargo workspace gets its own lock file in the system
+//! temporary directory, so unrelated projects (or CI jobs sharing a machine)
+//! don't serialize on each other. The workspace is found from
+//! `CARGO_MANIFEST_DIR`, which Cargo sets for every test process: it's the
+//! nearest directory with a `Cargo.lock`. Outside of Cargo, the lock file is
+//! `env-lock.lock` in the temporary directory.
+//!
+//! To use a specific file instead, call [set_lock_file] before the first lock,
+//! or set the `ENV_LOCK_FILE` variable for all test processes, e.g. in
+//! `.cargo/config.toml`:
+//!
+//! ```toml
+//! [env]
+//! ENV_LOCK_FILE = { value = "target/env-lock.lock", relative = true }
+//! ```
+//!
+//! File locks are only supported on Unix. On other platforms, this feature
+//! has no effect.
+
+use crate::sys;
+use std::{
+    collections::hash_map::DefaultHasher,
+    env,
+    fs::{File, OpenOptions},
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+/// Variable that overrides the path of the lock file
+const VARIABLE: &str = "ENV_LOCK_FILE";
+
+/// Name of the lock file in the temporary directory, when not running under
+/// Cargo
+const FILE_NAME: &str = "env-lock.lock";
+
+/// The lock file, once it's been chosen
+static PATH: OnceLock<PathBuf> = OnceLock::new();
+
+/// Proof that the file lock is held. The lock is released when the file is
+/// closed, i.e. when this is dropped.
+pub(crate) struct FileLockToken {
+    _file: File,
+}
+
+/// Block until the file lock is available, then acquire it. Shared locks can
+/// be held by multiple processes at once.
+///
+/// ## Panics
+/// Panics if the lock file can't be opened or locked. Continuing without the
+/// lock would silently let tests race.
+pub(crate) fn lock(exclusive: bool) -> FileLockToken {
+    lock_file(path(), exclusive)
+}
+
+fn lock_file(path: &Path, exclusive: bool) -> FileLockToken {
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .open(path)
+        .unwrap_or_else(|error| {
+            panic!("Error opening lock file {}: {error}", path.display())
+        });
+    #[cfg(unix)]
+    crate::sys::flock(&file, exclusive).unwrap_or_else(|error| {
+        panic!("Error locking lock file {}: {error}", path.display())
+    });
+    #[cfg(not(unix))]
+    let _ = exclusive;
+    FileLockToken { _file: file }
+}
+
+/// Use a specific lock file, instead of the default or the `ENV_LOCK_FILE`
+/// variable. Every process that should be serialized with this one must use
+/// the same file. This has to be called before the lock is first acquired,
+/// e.g. at the start of each test; calling it again with the same path has no
+/// effect.
+///
+/// ```
+/// env_lock::file_lock::set_lock_file(
+///     std::env::temp_dir().join("env-lock-example.lock"),
+/// );
+/// let _guard = env_lock::lock_env([("ENV_LOCK_FILE_LOCK_VARIABLE", Some("1"))]);
+/// ```
+///
+/// ## Panics
+/// Panics if a different lock file was already chosen, by an earlier call or
+/// by acquiring the lock. Switching files while a lock is held would let
+/// tests race.
+pub fn set_lock_file(path: impl Into<PathBuf>) {
+    set_path(&PATH, path.into());
+}
+
+fn set_path(cell: &OnceLock<PathBuf>, path: PathBuf) {
+    let current = cell.get_or_init(|| path.clone());
+    if *current != path {
+        panic!(
+            "Lock file is already {}, can't change it to {}",
+            current.display(),
+            path.display()
+        );
+    }
+}
+
+/// Get the path of the lock file. The path is only chosen once, so it can't
+/// change while the lock is held.
+fn path() -> &'static PathBuf {
+    PATH.get_or_init(|| {
+        sys::var_os(VARIABLE).map(PathBuf::from).unwrap_or_else(|| {
+            default_path(sys::var_os("CARGO_MANIFEST_DIR").map(PathBuf::from))
+        })
+    })
+}
+
+/// Get the lock file for the workspace containing a package, or a global lock
+/// file if there's no package. Every package in a workspace gets the same
+/// file, because they share a `Cargo.lock`.
+fn default_path(manifest_dir: Option<PathBuf>) -> PathBuf {
+    let Some(manifest_dir) = manifest_dir else {
+        return env::temp_dir().join(FILE_NAME);
+    };
+    let workspace = manifest_dir
+        .ancestors()
+        .find(|dir| dir.join("Cargo.lock").is_file())
+        .unwrap_or(&manifest_dir);
+    let mut hasher = DefaultHasher::new();
+    workspace.hash(&mut hasher);
+    env::temp_dir().join(format!("env-lock-{:016x}.lock", hasher.finish()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(unix)]
+    use std::{fs, process::Command};
+
+    /// Packages in the same workspace share a lock file, and other workspaces
+    /// get their own
+    #[test]
+    fn default_path() {
+        let root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        let path = super::default_path(Some(root.clone()));
+        assert_eq!(super::default_path(Some(root.join("ffi"))), path);
+        assert_ne!(super::default_path(Some(env::temp_dir())), path);
+        assert_eq!(super::default_path(None), env::temp_dir().join(FILE_NAME));
+    }
+
+    /// The lock file can be set repeatedly to the same path, but not changed
+    #[test]
+    fn set_path() {
+        let cell = OnceLock::new();
+        let path = env::temp_dir().join("env-lock-test-set.lock");
+        super::set_path(&cell, path.clone());
+        super::set_path(&cell, path.clone());
+        assert_eq!(cell.get(), Some(&path));
+        let result = std::panic::catch_unwind(|| {
+            super::set_path(&cell, env::temp_dir().join("other.lock"))
+        });
+        assert!(result.is_err());
+    }
+
+    /// Another process can't take an exclusive lock while any lock is held,
+    /// but can share a shared lock
+    #[cfg(unix)]
+    #[test]
+    fn exclusive() {
+        let path = env::temp_dir()
+            .join(format!("env-lock-test-{}.lock", std::process::id()));
+        // flock(1) isn't available everywhere, so use Python instead
+        let try_lock = |operation: &str| {
+            let script = format!(
+                "import fcntl, sys; \
+                fcntl.flock(open(sys.argv[1], 'w'), {operation} | fcntl.LOCK_NB)"
+            );
+            Command::new("python3")
+                .args(["-c", &script])
+                .arg(&path)
+                .status()
+                .expect("Error running python3, which this test requires")
+                .success()
+        };
+
+        let token = lock_file(&path, true);
+        assert!(!try_lock("fcntl.LOCK_SH"));
+        drop(token);
+        let token = lock_file(&path, false);
+        assert!(try_lock("fcntl.LOCK_SH"));
+        assert!(!try_lock("fcntl.LOCK_EX"));
+        drop(token);
+        assert!(try_lock("fcntl.LOCK_EX"));
+        fs::remove_file(&path).unwrap();
+    }
+}
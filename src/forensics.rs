@@ -0,0 +1,223 @@
+//! Capture the environment when a test panics while holding a guard, for
+//! debugging flaky tests after the fact. Once [install_panic_hook] is called,
+//! any panic on a thread holding a guard writes a report to
+//! `target/env-lock-forensics/`, including:
+//!
+//! - The panic message and location
+//! - How long the guard waited for the lock, and how long it's held it
+//! - Each managed variable's value from before the lock, and its current value
+//! - The full process environment
+//!
+//! Upload the directory as a CI artifact to investigate failures that only
+//! happen in CI. **The report includes every environment variable**, so don't
+//! publish it if your environment contains secrets.
+//!
+//! ```
+//! env_lock::forensics::install_panic_hook();
+//! ```
+
+use crate::sys;
+use std::{
+    env,
+    ffi::OsStr,
+    fmt::Write,
+    fs,
+    panic::{self, PanicInfo},
+    path::PathBuf,
+    process,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex, MutexGuard, Once,
+    },
+    thread::{self, ThreadId},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Directory name for reports, within the target directory
+const DIRECTORY: &str = "env-lock-forensics";
+
+static INSTALL: Once = Once::new();
+/// Are guards being tracked?
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+/// Every live guard, while enabled
+static GUARDS: Mutex<Vec<GuardRecord>> = Mutex::new(Vec::new());
+/// Override for the output directory
+static OUTPUT_DIRECTORY: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// What we know about a live guard
+struct GuardRecord {
+    id: u64,
+    /// Thread that acquired the guard
+    thread: ThreadId,
+    wait_duration: Duration,
+    acquired_at: Instant,
+    /// Each managed variable, and its value from before the lock
+    variables: Vec<(String, Option<String>)>,
+}
+
+/// Install a panic hook that writes a report whenever a thread holding a
+/// guard panics. The previous panic hook is still called afterward. Only
+/// guards created after this is called are tracked. Calling this more than
+/// once has no effect.
+pub fn install_panic_hook() {
+    INSTALL.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            write_report(info);
+            previous(info);
+        }));
+        ENABLED.store(true, Ordering::Relaxed);
+    });
+}
+
+/// Write reports to the given directory instead of
+/// `target/env-lock-forensics/`
+pub fn set_output_directory(directory: impl Into<PathBuf>) {
+    *OUTPUT_DIRECTORY
+        .lock()
+        .unwrap_or_else(|error| error.into_inner()) = Some(directory.into());
+}
+
+/// Start tracking a new guard. Returns `None` if the hook isn't installed.
+pub(crate) fn register(
+    wait_duration: Duration,
+    acquired_at: Instant,
+) -> Option<u64> {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return None;
+    }
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    lock_guards().push(GuardRecord {
+        id,
+        thread: thread::current().id(),
+        wait_duration,
+        acquired_at,
+        variables: Vec::new(),
+    });
+    Some(id)
+}
+
+/// Replace the list of variables managed by a guard
+pub(crate) fn update<'a>(
+    id: u64,
+    variables: impl Iterator<Item = (&'a str, Option<&'a OsStr>)>,
+) {
+    if let Some(record) =
+        lock_guards().iter_mut().find(|record| record.id == id)
+    {
+        record.variables = variables
+            .map(|(name, previous)| {
+                (
+                    name.to_owned(),
+                    previous.map(|value| value.to_string_lossy().into_owned()),
+                )
+            })
+            .collect();
+    }
+}
+
+/// Stop tracking a guard
+pub(crate) fn unregister(id: u64) {
+    lock_guards().retain(|record| record.id != id);
+}
+
+fn lock_guards() -> MutexGuard<'static, Vec<GuardRecord>> {
+    GUARDS.lock().unwrap_or_else(|error| error.into_inner())
+}
+
+/// Write a report if the current thread holds any guards
+fn write_report(info: &PanicInfo) {
+    // If we panicked while holding the registry lock, we can't report
+    let Ok(guards) = GUARDS.try_lock() else {
+        return;
+    };
+    let current = thread::current();
+    let held: Vec<&GuardRecord> = guards
+        .iter()
+        .filter(|record| record.thread == current.id())
+        .collect();
+    if held.is_empty() {
+        return;
+    }
+
+    let thread_name = current.name().unwrap_or("<unnamed>");
+    let mut report = String::new();
+    let _ = writeln!(report, "Panic: {info}");
+    let _ = writeln!(report, "Thread: {thread_name}");
+    for record in held {
+        let _ = writeln!(report, "\nGuard {}", record.id);
+        let _ =
+            writeln!(report, "  Waited for lock: {:?}", record.wait_duration);
+        let _ = writeln!(
+            report,
+            "  Held lock for: {:?}",
+            record.acquired_at.elapsed()
+        );
+        let _ = writeln!(report, "  Variables:");
+        for (name, previous) in &record.variables {
+            let _ = writeln!(report, "    {name}");
+            let _ = writeln!(report, "      Before lock: {previous:?}");
+            let _ =
+                writeln!(report, "      Current: {:?}", sys::var(name).ok());
+        }
+    }
+    drop(guards);
+
+    let mut environment: Vec<_> = sys::vars_os().collect();
+    environment.sort();
+    let _ = writeln!(report, "\nEnvironment:");
+    for (name, value) in environment {
+        let _ = writeln!(
+            report,
+            "  {}={}",
+            name.to_string_lossy(),
+            value.to_string_lossy()
+        );
+    }
+
+    let directory = output_directory();
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let file_name: String = thread_name
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '_' })
+        .collect();
+    let path = directory
+        .join(format!("{file_name}-{}-{timestamp}.txt", process::id()));
+    let result =
+        fs::create_dir_all(&directory).and_then(|()| fs::write(&path, report));
+    match result {
+        Ok(()) => eprintln!("env-lock forensics written to {}", path.display()),
+        Err(error) => eprintln!(
+            "Error writing env-lock forensics to {}: {error}",
+            path.display()
+        ),
+    }
+}
+
+/// Get the directory to write reports to. By default, this is within the
+/// `target` directory containing the current executable, which is where
+/// Cargo puts test binaries.
+fn output_directory() -> PathBuf {
+    if let Some(directory) = OUTPUT_DIRECTORY
+        .lock()
+        .unwrap_or_else(|error| error.into_inner())
+        .clone()
+    {
+        return directory;
+    }
+    env::current_exe()
+        .ok()
+        .and_then(|exe| {
+            exe.ancestors()
+                .find(|path| {
+                    path.file_name().is_some_and(|name| name == "target")
+                })
+                .map(PathBuf::from)
+        })
+        .unwrap_or_else(env::temp_dir)
+        .join(DIRECTORY)
+}
@@ -0,0 +1,133 @@
+//! Environment setup for fuzz targets. Fuzzers such as `cargo-fuzz` run every
+//! iteration on a single thread of a single process, so the global lock used
+//! by [lock_env](crate::lock_env) is pure overhead there. [apply] sets
+//! variables derived from the fuzzer's input without touching the lock, and
+//! guarantees the environment is restored before the next iteration begins.
+//!
+//! ```
+//! use std::env;
+//!
+//! let var = "ENV_LOCK_FUZZ_VARIABLE";
+//! // This is the body of a fuzz target
+//! let fuzz_target = |data: &[u8]| {
+//!     let _guard = env_lock::fuzz::apply(&[var], data);
+//!     // ...call the code under test...
+//! };
+//!
+//! fuzz_target(b"hello!");
+//! assert!(env::var(var).is_err());
+//! ```
+
+use crate::{set_or_remove, sys};
+use std::{cell::RefCell, ffi::OsString, marker::PhantomData};
+
+thread_local! {
+    /// Previous values of every variable modified by the current iteration.
+    /// This lives outside the guard so that if the guard is leaked (e.g. the
+    /// target returned early via `mem::forget` or a caught panic), the next
+    /// call to [apply] can still restore the environment.
+    static PENDING: RefCell<Vec<(String, Option<OsString>)>> =
+        const { RefCell::new(Vec::new()) };
+}
+
+/// Set the given variables from raw fuzzer input, *without* acquiring the
+/// global environment lock. The input is split on NUL bytes, and each chunk
+/// becomes the value of the corresponding variable in `variables`. Any
+/// variables without a corresponding chunk are removed. Invalid UTF-8 is
+/// replaced lossily.
+///
+/// If the guard from a previous iteration was never dropped, its changes are
+/// reverted before any new values are applied, so state can never leak from
+/// one iteration to the next.
+///
+/// ## Note
+/// This performs no synchronization, and is only intended for single-threaded
+/// fuzz processes. Don't use it in a test suite; use
+/// [lock_env](crate::lock_env) instead.
+pub fn apply(variables: &[&str], data: &[u8]) -> FuzzGuard {
+    restore_pending();
+
+    let mut chunks = data.split(|byte| *byte == 0);
+    let previous_values = variables
+        .iter()
+        .map(|&variable| {
+            let previous_value = sys::var_os(variable);
+            let new_value = chunks.next().map(String::from_utf8_lossy);
+            set_or_remove(variable, new_value.as_deref());
+            (variable.to_owned(), previous_value)
+        })
+        .collect();
+    PENDING.with(|pending| *pending.borrow_mut() = previous_values);
+
+    FuzzGuard {
+        _not_send: PhantomData,
+    }
+}
+
+/// Restore every variable modified by the last call to [apply]
+fn restore_pending() {
+    let previous_values = PENDING.with(|pending| pending.take());
+    for (variable, value) in &previous_values {
+        set_or_remove(variable, value.as_deref());
+    }
+}
+
+/// A guard returned by [apply]. This will restore the environment on drop.
+/// If the guard is leaked, the environment will instead be restored by the
+/// next call to [apply].
+pub struct FuzzGuard {
+    /// Restoration state is thread-local, so the guard must stay on the
+    /// thread that created it
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Drop for FuzzGuard {
+    fn drop(&mut self) {
+        restore_pending();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lock_raw, set_lock_mode, LockMode};
+    use std::{env, mem};
+
+    // [apply] doesn't take the lock, so these tests hold it themselves to
+    // run alongside the rest of the suite
+
+    /// Each chunk of input is assigned to a variable, and missing chunks
+    /// remove the variable
+    #[test]
+    fn apply_input() {
+        let vars = ["ENV_LOCK_TEST_FUZZ_A", "ENV_LOCK_TEST_FUZZ_B"];
+        set_lock_mode(LockMode::Always);
+        let _lock = lock_raw();
+        env::set_var(vars[1], "existing");
+
+        let guard = apply(&vars, b"hello!");
+        assert_eq!(env::var(vars[0]).unwrap(), "hello!");
+        assert!(env::var(vars[1]).is_err());
+        drop(guard);
+
+        assert!(env::var(vars[0]).is_err());
+        assert_eq!(env::var(vars[1]).unwrap(), "existing");
+    }
+
+    /// A leaked guard should be cleaned up by the next iteration
+    #[test]
+    fn restore_leaked() {
+        let var = "ENV_LOCK_TEST_FUZZ_LEAKED";
+        set_lock_mode(LockMode::Always);
+        let _lock = lock_raw();
+
+        mem::forget(apply(&[var], b"first"));
+        assert_eq!(env::var(var).unwrap(), "first");
+
+        let guard = apply(&[var], b"");
+        assert_eq!(env::var(var).unwrap(), "");
+        drop(guard);
+
+        assert!(env::var(var).is_err());
+    }
+}
@@ -0,0 +1,371 @@
+//! Record every variable that's set or read through env-lock, to build an
+//! inventory of the variables your code uses. For example, you can generate
+//! "supported environment variables" docs from your test suite.
+//!
+//! Recording is off by default. Turn it on with [enable], or by setting
+//! `ENV_LOCK_INVENTORY` to a file path before running tests. With the
+//! variable, the inventory is written to that path whenever a new variable is
+//! recorded, so it's complete when the test run exits. The format is JSON if
+//! the path ends in `.json`, otherwise Markdown.
+//!
+//! ```sh
+//! ENV_LOCK_INVENTORY=target/env-inventory.json cargo test
+//! ```
+//!
+//! Variables are recorded when set by a guard (including when restored), and
+//! when read via [EnvSnapshot::capture](crate::EnvSnapshot::capture) or an
+//! [EnvSchema](crate::schema::EnvSchema) check.
+//!
+//! ## Per-test reports
+//! Each use is also attributed to the current test, based on the thread name
+//! (the standard test harness names each test's thread after the test). Use
+//! [tests] to see which variables each test set and read, e.g. to find tests
+//! that don't need the global lock. Set `ENV_LOCK_TEST_REPORT` to a file path
+//! to write the per-test report, in the same way as `ENV_LOCK_INVENTORY`.
+//! Variables restored by a guard that was sent to another thread are
+//! attributed to that thread.
+
+use crate::sys;
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, MutexGuard, OnceLock,
+    },
+    thread,
+};
+
+/// Variable that enables recording, and where to write the inventory
+const INVENTORY_VARIABLE: &str = "ENV_LOCK_INVENTORY";
+/// Variable that enables recording, and where to write the per-test report
+const REPORT_VARIABLE: &str = "ENV_LOCK_TEST_REPORT";
+
+/// Enabled via [enable]
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Output paths from the environment, loaded once
+static OUTPUT_PATHS: OnceLock<OutputPaths> = OnceLock::new();
+static INVENTORY: Mutex<Inventory> = Mutex::new(Inventory {
+    variables: BTreeMap::new(),
+    tests: BTreeMap::new(),
+});
+/// Held while rendering and writing an output file. Output is rendered from
+/// the latest state while this is held, so the last file written is always
+/// the newest.
+static OUTPUT: Mutex<()> = Mutex::new(());
+
+struct Inventory {
+    /// Usage across all tests
+    variables: BTreeMap<String, Usage>,
+    /// Usage by each test, keyed by test (thread) name
+    tests: BTreeMap<String, BTreeMap<String, Usage>>,
+}
+
+struct OutputPaths {
+    inventory: Option<PathBuf>,
+    report: Option<PathBuf>,
+}
+
+/// How a variable was used
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+struct Usage {
+    set: bool,
+    read: bool,
+}
+
+/// A variable in the inventory
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VariableUsage {
+    /// Variable name
+    pub name: String,
+    /// Was the variable set or removed by a guard?
+    pub set: bool,
+    /// Was the variable read through env-lock?
+    pub read: bool,
+}
+
+/// Variables used by a single test
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TestUsage {
+    /// Test name, taken from the thread name
+    pub test: String,
+    /// Variables used by the test, sorted by name
+    pub variables: Vec<VariableUsage>,
+}
+
+/// Start recording variables for the rest of the process
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Get every variable recorded so far, sorted by name
+pub fn variables() -> Vec<VariableUsage> {
+    to_usages(&lock_inventory().variables)
+}
+
+/// Get the variables used by each test so far, sorted by test name
+pub fn tests() -> Vec<TestUsage> {
+    lock_inventory()
+        .tests
+        .iter()
+        .map(|(test, variables)| TestUsage {
+            test: test.clone(),
+            variables: to_usages(variables),
+        })
+        .collect()
+}
+
+/// Render the inventory as a JSON array of objects with the fields `name`,
+/// `set`, and `read`
+pub fn to_json() -> String {
+    let mut json = variables_json(&variables(), "");
+    json.push('\n');
+    json
+}
+
+/// Render the inventory as a Markdown table
+pub fn to_markdown() -> String {
+    variables_markdown(&variables())
+}
+
+/// Render the per-test report as a JSON array of objects with the fields
+/// `test` and `variables`, where `variables` is in the same format as
+/// [to_json]
+pub fn tests_to_json() -> String {
+    let mut json = String::from("[");
+    for (i, test) in tests().iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        let _ = write!(
+            json,
+            "\n  {{\"test\": \"{}\", \"variables\": {}}}",
+            escape_json(&test.test),
+            variables_json(&test.variables, "  ")
+        );
+    }
+    json.push_str("\n]\n");
+    json
+}
+
+/// Render the per-test report as Markdown, with a table for each test
+pub fn tests_to_markdown() -> String {
+    tests()
+        .iter()
+        .map(|test| {
+            format!(
+                "## {}\n\n{}",
+                test.test,
+                variables_markdown(&test.variables)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Record that a variable was set or removed
+pub(crate) fn record_set(variable: &str) {
+    record(variable, |usage| usage.set = true);
+}
+
+/// Record that a variable was read
+pub(crate) fn record_read(variable: &str) {
+    record(variable, |usage| usage.read = true);
+}
+
+fn record(variable: &str, update: impl Fn(&mut Usage)) {
+    let paths = OUTPUT_PATHS.get_or_init(|| OutputPaths {
+        inventory: sys::var_os(INVENTORY_VARIABLE).map(PathBuf::from),
+        report: sys::var_os(REPORT_VARIABLE).map(PathBuf::from),
+    });
+    if !ENABLED.load(Ordering::Relaxed)
+        && paths.inventory.is_none()
+        && paths.report.is_none()
+    {
+        return;
+    }
+
+    let thread = thread::current();
+    let test = thread.name().unwrap_or("<unnamed>");
+    let mut inventory = lock_inventory();
+    let inventory_changed =
+        update_usage(&mut inventory.variables, variable, &update);
+    let report_changed = update_usage(
+        inventory.tests.entry(test.to_owned()).or_default(),
+        variable,
+        &update,
+    );
+    drop(inventory);
+
+    if inventory_changed {
+        if let Some(path) = &paths.inventory {
+            write_output(path, to_json, to_markdown);
+        }
+    }
+    if report_changed {
+        if let Some(path) = &paths.report {
+            write_output(path, tests_to_json, tests_to_markdown);
+        }
+    }
+}
+
+/// Update a variable's usage, and return whether it changed
+fn update_usage(
+    usages: &mut BTreeMap<String, Usage>,
+    variable: &str,
+    update: impl Fn(&mut Usage),
+) -> bool {
+    let usage = usages.entry(variable.to_owned()).or_default();
+    let before = *usage;
+    update(usage);
+    *usage != before
+}
+
+/// Write a file as JSON if it has a `.json` extension, otherwise Markdown
+fn write_output(
+    path: &Path,
+    json: impl Fn() -> String,
+    markdown: impl Fn() -> String,
+) {
+    // Nothing is left inconsistent by a panic while writing
+    let _output = OUTPUT.lock().unwrap_or_else(|error| error.into_inner());
+    let contents = if path.extension().is_some_and(|ext| ext == "json") {
+        json()
+    } else {
+        markdown()
+    };
+    if let Err(error) = fs::write(path, contents) {
+        eprintln!(
+            "Error writing env-lock inventory to {}: {error}",
+            path.display()
+        );
+    }
+}
+
+fn to_usages(usages: &BTreeMap<String, Usage>) -> Vec<VariableUsage> {
+    usages
+        .iter()
+        .map(|(name, usage)| VariableUsage {
+            name: name.clone(),
+            set: usage.set,
+            read: usage.read,
+        })
+        .collect()
+}
+
+/// Render variables as a JSON array, with each line after the first indented
+fn variables_json(variables: &[VariableUsage], indent: &str) -> String {
+    let mut json = String::from("[");
+    for (i, variable) in variables.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        let _ = write!(
+            json,
+            "\n{indent}  {{\"name\": \"{}\", \"set\": {}, \"read\": {}}}",
+            escape_json(&variable.name),
+            variable.set,
+            variable.read
+        );
+    }
+    let _ = write!(json, "\n{indent}]");
+    json
+}
+
+fn variables_markdown(variables: &[VariableUsage]) -> String {
+    let mut markdown =
+        String::from("| Variable | Set | Read |\n| --- | --- | --- |\n");
+    let check = |value| if value { "✓" } else { "" };
+    for variable in variables {
+        let _ = writeln!(
+            markdown,
+            "| `{}` | {} | {} |",
+            variable.name,
+            check(variable.set),
+            check(variable.read)
+        );
+    }
+    markdown
+}
+
+fn lock_inventory() -> MutexGuard<'static, Inventory> {
+    INVENTORY.lock().unwrap_or_else(|error| error.into_inner())
+}
+
+fn escape_json(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(escaped, "\\u{:04x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lock_env, EnvSnapshot};
+
+    /// Sets and reads are recorded once enabled
+    #[test]
+    fn record_usage() {
+        let var1 = "ENV_LOCK_TEST_VARIABLE_INVENTORY_1";
+        let var2 = "ENV_LOCK_TEST_VARIABLE_INVENTORY_\"2\"";
+        enable();
+
+        let guard = lock_env([(var1, Some("1"))]);
+        EnvSnapshot::capture([var2]);
+        drop(guard);
+
+        let variables = variables();
+        let usage = |name| variables.iter().find(|usage| usage.name == name);
+        assert_eq!(
+            usage(var1),
+            Some(&VariableUsage {
+                name: var1.into(),
+                set: true,
+                read: false,
+            })
+        );
+        assert_eq!(
+            usage(var2),
+            Some(&VariableUsage {
+                name: var2.into(),
+                set: false,
+                read: true,
+            })
+        );
+        assert!(to_json().contains(
+            r#"{"name": "ENV_LOCK_TEST_VARIABLE_INVENTORY_\"2\"", "set": false, "read": true}"#
+        ));
+        assert!(to_markdown()
+            .contains("| `ENV_LOCK_TEST_VARIABLE_INVENTORY_1` | ✓ |  |"));
+    }
+
+    /// Usage is attributed to the test that performed it
+    #[test]
+    fn record_per_test() {
+        let var = "ENV_LOCK_TEST_VARIABLE_INVENTORY_PER_TEST";
+        enable();
+        let guard = lock_env([(var, Some("1"))]);
+        drop(guard);
+
+        let test = tests()
+            .into_iter()
+            .find(|test| test.test.ends_with("record_per_test"))
+            .unwrap();
+        assert!(test
+            .variables
+            .iter()
+            .any(|usage| usage.name == var && usage.set));
+        assert!(tests_to_markdown().contains("record_per_test\n\n| Variable"));
+    }
+}
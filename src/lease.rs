@@ -0,0 +1,204 @@
+use crate::{expand_names, EnvGuard, IntoEnvValue, VariableNames};
+use std::{
+    borrow::Cow,
+    fmt::{self, Display},
+    panic::Location,
+    sync::{mpsc, Arc, Mutex, MutexGuard, OnceLock},
+    thread,
+    time::Duration,
+};
+
+/// Hook to call when a lease expires. If unset, the expiry is printed to
+/// stderr and the holder panics when the guard is dropped.
+static EXPIRY_HOOK: Mutex<Option<ExpiryHook>> = Mutex::new(None);
+
+type ExpiryHook = Box<dyn Fn(&LeaseExpired) + Send>;
+
+/// Lock the environment like [lock_env](crate::lock_env), but only for a
+/// limited amount of time. If the returned guard is still held once the lease
+/// runs out, the expiry is reported immediately, from a background thread, so
+/// a runaway test is identified while it's still running. Accepts the same
+/// variables as [EnvGuard::child].
+///
+/// The lock is *not* released when the lease expires: the holder may still be
+/// modifying the environment, so other tests keep waiting until the guard is
+/// dropped. **By default, a hung holder still hangs the suite**; the expiry is
+/// only printed. To fail the suite instead, register a hook with
+/// [set_lease_expiry_hook] that aborts the process:
+///
+/// ```no_run
+/// env_lock::set_lease_expiry_hook(|expired| {
+///     eprintln!("{expired}");
+///     std::process::abort();
+/// });
+/// ```
+///
+/// When a lease expires, the hook registered with [set_lease_expiry_hook] is
+/// called. If no hook is registered, the expiry is printed to stderr, and the
+/// holder panics when the guard is eventually dropped, after the environment
+/// has been restored.
+///
+/// ```
+/// use std::{env, time::Duration};
+///
+/// let var = "ENV_LOCK_LEASE_VARIABLE";
+/// let guard = env_lock::lock_env_with_lease(
+///     [(var, Some("hello!"))],
+///     Duration::from_secs(10),
+/// );
+/// assert_eq!(env::var(var).unwrap(), "hello!");
+/// drop(guard);
+///
+/// assert!(env::var(var).is_err());
+/// ```
+#[track_caller]
+pub fn lock_env_with_lease<'a>(
+    variables: impl IntoIterator<
+        Item = (impl VariableNames<'a>, Option<impl IntoEnvValue>),
+    >,
+    lease: Duration,
+) -> LeasedEnvGuard {
+    let location = Location::caller();
+    // The guard is owned, so the leased guard doesn't need a lifetime
+    let guard =
+        EnvGuard::new(expand_names(variables).map(|(variable, value)| {
+            (Cow::Owned(variable.into_owned()), value)
+        }));
+    let expired = Arc::new(OnceLock::new());
+
+    let (done, watchdog_done) = mpsc::channel::<()>();
+    let watchdog_expired = Arc::clone(&expired);
+    thread::Builder::new()
+        .name("env-lock-lease".into())
+        .spawn(move || {
+            // A message or disconnect means the guard was dropped in time
+            if watchdog_done.recv_timeout(lease)
+                == Err(mpsc::RecvTimeoutError::Timeout)
+            {
+                let expired = LeaseExpired { location, lease };
+                let hook = lock_ignore_poison(&EXPIRY_HOOK);
+                let _ = watchdog_expired.set(hook.is_some());
+                match &*hook {
+                    Some(hook) => hook(&expired),
+                    None => eprintln!(
+                        "env-lock: {expired}; the environment stays locked \
+                        until the guard is dropped"
+                    ),
+                }
+            }
+        })
+        .expect("Error spawning env-lock lease thread");
+
+    LeasedEnvGuard {
+        guard: Some(guard),
+        expired,
+        _done: done,
+        location,
+        lease,
+    }
+}
+
+/// Register a hook to be called whenever a lease from [lock_env_with_lease]
+/// expires. The hook is called from a background thread while the holder
+/// still has the environment locked, so it can e.g. abort the process to stop
+/// a hung suite. Registering a hook replaces the previous one, and disables
+/// the default behavior of printing the expiry and panicking in the holder of
+/// the expired lease.
+pub fn set_lease_expiry_hook(hook: impl Fn(&LeaseExpired) + Send + 'static) {
+    *lock_ignore_poison(&EXPIRY_HOOK) = Some(Box::new(hook));
+}
+
+/// Details of an expired lease, passed to the hook registered with
+/// [set_lease_expiry_hook]
+#[derive(Debug)]
+pub struct LeaseExpired {
+    /// Where the expired lease was acquired
+    pub location: &'static Location<'static>,
+    /// Length of the lease
+    pub lease: Duration,
+}
+
+impl Display for LeaseExpired {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Environment lease acquired at {} expired after {:?}",
+            self.location, self.lease
+        )
+    }
+}
+
+/// A guard returned by [lock_env_with_lease]. This will restore and unlock the
+/// environment on drop, even if the lease has expired.
+pub struct LeasedEnvGuard {
+    /// Always `Some` until dropped
+    guard: Option<EnvGuard<'static>>,
+    /// Set by the watchdog once the lease runs out, to whether a hook handled
+    /// the expiry
+    expired: Arc<OnceLock<bool>>,
+    /// Dropped along with the guard to tell the watchdog to stand down
+    _done: mpsc::Sender<()>,
+    location: &'static Location<'static>,
+    lease: Duration,
+}
+
+impl Drop for LeasedEnvGuard {
+    fn drop(&mut self) {
+        // Restore and unlock before panicking
+        drop(self.guard.take());
+        if self.expired.get() == Some(&false) && !thread::panicking() {
+            panic!(
+                "{}",
+                LeaseExpired {
+                    location: self.location,
+                    lease: self.lease,
+                }
+            );
+        }
+    }
+}
+
+fn lock_ignore_poison<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|error| error.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lock_env, set_lock_mode, LockMode};
+    use std::{env, panic, time::Instant};
+
+    /// An expired lease keeps the environment locked until the guard is
+    /// dropped, then the holder panics
+    #[test]
+    fn lease_expired() {
+        let var = "ENV_LOCK_TEST_VARIABLE_LEASE_EXPIRED";
+        set_lock_mode(LockMode::Always);
+        let guard =
+            lock_env_with_lease([(var, Some("hello!"))], Duration::ZERO);
+        let start = Instant::now();
+        while guard.expired.get().is_none() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        // Other threads are still blocked after expiry
+        let (sender, receiver) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            let guard = lock_env([(var, Some("next!"))]);
+            sender.send(env::var(var).unwrap()).unwrap();
+            drop(guard);
+        });
+        assert!(receiver.recv_timeout(Duration::from_millis(50)).is_err());
+        assert_eq!(env::var(var).unwrap(), "hello!");
+
+        panic::catch_unwind(panic::AssertUnwindSafe(|| drop(guard)))
+            .unwrap_err();
+        assert_eq!(
+            receiver.recv_timeout(Duration::from_secs(5)).unwrap(),
+            "next!"
+        );
+        handle.join().unwrap();
+        assert!(env::var(var).is_err());
+    }
+}
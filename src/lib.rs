@@ -1,8 +1,8 @@
 //! Lock environment variables to prevent simultaneous access. Use [lock_env] to
 //! set values for whatever environment variables you intend to access in your
 //! test. This will return a guard that, when dropped, will revert the
-//! environment to its initial state. The guard uses a [Mutex] underneath to
-//! ensure that multiple tests within the same process can't access it at the
+//! environment to its initial state. The guard uses a global lock underneath
+//! to ensure that multiple tests within the same process can't access it at the
 //! same time.
 //!
 //! ```
@@ -17,19 +17,243 @@
 //!
 //! assert!(env::var(var).is_err());
 //! ```
+//!
+//! ## Soundness
+//! Modifying the environment while another thread reads it is undefined
+//! behavior on most platforms, which is why `std::env::set_var` is `unsafe` in
+//! Rust 2024. This crate's API is safe because every modification happens
+//! while holding the global lock, but that only protects other code that also
+//! holds the lock. Reads through the standard library are always
+//! synchronized, but if your tests call C code that reads the environment
+//! (e.g. `getenv`, or DNS resolution via `getaddrinfo`), make sure no such
+//! code runs on another thread while a guard modifies the environment.
+//!
+//! ## WebAssembly
+//! `wasm32-unknown-unknown` has no process environment: the standard library
+//! never finds any variables, and panics when setting one. On that target,
+//! guards modify an in-memory environment instead, which starts empty. Code
+//! that reads variables through this crate, such as [var] or
+//! [EnvGuard::get], sees it, but `std::env` doesn't, so code under test should
+//! read through this crate (or the [environment::Environment] trait) to
+//! work on every target. There are no threads, so the lock is never
+//! contended, and [set_hold_warning] panics because it needs a background
+//! thread. WASI targets have a real environment, and behave as everywhere
+//! else.
 
-#![forbid(unsafe_code)]
+// Not forbid, so the environment mutation module can opt in once `set_var`
+// becomes unsafe
+#![deny(unsafe_code)]
 #![deny(clippy::all)]
 
+// Allow macro-generated code to refer to this crate as `env_lock`
+#[cfg(all(test, feature = "macros"))]
+extern crate self as env_lock;
+
+pub mod actor;
+mod baseline;
+mod builder;
+mod changes;
+mod combined;
+#[cfg(feature = "test-context")]
+pub mod context;
+mod edit;
+#[cfg(feature = "encrypted")]
+pub mod encrypted;
+pub mod environment;
+pub mod ext;
+#[cfg(feature = "file-lock")]
+pub mod file_lock;
+pub mod forensics;
+pub mod fuzz;
+pub mod inventory;
+mod lease;
+mod load;
+mod lock;
+#[cfg(feature = "tracing-subscriber")]
+pub mod log_filter;
+mod mutation;
+mod oneshot;
+mod overlay;
+pub mod parse;
+pub mod prelude;
+pub mod presets;
+mod recovery;
+mod resource;
+#[cfg(feature = "rstest")]
+pub mod rstest;
+pub mod schema;
+mod snapshot;
+pub mod source;
+mod stats;
+mod stress;
+mod structured;
+mod sys;
+mod temp;
+#[cfg(feature = "tracing")]
+pub mod trace;
+mod transaction;
+mod typed;
+mod validate;
+mod watchdog;
+
+pub use baseline::{clear_baseline, set_baseline};
+pub use builder::EnvLock;
+pub use changes::EnvChange;
+pub use combined::{CombinedGuard, Lockable, OrderedGuard, OrderedLocks};
+pub use edit::{EnvEdit, IntoEnvEdit, IntoEnvValue};
+/// Generate one test per combination of variable values. Each argument is a
+/// variable name followed by a list of values for it, where `None` removes the
+/// variable. The annotated function is kept as-is, and a module of the same
+/// name is generated next to it, containing one `#[test]` function for every
+/// combination. Each test locks the environment with its combination applied,
+/// then calls the annotated function, so failures are reported
+/// per-combination by the test harness.
+///
+/// Any other attributes on the function (e.g. `#[should_panic]`) are applied
+/// to the generated tests. Don't add `#[test]` to the function yourself.
+/// Requires the `macros` feature.
+///
+/// ```
+/// use std::env;
+///
+/// // Generates tests named `mode::mode_a__tls_on`, `mode::mode_a__tls_none`,
+/// // etc.
+/// #[env_lock::env_matrix(MODE = ["a", "b"], TLS = ["on", None])]
+/// fn mode() {
+///     let mode = env::var("MODE").unwrap();
+///     assert!(mode == "a" || mode == "b");
+/// }
+/// ```
+#[cfg(feature = "macros")]
+pub use env_lock_macros::env_matrix;
+/// Lock the environment for the entire body of a function, typically a test.
+/// Each argument is a variable name and a string literal value, or `None` to
+/// remove the variable. This is equivalent to calling [lock_env] on the first
+/// line of the function, but the guard can't be dropped early or forgotten.
+/// Requires the `macros` feature.
+///
+/// ```
+/// use std::env;
+///
+/// #[env_lock::vars(ENV_LOCK_VARS_MODE = "test", ENV_LOCK_VARS_PROXY = None)]
+/// fn mode() -> String {
+///     assert!(env::var("ENV_LOCK_VARS_PROXY").is_err());
+///     env::var("ENV_LOCK_VARS_MODE").unwrap()
+/// }
+///
+/// assert_eq!(mode(), "test");
+/// assert!(env::var("ENV_LOCK_VARS_MODE").is_err());
+/// ```
+#[cfg(feature = "macros")]
+pub use env_lock_macros::vars;
+/// Derive [FromEnv] for a struct with named fields, so it can be read with
+/// [EnvGuard::deserialize]. Each field is read from one variable, named by
+/// [EnvNaming], and parsed with [FromStr]. `Option` fields are `None` if
+/// their variable is unset; any other unset field is an error. Requires the
+/// `macros` feature.
+///
+/// ```
+/// use env_lock::{EnvNaming, FromEnv};
+///
+/// #[derive(FromEnv)]
+/// struct Settings {
+///     port: u16,
+///     proxy: Option<String>,
+/// }
+///
+/// let guard = env_lock::lock_env([("ENV_LOCK_DERIVE_FROM_PORT", Some("8080"))]);
+/// let settings: Settings = guard
+///     .deserialize(&EnvNaming::new().prefix("ENV_LOCK_DERIVE_FROM"))
+///     .unwrap();
+/// assert_eq!(settings.port, 8080);
+/// assert_eq!(settings.proxy, None);
+/// ```
+#[cfg(feature = "macros")]
+pub use env_lock_macros::FromEnv;
+/// Derive [ToEnv] for a struct with named fields, so it can be locked with
+/// [lock_env_serialize]. Each field becomes one variable, named by
+/// [EnvNaming]. Fields are formatted with
+/// [Display](std::fmt::Display), and `Option` fields that are `None` are
+/// removed. Requires the `macros` feature.
+///
+/// ```
+/// use env_lock::{EnvNaming, ToEnv};
+/// use std::env;
+///
+/// #[derive(ToEnv)]
+/// struct Settings {
+///     log_level: String,
+///     proxy: Option<String>,
+/// }
+///
+/// let settings = Settings { log_level: "debug".into(), proxy: None };
+/// let _guard = env_lock::lock_env_serialize(
+///     &settings,
+///     &EnvNaming::new().prefix("ENV_LOCK_DERIVE"),
+/// );
+/// assert_eq!(env::var("ENV_LOCK_DERIVE_LOG_LEVEL").unwrap(), "debug");
+/// assert!(env::var("ENV_LOCK_DERIVE_PROXY").is_err());
+/// ```
+#[cfg(feature = "macros")]
+pub use env_lock_macros::ToEnv;
+pub use lease::{
+    lock_env_with_lease, set_lease_expiry_hook, LeaseExpired, LeasedEnvGuard,
+};
+#[cfg(feature = "dotenv")]
+pub use load::lock_env_file;
+pub use load::{
+    lock_env_from_json, lock_env_from_reader, lock_env_from_toml, LoadError,
+};
+pub use lock::{
+    clear_lock_yield, lock_holders, set_contention_threshold, set_lock_mode,
+    set_lock_policy, set_lock_yield, LockHolder, LockMode, LockPolicy,
+};
+pub use mutation::{set_mutation_check, MutationCheck};
+pub use oneshot::{remove_var, set_var, update_var, var};
+pub use overlay::{EnvOverlay, OverlayIter};
+pub use recovery::force_restore_all;
+pub use resource::{lock_resource, lock_resources, ResourceGuard};
+pub use snapshot::EnvSnapshot;
+pub use stats::{reset_stats, stats, LockStats};
+pub use stress::{stress, ScenarioVariables};
+pub use structured::{
+    lock_env_serialize, EnvFields, EnvNaming, FromEnv, FromEnvError, NameCase,
+    ToEnv,
+};
+pub use temp::TempVar;
+pub use transaction::{EnvTransaction, TransactionError};
+pub use typed::EnvVar;
+pub use validate::EnvError;
+pub use watchdog::{clear_hold_warning, set_hold_warning};
+
+use crate::lock::{HeldLock, ReadToken, ENV_LOCK};
 use std::{
+    array,
+    borrow::Cow,
+    collections::BTreeMap,
     env,
-    sync::{Mutex, MutexGuard},
+    error::Error,
+    ffi::{OsStr, OsString},
+    fmt::{self, Display},
+    io, iter, mem,
+    panic::{self, Location},
+    path::{Path, PathBuf},
+    process::Command,
+    slice,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
-/// Global mutex for accessing environment variables. Technically we could break
-/// this out into a map with one mutex per variable, but that adds a ton of
-/// complexity for very little value.
-static ENV_MUTEX: Mutex<()> = Mutex::new(());
+/// Incremented on every environment mutation made by this crate
+static GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+/// Total time spent waiting for the lock by all guards, in nanoseconds
+static TOTAL_WAIT_NANOS: AtomicU64 = AtomicU64::new(0);
 
 /// Lock the environment and set each given variable to its corresponding
 /// value. If the environment is already locked, this will block until the lock
@@ -39,109 +263,3263 @@ static ENV_MUTEX: Mutex<()> = Mutex::new(());
 /// unlocked.
 ///
 /// ## Note
-/// There is a single mutex per process that locks the *entire*
+/// There is a single lock per process that locks the *entire*
 /// environment. This means multiple usages of by `lock_env` cannot run
 /// concurrently, even if they don't modify any of the same environment
 /// variables. Keep your critical sections as short as possible to prevent
 /// slowdowns.
+///
+/// If the test harness is running tests on a single thread, the lock is
+/// skipped entirely. See [LockMode] to change this.
+///
+/// ## Reentrancy
+/// If the current thread already holds the lock, e.g. because a test helper
+/// locks the environment and then calls another helper that does the same,
+/// the lock is reentered instead of deadlocking. The lock is released once
+/// every guard is dropped. Nested guards should be dropped in the reverse
+/// order they were created, so each restores the values it replaced.
+/// Reentrancy is based on the thread that *acquired* the lock, so guards that
+/// have been sent to another thread, or acquired with [lock_env_async], can't
+/// be reentered.
+///
+/// ```
+/// use std::env;
+///
+/// let var = "ENV_LOCK_REENTRANT_VARIABLE";
+/// let outer = env_lock::lock_env([(var, Some("outer"))]);
+/// let inner = env_lock::lock_env([(var, Some("inner"))]);
+/// assert_eq!(env::var(var).unwrap(), "inner");
+/// drop(inner);
+/// assert_eq!(env::var(var).unwrap(), "outer");
+/// drop(outer);
+/// assert!(env::var(var).is_err());
+/// ```
+///
+/// ## Aliases
+/// Variables that come in families, such as proxy or locale variables, can
+/// share a single value by giving an array of names instead of a single name.
+/// Each name is still restored to its own previous value.
+///
+/// ```
+/// use std::env;
+///
+/// let _guard = env_lock::lock_env([(
+///     ["ENV_LOCK_HTTP_PROXY", "env_lock_http_proxy"],
+///     Some("http://proxy:3128"),
+/// )]);
+/// assert_eq!(env::var("ENV_LOCK_HTTP_PROXY").unwrap(), "http://proxy:3128");
+/// assert_eq!(env::var("env_lock_http_proxy").unwrap(), "http://proxy:3128");
+/// ```
+///
+/// ## Duplicate variables
+/// A variable may be given more than once, e.g. when a base map is chained
+/// with overrides. Entries are applied in order, so the last value wins, but
+/// the variable is only captured once, before the first entry. Dropping the
+/// guard always restores the value from before the guard was created. The
+/// same applies to variables set again later with [EnvGuard::set] or
+/// [EnvGuard::extend].
+///
+/// ```
+/// use std::env;
+///
+/// let var = "ENV_LOCK_DUPLICATE_VARIABLE";
+/// env::set_var(var, "original");
+/// let base = [(var, Some("base"))];
+/// let overrides = [(var, Some("override"))];
+/// let guard = env_lock::lock_env(base.into_iter().chain(overrides));
+/// assert_eq!(env::var(var).unwrap(), "override");
+/// drop(guard);
+/// assert_eq!(env::var(var).unwrap(), "original");
+/// ```
+///
+/// ## Owned names
+/// Names can be owned `String`s, so the variable list can be built
+/// dynamically, e.g. from a `HashMap`. If no names are borrowed, the guard is
+/// `'static`.
+///
+/// ```
+/// use env_lock::EnvGuard;
+/// use std::collections::HashMap;
+///
+/// fn lock(variables: HashMap<String, Option<String>>) -> EnvGuard<'static> {
+///     env_lock::lock_env(variables)
+/// }
+///
+/// let _guard = lock(HashMap::from([(
+///     format!("ENV_LOCK_OWNED_{}", 1),
+///     Some("hello!".to_owned()),
+/// )]));
+/// ```
+///
+/// ## List variables
+/// Instead of an `Option`, each value can be an [EnvEdit], which can also add
+/// an entry to the start or end of a list variable such as `PATH`.
+///
+/// ```
+/// use env_lock::EnvEdit;
+///
+/// let _guard = env_lock::lock_env([("PATH", EnvEdit::Prepend("/my/test/bin"))]);
+/// ```
+///
+/// ## Panics
+/// Panics if a name is empty or contains `=` or NUL, or a value contains NUL.
+/// Any variables that were already set are restored first. To get an error
+/// instead, use [try_lock_env_checked].
+#[track_caller]
 pub fn lock_env<'a>(
-    variables: impl IntoIterator<Item = (&'a str, Option<impl AsRef<str>>)>,
+    variables: impl IntoIterator<Item = (impl VariableNames<'a>, impl IntoEnvEdit)>,
 ) -> EnvGuard<'a> {
-    // We can ignore poison errors, because the Drop impl for EnvGuard restores
-    // the environment on panic
-    let guard = ENV_MUTEX.lock().unwrap_or_else(|error| error.into_inner());
+    match try_lock_env_checked(variables) {
+        Ok(guard) => guard,
+        Err(error) => panic!("{error}"),
+    }
+}
 
-    let previous_values = variables
-        .into_iter()
-        .map(|(variable, new_value)| {
-            let previous_value = env::var(variable).ok();
+/// Lock the environment like [lock_env], but return an error if a name or
+/// value can't be stored in the environment, instead of panicking. This is
+/// useful when variables come from fuzzers or test data. If there's an
+/// error, the lock is released and the environment is left unmodified.
+///
+/// ```
+/// use env_lock::EnvError;
+///
+/// let result = env_lock::try_lock_env_checked([("INVALID=NAME", Some("1"))]);
+/// assert_eq!(
+///     result.err(),
+///     Some(EnvError::InvalidName { variable: "INVALID=NAME".into() }),
+/// );
+/// ```
+#[track_caller]
+pub fn try_lock_env_checked<'a>(
+    variables: impl IntoIterator<Item = (impl VariableNames<'a>, impl IntoEnvEdit)>,
+) -> Result<EnvGuard<'a>, EnvError> {
+    let mut guard = EnvGuard::acquire();
+    for (names, edit) in variables {
+        let edit = edit.into_edit();
+        // Resolve each name separately, since list edits depend on the
+        // variable's current value
+        for name in names.into_names() {
+            let value = edit.resolve(&name);
+            // Dropping the guard restores anything that was already applied
+            validate::check(&name, value.as_deref())?;
+            guard.apply([(name, value)]);
+        }
+    }
+    Ok(guard)
+}
 
-            if let Some(value) = new_value {
-                env::set_var(variable, value.as_ref());
-            } else {
-                env::remove_var(variable);
-            }
+/// Lock only the given variables, instead of the entire environment. Tests
+/// that lock disjoint sets of variables can run concurrently, while tests
+/// with any variables in common still block each other. Guards from
+/// [lock_env] still get exclusive access to the entire environment, so they
+/// wait for all variable locks to be released, and vice versa.
+///
+/// Only the given variables are protected. Reading or modifying any other
+/// variable while holding this guard (including via methods such as
+/// [EnvGuard::swap]) may race with other tests. [lock_env_read] guards don't
+/// exclude variable locks, so variables may change while they're held.
+///
+/// ```
+/// use std::env;
+///
+/// let var = "ENV_LOCK_VARS_VARIABLE";
+/// let guard = env_lock::lock_env_vars([(var, Some("hello!"))]);
+/// assert_eq!(env::var(var).unwrap(), "hello!");
+/// drop(guard);
+/// assert!(env::var(var).is_err());
+/// ```
+#[track_caller]
+pub fn lock_env_vars<'a>(
+    variables: impl IntoIterator<
+        Item = (impl VariableNames<'a>, Option<impl IntoEnvValue>),
+    >,
+) -> EnvGuard<'a> {
+    let variables: Vec<_> = expand_names(variables).collect();
+    let start = Instant::now();
+    let location = Location::caller();
+    let lock = lock::should_lock().then(|| {
+        HeldLock::variables(
+            variables
+                .iter()
+                .map(|(variable, _)| sys::name_key(variable).into_owned()),
+            location,
+        )
+    });
+    let mut guard = EnvGuard::locked(lock, start.elapsed());
+    guard.apply(variables);
+    guard
+}
 
-            (variable, previous_value)
-        })
+/// Lock the environment like [lock_env], but give up if the lock can't be
+/// acquired within the given timeout. If a guard is leaked (e.g. via
+/// [std::mem::forget] or a stuck thread), this lets the test fail fast with a
+/// clear error instead of blocking forever. Nothing is modified unless the
+/// lock is acquired.
+///
+/// ```
+/// use std::{env, time::Duration};
+///
+/// let var = "ENV_LOCK_TIMEOUT_VARIABLE";
+/// let _guard = env_lock::lock_env_timeout(
+///     [(var, Some("hello!"))],
+///     Duration::from_secs(10),
+/// )
+/// .unwrap();
+/// assert_eq!(env::var(var).unwrap(), "hello!");
+/// ```
+#[track_caller]
+pub fn lock_env_timeout<'a>(
+    variables: impl IntoIterator<
+        Item = (impl VariableNames<'a>, Option<impl IntoEnvValue>),
+    >,
+    timeout: Duration,
+) -> Result<EnvGuard<'a>, TimeoutError> {
+    let start = Instant::now();
+    let lock = if lock::should_lock() {
+        let token = ENV_LOCK
+            .acquire_timeout(timeout, Location::caller())
+            .ok_or(TimeoutError { timeout })?;
+        Some(HeldLock::Exclusive(token))
+    } else {
+        None
+    };
+    let mut guard = EnvGuard::locked(lock, start.elapsed());
+    guard.apply(expand_names(variables));
+    Ok(guard)
+}
+
+/// The environment lock couldn't be acquired in time by [lock_env_timeout]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TimeoutError {
+    /// How long we waited for the lock
+    pub timeout: Duration,
+}
+
+impl Display for TimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Timed out after {:?} waiting for the environment lock. Another \
+            test may have leaked its guard.",
+            self.timeout
+        )
+    }
+}
+
+impl Error for TimeoutError {}
+
+/// Lock the environment, then clear *every* variable except those in `keep`,
+/// and set the given variables. When the guard is dropped, the entire
+/// environment is restored to its previous state, including removing any
+/// variables created in the meantime. This isolates tests from unrelated
+/// variables that happen to be set on a developer machine or in CI.
+///
+/// Variables whose name isn't valid UTF-8 are left alone, as are the hidden
+/// variables on Windows that track each drive's working directory (e.g.
+/// `=C:`).
+///
+/// ```
+/// use std::env;
+///
+/// env::set_var("ENV_LOCK_SANDBOX_STRAY", "1");
+/// let guard = env_lock::lock_env_sandbox(
+///     &["PATH"],
+///     [("ENV_LOCK_SANDBOX_MODE", Some("test"))],
+/// );
+/// assert!(env::var("ENV_LOCK_SANDBOX_STRAY").is_err());
+/// assert_eq!(env::var("ENV_LOCK_SANDBOX_MODE").unwrap(), "test");
+/// drop(guard);
+/// assert_eq!(env::var("ENV_LOCK_SANDBOX_STRAY").unwrap(), "1");
+/// ```
+#[track_caller]
+pub fn lock_env_sandbox<'a>(
+    keep: &[&str],
+    variables: impl IntoIterator<
+        Item = (impl VariableNames<'a>, Option<impl IntoEnvValue>),
+    >,
+) -> EnvGuard<'a> {
+    let mut guard = EnvGuard::acquire();
+    guard.sandboxed = true;
+    let names: Vec<String> = sys::vars_os()
+        .filter_map(|(name, _)| name.into_string().ok())
+        .collect();
+    for name in names {
+        if keep.iter().any(|kept| sys::same_name(kept, &name)) {
+            // Manage it anyway, so it isn't removed on drop
+            guard.manage(&name);
+        } else {
+            guard.set_managed(&name, None::<&str>);
+        }
+    }
+    guard.extend(variables);
+    guard
+}
+
+/// Lock the environment, then remove every variable except those in `keep`.
+/// When the guard is dropped, the entire environment is restored, so tests
+/// can check behavior in an empty environment. Shorthand for
+/// [lock_env_sandbox] without any variables to set; more can be set
+/// afterward with [EnvGuard::set].
+///
+/// ```
+/// use std::env;
+///
+/// env::set_var("ENV_LOCK_CLEAR_ALL_STRAY", "1");
+/// let guard = env_lock::lock_env_clear_all(&[]);
+/// // Hidden variables on Windows, such as `=C:`, are left alone
+/// assert!(env::vars().all(|(name, _)| name.starts_with('=')));
+/// drop(guard);
+/// assert_eq!(env::var("ENV_LOCK_CLEAR_ALL_STRAY").unwrap(), "1");
+///
+/// // Keep what's needed to run subprocesses
+/// let _guard = env_lock::lock_env_clear_all(&["PATH"]);
+/// assert!(env::var("ENV_LOCK_CLEAR_ALL_STRAY").is_err());
+/// ```
+#[track_caller]
+pub fn lock_env_clear_all(keep: &[&str]) -> EnvGuard<'static> {
+    lock_env_sandbox(keep, [] as [(&str, Option<&str>); 0])
+}
+
+/// Lock the environment, set the given variables, and change the current
+/// working directory. The variables and working directory are restored when
+/// the guard is dropped. See [EnvGuard::set_current_dir].
+///
+/// ```
+/// use std::env;
+///
+/// let directory = env::temp_dir();
+/// let _guard = env_lock::lock_env_with_cwd(
+///     [("ENV_LOCK_WITH_CWD_MODE", Some("test"))],
+///     &directory,
+/// );
+/// assert_eq!(
+///     env::current_dir().unwrap().canonicalize().unwrap(),
+///     directory.canonicalize().unwrap(),
+/// );
+/// ```
+///
+/// ## Panics
+/// Panics if the working directory can't be changed, e.g. because it doesn't
+/// exist. The variables are restored first.
+#[track_caller]
+pub fn lock_env_with_cwd<'a>(
+    variables: impl IntoIterator<
+        Item = (impl VariableNames<'a>, Option<impl IntoEnvValue>),
+    >,
+    path: impl AsRef<Path>,
+) -> EnvGuard<'a> {
+    let mut guard = EnvGuard::new(expand_names(variables));
+    if let Err(error) = guard.set_current_dir(&path) {
+        panic!(
+            "Error changing working directory to {:?}: {error}",
+            path.as_ref()
+        );
+    }
+    guard
+}
+
+/// Lock the environment, then remove every variable whose name matches a
+/// glob pattern, and set the given variables. The removed variables are
+/// restored when the guard is dropped. In the pattern, `*` matches any number
+/// of characters and `?` matches exactly one, so a prefix is matched with e.g.
+/// `MYAPP_*`. Variables whose name isn't valid UTF-8 are never matched.
+///
+/// ```
+/// use std::env;
+///
+/// env::set_var("ENV_LOCK_MATCHING_PORT", "80");
+/// let guard = env_lock::lock_env_matching(
+///     "ENV_LOCK_MATCHING_*",
+///     [("ENV_LOCK_MATCHING_HOST", Some("localhost"))],
+/// );
+/// assert!(env::var("ENV_LOCK_MATCHING_PORT").is_err());
+/// assert_eq!(env::var("ENV_LOCK_MATCHING_HOST").unwrap(), "localhost");
+/// drop(guard);
+/// assert_eq!(env::var("ENV_LOCK_MATCHING_PORT").unwrap(), "80");
+/// ```
+#[track_caller]
+pub fn lock_env_matching<'a>(
+    pattern: &str,
+    variables: impl IntoIterator<
+        Item = (impl VariableNames<'a>, Option<impl IntoEnvValue>),
+    >,
+) -> EnvGuard<'a> {
+    let mut guard = EnvGuard::acquire();
+    let names: Vec<String> = sys::vars_os()
+        .filter_map(|(name, _)| name.into_string().ok())
+        .filter(|name| glob_matches(pattern, name))
         .collect();
+    for name in names {
+        guard.set_managed(&name, None::<&str>);
+    }
+    guard.extend(variables);
+    guard
+}
+
+/// Does a name match a glob pattern? `*` matches any sequence of characters
+/// (including none), and `?` matches a single character. Like variable names,
+/// this is case-insensitive on Windows.
+fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Position of the last `*`, and the name position it's matched up to
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || sys::same_char(c, name[n]) => {
+                p += 1;
+                n += 1;
+            }
+            // Mismatch, so let the last `*` consume one more character
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    star = Some((star_p, star_n + 1));
+                    p = star_p + 1;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Lock the environment for reading only. Any number of read guards can be
+/// held at once, so tests that only read the environment can run
+/// concurrently, while guards from [lock_env] still get exclusive access.
+/// Read guards don't modify or restore any variables, and nothing prevents
+/// mutating the environment while holding one, so only use this in tests that
+/// never mutate it. When readers and writers are contending, the
+/// [LockPolicy] decides who goes first.
+///
+/// Like [lock_env], the lock is skipped according to the [LockMode].
+///
+/// ```
+/// use std::env;
+///
+/// let _guard = env_lock::lock_env_read();
+/// let _home = env::var("HOME");
+/// ```
+#[track_caller]
+pub fn lock_env_read() -> EnvReadGuard {
+    let location = Location::caller();
+    EnvReadGuard {
+        lock: lock::should_lock().then(|| ENV_LOCK.acquire_read(location)),
+    }
+}
+
+/// Acquire the environment lock without setting, tracking, or restoring any
+/// variables. This is for coordinating with code that serializes its tests
+/// some other way, such as the `#[serial]` attribute from `serial_test`.
+/// Those tests use a different lock, so they race with tests using env-lock.
+/// Acquire this at the start of each such test to exclude env-lock tests,
+/// then convert it with [into_guard](RawLockGuard::into_guard) if the test
+/// needs to modify variables.
+///
+/// Like [lock_env], the lock is skipped according to the [LockMode], and
+/// reentered if the current thread already holds it.
+///
+/// ```
+/// use std::env;
+///
+/// // #[serial]
+/// fn legacy_test() {
+///     let _lock = env_lock::lock_raw();
+///     // Nothing else can modify the environment via env-lock now
+///     let _home = env::var("HOME");
+/// }
+/// # legacy_test();
+/// ```
+#[track_caller]
+pub fn lock_raw() -> RawLockGuard {
+    let start = Instant::now();
+    let location = Location::caller();
+    let lock = lock::should_lock()
+        .then(|| HeldLock::Exclusive(ENV_LOCK.acquire(location)));
+    RawLockGuard {
+        lock,
+        wait_duration: start.elapsed(),
+    }
+}
+
+/// Lock the environment like [lock_env], but wait for the lock without
+/// blocking the thread, so the async executor can keep running other tasks.
+/// [EnvGuard] is `Send`, so it can be held across `.await` points in a
+/// multi-threaded runtime. Requires the `async` feature.
+///
+/// ```
+/// use std::env;
+///
+/// let var = "ENV_LOCK_ASYNC_VARIABLE";
+/// futures::executor::block_on(async {
+///     let _guard = env_lock::lock_env_async([(var, Some("hello!"))]).await;
+///     assert_eq!(env::var(var).unwrap(), "hello!");
+/// });
+/// ```
+#[cfg(feature = "async")]
+pub async fn lock_env_async<'a>(
+    variables: impl IntoIterator<
+        Item = (impl VariableNames<'a>, Option<impl IntoEnvValue>),
+    >,
+) -> EnvGuard<'a> {
+    let mut guard = EnvGuard::acquire_async().await;
+    guard.apply(expand_names(variables));
+    guard
+}
+
+/// Lock the environment like [lock_env_async], then await a future. Once the
+/// future completes (or panics), the environment is restored and unlocked,
+/// and the future's output is returned. This is the async version of
+/// [with_env]. Requires the `async` feature.
+///
+/// ```
+/// use std::env;
+///
+/// let var = "ENV_LOCK_WITH_ENV_ASYNC_VARIABLE";
+/// let value = futures::executor::block_on(env_lock::with_env_async(
+///     [(var, Some("hello!"))],
+///     async { env::var(var).unwrap() },
+/// ));
+/// assert_eq!(value, "hello!");
+/// assert!(env::var(var).is_err());
+/// ```
+#[cfg(feature = "async")]
+pub async fn with_env_async<'a, T>(
+    variables: impl IntoIterator<
+        Item = (impl VariableNames<'a>, Option<impl IntoEnvValue>),
+    >,
+    future: impl std::future::Future<Output = T>,
+) -> T {
+    let _guard = lock_env_async(variables).await;
+    future.await
+}
+
+/// Expand each group of aliased names into a separate variable
+fn expand_names<'a>(
+    variables: impl IntoIterator<
+        Item = (impl VariableNames<'a>, Option<impl IntoEnvValue>),
+    >,
+) -> impl Iterator<Item = (Cow<'a, str>, Option<OsString>)> {
+    variables.into_iter().flat_map(|(names, value)| {
+        let value = value.map(IntoEnvValue::into_env_value);
+        names.into_names().map(move |name| (name, value.clone()))
+    })
+}
+
+/// Lock the environment like [lock_env], but with [OsStr] values, which don't
+/// have to be valid UTF-8. Note that every guard, including those from
+/// [lock_env], restores non-UTF-8 values faithfully, and [lock_env] can also
+/// set them from [OsString] and path values (see [IntoEnvValue]). This is
+/// only needed for values of a generic `AsRef<OsStr>` type.
+///
+/// ```
+/// use std::{env, ffi::OsStr};
+///
+/// let var = "ENV_LOCK_OS_VARIABLE";
+/// let _guard = env_lock::lock_env_os([(var, Some(OsStr::new("hello!")))]);
+/// assert_eq!(env::var_os(var).unwrap(), "hello!");
+/// ```
+#[track_caller]
+pub fn lock_env_os<'a>(
+    variables: impl IntoIterator<
+        Item = (impl VariableNames<'a>, Option<impl AsRef<OsStr>>),
+    >,
+) -> EnvGuard<'a> {
+    EnvGuard::new(variables.into_iter().flat_map(|(names, value)| {
+        let value = value.as_ref().map(|value| value.as_ref().to_owned());
+        names.into_names().map(move |name| (name, value.clone()))
+    }))
+}
+
+/// Lock the environment, set the given variables, and run a closure. Once the
+/// closure returns (or panics), the environment is restored and unlocked, and
+/// the closure's return value is returned. Unlike [lock_env], there's no guard
+/// that can be dropped too early or forgotten about.
+///
+/// ```
+/// use std::env;
+///
+/// let var = "ENV_LOCK_WITH_ENV_VARIABLE";
+/// let value = env_lock::with_env([(var, Some("hello!"))], || {
+///     env::var(var).unwrap()
+/// });
+/// assert_eq!(value, "hello!");
+/// assert!(env::var(var).is_err());
+/// ```
+#[track_caller]
+pub fn with_env<'a, T>(
+    variables: impl IntoIterator<
+        Item = (impl VariableNames<'a>, Option<impl IntoEnvValue>),
+    >,
+    f: impl FnOnce() -> T,
+) -> T {
+    let _guard = lock_env(variables);
+    f()
+}
+
+/// One or more variable names that share a value, for [lock_env]. This is
+/// implemented for a single name (`&str`, `String`, or `Cow<str>`), and for
+/// arrays and slices of names.
+pub trait VariableNames<'a> {
+    /// Iterator over the names
+    type Iter: Iterator<Item = Cow<'a, str>>;
+
+    /// Get each name
+    fn into_names(self) -> Self::Iter;
+}
+
+/// Convert a borrowed name to a [Cow]. Named so it can be used in iterator
+/// types.
+type Borrow<'a> = fn(&'a str) -> Cow<'a, str>;
+
+impl<'a> VariableNames<'a> for &'a str {
+    type Iter = iter::Once<Cow<'a, str>>;
+
+    fn into_names(self) -> Self::Iter {
+        iter::once(Cow::Borrowed(self))
+    }
+}
+
+impl<'a> VariableNames<'a> for String {
+    type Iter = iter::Once<Cow<'a, str>>;
+
+    fn into_names(self) -> Self::Iter {
+        iter::once(Cow::Owned(self))
+    }
+}
+
+impl<'a> VariableNames<'a> for Cow<'a, str> {
+    type Iter = iter::Once<Cow<'a, str>>;
+
+    fn into_names(self) -> Self::Iter {
+        iter::once(self)
+    }
+}
+
+impl<'a, const N: usize> VariableNames<'a> for [&'a str; N] {
+    type Iter = iter::Map<array::IntoIter<&'a str, N>, Borrow<'a>>;
+
+    fn into_names(self) -> Self::Iter {
+        self.into_iter().map(Cow::Borrowed as Borrow)
+    }
+}
+
+impl<'a, const N: usize> VariableNames<'a> for [String; N] {
+    type Iter =
+        iter::Map<array::IntoIter<String, N>, fn(String) -> Cow<'a, str>>;
+
+    fn into_names(self) -> Self::Iter {
+        self.into_iter().map(Cow::Owned)
+    }
+}
+
+impl<'a> VariableNames<'a> for &'a [&'a str] {
+    type Iter = iter::Map<iter::Copied<slice::Iter<'a, &'a str>>, Borrow<'a>>;
 
-    EnvGuard {
-        previous_values,
-        guard,
+    fn into_names(self) -> Self::Iter {
+        self.iter().copied().map(Cow::Borrowed as Borrow)
     }
 }
 
+/// An [EnvGuard] that doesn't borrow anything, so it can be stored in
+/// fixture structs, statics, or returned from helper functions. Create one
+/// with [EnvGuard::into_owned], or by passing owned names to [lock_env].
+pub type OwnedEnvGuard = EnvGuard<'static>;
+
 /// A guard used to indicate that the current process environment is locked.
 /// Returned by [lock_env]. This will restore and unlock the environment on
 /// drop.
 pub struct EnvGuard<'a> {
-    previous_values: Vec<(&'a str, Option<String>)>,
+    variables: Vec<ManagedVariable<'a>>,
+    /// `None` if locking is disabled by the [LockMode]. Shared between guards
+    /// created by [split](Self::split), so the lock is held until all of them
+    /// are dropped.
     #[allow(unused)]
-    guard: MutexGuard<'static, ()>,
+    lock: Option<Arc<HeldLock>>,
+    /// How long it took to acquire the lock
+    wait_duration: Duration,
+    /// When the lock was acquired
+    acquired_at: Instant,
+    /// Maximum hold time, checked on drop
+    hold_budget: Option<(Duration, &'static Location<'static>)>,
+    /// ID in the forensics registry, if enabled
+    forensics_id: Option<u64>,
+    /// ID in the registry used by [force_restore_all]. `None` once moved out
+    /// by [into_owned](Self::into_owned).
+    recovery_id: Option<u64>,
+    /// Should variables that aren't managed be removed on drop? Set for
+    /// [lock_env_sandbox], where every variable is managed.
+    sandboxed: bool,
+    /// Environment established by this guard, if changes are being tracked
+    baseline: Option<changes::Baseline>,
+    /// Did this guard apply the [set_baseline] variables?
+    applied_baseline: bool,
+    /// Working directory from before [set_current_dir](Self::set_current_dir)
+    /// was first called, to restore on drop
+    previous_dir: Option<PathBuf>,
 }
 
-impl<'a> Drop for EnvGuard<'a> {
-    fn drop(&mut self) {
-        // Restore each env var
-        for (variable, value) in &self.previous_values {
-            if let Some(value) = value {
-                env::set_var(variable, value);
+/// A guard that holds shared access to the environment, returned by
+/// [lock_env_read]. The environment is unlocked for writers once every read
+/// guard is dropped.
+pub struct EnvReadGuard {
+    /// `None` if locking is disabled by the [LockMode]
+    #[allow(unused)]
+    lock: Option<ReadToken>,
+}
+
+/// Exclusive access to the environment, without any managed variables.
+/// Returned by [lock_raw]. The environment is unlocked when this is dropped.
+pub struct RawLockGuard {
+    /// `None` if locking is disabled by the [LockMode]
+    lock: Option<HeldLock>,
+    wait_duration: Duration,
+}
+
+impl RawLockGuard {
+    /// Convert into a regular guard and apply the given variables, without
+    /// releasing the lock in between. The variables are restored when the
+    /// returned guard is dropped. See [lock_env].
+    ///
+    /// ```
+    /// use std::env;
+    ///
+    /// let var = "ENV_LOCK_RAW_VARIABLE";
+    /// let lock = env_lock::lock_raw();
+    /// let guard = lock.into_guard([(var, Some("hello!"))]);
+    /// assert_eq!(env::var(var).unwrap(), "hello!");
+    /// drop(guard);
+    /// assert!(env::var(var).is_err());
+    /// ```
+    pub fn into_guard<'a>(
+        self,
+        variables: impl IntoIterator<
+            Item = (impl VariableNames<'a>, Option<impl IntoEnvValue>),
+        >,
+    ) -> EnvGuard<'a> {
+        let mut guard = EnvGuard::locked(self.lock, self.wait_duration);
+        guard.apply(expand_names(variables));
+        guard
+    }
+}
+
+/// A variable whose value will be reverted when its guard is dropped
+struct ManagedVariable<'a> {
+    name: Cow<'a, str>,
+    previous_value: Option<OsString>,
+    policy: RestorePolicy,
+    /// Value last set through the guard, or the value when it was first
+    /// managed if it hasn't been set. Anything else was set externally.
+    expected_value: Option<OsString>,
+}
+
+/// A variable managed by an [EnvGuard], returned by [EnvGuard::iter]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VariableState<'g> {
+    /// Variable name
+    pub name: &'g str,
+    /// Value before the guard modified it, or `None` if it was unset. This is
+    /// the value it will be restored to.
+    pub previous: Option<&'g OsStr>,
+    /// Current value, or `None` if it's unset
+    pub current: Option<OsString>,
+    /// What will happen to the variable when the guard is dropped
+    pub policy: RestorePolicy,
+}
+
+/// What to do with a variable when its guard is dropped
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum RestorePolicy {
+    /// Restore the variable to the value it had before it was modified
+    #[default]
+    Restore,
+    /// Leave the variable with whatever value it has when the guard is dropped
+    Keep,
+    /// Remove the variable, regardless of whether it was set before it was
+    /// modified
+    RemoveOnDrop,
+}
+
+impl<'a> EnvGuard<'a> {
+    /// Acquire the lock, then apply each variable
+    #[track_caller]
+    fn new(
+        variables: impl IntoIterator<
+            Item = (Cow<'a, str>, Option<impl AsRef<OsStr>>),
+        >,
+    ) -> Self {
+        let mut guard = Self::acquire();
+        guard.apply(variables);
+        guard
+    }
+
+    /// Acquire the lock without modifying any variables
+    #[track_caller]
+    fn acquire() -> Self {
+        let start = Instant::now();
+        let location = Location::caller();
+        let lock = lock::should_lock()
+            .then(|| HeldLock::Exclusive(ENV_LOCK.acquire(location)));
+        Self::locked(lock, start.elapsed())
+    }
+
+    /// Acquire the lock without blocking the thread, and without modifying
+    /// any variables
+    #[cfg(feature = "async")]
+    async fn acquire_async() -> Self {
+        let start = Instant::now();
+        let lock = if lock::should_lock() {
+            Some(HeldLock::Exclusive(
+                ENV_LOCK.acquire_async(Location::caller()).await,
+            ))
+        } else {
+            None
+        };
+        Self::locked(lock, start.elapsed())
+    }
+
+    /// Create a guard for a lock that was just acquired
+    fn locked(lock: Option<HeldLock>, wait_duration: Duration) -> Self {
+        if lock.is_some() {
+            stats::record_acquire(wait_duration);
+            #[cfg(feature = "tracing")]
+            trace::acquired(wait_duration);
+        }
+        let lock = lock.map(Arc::new);
+        TOTAL_WAIT_NANOS.fetch_add(
+            wait_duration.as_nanos().try_into().unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+        let acquired_at = Instant::now();
+        let mut guard = Self {
+            variables: Vec::new(),
+            lock,
+            wait_duration,
+            acquired_at,
+            hold_budget: None,
+            forensics_id: forensics::register(wait_duration, acquired_at),
+            recovery_id: Some(recovery::register()),
+            sandboxed: false,
+            baseline: None,
+            applied_baseline: false,
+            previous_dir: None,
+        };
+        // Guards that only lock some variables can't modify the rest
+        if !matches!(guard.lock.as_deref(), Some(HeldLock::Variables { .. })) {
+            guard.applied_baseline = baseline::enter(&mut guard);
+        }
+        guard
+    }
+
+    /// Apply each variable, and manage it so it's restored on drop
+    fn apply(
+        &mut self,
+        variables: impl IntoIterator<
+            Item = (Cow<'a, str>, Option<impl AsRef<OsStr>>),
+        >,
+    ) {
+        for (variable, new_value) in variables {
+            let previous_value = sys::var_os(&variable);
+            set_or_remove(&variable, new_value);
+            #[cfg(feature = "tracing-subscriber")]
+            log_filter::variables_changed([variable.as_ref()].into_iter());
+            // A variable given twice (or under two spellings on Windows) is
+            // restored to its value from before the first
+            if self.is_managed(&variable) {
+                self.update_expected(&variable);
+                continue;
+            }
+            // Manage each variable as soon as it's set, so if a later one
+            // panics (e.g. an invalid name), this one is restored on drop
+            self.variables.push(ManagedVariable {
+                expected_value: sys::var_os(&variable),
+                name: variable,
+                previous_value,
+                policy: RestorePolicy::default(),
+            });
+        }
+        self.update_registries();
+    }
+
+    /// Get how long this guard waited to acquire the lock. Long waits mean
+    /// tests are contending for the environment. See also
+    /// [total_wait_duration].
+    pub fn wait_duration(&self) -> Duration {
+        self.wait_duration
+    }
+
+    /// Require this guard to be dropped within the given duration of acquiring
+    /// the lock. This lets a test enforce a budget on its own critical
+    /// section, so it fails loudly if a change makes it hold the lock for too
+    /// long. The check happens on drop; the variables are still restored and
+    /// the lock is still released.
+    ///
+    /// ## Panics
+    /// The guard will panic on drop if the budget is exceeded, unless the
+    /// thread is already panicking.
+    #[track_caller]
+    pub fn assert_released_within(&mut self, budget: Duration) {
+        self.hold_budget = Some((budget, Location::caller()));
+    }
+
+    /// Convert this guard into one that owns all its variable names, so it's
+    /// no longer tied to the lifetime of the names it was created with. The
+    /// lock is still held and the variables are still restored on drop.
+    ///
+    /// ```
+    /// use env_lock::OwnedEnvGuard;
+    ///
+    /// struct Fixture {
+    ///     _guard: OwnedEnvGuard,
+    /// }
+    ///
+    /// fn setup(prefix: &str) -> Fixture {
+    ///     let name = format!("{prefix}_MODE");
+    ///     let guard = env_lock::lock_env([(name.as_str(), Some("test"))]);
+    ///     Fixture {
+    ///         _guard: guard.into_owned(),
+    ///     }
+    /// }
+    ///
+    /// let _fixture = setup("ENV_LOCK_OWNED");
+    /// assert_eq!(std::env::var("ENV_LOCK_OWNED_MODE").unwrap(), "test");
+    /// ```
+    pub fn into_owned(mut self) -> OwnedEnvGuard {
+        let variables = mem::take(&mut self.variables)
+            .into_iter()
+            .map(|managed| ManagedVariable {
+                name: Cow::Owned(managed.name.into_owned()),
+                previous_value: managed.previous_value,
+                policy: managed.policy,
+                expected_value: managed.expected_value,
+            })
+            .collect();
+        // Everything is moved out, so dropping self is a no-op
+        EnvGuard {
+            variables,
+            lock: self.lock.take(),
+            wait_duration: self.wait_duration,
+            acquired_at: self.acquired_at,
+            hold_budget: self.hold_budget.take(),
+            forensics_id: self.forensics_id.take(),
+            recovery_id: self.recovery_id.take(),
+            sandboxed: mem::take(&mut self.sandboxed),
+            baseline: self.baseline.take(),
+            applied_baseline: mem::take(&mut self.applied_baseline),
+            previous_dir: self.previous_dir.take(),
+        }
+    }
+
+    /// Combine two guards into one, which restores the variables of both and
+    /// holds both of their locks until it's dropped. This lets a fixture
+    /// compose helpers that each return a guard (e.g. one for the database
+    /// and one for auth) and hand back a single guard. If both guards manage
+    /// the same variable, it's restored to its value from before the guard
+    /// that was created first.
+    ///
+    /// ```
+    /// use std::env;
+    ///
+    /// fn database() -> env_lock::EnvGuard<'static> {
+    ///     env_lock::lock_env([("ENV_LOCK_MERGE_DATABASE", Some("postgres://"))])
+    /// }
+    ///
+    /// fn auth() -> env_lock::EnvGuard<'static> {
+    ///     env_lock::lock_env([("ENV_LOCK_MERGE_TOKEN", Some("hunter2"))])
+    /// }
+    ///
+    /// let guard = database().merge(auth());
+    /// assert_eq!(env::var("ENV_LOCK_MERGE_DATABASE").unwrap(), "postgres://");
+    /// assert_eq!(env::var("ENV_LOCK_MERGE_TOKEN").unwrap(), "hunter2");
+    /// drop(guard);
+    /// assert!(env::var("ENV_LOCK_MERGE_DATABASE").is_err());
+    /// assert!(env::var("ENV_LOCK_MERGE_TOKEN").is_err());
+    /// ```
+    pub fn merge(self, other: EnvGuard<'a>) -> EnvGuard<'a> {
+        // Recovery IDs are assigned in creation order
+        let (mut first, mut second) = if other.recovery_id < self.recovery_id {
+            (other, self)
+        } else {
+            (self, other)
+        };
+        for managed in mem::take(&mut second.variables) {
+            // The first guard's previous value is older
+            if !first.is_managed(&managed.name) {
+                first.variables.push(managed);
+            }
+        }
+        // Release the second lock first, since it was acquired last
+        first.lock = match (first.lock.take(), second.lock.take()) {
+            (Some(first), Some(second)) => {
+                Some(Arc::new(HeldLock::Merged(vec![second, first])))
+            }
+            (first, second) => first.or(second),
+        };
+        first.wait_duration += second.wait_duration;
+        first.hold_budget = first.hold_budget.or(second.hold_budget.take());
+        first.sandboxed |= mem::take(&mut second.sandboxed);
+        first.baseline = first.baseline.take().or(second.baseline.take());
+        first.previous_dir =
+            first.previous_dir.take().or(second.previous_dir.take());
+        if mem::take(&mut second.applied_baseline) {
+            if first.applied_baseline {
+                baseline::exit();
             } else {
-                env::remove_var(variable);
+                first.applied_baseline = true;
             }
         }
+        first.update_registries();
+        // Everything is moved out, so dropping the second guard is a no-op
+        first
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::panic;
+    /// Start tracking changes to the environment that aren't made through
+    /// this guard, e.g. by the code under test. The current environment is
+    /// the baseline, and changes made with guard methods such as
+    /// [set](Self::set) update it. Use [changes](Self::changes) or
+    /// [into_changes](Self::into_changes) to get the differences, before
+    /// they're clobbered by the restore on drop.
+    ///
+    /// ```
+    /// use env_lock::EnvChange;
+    /// use std::env;
+    ///
+    /// let var = "ENV_LOCK_CHANGES_RUST_LOG";
+    /// let mut guard = env_lock::lock_env([(var, None::<&str>)]);
+    /// guard.track_changes();
+    /// env::set_var(var, "debug"); // Code under test
+    /// assert_eq!(
+    ///     guard.into_changes(),
+    ///     [EnvChange {
+    ///         name: var.into(),
+    ///         before: None,
+    ///         after: Some("debug".into()),
+    ///     }]
+    /// );
+    /// assert!(env::var(var).is_err());
+    /// ```
+    pub fn track_changes(&mut self) {
+        self.baseline = Some(changes::capture());
+    }
 
-    // NOTE: Because these tests specifically modify environment variables
-    // *outside* the env lock, they each need to use a different variable. If
-    // only someone make a library that would avoid that...
+    /// Get every variable that differs from the environment established by
+    /// this guard, sorted by name. Variables whose name isn't valid UTF-8 are
+    /// skipped.
+    ///
+    /// ## Panics
+    /// Panics if [track_changes](Self::track_changes) hasn't been called.
+    #[track_caller]
+    pub fn changes(&self) -> Vec<EnvChange> {
+        let baseline = self
+            .baseline
+            .as_ref()
+            .expect("Changes aren't tracked; call `track_changes` first");
+        changes::diff(baseline)
+    }
 
-    /// Set a value for a variable that doesn't exist yet
-    #[test]
-    fn set_missing_var() {
-        let var = "ENV_LOCK_TEST_VARIABLE_SET_MISSING";
-        assert!(env::var(var).is_err());
+    /// Get every change like [changes](Self::changes), then restore and
+    /// unlock the environment by dropping the guard
+    ///
+    /// ## Panics
+    /// Panics if [track_changes](Self::track_changes) hasn't been called.
+    #[track_caller]
+    pub fn into_changes(self) -> Vec<EnvChange> {
+        self.changes()
+    }
 
-        let guard = lock_env([(var, Some("hello!"))]);
-        assert_eq!(env::var(var).unwrap(), "hello!");
-        drop(guard);
+    /// Split the given variables off into a new guard. The new guard restores
+    /// those variables when it's dropped, while this guard keeps managing the
+    /// rest. This is useful to restore some variables partway through a test,
+    /// e.g. once a child process has been spawned with them. The lock is
+    /// shared between both guards, and is only released once both are
+    /// dropped. Variables not managed by this guard are ignored.
+    ///
+    /// ```
+    /// use std::env;
+    ///
+    /// let var1 = "ENV_LOCK_SPLIT_1";
+    /// let var2 = "ENV_LOCK_SPLIT_2";
+    /// let mut guard = env_lock::lock_env([(var1, Some("1")), (var2, Some("2"))]);
+    /// let early = guard.split([var1]);
+    /// drop(early);
+    /// assert!(env::var(var1).is_err());
+    /// assert_eq!(env::var(var2).unwrap(), "2");
+    /// ```
+    pub fn split<'b>(
+        &mut self,
+        variables: impl IntoIterator<Item = &'b str>,
+    ) -> EnvGuard<'a> {
+        let variables: Vec<&str> = variables.into_iter().collect();
+        let (split, kept) = self.variables.drain(..).partition(|managed| {
+            variables
+                .iter()
+                .any(|variable| sys::same_name(&managed.name, variable))
+        });
+        self.variables = kept;
+        self.update_registries();
 
-        assert!(env::var(var).is_err());
+        let guard = EnvGuard {
+            variables: split,
+            lock: self.lock.clone(),
+            wait_duration: self.wait_duration,
+            acquired_at: self.acquired_at,
+            hold_budget: None,
+            forensics_id: forensics::register(
+                self.wait_duration,
+                self.acquired_at,
+            ),
+            recovery_id: Some(recovery::register()),
+            sandboxed: false,
+            baseline: None,
+            applied_baseline: false,
+            previous_dir: None,
+        };
+        guard.update_registries();
+        guard
     }
 
-    /// Override the value for a preexisting variable
-    #[test]
-    fn set_existing_var() {
-        let var = "ENV_LOCK_TEST_VARIABLE_SET_EXISTING";
-        env::set_var(var, "existing");
-        assert_eq!(env::var(var).unwrap(), "existing");
+    /// Layer more variables on top of this guard, in a child guard that shares
+    /// its lock. Dropping the child restores only the variables it set, and
+    /// the lock stays held by this guard, so a test can run the same code
+    /// under several environments without re-acquiring the lock in between.
+    /// The child borrows this guard, so it must be dropped first.
+    ///
+    /// ```
+    /// use std::env;
+    ///
+    /// let var = "ENV_LOCK_CHILD_VARIABLE";
+    /// let mut guard = env_lock::lock_env([(var, Some("outer"))]);
+    /// for value in ["a", "b"] {
+    ///     let child = guard.child([(var, Some(value))]);
+    ///     assert_eq!(env::var(var).unwrap(), value);
+    ///     drop(child);
+    ///     assert_eq!(env::var(var).unwrap(), "outer");
+    /// }
+    /// ```
+    #[track_caller]
+    pub fn child<'b>(
+        &'b mut self,
+        variables: impl IntoIterator<
+            Item = (impl VariableNames<'b>, Option<impl IntoEnvValue>),
+        >,
+    ) -> EnvGuard<'b> {
+        let mut child = EnvGuard {
+            variables: Vec::new(),
+            lock: self.lock.clone(),
+            wait_duration: Duration::ZERO,
+            acquired_at: Instant::now(),
+            hold_budget: None,
+            forensics_id: forensics::register(Duration::ZERO, Instant::now()),
+            recovery_id: Some(recovery::register()),
+            sandboxed: false,
+            baseline: None,
+            applied_baseline: false,
+            previous_dir: None,
+        };
+        child.apply(expand_names(variables));
+        child
+    }
 
-        let guard = lock_env([(var, Some("hello!"))]);
-        assert_eq!(env::var(var).unwrap(), "hello!");
-        drop(guard);
+    /// Set variables in a [child](Self::child) guard for the duration of a
+    /// closure. Once the closure returns (or panics), only those variables are
+    /// restored, and this guard keeps the lock.
+    ///
+    /// ```
+    /// use std::env;
+    ///
+    /// let var = "ENV_LOCK_SCOPE_VARIABLE";
+    /// let mut guard = env_lock::lock_env([(var, Some("outer"))]);
+    /// let value = guard.scope([(var, Some("inner"))], || env::var(var).unwrap());
+    /// assert_eq!(value, "inner");
+    /// assert_eq!(env::var(var).unwrap(), "outer");
+    /// ```
+    #[track_caller]
+    pub fn scope<'b, T>(
+        &'b mut self,
+        variables: impl IntoIterator<
+            Item = (impl VariableNames<'b>, Option<impl IntoEnvValue>),
+        >,
+        f: impl FnOnce() -> T,
+    ) -> T {
+        let _child = self.child(variables);
+        f()
+    }
 
-        assert_eq!(env::var(var).unwrap(), "existing");
+    /// Spawn threads that read or modify the environment under this guard.
+    /// Like [std::thread::scope], every thread spawned in the closure is
+    /// joined before this returns, so none of them can outlive the guard and
+    /// see the environment after it's restored, or while another test holds
+    /// the lock.
+    ///
+    /// The spawned threads don't hold the lock themselves, so they can't
+    /// reenter it: calling [lock_env] from one of them blocks until this guard
+    /// is dropped.
+    ///
+    /// ```
+    /// use std::env;
+    ///
+    /// let var = "ENV_LOCK_THREAD_SCOPE_VARIABLE";
+    /// let guard = env_lock::lock_env([(var, Some("hello!"))]);
+    /// let value = guard.thread_scope(|s| {
+    ///     s.spawn(|| env::var(var).unwrap()).join().unwrap()
+    /// });
+    /// assert_eq!(value, "hello!");
+    /// ```
+    pub fn thread_scope<'env, T>(
+        &'env self,
+        f: impl for<'scope> FnOnce(&'scope thread::Scope<'scope, 'env>) -> T,
+    ) -> T {
+        thread::scope(f)
     }
 
-    /// Remove the value for a preexisting variable
-    #[test]
-    fn clear_existing_var() {
-        let var = "ENV_LOCK_TEST_VARIABLE_CLEAR_EXISTING";
-        env::set_var(var, "existing");
-        assert_eq!(env::var(var).unwrap(), "existing");
+    /// Temporarily restore every managed variable and release the lock while
+    /// a closure runs, e.g. during a slow operation that doesn't read the
+    /// environment. Once the closure returns, the lock is re-acquired and the
+    /// variables are set back to their values from before the call. If the
+    /// closure panics, the lock is still re-acquired before unwinding, so the
+    /// guard can restore the environment on drop.
+    ///
+    /// If the current thread holds another guard that was created before this
+    /// one, the lock isn't actually released until that guard is dropped too.
+    ///
+    /// ```
+    /// use std::env;
+    ///
+    /// let var = "ENV_LOCK_UNLOCKED_VARIABLE";
+    /// let mut guard = env_lock::lock_env([(var, Some("hello!"))]);
+    /// guard.unlocked(|| {
+    ///     // Other tests can lock the environment here
+    ///     assert!(env::var(var).is_err());
+    /// });
+    /// assert_eq!(env::var(var).unwrap(), "hello!");
+    /// ```
+    ///
+    /// ## Panics
+    /// Panics if the lock is shared with a guard created by
+    /// [split](Self::split), because releasing it would leave that guard
+    /// unprotected.
+    #[track_caller]
+    pub fn unlocked<T>(&mut self, f: impl FnOnce() -> T) -> T {
+        let location = Location::caller();
+        let lock = match self.lock.take().map(Arc::try_unwrap) {
+            Some(Ok(lock)) => Some(lock),
+            Some(Err(lock)) => {
+                self.lock = Some(lock);
+                panic!(
+                    "The environment lock can't be released while it's shared \
+                    with a split guard"
+                );
+            }
+            None => None,
+        };
+        let current: Vec<(String, Option<OsString>)> = self
+            .variables
+            .iter()
+            .map(|managed| {
+                (managed.name.to_string(), sys::var_os(&managed.name))
+            })
+            .collect();
+        for managed in &self.variables {
+            set_or_remove(&managed.name, managed.previous_value.as_deref());
+        }
+        let current_dir = self.previous_dir.as_ref().and_then(|previous| {
+            let current = env::current_dir().ok()?;
+            env::set_current_dir(previous).ok()?;
+            Some(current)
+        });
+        let exclusive = lock
+            .as_ref()
+            .map(|lock| !matches!(lock, HeldLock::Variables { .. }));
+        if self.applied_baseline {
+            baseline::exit();
+        }
+        if lock.is_some() {
+            let held = self.acquired_at.elapsed();
+            drop(lock);
+            stats::record_release(held);
+            #[cfg(feature = "tracing")]
+            trace::released(held);
+        }
 
-        let guard = lock_env([(var, None::<&str>)]);
-        assert!(env::var(var).is_err());
-        drop(guard);
+        let released_at = Instant::now();
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(f));
 
-        assert_eq!(env::var(var).unwrap(), "existing");
+        let start = Instant::now();
+        let lock = exclusive.map(|exclusive| {
+            if exclusive {
+                HeldLock::Exclusive(ENV_LOCK.acquire(location))
+            } else {
+                HeldLock::variables(
+                    current
+                        .iter()
+                        .map(|(name, _)| sys::name_key(name).into_owned()),
+                    location,
+                )
+            }
+        });
+        if lock.is_some() {
+            let wait_duration = start.elapsed();
+            stats::record_acquire(wait_duration);
+            #[cfg(feature = "tracing")]
+            trace::acquired(wait_duration);
+            self.wait_duration += wait_duration;
+        }
+        self.lock = lock.map(Arc::new);
+        if self.applied_baseline {
+            baseline::reenter();
+        }
+        // Time spent unlocked doesn't count against the hold budget
+        self.acquired_at += released_at.elapsed();
+
+        match result {
+            Ok(value) => {
+                for (name, value) in current {
+                    set_or_remove(&name, value);
+                }
+                if let Some(current_dir) = current_dir {
+                    let _ = env::set_current_dir(current_dir);
+                }
+                value
+            }
+            Err(error) => panic::resume_unwind(error),
+        }
+    }
+
+    /// Release the lock *without* restoring any variables, so every change
+    /// made under this guard persists for the rest of the process, including
+    /// a new [working directory](Self::set_current_dir). This is
+    /// useful for one-time global setup that needs exclusive access while it
+    /// modifies the environment.
+    ///
+    /// ```
+    /// use std::env;
+    ///
+    /// let var = "ENV_LOCK_COMMIT_VARIABLE";
+    /// let guard = env_lock::lock_env([(var, Some("hello!"))]);
+    /// guard.commit();
+    /// assert_eq!(env::var(var).unwrap(), "hello!");
+    /// ```
+    pub fn commit(mut self) {
+        self.variables.clear();
+        self.sandboxed = false;
+        self.previous_dir = None;
+        self.update_registries();
+    }
+
+    /// Stop tracking a variable, so that its current value persists after the
+    /// guard is dropped. All other variables will still be restored. This has
+    /// no effect if the variable isn't managed by this guard.
+    pub fn forget_var(&mut self, variable: &str) {
+        self.variables
+            .retain(|managed| !sys::same_name(&managed.name, variable));
+        self.update_registries();
+    }
+
+    /// Get the value a managed variable had before this guard modified it,
+    /// i.e. the value it will be restored to. The outer `Option` is `None` if
+    /// the variable isn't managed by this guard; the inner is `None` if the
+    /// variable was unset.
+    ///
+    /// ```
+    /// use std::{env, ffi::OsStr};
+    ///
+    /// let var = "ENV_LOCK_PREVIOUS_VARIABLE";
+    /// env::set_var(var, "existing");
+    /// let guard = env_lock::lock_env([(var, Some("hello!"))]);
+    /// assert_eq!(guard.previous(var), Some(Some(OsStr::new("existing"))));
+    /// assert_eq!(guard.previous("ENV_LOCK_PREVIOUS_UNMANAGED"), None);
+    /// ```
+    pub fn previous(&self, variable: &str) -> Option<Option<&OsStr>> {
+        self.variables
+            .iter()
+            .find(|managed| sys::same_name(&managed.name, variable))
+            .map(|managed| managed.previous_value.as_deref())
+    }
+
+    /// Get every managed variable whose value was changed without going
+    /// through this guard, e.g. by code calling [std::env::set_var] directly
+    /// while the lock is held. Each change is from the value this guard last
+    /// set to the current value. Unlike [changes](Self::changes), this only
+    /// covers managed variables, and doesn't require any setup. See also
+    /// [set_mutation_check] to check every guard on drop.
+    ///
+    /// ```
+    /// use std::env;
+    ///
+    /// let var = "ENV_LOCK_EXTERNAL_VARIABLE";
+    /// let guard = env_lock::lock_env([(var, Some("1"))]);
+    /// assert!(guard.external_mutations().is_empty());
+    /// env::set_var(var, "2");
+    /// let mutation = &guard.external_mutations()[0];
+    /// assert_eq!(mutation.before.as_deref(), Some("1"));
+    /// assert_eq!(mutation.after.as_deref(), Some("2"));
+    /// ```
+    pub fn external_mutations(&self) -> Vec<EnvChange> {
+        let lossy = |value: &OsStr| value.to_string_lossy().into_owned();
+        self.variables
+            .iter()
+            .filter_map(|managed| {
+                let current = sys::var_os(&managed.name);
+                (current != managed.expected_value).then(|| EnvChange {
+                    name: managed.name.to_string(),
+                    before: managed.expected_value.as_deref().map(lossy),
+                    after: current.as_deref().map(lossy),
+                })
+            })
+            .collect()
+    }
+
+    /// Iterate over the variables managed by this guard, in the order they
+    /// were first modified, with their previous and current values. Use this
+    /// to log the environment as configured for a test.
+    ///
+    /// ```
+    /// let guard = env_lock::lock_env([
+    ///     ("ENV_LOCK_ITER_HOST", Some("localhost")),
+    ///     ("ENV_LOCK_ITER_PORT", Some("8080")),
+    /// ]);
+    /// for variable in guard.iter() {
+    ///     println!("{}={:?}", variable.name, variable.current);
+    /// }
+    /// ```
+    pub fn iter(&self) -> impl Iterator<Item = VariableState<'_>> {
+        self.variables.iter().map(|managed| VariableState {
+            name: &managed.name,
+            previous: managed.previous_value.as_deref(),
+            current: sys::var_os(&managed.name),
+            policy: managed.policy,
+        })
+    }
+
+    /// Get the current value of each variable managed by this guard, keyed by
+    /// name. Unset variables and values that aren't valid UTF-8 are omitted.
+    /// Use this to feed the same overrides to a config layer directly, e.g.
+    /// with figment's `Serialized::defaults` or config's `set_override`, so
+    /// tests that build config from the environment and from a map can share
+    /// a single list of variables.
+    ///
+    /// ```
+    /// use std::collections::BTreeMap;
+    ///
+    /// let guard = env_lock::lock_env([
+    ///     ("ENV_LOCK_MAP_HOST", Some("localhost")),
+    ///     ("ENV_LOCK_MAP_PORT", None),
+    /// ]);
+    /// assert_eq!(
+    ///     guard.to_map(),
+    ///     BTreeMap::from([("ENV_LOCK_MAP_HOST".to_owned(), "localhost".to_owned())]),
+    /// );
+    /// ```
+    pub fn to_map(&self) -> BTreeMap<String, String> {
+        self.iter()
+            .filter_map(|variable| {
+                let value = variable.current?.into_string().ok()?;
+                Some((variable.name.to_owned(), value))
+            })
+            .collect()
+    }
+
+    /// Restore a managed variable to its previous value now, while keeping
+    /// the lock. The variable is still managed, so it's restored again when
+    /// the guard is dropped. This has no effect if the variable isn't managed
+    /// by this guard.
+    ///
+    /// ```
+    /// use std::env;
+    ///
+    /// let var = "ENV_LOCK_RESTORE_VARIABLE";
+    /// let mut guard = env_lock::lock_env([(var, Some("hello!"))]);
+    /// guard.restore(var);
+    /// assert!(env::var(var).is_err());
+    /// ```
+    pub fn restore(&mut self, variable: &str) {
+        if let Some(previous) = self.previous(variable) {
+            let previous = previous.map(OsStr::to_owned);
+            self.set_managed(variable, previous);
+        }
+    }
+
+    /// Set what happens to a variable when this guard is dropped. By default,
+    /// every variable is [restored](RestorePolicy::Restore) to its previous
+    /// value. If the variable isn't already managed by this guard, it will be
+    /// added with its current value as the previous value.
+    ///
+    /// ```
+    /// use env_lock::RestorePolicy;
+    /// use std::env;
+    ///
+    /// let var = "ENV_LOCK_POLICY_VARIABLE";
+    /// env::set_var(var, "existing");
+    ///
+    /// let mut guard = env_lock::lock_env([(var, Some("hello!"))]);
+    /// guard.set_restore_policy(var, RestorePolicy::RemoveOnDrop);
+    /// drop(guard);
+    ///
+    /// assert!(env::var(var).is_err());
+    /// ```
+    pub fn set_restore_policy(
+        &mut self,
+        variable: &str,
+        policy: RestorePolicy,
+    ) {
+        self.manage(variable);
+        for managed in &mut self.variables {
+            if sys::same_name(&managed.name, variable) {
+                managed.policy = policy;
+            }
+        }
+        self.update_registries();
+    }
+
+    /// Set more variables on a guard that's already held, e.g. so a shared
+    /// helper can layer its own variables onto a guard created by a fixture.
+    /// The new variables are restored along with the rest when the guard is
+    /// dropped. Accepts the same input as [lock_env].
+    ///
+    /// ```
+    /// use std::env;
+    ///
+    /// fn with_proxy(guard: &mut env_lock::EnvGuard) {
+    ///     guard.extend([("ENV_LOCK_EXTEND_PROXY", Some("http://proxy"))]);
+    /// }
+    ///
+    /// let mut guard = env_lock::lock_env([("ENV_LOCK_EXTEND_MODE", Some("a"))]);
+    /// with_proxy(&mut guard);
+    /// assert_eq!(env::var("ENV_LOCK_EXTEND_PROXY").unwrap(), "http://proxy");
+    /// drop(guard);
+    /// assert!(env::var("ENV_LOCK_EXTEND_PROXY").is_err());
+    /// ```
+    pub fn extend(
+        &mut self,
+        variables: impl IntoIterator<
+            Item = (impl VariableNames<'a>, Option<impl IntoEnvValue>),
+        >,
+    ) {
+        for (variable, value) in expand_names(variables) {
+            self.set_managed(&variable, value);
+        }
+    }
+
+    /// Set a variable while the lock is held. If the variable isn't already
+    /// managed by this guard, its current value is captured first, so it's
+    /// restored when the guard is dropped.
+    ///
+    /// ```
+    /// use std::env;
+    ///
+    /// let var = "ENV_LOCK_SET_VARIABLE";
+    /// let mut guard = env_lock::lock_env([("ENV_LOCK_SET_MODE", Some("a"))]);
+    /// guard.set(var, "hello!");
+    /// assert_eq!(env::var(var).unwrap(), "hello!");
+    /// guard.remove(var);
+    /// assert!(env::var(var).is_err());
+    /// drop(guard);
+    /// assert!(env::var(var).is_err());
+    /// ```
+    pub fn set(&mut self, variable: &str, value: impl AsRef<OsStr>) {
+        self.set_managed(variable, Some(value));
+    }
+
+    /// Remove a variable while the lock is held. Like [set](Self::set), its
+    /// current value is captured first if it isn't already managed.
+    pub fn remove(&mut self, variable: &str) {
+        self.set_managed(variable, None::<&str>);
+    }
+
+    /// Change the current working directory while the lock is held. The
+    /// working directory is process-wide state just like the environment, so
+    /// tests that rely on relative paths can share the environment lock
+    /// instead of needing another one. The original directory is restored
+    /// when the guard is dropped. See also [lock_env_with_cwd].
+    ///
+    /// ```
+    /// use std::env;
+    ///
+    /// let original = env::current_dir().unwrap();
+    /// let mut guard = env_lock::lock_env([("ENV_LOCK_CWD_MODE", Some("test"))]);
+    /// guard.set_current_dir(env::temp_dir()).unwrap();
+    /// assert_ne!(env::current_dir().unwrap(), original);
+    /// drop(guard);
+    /// assert_eq!(env::current_dir().unwrap(), original);
+    /// ```
+    ///
+    /// ## Panics
+    /// Panics if the guard only locks some variables, e.g. from
+    /// [lock_env_vars], because it doesn't exclude other guards from changing
+    /// the working directory.
+    pub fn set_current_dir(
+        &mut self,
+        path: impl AsRef<Path>,
+    ) -> io::Result<()> {
+        if matches!(self.lock.as_deref(), Some(HeldLock::Variables { .. })) {
+            panic!(
+                "Changing the working directory requires exclusive access to \
+                the environment, but this guard only locks some variables"
+            );
+        }
+        let current = env::current_dir()?;
+        env::set_current_dir(path)?;
+        self.previous_dir.get_or_insert(current);
+        Ok(())
+    }
+
+    /// Get and parse the value of a typed variable while the lock is held.
+    /// Returns `None` if the variable is unset or isn't valid UTF-8. See
+    /// [EnvVar].
+    ///
+    /// ## Panics
+    /// Panics if the value can't be parsed as `T`
+    #[track_caller]
+    pub fn get<T>(&self, var: &EnvVar<T>) -> Option<T>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        typed::parse(var)
+    }
+
+    /// Read a struct from the environment while the lock is held, one
+    /// variable per field, to check the configuration the code under test
+    /// would see. Variable names are derived from field names by `naming`.
+    /// See [FromEnv].
+    pub fn deserialize<T: FromEnv>(
+        &self,
+        naming: &EnvNaming,
+    ) -> Result<T, FromEnvError> {
+        structured::deserialize(naming)
+    }
+
+    /// Create a command for a program, configured with every variable this
+    /// guard manages. See [configure_command](Self::configure_command).
+    ///
+    /// ```no_run
+    /// use std::env;
+    ///
+    /// let var = "ENV_LOCK_COMMAND_VARIABLE";
+    /// let guard = env_lock::lock_env([(var, Some("hello!"))]);
+    /// let mut command = guard.command("env");
+    /// drop(guard);
+    /// // The command still sets the variable after the guard is dropped
+    /// let output = command.output().unwrap();
+    /// let stdout = String::from_utf8(output.stdout).unwrap();
+    /// assert!(stdout.contains("ENV_LOCK_COMMAND_VARIABLE=hello!"));
+    /// ```
+    pub fn command(&self, program: impl AsRef<OsStr>) -> Command {
+        let mut command = Command::new(program);
+        self.configure_command(&mut command);
+        command
+    }
+
+    /// Configure a command with every variable this guard manages, with its
+    /// current value. Variables that are currently unset are removed from the
+    /// command with [Command::env_remove]. A subprocess spawned while the
+    /// guard is held inherits these values anyway, but configuring them
+    /// explicitly keeps them in effect after the guard is dropped, and makes
+    /// them visible via [Command::get_envs].
+    pub fn configure_command<'c>(
+        &self,
+        command: &'c mut Command,
+    ) -> &'c mut Command {
+        for managed in &self.variables {
+            match sys::var_os(&managed.name) {
+                Some(value) => command.env(&*managed.name, value),
+                None => command.env_remove(&*managed.name),
+            };
+        }
+        command
+    }
+
+    /// Set a variable to a new value (or remove it if the value is `None`),
+    /// and return the value it had immediately before. This is the current
+    /// value, *not* the value from before the environment was locked. The
+    /// variable will still be restored to its pre-lock value when the guard
+    /// is dropped. If the variable isn't already managed by this guard, it
+    /// will be added. Accepts any value that [lock_env] does.
+    ///
+    /// ```
+    /// use std::{env, ffi::OsStr};
+    ///
+    /// let var = "ENV_LOCK_SWAP_VARIABLE";
+    /// let mut guard = env_lock::lock_env([(var, Some("ping"))]);
+    /// assert_eq!(guard.swap(var, Some("pong")).as_deref(), Some(OsStr::new("ping")));
+    /// assert_eq!(guard.swap(var, Some(1)).as_deref(), Some(OsStr::new("pong")));
+    /// drop(guard);
+    /// assert!(env::var(var).is_err());
+    /// ```
+    pub fn swap(
+        &mut self,
+        variable: &str,
+        value: Option<impl IntoEnvValue>,
+    ) -> Option<OsString> {
+        let current = sys::var_os(variable);
+        self.set_managed(variable, value.map(IntoEnvValue::into_env_value));
+        current
+    }
+
+    /// Copy every variable whose name starts with `from` to a new name with
+    /// `from` replaced by `to`, e.g. to mirror `MYAPP_*` into `LEGACYAPP_*`.
+    /// Both the original and new variables are restored when the guard is
+    /// dropped. Returns the number of variables copied. Variables whose name
+    /// isn't valid UTF-8 are skipped.
+    ///
+    /// ```
+    /// use std::env;
+    ///
+    /// let mut guard = env_lock::lock_env([("MYAPP_PORT", Some("80"))]);
+    /// guard.copy_prefix("MYAPP_", "LEGACYAPP_");
+    /// assert_eq!(env::var("MYAPP_PORT").unwrap(), "80");
+    /// assert_eq!(env::var("LEGACYAPP_PORT").unwrap(), "80");
+    /// ```
+    pub fn copy_prefix(&mut self, from: &str, to: &str) -> usize {
+        self.remap_prefix(from, to, false)
+    }
+
+    /// Like [copy_prefix](Self::copy_prefix), but the original variables are
+    /// removed. An empty `to` strips the prefix, e.g. to remove a deployment
+    /// prefix. Both namespaces are restored when the guard is dropped.
+    pub fn rename_prefix(&mut self, from: &str, to: &str) -> usize {
+        self.remap_prefix(from, to, true)
+    }
+
+    fn remap_prefix(&mut self, from: &str, to: &str, rename: bool) -> usize {
+        if from == to {
+            return 0;
+        }
+        // Collect everything first, so new variables aren't remapped again
+        let remapped: Vec<(String, String, OsString)> = sys::vars_os()
+            .filter_map(|(name, value)| {
+                let name = name.into_string().ok()?;
+                let new_name = format!("{to}{}", name.strip_prefix(from)?);
+                Some((name, new_name, value))
+            })
+            .collect();
+        for (_, new_name, value) in &remapped {
+            self.set_managed(new_name, Some(value));
+        }
+        if rename {
+            for (name, _, _) in &remapped {
+                // Don't remove anything we just created
+                if !remapped
+                    .iter()
+                    .any(|(_, new_name, _)| sys::same_name(new_name, name))
+                {
+                    self.set_managed(name, None::<&str>);
+                }
+            }
+        }
+        remapped.len()
+    }
+
+    /// Set a variable, managing it if it isn't already
+    fn set_managed(
+        &mut self,
+        variable: &str,
+        value: Option<impl AsRef<OsStr>>,
+    ) {
+        self.manage(variable);
+        set_or_remove(variable, value);
+        self.update_expected(variable);
+        // Changes made through the guard aren't reported
+        if let Some(baseline) = &mut self.baseline {
+            match sys::var_os(variable) {
+                Some(value) => {
+                    baseline.insert(sys::name_key(variable).into_owned(), value)
+                }
+                None => baseline.remove(&*sys::name_key(variable)),
+            };
+        }
+        #[cfg(feature = "tracing-subscriber")]
+        log_filter::variables_changed([variable].into_iter());
+    }
+
+    /// If a variable isn't managed by this guard, start managing it, with its
+    /// current value as the value to restore
+    fn manage(&mut self, variable: &str) {
+        if !self.is_managed(variable) {
+            let value = sys::var_os(variable);
+            self.variables.push(ManagedVariable {
+                name: Cow::Owned(variable.to_owned()),
+                previous_value: value.clone(),
+                policy: RestorePolicy::default(),
+                expected_value: value,
+            });
+            self.update_registries();
+        }
+    }
+
+    /// Record the current value of a managed variable as set by this guard
+    fn update_expected(&mut self, variable: &str) {
+        if let Some(managed) = self
+            .variables
+            .iter_mut()
+            .find(|managed| sys::same_name(&managed.name, variable))
+        {
+            managed.expected_value = sys::var_os(variable);
+        }
+    }
+
+    /// Is a variable managed by this guard?
+    fn is_managed(&self, variable: &str) -> bool {
+        self.variables
+            .iter()
+            .any(|managed| sys::same_name(&managed.name, variable))
+    }
+
+    /// Update the forensics and recovery registries with the current set of
+    /// variables
+    fn update_registries(&self) {
+        if let Some(id) = self.forensics_id {
+            forensics::update(
+                id,
+                self.variables.iter().map(|managed| {
+                    (managed.name.as_ref(), managed.previous_value.as_deref())
+                }),
+            );
+        }
+        if let Some(id) = self.recovery_id {
+            recovery::update(
+                id,
+                self.variables.iter().map(|managed| {
+                    (
+                        managed.name.as_ref(),
+                        managed.previous_value.as_deref(),
+                        managed.policy,
+                    )
+                }),
+            );
+        }
+    }
+}
+
+impl<'a> Drop for EnvGuard<'a> {
+    fn drop(&mut self) {
+        if let Some(id) = self.forensics_id {
+            forensics::unregister(id);
+        }
+        // If force_restore_all already restored this guard, the environment
+        // may belong to someone else now, so leave it alone
+        if self
+            .recovery_id
+            .take()
+            .is_some_and(|id| !recovery::unregister(id))
+        {
+            return;
+        }
+        let check = mutation::check();
+        let mutations = if check == MutationCheck::Off {
+            Vec::new()
+        } else {
+            self.external_mutations()
+        };
+        if self.sandboxed {
+            // Remove anything created while the sandbox was held
+            let created: Vec<String> = sys::vars_os()
+                .filter_map(|(name, _)| name.into_string().ok())
+                .filter(|name| !self.is_managed(name))
+                .collect();
+            for name in created {
+                set_or_remove(&name, None::<&str>);
+            }
+        }
+        // Restore each env var
+        #[cfg(feature = "tracing")]
+        let span = trace::restore_span();
+        for managed in &self.variables {
+            match managed.policy {
+                RestorePolicy::Restore => set_or_remove(
+                    &managed.name,
+                    managed.previous_value.as_deref(),
+                ),
+                RestorePolicy::Keep => {}
+                RestorePolicy::RemoveOnDrop => {
+                    set_or_remove(&managed.name, None::<&str>)
+                }
+            }
+        }
+        #[cfg(feature = "tracing")]
+        drop(span);
+        #[cfg(feature = "tracing-subscriber")]
+        log_filter::variables_changed(
+            self.variables.iter().map(|m| m.name.as_ref()),
+        );
+        if let Some(previous_dir) = self.previous_dir.take() {
+            if let Err(error) = env::set_current_dir(&previous_dir) {
+                eprintln!(
+                    "env-lock: error restoring working directory to \
+                    {previous_dir:?}: {error}"
+                );
+            }
+        }
+        if self.applied_baseline {
+            baseline::exit();
+        }
+
+        // Split guards share the lock, so only the last one releases it
+        if self
+            .lock
+            .as_ref()
+            .is_some_and(|lock| Arc::strong_count(lock) == 1)
+        {
+            let held = self.acquired_at.elapsed();
+            stats::record_release(held);
+            #[cfg(feature = "tracing")]
+            trace::released(held);
+        }
+
+        if !mutations.is_empty() {
+            let description = mutation::describe(&mutations);
+            match check {
+                MutationCheck::Panic if !thread::panicking() => {
+                    panic!("{description}")
+                }
+                _ => eprintln!("{description}"),
+            }
+        }
+
+        if let Some((budget, location)) = self.hold_budget {
+            let held = self.acquired_at.elapsed();
+            if held > budget && !thread::panicking() {
+                panic!(
+                    "Environment lock was held for {held:?}, exceeding the \
+                    budget of {budget:?} set at {location}"
+                );
+            }
+        }
+    }
+}
+
+/// Assert that no guard currently holds the environment lock. This is useful
+/// for global setup code that must not run concurrently with any env-locked
+/// test, or to detect guards that were leaked with [std::mem::forget].
+///
+/// ## Panics
+/// Panics if the lock is currently held. To wait for the lock to be released
+/// instead of failing immediately, use [assert_unlocked_within].
+#[track_caller]
+pub fn assert_unlocked() {
+    assert_unlocked_within(Duration::ZERO);
+}
+
+/// Assert that the environment lock is released within the given timeout.
+/// This will block until the lock is free, and panic if it's still held once
+/// the timeout expires. Note that this does *not* acquire the lock, so another
+/// guard may lock the environment immediately after this returns.
+#[track_caller]
+pub fn assert_unlocked_within(timeout: Duration) {
+    assert!(
+        ENV_LOCK.wait_unlocked(timeout),
+        "Expected environment to be unlocked, but it was still locked after \
+        {timeout:?}"
+    );
+}
+
+/// Assert that an expression acquiring the environment lock is exclusive: a
+/// second acquisition on another thread must block until the first guard is
+/// dropped. This is for crates that wrap env-lock, to verify their wrapper
+/// doesn't bypass the lock. The expression is evaluated once on the current
+/// thread and once on another thread, and each result is held as a guard.
+///
+/// The second acquisition must still be blocked after a grace period, which
+/// defaults to 100ms and can be given as a second argument. If the test
+/// harness is running on a single thread, set [LockMode::Always] first, or the
+/// lock will be skipped.
+///
+/// ```
+/// env_lock::set_lock_mode(env_lock::LockMode::Always);
+/// env_lock::assert_exclusive!(env_lock::lock_env([(
+///     "ENV_LOCK_EXCLUSIVE",
+///     Some("1"),
+/// )]));
+/// ```
+///
+/// ## Panics
+/// Panics if the second acquisition completes while the first guard is held,
+/// or if the expression panics.
+#[macro_export]
+macro_rules! assert_exclusive {
+    ($acquire:expr $(,)?) => {
+        $crate::assert_exclusive!(
+            $acquire,
+            ::std::time::Duration::from_millis(100)
+        )
+    };
+    ($acquire:expr, $grace_period:expr $(,)?) => {
+        $crate::__assert_exclusive(|| $acquire, $grace_period)
+    };
+}
+
+/// Implementation of [assert_exclusive]
+#[doc(hidden)]
+#[track_caller]
+pub fn __assert_exclusive<G>(acquire: impl Fn() -> G + Sync, grace: Duration) {
+    let first = acquire();
+    let acquired = AtomicBool::new(false);
+    let (started_tx, started_rx) = mpsc::channel();
+    let second_acquired = thread::scope(|scope| {
+        let second = scope.spawn(|| {
+            let _ = started_tx.send(());
+            let guard = acquire();
+            acquired.store(true, Ordering::Release);
+            drop(guard);
+        });
+        let _ = started_rx.recv();
+        thread::sleep(grace);
+        let second_acquired = acquired.load(Ordering::Acquire);
+        drop(first);
+        // Propagate panics from the second acquisition
+        if let Err(error) = second.join() {
+            panic::resume_unwind(error);
+        }
+        second_acquired
+    });
+    assert!(
+        !second_acquired,
+        "Lock was acquired by a second thread while the first guard was held"
+    );
+}
+
+/// Assert that a variable has a value. The lock is held while reading the
+/// variable, so the check can't race with other tests (if the current thread
+/// already holds the lock, it's reentered). On failure, the message includes
+/// the actual value and every variable sharing the name's prefix (up to the
+/// first `_`), to help diagnose misconfiguration. An optional format string
+/// and arguments can be given for additional context, as with [assert_eq].
+///
+/// ```
+/// let _guard = env_lock::lock_env([("ENV_LOCK_ASSERT_MODE", Some("test"))]);
+/// env_lock::assert_env_eq!("ENV_LOCK_ASSERT_MODE", "test");
+/// env_lock::assert_env_eq!("ENV_LOCK_ASSERT_MODE", "test", "mode for {}", "this test");
+/// ```
+///
+/// ```should_panic
+/// let _guard = env_lock::lock_env([("ENV_LOCK_ASSERT_MODE", Some("test"))]);
+/// env_lock::assert_env_eq!("ENV_LOCK_ASSERT_MODE", "prod");
+/// ```
+#[macro_export]
+macro_rules! assert_env_eq {
+    ($variable:expr, $expected:expr $(,)?) => {
+        $crate::__assert_env(
+            ::std::convert::AsRef::<str>::as_ref(&$variable),
+            ::std::option::Option::Some(
+                ::std::convert::AsRef::<str>::as_ref(&$expected),
+            ),
+            ::std::option::Option::None,
+        )
+    };
+    ($variable:expr, $expected:expr, $($arg:tt)+) => {
+        $crate::__assert_env(
+            ::std::convert::AsRef::<str>::as_ref(&$variable),
+            ::std::option::Option::Some(
+                ::std::convert::AsRef::<str>::as_ref(&$expected),
+            ),
+            ::std::option::Option::Some(::std::format_args!($($arg)+)),
+        )
+    };
+}
+
+/// Assert that a variable is unset, while holding the lock. See
+/// [assert_env_eq].
+///
+/// ```
+/// let _guard = env_lock::lock_env([("ENV_LOCK_ASSERT_PROXY", None::<&str>)]);
+/// env_lock::assert_env_unset!("ENV_LOCK_ASSERT_PROXY");
+/// ```
+#[macro_export]
+macro_rules! assert_env_unset {
+    ($variable:expr $(,)?) => {
+        $crate::__assert_env(
+            ::std::convert::AsRef::<str>::as_ref(&$variable),
+            ::std::option::Option::None,
+            ::std::option::Option::None,
+        )
+    };
+    ($variable:expr, $($arg:tt)+) => {
+        $crate::__assert_env(
+            ::std::convert::AsRef::<str>::as_ref(&$variable),
+            ::std::option::Option::None,
+            ::std::option::Option::Some(::std::format_args!($($arg)+)),
+        )
+    };
+}
+
+/// Implementation of [assert_env_eq] and [assert_env_unset]
+#[doc(hidden)]
+#[track_caller]
+pub fn __assert_env(
+    variable: &str,
+    expected: Option<&str>,
+    message: Option<fmt::Arguments<'_>>,
+) {
+    let _guard = EnvGuard::acquire();
+    let actual = sys::var_os(variable);
+    if actual.as_deref() == expected.map(OsStr::new) {
+        return;
+    }
+
+    let describe = |value: Option<&OsStr>| match value {
+        Some(value) => format!("{value:?}"),
+        None => "<unset>".to_owned(),
+    };
+    let mut failure = format!(
+        "assertion failed: `{variable}`\n  expected: {}\n    actual: {}",
+        describe(expected.map(OsStr::new)),
+        describe(actual.as_deref()),
+    );
+    if let Some(message) = message {
+        failure = format!("{failure}\n   message: {message}");
+    }
+    // Show variables that are likely to be related, e.g. MYAPP_*
+    let prefix = match variable.find('_') {
+        Some(index) => &variable[..=index],
+        None => variable,
+    };
+    let mut related: Vec<_> = sys::vars_os()
+        .filter(|(name, _)| {
+            name.to_str().is_some_and(|name| {
+                name.get(..prefix.len())
+                    .is_some_and(|start| sys::same_name(start, prefix))
+            })
+        })
+        .collect();
+    related.sort();
+    if !related.is_empty() {
+        failure.push_str(&format!("\nvariables matching `{prefix}*`:"));
+        for (name, value) in related {
+            failure
+                .push_str(&format!("\n  {}={value:?}", name.to_string_lossy()));
+        }
+    }
+    panic!("{failure}");
+}
+
+/// Lock the environment with map-like syntax. This expands to [lock_env], but
+/// values don't need to be wrapped in `Some`, and `None` removes a variable
+/// without a type annotation. Values can be `&str`, `String`, or an `Option`
+/// of either.
+///
+/// ```
+/// use std::env;
+///
+/// let port = 8080.to_string();
+/// let _guard = env_lock::lock! {
+///     "ENV_LOCK_LOCK_MACRO_HOST" => "localhost",
+///     "ENV_LOCK_LOCK_MACRO_PORT" => port,
+///     "ENV_LOCK_LOCK_MACRO_PROXY" => None,
+/// };
+/// assert_eq!(env::var("ENV_LOCK_LOCK_MACRO_HOST").unwrap(), "localhost");
+/// assert_eq!(env::var("ENV_LOCK_LOCK_MACRO_PORT").unwrap(), "8080");
+/// assert!(env::var("ENV_LOCK_LOCK_MACRO_PROXY").is_err());
+/// ```
+#[macro_export]
+macro_rules! lock {
+    (@pairs [$($pairs:tt)*]) => {
+        $crate::__lock([$($pairs)*])
+    };
+    (@pairs [$($pairs:tt)*] $name:expr => None $(, $($rest:tt)*)?) => {
+        $crate::lock!(
+            @pairs [$($pairs)* (
+                ::std::borrow::Cow::<str>::from($name),
+                ::std::option::Option::None::<::std::string::String>,
+            ),]
+            $($($rest)*)?
+        )
+    };
+    (@pairs [$($pairs:tt)*] $name:expr => $value:expr $(, $($rest:tt)*)?) => {
+        $crate::lock!(
+            @pairs [$($pairs)* (
+                ::std::borrow::Cow::<str>::from($name),
+                $crate::__LockValue::into_value($value),
+            ),]
+            $($($rest)*)?
+        )
+    };
+    ($($variables:tt)*) => {
+        $crate::lock!(@pairs [] $($variables)*)
+    };
+}
+
+/// Implementation of [lock!]. The concrete types allow an empty list.
+#[doc(hidden)]
+#[track_caller]
+pub fn __lock<const N: usize>(
+    variables: [(Cow<'_, str>, Option<String>); N],
+) -> EnvGuard<'_> {
+    lock_env(variables)
+}
+
+/// A value accepted by [lock!]
+#[doc(hidden)]
+pub trait __LockValue {
+    fn into_value(self) -> Option<String>;
+}
+
+impl __LockValue for &str {
+    fn into_value(self) -> Option<String> {
+        Some(self.to_owned())
+    }
+}
+
+impl __LockValue for String {
+    fn into_value(self) -> Option<String> {
+        Some(self)
+    }
+}
+
+impl __LockValue for &String {
+    fn into_value(self) -> Option<String> {
+        Some(self.clone())
+    }
+}
+
+impl<T: __LockValue> __LockValue for Option<T> {
+    fn into_value(self) -> Option<String> {
+        self.and_then(T::into_value)
+    }
+}
+
+/// Get the current environment generation. This number is incremented every
+/// time env-lock sets or removes a variable, so code under test can cheaply
+/// check if the environment has changed since it was last read (e.g. to
+/// invalidate a cache). Modifications made directly through [std::env] are
+/// *not* tracked.
+///
+/// ```
+/// let before = env_lock::generation();
+/// let _guard = env_lock::lock_env([("ENV_LOCK_GENERATION", Some("1"))]);
+/// assert!(env_lock::generation() > before);
+/// ```
+pub fn generation() -> usize {
+    GENERATION.load(Ordering::Acquire)
+}
+
+/// Get the total time that all guards in this process have spent waiting to
+/// acquire the lock. Use this in a test that runs last (or in a custom
+/// harness) to fail the suite when contention exceeds a budget, catching
+/// regressions that serialize tests.
+///
+/// ```
+/// use std::time::Duration;
+///
+/// let _guard = env_lock::lock_env([("ENV_LOCK_WAIT", Some("1"))]);
+/// assert!(env_lock::total_wait_duration() < Duration::from_secs(60));
+/// ```
+pub fn total_wait_duration() -> Duration {
+    Duration::from_nanos(TOTAL_WAIT_NANOS.load(Ordering::Relaxed))
+}
+
+/// Set a variable to the given value, or remove it if the value is `None`
+fn set_or_remove(variable: &str, value: Option<impl AsRef<OsStr>>) {
+    // Fail with a clear message, rather than deep inside std
+    if let Err(error) =
+        validate::check(variable, value.as_ref().map(AsRef::as_ref))
+    {
+        panic!("{error}");
+    }
+
+    #[cfg(feature = "tracing")]
+    trace::variable_set(variable, value.as_ref().map(AsRef::as_ref));
+    if let Some(value) = value {
+        sys::set_var(variable, value.as_ref());
+    } else {
+        sys::remove_var(variable);
+    }
+    GENERATION.fetch_add(1, Ordering::AcqRel);
+    inventory::record_set(variable);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, panic};
+
+    // NOTE: Because these tests specifically modify environment variables
+    // *outside* the env lock, they each need to use a different variable. If
+    // only someone make a library that would avoid that...
+
+    /// Run a closure when dropped, to put back a global setting that a test
+    /// changed, even if the test panics
+    struct Reset<F: FnMut()>(F);
+
+    impl<F: FnMut()> Drop for Reset<F> {
+        fn drop(&mut self) {
+            (self.0)();
+        }
+    }
+
+    /// Set a value for a variable that doesn't exist yet
+    #[test]
+    fn set_missing_var() {
+        let var = "ENV_LOCK_TEST_VARIABLE_SET_MISSING";
+        assert!(env::var(var).is_err());
+
+        let guard = lock_env([(var, Some("hello!"))]);
+        assert_eq!(env::var(var).unwrap(), "hello!");
+        drop(guard);
+
+        assert!(env::var(var).is_err());
+    }
+
+    /// Override the value for a preexisting variable
+    #[test]
+    fn set_existing_var() {
+        let var = "ENV_LOCK_TEST_VARIABLE_SET_EXISTING";
+        env::set_var(var, "existing");
+        assert_eq!(env::var(var).unwrap(), "existing");
+
+        let guard = lock_env([(var, Some("hello!"))]);
+        assert_eq!(env::var(var).unwrap(), "hello!");
+        drop(guard);
+
+        assert_eq!(env::var(var).unwrap(), "existing");
+    }
+
+    /// Remove the value for a preexisting variable
+    #[test]
+    fn clear_existing_var() {
+        let var = "ENV_LOCK_TEST_VARIABLE_CLEAR_EXISTING";
+        env::set_var(var, "existing");
+        assert_eq!(env::var(var).unwrap(), "existing");
+
+        let guard = lock_env([(var, None::<&str>)]);
+        assert!(env::var(var).is_err());
+        drop(guard);
+
+        assert_eq!(env::var(var).unwrap(), "existing");
+    }
+
+    /// Variables mutated through the guard are restored to their pre-lock
+    /// values, even if they're touched more than once
+    #[test]
+    fn set_remove() {
+        let var1 = "ENV_LOCK_TEST_VARIABLE_SET_REMOVE_1";
+        let var2 = "ENV_LOCK_TEST_VARIABLE_SET_REMOVE_2";
+        env::set_var(var2, "existing");
+
+        let mut guard = lock_env([(var1, Some("1"))]);
+        guard.set(var1, "2");
+        guard.remove(var2);
+        guard.set(var2, "3");
+        assert_eq!(env::var(var1).unwrap(), "2");
+        assert_eq!(env::var(var2).unwrap(), "3");
+        drop(guard);
+        assert!(env::var(var1).is_err());
+        assert_eq!(env::var(var2).unwrap(), "existing");
+    }
+
+    /// Extended variables are restored with the rest, and variables that were
+    /// already managed keep their pre-lock value
+    #[test]
+    fn extend() {
+        let var1 = "ENV_LOCK_TEST_VARIABLE_EXTEND_1";
+        let var2 = "ENV_LOCK_TEST_VARIABLE_EXTEND_2";
+        env::set_var(var1, "existing");
+
+        let mut guard = lock_env([(var1, Some("1"))]);
+        guard.extend([(var1, Some("2")), (var2, Some("2"))]);
+        assert_eq!(env::var(var1).unwrap(), "2");
+        assert_eq!(env::var(var2).unwrap(), "2");
+        drop(guard);
+        assert_eq!(env::var(var1).unwrap(), "existing");
+        assert!(env::var(var2).is_err());
+    }
+
+    /// Variables matching a pattern are removed, then restored on drop
+    #[test]
+    fn lock_env_matching() {
+        let var1 = "ENV_LOCK_TEST_VARIABLE_MATCHING_1";
+        let var2 = "ENV_LOCK_TEST_VARIABLE_MATCHING_2";
+        let var3 = "ENV_LOCK_TEST_VARIABLE_MATCHING_3";
+        env::set_var(var1, "1");
+        env::set_var(var2, "2");
+
+        let guard = super::lock_env_matching(
+            "ENV_LOCK_TEST_VARIABLE_MATCHING_*",
+            [(var3, Some("3"))],
+        );
+        assert!(env::var(var1).is_err());
+        assert!(env::var(var2).is_err());
+        assert_eq!(env::var(var3).unwrap(), "3");
+        drop(guard);
+        assert_eq!(env::var(var1).unwrap(), "1");
+        assert_eq!(env::var(var2).unwrap(), "2");
+        assert!(env::var(var3).is_err());
+    }
+
+    /// Glob patterns support `*` and `?` anywhere in the pattern
+    #[test]
+    fn glob() {
+        assert!(glob_matches("MYAPP_*", "MYAPP_PORT"));
+        assert!(glob_matches("MYAPP_*", "MYAPP_"));
+        assert!(!glob_matches("MYAPP_*", "OTHER_MYAPP_PORT"));
+        assert!(glob_matches("*_PROXY", "HTTP_PROXY"));
+        assert!(glob_matches("*_*_URL", "MYAPP_DB_URL"));
+        assert!(!glob_matches("*_*_URL", "MYAPP_URL"));
+        assert!(glob_matches("LC_????", "LC_TIME"));
+        assert!(!glob_matches("LC_????", "LC_CTYPE"));
+        assert!(glob_matches("PATH", "PATH"));
+        assert!(!glob_matches("PATH", "PATHS"));
+        assert!(glob_matches("*", ""));
+        assert_eq!(glob_matches("MYAPP_*", "MyApp_X"), cfg!(windows));
+    }
+
+    /// Changes made outside the guard are reported, but not those made
+    /// through it
+    #[test]
+    fn changes() {
+        let var1 = "ENV_LOCK_TEST_VARIABLE_CHANGES_1";
+        let var2 = "ENV_LOCK_TEST_VARIABLE_CHANGES_2";
+        let var3 = "ENV_LOCK_TEST_VARIABLE_CHANGES_3";
+        let mut guard = lock_env([(var1, Some("1")), (var2, Some("2"))]);
+        guard.track_changes();
+        guard.set(var3, "3");
+        // Other tests may modify their own variables concurrently
+        let ours = |changes: Vec<EnvChange>| {
+            changes
+                .into_iter()
+                .filter(|change| {
+                    change.name.starts_with("ENV_LOCK_TEST_VARIABLE_CHANGES_")
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ours(guard.changes()), []);
+
+        env::set_var(var1, "changed");
+        env::remove_var(var2);
+        assert_eq!(
+            ours(guard.into_changes()),
+            [
+                EnvChange {
+                    name: var1.into(),
+                    before: Some("1".into()),
+                    after: Some("changed".into()),
+                },
+                EnvChange {
+                    name: var2.into(),
+                    before: Some("2".into()),
+                    after: None,
+                },
+            ]
+        );
+        assert!(env::var(var1).is_err());
+        assert!(env::var(var3).is_err());
+    }
+
+    /// Committed changes persist, and the lock is released
+    #[test]
+    fn commit() {
+        let var1 = "ENV_LOCK_TEST_VARIABLE_COMMIT_1";
+        let var2 = "ENV_LOCK_TEST_VARIABLE_COMMIT_2";
+        env::set_var(var2, "existing");
+
+        let mut guard = lock_env([(var1, Some("1"))]);
+        guard.remove(var2);
+        guard.commit();
+        assert_eq!(env::var(var1).unwrap(), "1");
+        assert!(env::var(var2).is_err());
+        // The lock was released, so this doesn't deadlock
+        let _guard = lock_env([(var1, None::<&str>)]);
+    }
+
+    /// Child guards restore only their own changes, and keep the lock held
+    #[test]
+    fn child() {
+        let var1 = "ENV_LOCK_TEST_VARIABLE_CHILD_1";
+        let var2 = "ENV_LOCK_TEST_VARIABLE_CHILD_2";
+        set_lock_mode(LockMode::Always);
+        let mut guard = lock_env([(var1, Some("outer"))]);
+        let child = guard.child([(var1, Some("inner")), (var2, Some("inner"))]);
+        drop(child);
+        assert_eq!(env::var(var1).unwrap(), "outer");
+        assert!(env::var(var2).is_err());
+        assert!(!ENV_LOCK.wait_unlocked(Duration::ZERO));
+
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            guard.scope([(var2, Some("inner"))], || panic!("Oh no!"))
+        }));
+        assert!(result.is_err());
+        assert!(env::var(var2).is_err());
+        drop(guard);
+        assert!(env::var(var1).is_err());
+    }
+
+    /// Threads spawned in the scope are joined before it returns, even if
+    /// they're never joined explicitly
+    #[test]
+    fn thread_scope() {
+        let var = "ENV_LOCK_TEST_VARIABLE_THREAD_SCOPE";
+        set_lock_mode(LockMode::Always);
+        let guard = lock_env([(var, Some("1"))]);
+        let (sender, receiver) = mpsc::channel();
+        guard.thread_scope(|s| {
+            s.spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                sender.send(env::var(var).unwrap()).unwrap();
+            });
+        });
+        assert_eq!(receiver.try_recv().unwrap(), "1");
+        drop(guard);
+        assert!(env::var(var).is_err());
+    }
+
+    /// The lock is released and variables are restored during the closure,
+    /// then both are re-applied, even if the closure panics
+    #[test]
+    fn unlocked() {
+        let var = "ENV_LOCK_TEST_VARIABLE_UNLOCKED";
+        set_lock_mode(LockMode::Always);
+        let mut guard = lock_env([(var, Some("1"))]);
+        guard.set(var, "2");
+        let value = guard.unlocked(|| {
+            // This would deadlock if the lock were still held
+            thread::spawn(move || lock_env([(var, Some("other"))]).commit())
+                .join()
+                .unwrap();
+            env::var(var).unwrap()
+        });
+        assert_eq!(value, "other");
+        assert!(!ENV_LOCK.wait_unlocked(Duration::ZERO));
+        assert_eq!(env::var(var).unwrap(), "2");
+
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            guard.unlocked(|| panic!("Oh no!"))
+        }));
+        assert!(result.is_err());
+        assert!(!ENV_LOCK.wait_unlocked(Duration::ZERO));
+        drop(guard);
+        assert!(env::var(var).is_err());
+    }
+
+    /// Changes made without the guard are detected, and changes made
+    /// through it aren't
+    #[test]
+    fn external_mutations() {
+        let var1 = "ENV_LOCK_TEST_VARIABLE_MUTATION_1";
+        let var2 = "ENV_LOCK_TEST_VARIABLE_MUTATION_2";
+        let mut guard = lock_env([(var1, Some("1")), (var1, Some("2"))]);
+        guard.set_restore_policy(var2, RestorePolicy::Restore);
+        guard.swap(var1, Some("3"));
+        assert_eq!(guard.external_mutations(), []);
+
+        env::set_var(var1, "external");
+        env::set_var(var2, "external");
+        assert_eq!(
+            guard.external_mutations(),
+            [
+                EnvChange {
+                    name: var1.into(),
+                    before: Some("3".into()),
+                    after: Some("external".into()),
+                },
+                EnvChange {
+                    name: var2.into(),
+                    before: None,
+                    after: Some("external".into()),
+                },
+            ]
+        );
+        // Only warn, since other tests may be dropping guards concurrently
+        let check = mutation::check();
+        set_mutation_check(MutationCheck::Warn);
+        let reset = Reset(|| set_mutation_check(check));
+        drop(guard);
+        drop(reset);
+        assert!(env::var(var1).is_err());
+        assert!(env::var(var2).is_err());
+    }
+
+    /// Managed variables can be inspected and restored individually
+    #[test]
+    fn introspection() {
+        let var1 = "ENV_LOCK_TEST_VARIABLE_INTROSPECTION_1";
+        let var2 = "ENV_LOCK_TEST_VARIABLE_INTROSPECTION_2";
+        env::set_var(var1, "existing");
+        let mut guard = lock_env([(var1, Some("1")), (var2, Some("2"))]);
+        guard.set_restore_policy(var2, RestorePolicy::Keep);
+        assert_eq!(
+            guard.iter().collect::<Vec<_>>(),
+            [
+                VariableState {
+                    name: var1,
+                    previous: Some(OsStr::new("existing")),
+                    current: Some("1".into()),
+                    policy: RestorePolicy::Restore,
+                },
+                VariableState {
+                    name: var2,
+                    previous: None,
+                    current: Some("2".into()),
+                    policy: RestorePolicy::Keep,
+                },
+            ]
+        );
+
+        guard.restore(var1);
+        guard.restore(var2);
+        assert_eq!(env::var(var1).unwrap(), "existing");
+        assert!(env::var(var2).is_err());
+        guard.set(var1, "3");
+        assert_eq!(guard.previous(var1), Some(Some(OsStr::new("existing"))));
+        drop(guard);
+        assert_eq!(env::var(var1).unwrap(), "existing");
+    }
+
+    /// Assertion failures include the actual value and related variables
+    #[test]
+    fn assert_env() {
+        let var = "ENV_LOCK_TEST_VARIABLE_ASSERT";
+        let guard = lock_env([(var, Some("actual"))]);
+        assert_env_eq!(var, "actual");
+        let message = panic::catch_unwind(|| {
+            assert_env_eq!(var, "expected", "context {}", 1);
+        })
+        .unwrap_err();
+        let message = message.downcast_ref::<String>().unwrap();
+        assert!(message.contains(r#"expected: "expected""#), "{message}");
+        assert!(message.contains(r#"actual: "actual""#), "{message}");
+        assert!(message.contains("context 1"), "{message}");
+        assert!(message.contains(&format!("{var}=\"actual\"")), "{message}");
+        drop(guard);
+
+        assert_env_unset!(var);
+        let message =
+            panic::catch_unwind(|| assert_env_eq!(var, "")).unwrap_err();
+        let message = message.downcast_ref::<String>().unwrap();
+        assert!(message.contains("actual: <unset>"), "{message}");
+    }
+
+    /// A variable given twice is restored to its value from before the first
+    #[test]
+    fn duplicate_names() {
+        let var = "ENV_LOCK_TEST_VARIABLE_DUPLICATE";
+        env::set_var(var, "existing");
+        let guard = lock_env([(var, Some("1")), (var, Some("2"))]);
+        assert_eq!(env::var(var).unwrap(), "2");
+        drop(guard);
+        assert_eq!(env::var(var).unwrap(), "existing");
+    }
+
+    /// Every way of applying variables restores duplicates to their value
+    /// from before the guard
+    #[test]
+    fn duplicate_names_entry_points() {
+        let var = "ENV_LOCK_TEST_VARIABLE_DUPLICATE_ENTRY_POINTS";
+        let alias = ([var, var], Some("alias"));
+        let assert_restored = |guard: EnvGuard| {
+            assert_eq!(env::var(var).unwrap(), "2");
+            drop(guard);
+            assert_eq!(env::var(var).unwrap(), "existing");
+        };
+        env::set_var(var, "existing");
+
+        assert_restored(super::lock_env_vars([
+            (var, Some("1")),
+            (var, Some("2")),
+        ]));
+        assert_restored(EnvLock::new().set(var, "1").set(var, "2").lock());
+        let mut guard = lock_env([alias]);
+        assert_eq!(env::var(var).unwrap(), "alias");
+        guard.extend([(var, Some("1"))]);
+        guard.set(var, "2");
+        assert_restored(guard);
+        let mut guard = super::lock_env_os([(var, Some(OsStr::new("1")))]);
+        guard.remove(var);
+        guard.set(var, "2");
+        assert_restored(guard);
+    }
+
+    /// The raw lock excludes other guards, and can be upgraded in place
+    #[test]
+    fn lock_raw() {
+        let var = "ENV_LOCK_TEST_VARIABLE_RAW";
+        set_lock_mode(LockMode::Always);
+        let lock = super::lock_raw();
+        assert!(!ENV_LOCK.wait_unlocked(Duration::ZERO));
+        let guard = lock.into_guard([(var, Some("1"))]);
+        assert!(!ENV_LOCK.wait_unlocked(Duration::ZERO));
+        assert_eq!(env::var(var).unwrap(), "1");
+        drop(guard);
+        assert!(env::var(var).is_err());
+    }
+
+    /// Nested guards on the same thread don't deadlock, and restore in order
+    #[test]
+    fn reentrant() {
+        let var = "ENV_LOCK_TEST_VARIABLE_REENTRANT";
+        set_lock_mode(LockMode::Always);
+        let outer = lock_env([(var, Some("outer"))]);
+        let inner = lock_env([(var, Some("inner"))]);
+        assert_eq!(env::var(var).unwrap(), "inner");
+        drop(inner);
+        assert_eq!(env::var(var).unwrap(), "outer");
+        // Still held by the outer guard
+        assert!(!ENV_LOCK.wait_unlocked(Duration::ZERO));
+        drop(outer);
+        assert!(env::var(var).is_err());
+    }
+
+    /// Taking a read lock while holding the write lock on the same thread
+    /// panics, naming both call sites
+    #[test]
+    fn deadlock() {
+        set_lock_mode(LockMode::Always);
+        let guard = lock_env([("ENV_LOCK_TEST_VARIABLE_DEADLOCK", Some("1"))]);
+        let error = panic::catch_unwind(lock_env_read).err().unwrap();
+        let message = error.downcast_ref::<String>().unwrap();
+        assert_eq!(message.matches("src/lib.rs").count(), 2, "{message}");
+        drop(guard);
+    }
+
+    /// Each acquisition is recorded, and its hold time once it's released
+    #[test]
+    fn stats() {
+        set_lock_mode(LockMode::Always);
+        let before = super::stats();
+        let mut guard = lock_env([("ENV_LOCK_TEST_VARIABLE_STATS", Some("1"))]);
+        let split = guard.split(["ENV_LOCK_TEST_VARIABLE_STATS"]);
+        thread::sleep(Duration::from_millis(10));
+        drop(split);
+        drop(guard);
+        let after = super::stats();
+        assert!(after.acquisitions > before.acquisitions);
+        assert!(after.max_hold >= Duration::from_millis(10));
+        assert!(
+            after.total_hold >= before.total_hold + Duration::from_millis(10)
+        );
+    }
+
+    /// The command gets each managed variable's current value
+    #[test]
+    fn command() {
+        let var1 = "ENV_LOCK_TEST_VARIABLE_COMMAND_1";
+        let var2 = "ENV_LOCK_TEST_VARIABLE_COMMAND_2";
+        let var3 = "ENV_LOCK_TEST_VARIABLE_COMMAND_3";
+        env::set_var(var2, "existing");
+
+        let mut guard = lock_env([(var1, Some("1")), (var2, None)]);
+        guard.set(var3, "3");
+        let command = guard.command("env");
+        drop(guard);
+        assert_eq!(
+            command.get_envs().collect::<Vec<_>>(),
+            [
+                (OsStr::new(var1), Some(OsStr::new("1"))),
+                (OsStr::new(var2), None),
+                (OsStr::new(var3), Some(OsStr::new("3"))),
+            ]
+        );
+    }
+
+    /// A forgotten variable should keep its value, while others are restored
+    #[test]
+    fn forget_var() {
+        let var1 = "ENV_LOCK_TEST_VARIABLE_FORGET_1";
+        let var2 = "ENV_LOCK_TEST_VARIABLE_FORGET_2";
+
+        let mut guard = lock_env([(var1, Some("kept")), (var2, Some("temp"))]);
+        guard.forget_var(var1);
+        drop(guard);
+
+        assert_eq!(env::var(var1).unwrap(), "kept");
+        assert!(env::var(var2).is_err());
+    }
+
+    /// Give up waiting for the lock once the timeout expires
+    #[test]
+    fn lock_env_timeout() {
+        let var = "ENV_LOCK_TEST_VARIABLE_TIMEOUT";
+        set_lock_mode(LockMode::Always);
+        let guard = lock_env([(var, Some("1"))]);
+        let timeout = Duration::from_millis(10);
+        let error = thread::spawn(move || {
+            super::lock_env_timeout([(var, Some("2"))], timeout)
+                .err()
+                .unwrap()
+        })
+        .join()
+        .unwrap();
+        assert_eq!(error, TimeoutError { timeout });
+        assert_eq!(env::var(var).unwrap(), "1");
+        drop(guard);
+        assert!(env::var(var).is_err());
+    }
+
+    /// Variable locks only block each other if they share a variable
+    #[test]
+    fn lock_env_vars() {
+        let var1 = "ENV_LOCK_TEST_VARIABLE_VARS_1";
+        let var2 = "ENV_LOCK_TEST_VARIABLE_VARS_2";
+        set_lock_mode(LockMode::Always);
+        // Don't let writers in other tests block the second reader
+        let policy = lock::policy();
+        set_lock_policy(LockPolicy::ReadPreferring);
+        let _reset = Reset(|| set_lock_policy(policy));
+
+        let guard = super::lock_env_vars([(var1, Some("1"))]);
+        let (sender, receiver) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            let guard = super::lock_env_vars([(var2, Some("2"))]);
+            sender.send(()).unwrap();
+            drop(guard);
+            let _guard = super::lock_env_vars([(var1, Some("3"))]);
+            sender.send(()).unwrap();
+        });
+        // Disjoint variables can be held at once
+        receiver.recv().unwrap();
+        // Overlapping variables block
+        assert!(receiver.recv_timeout(Duration::from_millis(50)).is_err());
+        assert_eq!(env::var(var1).unwrap(), "1");
+        drop(guard);
+        receiver.recv().unwrap();
+        thread.join().unwrap();
+        assert!(env::var(var1).is_err());
+        assert!(env::var(var2).is_err());
+    }
+
+    /// Read guards exclude writers
+    #[test]
+    fn lock_env_read() {
+        set_lock_mode(LockMode::Always);
+        let guard = super::lock_env_read();
+        assert!(!ENV_LOCK.wait_unlocked(Duration::ZERO));
+        let (sender, receiver) = mpsc::channel();
+        let writer = thread::spawn(move || {
+            let _guard = lock_env([("ENV_LOCK_TEST_VARIABLE_READ", Some("1"))]);
+            sender.send(()).unwrap();
+        });
+        assert!(receiver.recv_timeout(Duration::from_millis(50)).is_err());
+        drop(guard);
+        receiver.recv().unwrap();
+        writer.join().unwrap();
+    }
+
+    /// The environment is restored after the closure, even if it panics
+    #[test]
+    fn with_env_panic() {
+        let var = "ENV_LOCK_TEST_VARIABLE_WITH_ENV";
+        assert_eq!(
+            with_env([(var, Some("1"))], || env::var(var).unwrap()),
+            "1"
+        );
+        assert!(env::var(var).is_err());
+
+        let result = panic::catch_unwind(|| {
+            with_env([(var, Some("1"))], || panic!("Oh no!"))
+        });
+        assert!(result.is_err());
+        assert!(env::var(var).is_err());
+    }
+
+    /// Async locking waits for the lock without blocking, and the guard can
+    /// be sent between threads
+    #[cfg(feature = "async")]
+    #[test]
+    fn lock_env_async() {
+        use std::future::Future;
+
+        fn assert_send<T: Send>(_: &T) {}
+
+        let var = "ENV_LOCK_TEST_VARIABLE_ASYNC";
+        set_lock_mode(LockMode::Always);
+        let guard = lock_env([(var, Some("1"))]);
+
+        let mut future = Box::pin(super::lock_env_async([(var, Some("2"))]));
+        let waker = futures::task::noop_waker();
+        let mut context = std::task::Context::from_waker(&waker);
+        assert!(future.as_mut().poll(&mut context).is_pending());
+        drop(guard);
+
+        let guard = futures::executor::block_on(future);
+        assert_send(&guard);
+        assert_eq!(env::var(var).unwrap(), "2");
+        drop(guard);
+        assert!(env::var(var).is_err());
+    }
+
+    /// The environment is locked for the duration of the future
+    #[cfg(feature = "async")]
+    #[test]
+    fn with_env_async() {
+        let var = "ENV_LOCK_TEST_VARIABLE_WITH_ENV_ASYNC";
+        let value = futures::executor::block_on(super::with_env_async(
+            [(var, Some("1"))],
+            async { env::var(var).unwrap() },
+        ));
+        assert_eq!(value, "1");
+        assert!(env::var(var).is_err());
+    }
+
+    /// Owned names produce a static guard
+    #[test]
+    fn owned_names() {
+        fn lock(name: String) -> EnvGuard<'static> {
+            lock_env([([name.clone(), name.to_lowercase()], Some("1"))])
+        }
+
+        let var = "ENV_LOCK_TEST_VARIABLE_OWNED";
+        let guard = lock(var.to_owned());
+        assert_eq!(env::var(var).unwrap(), "1");
+        assert_eq!(env::var(var.to_lowercase()).unwrap(), "1");
+        drop(guard);
+        assert!(env::var(var).is_err());
+    }
+
+    /// An owned guard outlives the names it was created with
+    #[test]
+    fn into_owned() {
+        let var = "ENV_LOCK_TEST_VARIABLE_INTO_OWNED";
+        let guard = {
+            let name = var.to_owned();
+            lock_env([(name.as_str(), Some("1"))]).into_owned()
+        };
+        assert_eq!(env::var(var).unwrap(), "1");
+        drop(guard);
+        assert!(env::var(var).is_err());
+    }
+
+    /// Values that aren't valid UTF-8 are set and restored faithfully
+    #[cfg(unix)]
+    #[test]
+    fn lock_env_os() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let var1 = "ENV_LOCK_TEST_VARIABLE_OS_1";
+        let var2 = "ENV_LOCK_TEST_VARIABLE_OS_2";
+        let value = OsStr::from_bytes(b"\xff\xfe");
+        let guard = super::lock_env_os([(var1, Some(value))]);
+        assert_eq!(env::var_os(var1).unwrap(), value);
+        env::set_var(var2, value);
+        drop(guard);
+        assert_eq!(env::var_os(var1), None);
+
+        // Restore a non-UTF-8 value set outside the lock
+        let guard = lock_env([(var2, Some("utf-8"))]);
+        assert_eq!(env::var(var2).unwrap(), "utf-8");
+        drop(guard);
+        assert_eq!(env::var_os(var2).unwrap(), value);
+    }
+
+    /// If setting a variable panics, those already set are restored
+    #[test]
+    fn apply_panic() {
+        let var = "ENV_LOCK_TEST_VARIABLE_APPLY_PANIC";
+        let result = panic::catch_unwind(|| {
+            lock_env([(var, Some("1")), ("INVALID=NAME", Some("2"))])
+        });
+        assert!(result.is_err());
+        assert!(env::var(var).is_err());
+    }
+
+    /// A split guard restores its variables independently, and the lock is
+    /// held until both guards are dropped
+    #[test]
+    fn split() {
+        let var1 = "ENV_LOCK_TEST_VARIABLE_SPLIT_1";
+        let var2 = "ENV_LOCK_TEST_VARIABLE_SPLIT_2";
+        set_lock_mode(LockMode::Always);
+
+        let mut guard = lock_env([(var1, Some("1")), (var2, Some("2"))]);
+        let split = guard.split([var1]);
+        drop(split);
+        assert!(env::var(var1).is_err());
+        assert_eq!(env::var(var2).unwrap(), "2");
+
+        let split = guard.split([var2]);
+        drop(guard);
+        assert_eq!(env::var(var2).unwrap(), "2");
+        assert!(!ENV_LOCK.wait_unlocked(Duration::ZERO));
+        drop(split);
+        assert!(env::var(var2).is_err());
+    }
+
+    /// A merged guard restores both guards' variables, to their values from
+    /// before the first guard, and holds both locks until it's dropped
+    #[test]
+    fn merge() {
+        let var1 = "ENV_LOCK_TEST_VARIABLE_MERGE_1";
+        let var2 = "ENV_LOCK_TEST_VARIABLE_MERGE_2";
+        set_lock_mode(LockMode::Always);
+
+        let outer = lock_env([(var1, Some("outer"))]);
+        let inner = lock_env([(var1, Some("inner")), (var2, Some("2"))]);
+        let guard = inner.merge(outer);
+        assert_eq!(env::var(var1).unwrap(), "inner");
+        assert!(!ENV_LOCK.wait_unlocked(Duration::ZERO));
+        drop(guard);
+        assert!(env::var(var1).is_err());
+        assert!(env::var(var2).is_err());
+
+        let first = super::lock_env_vars([(var1, Some("1"))]);
+        let second = super::lock_env_vars([(var2, Some("2"))]);
+        let mut guard = first.merge(second);
+        guard.unlocked(|| {
+            assert!(env::var(var1).is_err());
+            assert!(env::var(var2).is_err());
+        });
+        assert_eq!(env::var(var1).unwrap(), "1");
+        assert_eq!(env::var(var2).unwrap(), "2");
+        drop(guard);
+        assert!(env::var(var1).is_err());
+        assert!(env::var(var2).is_err());
+    }
+
+    /// The working directory is restored on drop, and while unlocked
+    #[test]
+    fn current_dir() {
+        let var = "ENV_LOCK_TEST_VARIABLE_CURRENT_DIR";
+        set_lock_mode(LockMode::Always);
+        let original = env::current_dir().unwrap();
+        let directory = env::temp_dir().canonicalize().unwrap();
+
+        let mut guard =
+            EnvLock::new().set(var, "1").current_dir(&directory).lock();
+        assert_eq!(env::current_dir().unwrap(), directory);
+        guard.unlocked(|| assert_eq!(env::current_dir().unwrap(), original));
+        assert_eq!(env::current_dir().unwrap(), directory);
+        drop(guard);
+        assert_eq!(env::current_dir().unwrap(), original);
+
+        // Guards that only lock some variables can't change it
+        let mut guard = super::lock_env_vars([(var, Some("1"))]);
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            guard.set_current_dir(&directory)
+        }));
+        assert!(result.is_err());
+        assert_eq!(env::current_dir().unwrap(), original);
+        drop(guard);
+
+        // Committing keeps the new directory
+        let mut guard = lock_env([(var, None::<&str>)]);
+        guard.set_current_dir(&directory).unwrap();
+        guard.commit();
+        assert_eq!(env::current_dir().unwrap(), directory);
+        let mut guard = lock_env([(var, None::<&str>)]);
+        guard.set_current_dir(&original).unwrap();
+        guard.commit();
+        assert_eq!(env::current_dir().unwrap(), original);
+    }
+
+    /// Each variable should be handled according to its restore policy
+    #[test]
+    fn restore_policy() {
+        let restore = "ENV_LOCK_TEST_VARIABLE_POLICY_RESTORE";
+        let keep = "ENV_LOCK_TEST_VARIABLE_POLICY_KEEP";
+        let remove = "ENV_LOCK_TEST_VARIABLE_POLICY_REMOVE";
+        let unmanaged = "ENV_LOCK_TEST_VARIABLE_POLICY_UNMANAGED";
+        env::set_var(restore, "existing");
+        env::set_var(remove, "existing");
+
+        let mut guard = lock_env([
+            (restore, Some("new")),
+            (keep, Some("new")),
+            (remove, Some("new")),
+        ]);
+        guard.set_restore_policy(keep, RestorePolicy::Keep);
+        guard.set_restore_policy(remove, RestorePolicy::RemoveOnDrop);
+        guard.set_restore_policy(unmanaged, RestorePolicy::RemoveOnDrop);
+        env::set_var(unmanaged, "set by test");
+        drop(guard);
+
+        assert_eq!(env::var(restore).unwrap(), "existing");
+        assert_eq!(env::var(keep).unwrap(), "new");
+        assert!(env::var(remove).is_err());
+        assert!(env::var(unmanaged).is_err());
+    }
+
+    /// Wait time is recorded on the guard and in the total
+    #[test]
+    fn wait_duration() {
+        let var = "ENV_LOCK_TEST_VARIABLE_WAIT";
+        // There's no waiting if the lock is skipped
+        set_lock_mode(LockMode::Always);
+        let guard = lock_env([(var, Some("1"))]);
+        let waiter = thread::spawn(move || {
+            let guard = lock_env([(var, Some("2"))]);
+            guard.wait_duration()
+        });
+        thread::sleep(Duration::from_millis(50));
+        let before = total_wait_duration();
+        drop(guard);
+
+        let waited = waiter.join().unwrap();
+        assert!(waited >= Duration::from_millis(50));
+        assert!(total_wait_duration() >= before + waited);
+    }
+
+    /// Exceeding the hold budget panics, but still restores
+    #[test]
+    fn hold_budget() {
+        let var = "ENV_LOCK_TEST_VARIABLE_HOLD_BUDGET";
+
+        let mut guard = lock_env([(var, Some("1"))]);
+        guard.assert_released_within(Duration::from_secs(60));
+        drop(guard);
+
+        let result = panic::catch_unwind(|| {
+            let mut guard = lock_env([(var, Some("1"))]);
+            guard.assert_released_within(Duration::ZERO);
+            thread::sleep(Duration::from_millis(1));
+        });
+        assert!(result.is_err());
+        assert!(env::var(var).is_err());
+    }
+
+    /// A wrapper that bypasses the lock isn't exclusive
+    #[test]
+    fn assert_exclusive() {
+        set_lock_mode(LockMode::Always);
+        let var = "ENV_LOCK_TEST_VARIABLE_EXCLUSIVE";
+        assert_exclusive!(lock_env([(var, Some("1"))]));
+
+        let result = panic::catch_unwind(|| {
+            assert_exclusive!((), Duration::from_millis(10));
+        });
+        assert!(result.is_err());
+    }
+
+    /// Swapping returns the current value, and unmanaged variables are
+    /// restored too
+    #[test]
+    fn swap() {
+        let var1 = "ENV_LOCK_TEST_VARIABLE_SWAP_1";
+        let var2 = "ENV_LOCK_TEST_VARIABLE_SWAP_2";
+        env::set_var(var2, "existing");
+
+        let mut guard = lock_env([(var1, Some("1"))]);
+        assert_eq!(guard.swap(var1, Some("2")), Some("1".into()));
+        assert_eq!(guard.swap(var1, None::<&str>), Some("2".into()));
+        assert_eq!(guard.swap(var1, Some("3")), None);
+        assert_eq!(guard.swap(var2, None::<&str>), Some("existing".into()));
+        // Non-UTF-8 values are returned as they are, not as if unset
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            let value = OsStr::from_bytes(b"\xff");
+            guard.swap(var1, Some(value));
+            assert_eq!(guard.swap(var1, None::<&str>).as_deref(), Some(value));
+        }
+        drop(guard);
+
+        assert!(env::var(var1).is_err());
+        assert_eq!(env::var(var2).unwrap(), "existing");
+    }
+
+    /// Renaming a prefix restores both namespaces
+    #[test]
+    fn rename_prefix() {
+        let old1 = "ENV_LOCK_TEST_PREFIX_OLD_1";
+        let old2 = "ENV_LOCK_TEST_PREFIX_OLD_2";
+        let new1 = "ENV_LOCK_TEST_PREFIX_NEW_1";
+        let new2 = "ENV_LOCK_TEST_PREFIX_NEW_2";
+        env::set_var(new2, "existing");
+
+        let mut guard = lock_env([(old1, Some("1")), (old2, Some("2"))]);
+        let count = guard.rename_prefix(
+            "ENV_LOCK_TEST_PREFIX_OLD_",
+            "ENV_LOCK_TEST_PREFIX_NEW_",
+        );
+        assert_eq!(count, 2);
+        assert!(env::var(old1).is_err());
+        assert!(env::var(old2).is_err());
+        assert_eq!(env::var(new1).unwrap(), "1");
+        assert_eq!(env::var(new2).unwrap(), "2");
+        drop(guard);
+
+        assert!(env::var(old1).is_err());
+        assert!(env::var(new1).is_err());
+        assert_eq!(env::var(new2).unwrap(), "existing");
+    }
+
+    /// Aliases share a value, but are restored individually
+    #[test]
+    fn aliases() {
+        let var1 = "ENV_LOCK_TEST_VARIABLE_ALIAS_1";
+        let var2 = "ENV_LOCK_TEST_VARIABLE_ALIAS_2";
+        env::set_var(var2, "existing");
+
+        let guard = lock_env([([var1, var2], Some("shared"))]);
+        assert_eq!(env::var(var1).unwrap(), "shared");
+        assert_eq!(env::var(var2).unwrap(), "shared");
+        drop(guard);
+
+        assert!(env::var(var1).is_err());
+        assert_eq!(env::var(var2).unwrap(), "existing");
+    }
+
+    /// Generation should be bumped when setting and restoring
+    #[test]
+    fn generation_bumped() {
+        let var = "ENV_LOCK_TEST_VARIABLE_GENERATION";
+        let initial = generation();
+
+        let guard = lock_env([(var, Some("hello!"))]);
+        let applied = generation();
+        assert!(applied > initial);
+        drop(guard);
+
+        assert!(generation() > applied);
+    }
+
+    /// Unlocked assertions should fail while a guard is held
+    #[test]
+    fn assert_unlocked_while_locked() {
+        let var = "ENV_LOCK_TEST_VARIABLE_ASSERT_UNLOCKED";
+        let guard = lock_env([(var, None::<&str>)]);
+        panic::catch_unwind(assert_unlocked).unwrap_err();
+        panic::catch_unwind(|| {
+            assert_unlocked_within(Duration::from_millis(10))
+        })
+        .unwrap_err();
+        drop(guard);
+
+        // Other tests may grab the lock, but they'll release it eventually
+        assert_unlocked_within(Duration::from_secs(10));
+    }
+
+    /// Generate a test per combination of values
+    #[cfg(feature = "macros")]
+    #[env_matrix(ENV_LOCK_TEST_MATRIX_A = ["1", "2"], ENV_LOCK_TEST_MATRIX_B = [None])]
+    fn matrix() {
+        let a = env::var("ENV_LOCK_TEST_MATRIX_A").unwrap();
+        assert!(a == "1" || a == "2");
+        assert!(env::var("ENV_LOCK_TEST_MATRIX_B").is_err());
+    }
+
+    /// The lock! macro accepts bare values and untyped `None`
+    #[test]
+    fn lock_macro() {
+        let var1 = "ENV_LOCK_TEST_VARIABLE_LOCK_MACRO_1";
+        let var2 = "ENV_LOCK_TEST_VARIABLE_LOCK_MACRO_2";
+        let var3 = "ENV_LOCK_TEST_VARIABLE_LOCK_MACRO_3";
+        env::set_var(var2, "existing");
+
+        let value: Option<&str> = Some("3");
+        let guard = lock! {
+            var1 => "1",
+            var2.to_owned() => None,
+            var3 => value
+        };
+        assert_eq!(env::var(var1).unwrap(), "1");
+        assert!(env::var(var2).is_err());
+        assert_eq!(env::var(var3).unwrap(), "3");
+        drop(guard);
+        assert!(env::var(var1).is_err());
+        assert_eq!(env::var(var2).unwrap(), "existing");
+
+        let _guard = lock! {};
+    }
+
+    /// Lock the environment for a whole test
+    #[cfg(feature = "macros")]
+    #[test]
+    #[vars(ENV_LOCK_TEST_VARS_A = "1", ENV_LOCK_TEST_VARS_B = None)]
+    fn vars() {
+        assert_eq!(env::var("ENV_LOCK_TEST_VARS_A").unwrap(), "1");
+        assert!(env::var("ENV_LOCK_TEST_VARS_B").is_err());
     }
 
     /// Environment should be restored correctly if a panic occurs while it's
@@ -0,0 +1,168 @@
+use crate::{
+    parse::{self, ParseError},
+    presets::Preset,
+    EnvGuard,
+};
+use std::{
+    error::Error,
+    fmt::{self, Display},
+    io::{self, Read},
+};
+#[cfg(feature = "dotenv")]
+use std::{fs, path::Path};
+
+/// Read a document from a reader, parse it, then lock the environment and
+/// apply its variables. `parser` is one of the [parse] functions, matching
+/// the format of the document. The document is read and parsed *before* the
+/// lock is acquired, so nothing is modified if it's invalid.
+///
+/// ```no_run
+/// use env_lock::parse;
+/// use std::fs::File;
+///
+/// let file = File::open("tests/fixtures/env.toml").unwrap();
+/// let _guard = env_lock::lock_env_from_reader(file, parse::toml).unwrap();
+/// ```
+#[track_caller]
+pub fn lock_env_from_reader(
+    mut reader: impl Read,
+    parser: fn(&str) -> Result<Preset, ParseError>,
+) -> Result<EnvGuard<'static>, LoadError> {
+    let mut input = String::new();
+    reader.read_to_string(&mut input).map_err(LoadError::Io)?;
+    let preset = parser(&input).map_err(LoadError::Parse)?;
+    Ok(preset.lock())
+}
+
+/// Parse a JSON object with [parse::json], then lock the environment and
+/// apply its variables
+///
+/// ```
+/// use std::env;
+///
+/// let _guard = env_lock::lock_env_from_json(
+///     r#"{"ENV_LOCK_JSON_PORT": 8080, "ENV_LOCK_JSON_PROXY": null}"#,
+/// )
+/// .unwrap();
+/// assert_eq!(env::var("ENV_LOCK_JSON_PORT").unwrap(), "8080");
+/// assert!(env::var("ENV_LOCK_JSON_PROXY").is_err());
+/// ```
+#[track_caller]
+pub fn lock_env_from_json(
+    input: &str,
+) -> Result<EnvGuard<'static>, ParseError> {
+    Ok(parse::json(input)?.lock())
+}
+
+/// Parse a TOML document with [parse::toml], then lock the environment and
+/// apply its variables
+///
+/// ```
+/// use std::env;
+///
+/// let _guard =
+///     env_lock::lock_env_from_toml("ENV_LOCK_TOML_HOST = \"localhost\"")
+///         .unwrap();
+/// assert_eq!(env::var("ENV_LOCK_TOML_HOST").unwrap(), "localhost");
+/// ```
+#[track_caller]
+pub fn lock_env_from_toml(
+    input: &str,
+) -> Result<EnvGuard<'static>, ParseError> {
+    Ok(parse::toml(input)?.lock())
+}
+
+/// Read a dotenv file, then lock the environment and apply its variables.
+/// The file is parsed with [parse::dotenv], and read *before* the lock is
+/// acquired, so nothing is modified if it's invalid. Requires the `dotenv`
+/// feature.
+///
+/// ```no_run
+/// let _guard = env_lock::lock_env_file("tests/fixtures/test.env").unwrap();
+/// ```
+#[cfg(feature = "dotenv")]
+#[track_caller]
+pub fn lock_env_file(
+    path: impl AsRef<Path>,
+) -> Result<EnvGuard<'static>, LoadError> {
+    let input = fs::read_to_string(path).map_err(LoadError::Io)?;
+    let preset = parse::dotenv(&input).map_err(LoadError::Parse)?;
+    Ok(preset.lock())
+}
+
+/// An error encountered while loading variables from a file or reader
+#[derive(Debug)]
+pub enum LoadError {
+    /// The input couldn't be read
+    Io(io::Error),
+    /// The input couldn't be parsed
+    Parse(ParseError),
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(error) => write!(f, "Error reading input: {error}"),
+            Self::Parse(error) => write!(f, "{error}"),
+        }
+    }
+}
+
+impl Error for LoadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io(error) => Some(error),
+            Self::Parse(error) => Some(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    /// Documents are read and parsed before anything is locked
+    #[test]
+    fn lock_env_from_reader() {
+        let var = "ENV_LOCK_TEST_VARIABLE_FROM_READER";
+        let input = format!("{{\"{var}\": \"hello\"}}");
+        let guard =
+            super::lock_env_from_reader(input.as_bytes(), parse::json).unwrap();
+        assert_eq!(env::var(var).unwrap(), "hello");
+        drop(guard);
+        assert!(env::var(var).is_err());
+
+        assert!(matches!(
+            super::lock_env_from_reader(&b"\xff"[..], parse::json),
+            Err(LoadError::Io(_))
+        ));
+        assert!(matches!(
+            super::lock_env_from_reader(input.as_bytes(), parse::toml),
+            Err(LoadError::Parse(_))
+        ));
+    }
+
+    /// Variables from the file are applied, and restored on drop
+    #[cfg(feature = "dotenv")]
+    #[test]
+    fn lock_env_file() {
+        let var = "ENV_LOCK_TEST_VARIABLE_DOTENV";
+        let path = env::temp_dir()
+            .join(format!("env-lock-dotenv-{}.env", std::process::id()));
+        fs::write(&path, format!("{var}=\"hello world\"\n")).unwrap();
+
+        let guard = super::lock_env_file(&path).unwrap();
+        assert_eq!(env::var(var).unwrap(), "hello world");
+        drop(guard);
+        assert!(env::var(var).is_err());
+
+        fs::write(&path, format!("{var}\n")).unwrap();
+        assert!(matches!(
+            super::lock_env_file(&path),
+            Err(LoadError::Parse(ParseError { line: 1, .. }))
+        ));
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(super::lock_env_file(&path), Err(LoadError::Io(_))));
+    }
+}
@@ -0,0 +1,1206 @@
+//! The global environment lock. This is a hand-rolled lock rather than a
+//! plain [Mutex], because the guard needs to be owned (so it can be released
+//! from a thread other than the one that acquired it) and we want to attach
+//! bookkeeping to the lock state.
+
+#[cfg(feature = "file-lock")]
+use crate::file_lock;
+use crate::sys;
+use env_lock_shared::ProcessLockToken;
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    env,
+    fmt::{self, Display},
+    mem,
+    panic::Location,
+    sync::{
+        atomic::{AtomicU64, AtomicU8, Ordering},
+        Arc, Condvar, Mutex, MutexGuard, OnceLock,
+    },
+    task::Waker,
+    thread::{self, ThreadId},
+    time::{Duration, Instant},
+};
+#[cfg(feature = "async")]
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// Global lock for accessing environment variables
+pub(crate) static ENV_LOCK: Lock = Lock::new();
+
+/// Locks on individual variables. Holders of these also hold shared access to
+/// [ENV_LOCK], so they exclude anyone locking the entire environment.
+pub(crate) static VARIABLE_LOCKS: VariableLocks = VariableLocks::new();
+
+/// Locks on named resources, from [lock_resource](crate::lock_resource).
+/// These are independent of [ENV_LOCK].
+pub(crate) static RESOURCE_LOCKS: VariableLocks = VariableLocks::new();
+
+/// Current [LockMode], stored as its discriminant
+static MODE: AtomicU8 = AtomicU8::new(LockMode::Auto as u8);
+
+/// Current [LockPolicy], stored as its discriminant
+static POLICY: AtomicU8 = AtomicU8::new(LockPolicy::WritePreferring as u8);
+
+/// Contention threshold in milliseconds, or [u64::MAX] if disabled
+static CONTENTION_THRESHOLD: AtomicU64 = AtomicU64::new(5000);
+
+/// Control whether guards acquire the global lock. By default, the lock is
+/// skipped when the test harness is known to run tests one at a time, or
+/// each test in its own process, because it can't provide any additional
+/// protection. Use [set_lock_mode] to override the detection.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[repr(u8)]
+pub enum LockMode {
+    /// Acquire the lock, unless the test harness is running on a single
+    /// thread, or each test in its own process. A single thread is detected
+    /// via the `RUST_TEST_THREADS` environment variable and the
+    /// `--test-threads` command line argument. A process per test is detected
+    /// via the `NEXTEST_EXECUTION_MODE` variable, which is set by
+    /// [cargo-nextest](https://nexte.st).
+    #[default]
+    Auto,
+    /// Always acquire the lock. Use this if your tests are run on a single
+    /// thread, but spawn their own threads that modify the environment.
+    Always,
+    /// Never acquire the lock. Variables are still set and restored. Use
+    /// this if your test runner runs each test in its own process, but isn't
+    /// detected by [Auto](Self::Auto).
+    Never,
+}
+
+/// Set the [LockMode] for all subsequently created guards. Guards that are
+/// already held are unaffected.
+pub fn set_lock_mode(mode: LockMode) {
+    MODE.store(mode as u8, Ordering::Release);
+}
+
+/// Should new guards acquire the global lock?
+pub(crate) fn should_lock() -> bool {
+    static SERIAL: OnceLock<bool> = OnceLock::new();
+
+    match MODE.load(Ordering::Acquire) {
+        mode if mode == LockMode::Always as u8 => true,
+        mode if mode == LockMode::Never as u8 => false,
+        _ => !*SERIAL.get_or_init(|| {
+            is_process_per_test(
+                sys::var("NEXTEST_EXECUTION_MODE").ok().as_deref(),
+            ) || is_serial(
+                sys::var("RUST_TEST_THREADS").ok().as_deref(),
+                env::args(),
+            )
+        }),
+    }
+}
+
+/// Is the test harness configured to run only one test at a time?
+fn is_serial(
+    test_threads_var: Option<&str>,
+    args: impl IntoIterator<Item = String>,
+) -> bool {
+    // The command line argument takes precedence over the variable
+    let mut test_threads = None;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == "--test-threads" {
+            test_threads = args.next();
+        } else if let Some(value) = arg.strip_prefix("--test-threads=") {
+            test_threads = Some(value.to_owned());
+        }
+    }
+    test_threads.as_deref().or(test_threads_var).map(str::trim) == Some("1")
+}
+
+/// Is the test runner running each test in its own process? Only other
+/// threads in the same process can contend for the lock, and there are none.
+fn is_process_per_test(execution_mode_var: Option<&str>) -> bool {
+    execution_mode_var == Some("process-per-test")
+}
+
+pub(crate) struct Lock {
+    state: Mutex<State>,
+    /// Notified whenever the lock is released
+    released: Condvar,
+}
+
+/// Which waiters get the lock first when readers and writers are contending.
+/// Set with [set_lock_policy].
+///
+/// The environment lock isn't a plain mutex held for the life of each guard:
+/// waiters are queued, and the internal mutex is only held briefly to grant
+/// or release access. With the default policy, waiting writers compete for
+/// the lock each time it's released, so in a suite with hundreds of
+/// env-locked tests, one unlucky test can wait much longer than the rest. Use
+/// [Fair](LockPolicy::Fair) to grant the lock strictly in request order,
+/// which keeps the wait time of every test bounded.
+///
+/// ```
+/// env_lock::set_lock_policy(env_lock::LockPolicy::Fair);
+/// ```
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[repr(u8)]
+pub enum LockPolicy {
+    /// New readers wait while any writer is waiting, so writers can't be
+    /// starved by a steady stream of readers
+    #[default]
+    WritePreferring,
+    /// Readers can always join other readers, so read-only tests are never
+    /// held up by waiting writers. A large suite with many readers may starve
+    /// its writers.
+    ReadPreferring,
+    /// The lock is granted in the order it was requested, so no waiter can
+    /// be starved. Consecutive readers still share the lock.
+    Fair,
+}
+
+/// Set the [LockPolicy] for all subsequent lock requests
+pub fn set_lock_policy(policy: LockPolicy) {
+    POLICY.store(policy as u8, Ordering::Release);
+}
+
+/// Get the current [LockPolicy]
+pub(crate) fn policy() -> LockPolicy {
+    match POLICY.load(Ordering::Acquire) {
+        policy if policy == LockPolicy::ReadPreferring as u8 => {
+            LockPolicy::ReadPreferring
+        }
+        policy if policy == LockPolicy::Fair as u8 => LockPolicy::Fair,
+        _ => LockPolicy::WritePreferring,
+    }
+}
+
+/// After waiting this long for the lock, print a warning to stderr naming the
+/// guards that hold it, and where they were acquired. Pass `None` to disable
+/// the warning. The default is 5 seconds. The warning is printed at most once
+/// per wait. The test harness captures it along with the test's other output,
+/// so it's shown when a slow test fails.
+///
+/// ```
+/// use std::time::Duration;
+///
+/// env_lock::set_contention_threshold(Some(Duration::from_secs(1)));
+/// ```
+pub fn set_contention_threshold(threshold: Option<Duration>) {
+    let millis = threshold.map_or(u64::MAX, |threshold| {
+        threshold.as_millis().try_into().unwrap_or(u64::MAX - 1)
+    });
+    CONTENTION_THRESHOLD.store(millis, Ordering::Release);
+}
+
+/// Get the current contention threshold
+fn contention_threshold() -> Option<Duration> {
+    match CONTENTION_THRESHOLD.load(Ordering::Acquire) {
+        u64::MAX => None,
+        millis => Some(Duration::from_millis(millis)),
+    }
+}
+
+/// Function called instead of blocking while waiting for a lock
+type YieldHook = Arc<dyn Fn() + Send + Sync>;
+
+/// Set by [set_lock_yield]
+static YIELD_HOOK: Mutex<Option<YieldHook>> = Mutex::new(None);
+
+/// Wait for locks by calling a function in a loop, instead of blocking the
+/// thread. This is a seam for deterministic schedulers, such as
+/// [loom](https://docs.rs/loom) or [shuttle](https://docs.rs/shuttle), which
+/// can't see the standard library's blocking primitives: pass their
+/// `thread::yield_now`, and every wait for the environment lock (or for
+/// individual variables) becomes a point where the scheduler can switch
+/// threads. That lets a model checker explore the interleavings of your
+/// tests' guards, and of this crate's own lock. The function should return
+/// once other threads have had a chance to run.
+///
+/// The lock shared with other versions of env-lock in the process, and the
+/// file lock, still block normally. They're only contended when multiple
+/// versions of env-lock or the file lock are in use.
+///
+/// ```
+/// env_lock::set_lock_yield(std::thread::yield_now);
+/// let _guard = env_lock::lock_env([("ENV_LOCK_YIELD", Some("1"))]);
+/// # env_lock::clear_lock_yield();
+/// ```
+pub fn set_lock_yield(yield_now: impl Fn() + Send + Sync + 'static) {
+    *yield_hook() = Some(Arc::new(yield_now));
+}
+
+/// Go back to blocking while waiting for locks. See [set_lock_yield].
+pub fn clear_lock_yield() {
+    *yield_hook() = None;
+}
+
+fn yield_hook() -> MutexGuard<'static, Option<YieldHook>> {
+    // The hook is only replaced whole, so it can't be left inconsistent
+    YIELD_HOOK.lock().unwrap_or_else(|error| error.into_inner())
+}
+
+/// Get every guard currently holding the environment lock. Use this to find
+/// out who's hogging the lock when a test is stuck waiting for it. Guards
+/// that only lock individual variables hold the lock in shared mode.
+///
+/// ```
+/// let guard = env_lock::lock_env([("ENV_LOCK_HOLDERS", Some("1"))]);
+/// let holders = env_lock::lock_holders();
+/// assert!(holders[0].location.file().ends_with(".rs"));
+/// ```
+pub fn lock_holders() -> Vec<LockHolder> {
+    ENV_LOCK.holders()
+}
+
+/// A guard holding the environment lock, from [lock_holders]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LockHolder {
+    /// Where the lock was acquired
+    pub location: &'static Location<'static>,
+    /// Name of the thread that acquired the lock. The test harness names each
+    /// test's thread after the test. `None` if the thread is unnamed, or the
+    /// lock was acquired asynchronously.
+    pub thread_name: Option<String>,
+    /// Is the lock shared with other guards?
+    pub shared: bool,
+}
+
+impl LockHolder {
+    fn new(location: &'static Location<'static>, shared: bool) -> Self {
+        Self {
+            location,
+            thread_name: thread::current().name().map(String::from),
+            shared,
+        }
+    }
+}
+
+impl Display for LockHolder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.location)?;
+        if let Some(name) = &self.thread_name {
+            write!(f, " (thread `{name}`)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Kind of lock being requested
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Access {
+    /// Shared access, for guards that only read the environment
+    Read,
+    /// Exclusive access
+    Write,
+}
+
+#[derive(Default)]
+struct State {
+    /// ID of the token currently holding exclusive access
+    holder: Option<u64>,
+    /// Thread that acquired exclusive access, if it can be reentered. Async
+    /// acquisitions can't be reentered, because other tasks may run on the
+    /// same thread.
+    holder_thread: Option<ThreadId>,
+    /// Number of tokens sharing exclusive access, including reentrant ones
+    depth: usize,
+    /// Where and by whom exclusive access was acquired
+    holder_info: Option<LockHolder>,
+    /// When exclusive access was acquired
+    holder_since: Option<Instant>,
+    /// Number of tokens currently holding shared access
+    readers: usize,
+    /// Thread and holder info for each reader
+    reader_threads: Vec<(ThreadId, LockHolder)>,
+    /// Process lock held on behalf of all readers, while there are any
+    reader_process: Option<ProcessLock>,
+    /// The first reader is acquiring [Self::reader_process]. Other readers
+    /// wait until it's held.
+    reader_process_pending: bool,
+    /// Process lock held on behalf of the exclusive holder. It's stored here
+    /// rather than in the token so [Lock::force_release] can release it.
+    holder_process: Option<ProcessLock>,
+    /// ID to assign to the next request
+    next_id: u64,
+    /// Requests waiting for the lock, in the order they were made
+    waiting: VecDeque<(u64, Access)>,
+    /// Async tasks waiting for the lock, woken when it's released
+    wakers: Vec<Waker>,
+}
+
+impl State {
+    /// Register a new request for the lock, and return its ID
+    fn enqueue(&mut self, access: Access) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.waiting.push_back((id, access));
+        id
+    }
+
+    fn dequeue(&mut self, id: u64) {
+        self.waiting.retain(|(waiting, _)| *waiting != id);
+    }
+
+    /// Can the given request take the lock now?
+    fn can_acquire(&self, id: u64, access: Access, policy: LockPolicy) -> bool {
+        if self.holder.is_some() {
+            return false;
+        }
+        if policy == LockPolicy::Fair
+            && self.waiting.front().map(|(waiting, _)| *waiting) != Some(id)
+        {
+            return false;
+        }
+        match access {
+            Access::Write => self.readers == 0,
+            Access::Read if policy == LockPolicy::WritePreferring => !self
+                .waiting
+                .iter()
+                .any(|(_, access)| *access == Access::Write),
+            Access::Read => true,
+        }
+    }
+
+    fn is_locked(&self) -> bool {
+        self.holder.is_some() || self.readers > 0
+    }
+
+    /// Would the current thread deadlock by waiting for the given access,
+    /// because it holds a conflicting lock itself? If so, return where that
+    /// lock was acquired.
+    fn deadlock(&self, access: Access) -> Option<&'static Location<'static>> {
+        let current = thread::current().id();
+        match access {
+            Access::Read if self.holder_thread == Some(current) => {
+                self.holder_info.as_ref().map(|holder| holder.location)
+            }
+            Access::Read => None,
+            Access::Write => self
+                .reader_threads
+                .iter()
+                .find(|(thread, _)| *thread == current)
+                .map(|(_, holder)| holder.location),
+        }
+    }
+
+    /// Does the current thread already have shared access? If so, it can
+    /// always be given more, because waiting for a writer would deadlock.
+    fn is_reader(&self) -> bool {
+        let current = thread::current().id();
+        self.reader_threads
+            .iter()
+            .any(|(thread, _)| *thread == current)
+    }
+}
+
+impl Lock {
+    const fn new() -> Self {
+        Self {
+            state: Mutex::new(State {
+                holder: None,
+                holder_thread: None,
+                depth: 0,
+                holder_info: None,
+                holder_since: None,
+                readers: 0,
+                reader_threads: Vec::new(),
+                reader_process: None,
+                reader_process_pending: false,
+                holder_process: None,
+                next_id: 0,
+                waiting: VecDeque::new(),
+                wakers: Vec::new(),
+            }),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Block until exclusive access is available, then acquire it. If the
+    /// current thread already has exclusive access, it's reentered instead.
+    pub fn acquire(
+        &'static self,
+        location: &'static Location<'static>,
+    ) -> LockToken {
+        if let Some(token) = self.reenter() {
+            return token;
+        }
+        let (state, id) = self
+            .wait(Access::Write, None, location)
+            .expect("Wait without a deadline can't time out");
+        self.take(state, id, Some(thread::current().id()), location)
+    }
+
+    /// Block until exclusive access is available, then acquire it. Return
+    /// `None` if it isn't available before the timeout expires.
+    pub fn acquire_timeout(
+        &'static self,
+        timeout: Duration,
+        location: &'static Location<'static>,
+    ) -> Option<LockToken> {
+        if let Some(token) = self.reenter() {
+            return Some(token);
+        }
+        let deadline = Instant::now().checked_add(timeout);
+        let (state, id) = self.wait(Access::Write, deadline, location)?;
+        Some(self.take(state, id, Some(thread::current().id()), location))
+    }
+
+    /// If the current thread already has exclusive access, share it with a
+    /// new token. Exclusive access is released once every token is dropped.
+    fn reenter(&'static self) -> Option<LockToken> {
+        let mut state = self.state();
+        let id = state
+            .holder
+            .filter(|_| state.holder_thread == Some(thread::current().id()))?;
+        state.depth += 1;
+        Some(LockToken { lock: self, id })
+    }
+
+    /// Panic if the current thread holds any access, because waiting for
+    /// another thread to acquire exclusive access would deadlock
+    pub fn assert_not_held(&self, location: &'static Location<'static>) {
+        let state = self.state();
+        let held = state
+            .deadlock(Access::Read)
+            .or_else(|| state.deadlock(Access::Write));
+        if let Some(held) = held {
+            drop(state);
+            deadlock(held, location);
+        }
+    }
+
+    /// Block until shared access is available, then acquire it
+    pub fn acquire_read(
+        &'static self,
+        location: &'static Location<'static>,
+    ) -> ReadToken {
+        let (mut state, id) = self
+            .wait(Access::Read, None, location)
+            .expect("Wait without a deadline can't time out");
+        state.dequeue(id);
+        state.readers += 1;
+        let thread = thread::current().id();
+        state
+            .reader_threads
+            .push((thread, LockHolder::new(location, true)));
+        if state.readers == 1 {
+            // Other versions of env-lock only support exclusive access, so
+            // the first reader takes the process lock on behalf of the rest.
+            // It may block on another process, so don't hold our state
+            // meanwhile, or every thread in this process would stall. Other
+            // readers wait until it's held.
+            state.reader_process_pending = true;
+            drop(state);
+            let process = ProcessLock::acquire(false);
+            state = self.state();
+            state.reader_process = Some(process);
+            state.reader_process_pending = false;
+        } else {
+            while state.reader_process_pending {
+                state = match yield_hook().clone() {
+                    Some(hook) => {
+                        drop(state);
+                        hook();
+                        self.state()
+                    }
+                    None => self
+                        .released
+                        .wait(state)
+                        .unwrap_or_else(|error| error.into_inner()),
+                };
+            }
+        }
+        // The queue changed, so other readers may be able to proceed
+        self.notify(state);
+        ReadToken {
+            lock: self,
+            thread,
+            location,
+        }
+    }
+
+    /// Wait for exclusive access without blocking the thread, then acquire it
+    #[cfg(feature = "async")]
+    pub fn acquire_async(
+        &'static self,
+        location: &'static Location<'static>,
+    ) -> Acquire {
+        Acquire {
+            lock: self,
+            id: None,
+            location,
+        }
+    }
+
+    /// Block until the lock is available for the requested access. Return the
+    /// state, still locked, and the request ID. If the deadline passes first,
+    /// withdraw the request and return `None`.
+    ///
+    /// ## Panics
+    /// Panics if the current thread holds a conflicting lock, because it
+    /// would wait forever.
+    fn wait(
+        &self,
+        access: Access,
+        deadline: Option<Instant>,
+        location: &'static Location<'static>,
+    ) -> Option<(MutexGuard<'_, State>, u64)> {
+        let mut state = self.state();
+        if let Some(held) = state.deadlock(access) {
+            drop(state);
+            deadlock(held, location);
+        }
+        let id = state.enqueue(access);
+        let start = Instant::now();
+        let mut report_at = contention_threshold()
+            .and_then(|threshold| start.checked_add(threshold));
+        while !(state.can_acquire(id, access, policy())
+            || (access == Access::Read
+                && state.holder.is_none()
+                && state.is_reader()))
+        {
+            let now = Instant::now();
+            if deadline.is_some_and(|deadline| now >= deadline) {
+                state.dequeue(id);
+                // Others may have been waiting behind us
+                self.notify(state);
+                return None;
+            }
+            if report_at.is_some_and(|report_at| now >= report_at) {
+                report_contention(&state, location, now - start);
+                report_at = None;
+            }
+            if let Some(hook) = yield_hook().clone() {
+                drop(state);
+                hook();
+                state = self.state();
+                continue;
+            }
+            state = match deadline.into_iter().chain(report_at).min() {
+                None => self
+                    .released
+                    .wait(state)
+                    .unwrap_or_else(|error| error.into_inner()),
+                Some(wake_at) => {
+                    self.released
+                        .wait_timeout(state, wake_at - now)
+                        .unwrap_or_else(|error| error.into_inner())
+                        .0
+                }
+            };
+        }
+        Some((state, id))
+    }
+
+    /// Take exclusive access for a request that's able to acquire it. If a
+    /// thread is given, it can reenter the lock.
+    fn take(
+        &'static self,
+        mut state: MutexGuard<'_, State>,
+        id: u64,
+        thread: Option<ThreadId>,
+        location: &'static Location<'static>,
+    ) -> LockToken {
+        state.dequeue(id);
+        state.holder = Some(id);
+        state.holder_thread = thread;
+        state.depth = 1;
+        state.holder_since = Some(Instant::now());
+        state.holder_info = Some(match thread {
+            Some(_) => LockHolder::new(location, false),
+            None => LockHolder {
+                location,
+                thread_name: None,
+                shared: false,
+            },
+        });
+        drop(state);
+
+        // Other versions of env-lock in this process may be holding the
+        // shared process lock, or other processes the file lock. These have to
+        // be acquired *after* our own lock, so we don't hold them while
+        // waiting for someone else in this version. This blocks even for async
+        // acquisition, but it's only contended when multiple versions of
+        // env-lock or the file lock are in use.
+        let process = ProcessLock::acquire(true);
+        self.state().holder_process = Some(process);
+        LockToken { lock: self, id }
+    }
+
+    /// Release exclusive access, regardless of which token holds it. The
+    /// holder's token becomes a no-op when it's dropped, if ever. Return
+    /// `true` if the lock was held.
+    pub fn force_release(&self) -> bool {
+        let mut state = self.state();
+        if state.holder.is_none() {
+            return false;
+        }
+        state.holder = None;
+        state.holder_thread = None;
+        state.depth = 0;
+        state.holder_info = None;
+        state.holder_since = None;
+        let process = state.holder_process.take();
+        self.notify(state);
+        // Release the process locks after our own, as in a normal release
+        drop(process);
+        true
+    }
+
+    /// Get the ID, info, and acquisition time of the exclusive holder, if any
+    pub fn exclusive_holder(&self) -> Option<(u64, LockHolder, Instant)> {
+        let state = self.state();
+        Some((
+            state.holder?,
+            state.holder_info.clone()?,
+            state.holder_since?,
+        ))
+    }
+
+    /// Get every current holder of the lock
+    fn holders(&self) -> Vec<LockHolder> {
+        let state = self.state();
+        state
+            .holder_info
+            .iter()
+            .cloned()
+            .chain(
+                state
+                    .reader_threads
+                    .iter()
+                    .map(|(_, holder)| holder.clone()),
+            )
+            .collect()
+    }
+
+    /// Block until the lock is not held, or the timeout expires. Return
+    /// `true` if the lock is free. This does *not* acquire the lock, so it may
+    /// be acquired by someone else immediately after.
+    pub fn wait_unlocked(&self, timeout: Duration) -> bool {
+        let (state, _) = self
+            .released
+            .wait_timeout_while(self.state(), timeout, |state| {
+                state.is_locked()
+            })
+            .unwrap_or_else(|error| error.into_inner());
+        !state.is_locked()
+    }
+
+    /// Wake everyone waiting on the lock. Wake all waiters, because some may
+    /// just be checking if the lock is free rather than trying to acquire it.
+    fn notify(&self, mut state: MutexGuard<'_, State>) {
+        let wakers = mem::take(&mut state.wakers);
+        drop(state);
+        self.released.notify_all();
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+
+    /// Lock the internal state. The state is only ever held for short,
+    /// non-panicking critical sections, so we can ignore poisoning.
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|error| error.into_inner())
+    }
+}
+
+/// Future that resolves once exclusive access is acquired. If dropped before
+/// then, the request is withdrawn.
+#[cfg(feature = "async")]
+pub(crate) struct Acquire {
+    lock: &'static Lock,
+    /// Request ID, assigned on first poll
+    id: Option<u64>,
+    location: &'static Location<'static>,
+}
+
+#[cfg(feature = "async")]
+impl Future for Acquire {
+    type Output = LockToken;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<LockToken> {
+        let mut state = self.lock.state();
+        let id = match self.id {
+            Some(id) => id,
+            None => {
+                let id = state.enqueue(Access::Write);
+                self.id = Some(id);
+                id
+            }
+        };
+        if state.can_acquire(id, Access::Write, policy()) {
+            self.id = None;
+            Poll::Ready(self.lock.take(state, id, None, self.location))
+        } else {
+            state.wakers.push(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl Drop for Acquire {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            let mut state = self.lock.state();
+            state.dequeue(id);
+            // Others may have been waiting behind us
+            self.lock.notify(state);
+        }
+    }
+}
+
+/// Locks held alongside our own: the lock shared by every version of
+/// env-lock in this process, and the cross-process file lock if enabled
+struct ProcessLock {
+    _shared: ProcessLockToken,
+    /// Released *after* the shared lock, when fields are dropped
+    #[cfg(feature = "file-lock")]
+    _file: file_lock::FileLockToken,
+}
+
+impl ProcessLock {
+    /// Block until all process locks are acquired. Other versions of
+    /// env-lock only support exclusive access, so the shared lock is always
+    /// exclusive.
+    fn acquire(exclusive: bool) -> Self {
+        #[cfg(not(feature = "file-lock"))]
+        let _ = exclusive;
+        Self {
+            _shared: env_lock_shared::lock(),
+            #[cfg(feature = "file-lock")]
+            _file: file_lock::lock(exclusive),
+        }
+    }
+}
+
+/// Proof that the lock is held. The lock is released when this is dropped.
+/// Unlike a [MutexGuard], this can be sent to and released from any thread.
+pub(crate) struct LockToken {
+    lock: &'static Lock,
+    id: u64,
+}
+
+impl Drop for LockToken {
+    fn drop(&mut self) {
+        let mut state = self.lock.state();
+        if state.holder == Some(self.id) {
+            state.depth -= 1;
+            if state.depth == 0 {
+                state.holder = None;
+                state.holder_thread = None;
+                state.holder_info = None;
+                state.holder_since = None;
+                let process = state.holder_process.take();
+                self.lock.notify(state);
+                // Released *after* our own lock
+                drop(process);
+            }
+        }
+    }
+}
+
+/// Proof that shared access to the lock is held. Shared access is released
+/// when this is dropped.
+pub(crate) struct ReadToken {
+    lock: &'static Lock,
+    /// Thread that acquired the token, which may not be the one dropping it
+    thread: ThreadId,
+    location: &'static Location<'static>,
+}
+
+impl Drop for ReadToken {
+    fn drop(&mut self) {
+        let mut state = self.lock.state();
+        state.readers -= 1;
+        if let Some(index) =
+            state.reader_threads.iter().position(|(thread, holder)| {
+                *thread == self.thread && holder.location == self.location
+            })
+        {
+            state.reader_threads.swap_remove(index);
+        }
+        if state.readers == 0 {
+            // Release the process lock *after* our own, like LockToken does
+            let process = state.reader_process.take();
+            self.lock.notify(state);
+            drop(process);
+        } else {
+            drop(state);
+        }
+    }
+}
+
+/// A lock held by a guard
+pub(crate) enum HeldLock {
+    /// Exclusive access to the entire environment
+    Exclusive(LockToken),
+    /// Exclusive access to some variables
+    Variables {
+        _shared: ReadToken,
+        _variables: VariablesToken,
+    },
+    /// Locks of several guards combined by
+    /// [EnvGuard::merge](crate::EnvGuard::merge), released in order
+    Merged(Vec<Arc<HeldLock>>),
+}
+
+impl HeldLock {
+    /// Lock only the given variables. Shared access to the global lock is
+    /// acquired first, then every variable at once, so this can't deadlock
+    /// with other variable locks.
+    pub fn variables(
+        names: impl IntoIterator<Item = String>,
+        location: &'static Location<'static>,
+    ) -> Self {
+        let shared = ENV_LOCK.acquire_read(location);
+        let variables =
+            VARIABLE_LOCKS.acquire(names.into_iter().collect(), location);
+        Self::Variables {
+            _shared: shared,
+            _variables: variables,
+        }
+    }
+}
+
+/// A set of individually locked names: variables, or named resources
+pub(crate) struct VariableLocks {
+    /// Each locked variable, with the thread and location that locked it
+    locked: Mutex<BTreeMap<String, (ThreadId, &'static Location<'static>)>>,
+    /// Notified whenever variables are released
+    released: Condvar,
+}
+
+impl VariableLocks {
+    const fn new() -> Self {
+        Self {
+            locked: Mutex::new(BTreeMap::new()),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Block until none of the given variables are locked, then lock all of
+    /// them at once
+    ///
+    /// ## Panics
+    /// Panics if the current thread already holds any of the variables,
+    /// because it would wait forever.
+    pub fn acquire(
+        &'static self,
+        names: BTreeSet<String>,
+        location: &'static Location<'static>,
+    ) -> VariablesToken {
+        let current = thread::current().id();
+        let mut locked = self.locked();
+        loop {
+            let mut held = names.iter().filter_map(|name| locked.get(name));
+            match held.next() {
+                None => break,
+                Some((thread, held)) if *thread == current => {
+                    let held = *held;
+                    drop(locked);
+                    deadlock(held, location);
+                }
+                Some(_) => {
+                    if let Some(hook) = yield_hook().clone() {
+                        drop(locked);
+                        hook();
+                        locked = self.locked();
+                        continue;
+                    }
+                    locked = self
+                        .released
+                        .wait(locked)
+                        .unwrap_or_else(|error| error.into_inner());
+                }
+            }
+        }
+        locked.extend(
+            names.iter().map(|name| (name.clone(), (current, location))),
+        );
+        VariablesToken { locks: self, names }
+    }
+
+    /// Lock the set of locked variables. It's only held for short,
+    /// non-panicking critical sections, so we can ignore poisoning.
+    fn locked(
+        &self,
+    ) -> MutexGuard<'_, BTreeMap<String, (ThreadId, &'static Location<'static>)>>
+    {
+        self.locked
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+    }
+}
+
+/// Warn that a lock request at the given location has been waiting a while
+fn report_contention(
+    state: &State,
+    location: &'static Location<'static>,
+    waited: Duration,
+) {
+    let holders: Vec<String> = state
+        .holder_info
+        .iter()
+        .chain(state.reader_threads.iter().map(|(_, holder)| holder))
+        .map(LockHolder::to_string)
+        .collect();
+    eprintln!(
+        "env-lock: waited {waited:?} for the environment lock at {location}; \
+        held by: {}",
+        if holders.is_empty() {
+            "nobody (waiting behind other requests)".to_owned()
+        } else {
+            holders.join(", ")
+        }
+    );
+}
+
+/// Panic because the current thread tried to acquire a lock that it already
+/// holds, and would wait forever
+#[track_caller]
+fn deadlock(
+    held: &'static Location<'static>,
+    location: &'static Location<'static>,
+) -> ! {
+    panic!(
+        "Deadlock: the lock acquired at {held} is still held by this thread, \
+        so it can't be acquired again at {location}. Drop the first guard \
+        before acquiring another."
+    )
+}
+
+/// Proof that some variables are locked. They're released when this is
+/// dropped.
+pub(crate) struct VariablesToken {
+    locks: &'static VariableLocks,
+    names: BTreeSet<String>,
+}
+
+impl VariablesToken {
+    /// Get the locked names, in sorted order
+    pub fn names(&self) -> impl Iterator<Item = &String> {
+        self.names.iter()
+    }
+}
+
+impl Drop for VariablesToken {
+    fn drop(&mut self) {
+        let mut locked = self.locks.locked();
+        for name in &self.names {
+            locked.remove(name);
+        }
+        drop(locked);
+        self.locks.released.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Waiters call the yield hook instead of blocking, and go back to
+    /// blocking once it's cleared
+    #[test]
+    fn lock_yield() {
+        let yields = Arc::new(AtomicU64::new(0));
+        let token = ENV_LOCK.acquire(Location::caller());
+        let counter = Arc::clone(&yields);
+        set_lock_yield(move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+            thread::yield_now();
+        });
+        let handle =
+            thread::spawn(|| drop(ENV_LOCK.acquire(Location::caller())));
+        while yields.load(Ordering::Relaxed) == 0 {
+            thread::yield_now();
+        }
+        clear_lock_yield();
+        drop(token);
+        handle.join().unwrap();
+    }
+
+    /// Detect single-threaded test runs
+    #[test]
+    fn detect_serial() {
+        let args = |args: &[&str]| {
+            args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>()
+        };
+
+        assert!(!is_serial(None, args(&["test"])));
+        assert!(!is_serial(Some("4"), args(&["test"])));
+        assert!(is_serial(Some("1"), args(&["test"])));
+        assert!(is_serial(None, args(&["test", "--test-threads=1"])));
+        assert!(is_serial(None, args(&["test", "--test-threads", "1"])));
+        assert!(!is_serial(Some("1"), args(&["test", "--test-threads=2"])));
+
+        assert!(!is_process_per_test(None));
+        assert!(!is_process_per_test(Some("process-per-test-group")));
+        assert!(is_process_per_test(Some("process-per-test")));
+    }
+
+    /// Readers share the lock, and writers get exclusive access
+    #[test]
+    fn read_write() {
+        static LOCK: Lock = Lock::new();
+
+        let read1 = LOCK.acquire_read(Location::caller());
+        let read2 = LOCK.acquire_read(Location::caller());
+        assert!(!LOCK.wait_unlocked(Duration::ZERO));
+        drop(read1);
+        drop(read2);
+        assert!(LOCK.wait_unlocked(Duration::ZERO));
+
+        let write = LOCK.acquire(Location::caller());
+        assert!(!LOCK.wait_unlocked(Duration::ZERO));
+        drop(write);
+        assert!(LOCK.wait_unlocked(Duration::ZERO));
+    }
+
+    /// The holding thread can reenter the lock, and it's released once every
+    /// token is dropped, in any order
+    #[test]
+    fn reentrant() {
+        static LOCK: Lock = Lock::new();
+
+        let outer = LOCK.acquire(Location::caller());
+        let inner = LOCK.acquire(Location::caller());
+        assert!(thread::spawn(|| LOCK
+            .acquire_timeout(Duration::from_millis(10), Location::caller())
+            .is_none())
+        .join()
+        .unwrap());
+        drop(outer);
+        assert!(!LOCK.wait_unlocked(Duration::ZERO));
+        drop(inner);
+        assert!(LOCK.wait_unlocked(Duration::ZERO));
+    }
+
+    /// Waiting for a lock held by the same thread panics instead of hanging
+    #[test]
+    fn deadlock() {
+        static LOCK: Lock = Lock::new();
+        static VARIABLES: VariableLocks = VariableLocks::new();
+
+        let read = LOCK.acquire_read(Location::caller());
+        let error =
+            std::panic::catch_unwind(|| LOCK.acquire(Location::caller()))
+                .err()
+                .unwrap();
+        let message = error.downcast_ref::<String>().unwrap();
+        assert!(message.starts_with("Deadlock"));
+        assert_eq!(message.matches("src/lock.rs").count(), 2);
+        // Readers can always get more shared access
+        let _read = LOCK.acquire_read(Location::caller());
+        drop(read);
+
+        let names = || BTreeSet::from(["A".to_owned()]);
+        let _variables = VARIABLES.acquire(names(), Location::caller());
+        assert!(std::panic::catch_unwind(|| {
+            VARIABLES.acquire(names(), Location::caller())
+        })
+        .is_err());
+    }
+
+    /// Each holder is recorded with its location and thread
+    #[test]
+    fn holders() {
+        static LOCK: Lock = Lock::new();
+
+        assert_eq!(LOCK.holders(), []);
+        let location = Location::caller();
+        let write = LOCK.acquire(location);
+        assert_eq!(
+            LOCK.holders(),
+            [LockHolder {
+                location,
+                thread_name: thread::current().name().map(String::from),
+                shared: false,
+            }]
+        );
+        drop(write);
+
+        let read1 = LOCK.acquire_read(Location::caller());
+        let read2 = LOCK.acquire_read(Location::caller());
+        assert_eq!(LOCK.holders().len(), 2);
+        assert!(LOCK.holders().iter().all(|holder| holder.shared));
+        drop((read1, read2));
+        assert_eq!(LOCK.holders(), []);
+    }
+
+    /// Acquisition gives up once the timeout expires, and withdraws its
+    /// request so it doesn't block anyone else
+    #[test]
+    fn acquire_timeout() {
+        static LOCK: Lock = Lock::new();
+
+        let write = LOCK.acquire(Location::caller());
+        // Another thread, so the lock isn't reentered
+        assert!(thread::spawn(|| LOCK
+            .acquire_timeout(Duration::from_millis(10), Location::caller())
+            .is_none())
+        .join()
+        .unwrap());
+        assert!(LOCK.state().waiting.is_empty());
+        drop(write);
+        assert!(LOCK
+            .acquire_timeout(Duration::from_millis(10), Location::caller())
+            .is_some());
+    }
+
+    /// Each policy decides whether readers can pass waiting writers
+    #[test]
+    fn policy() {
+        let mut state = State {
+            readers: 1,
+            ..State::default()
+        };
+        let writer = state.enqueue(Access::Write);
+        let reader = state.enqueue(Access::Read);
+
+        assert!(!state.can_acquire(writer, Access::Write, LockPolicy::Fair));
+        assert!(!state.can_acquire(
+            reader,
+            Access::Read,
+            LockPolicy::WritePreferring
+        ));
+        assert!(state.can_acquire(
+            reader,
+            Access::Read,
+            LockPolicy::ReadPreferring
+        ));
+        assert!(!state.can_acquire(reader, Access::Read, LockPolicy::Fair));
+
+        state.readers = 0;
+        assert!(state.can_acquire(writer, Access::Write, LockPolicy::Fair));
+        state.dequeue(writer);
+        state.holder = Some(writer);
+        assert!(!state.can_acquire(
+            reader,
+            Access::Read,
+            LockPolicy::ReadPreferring
+        ));
+    }
+
+    /// The first reader doesn't hold the internal state while it waits for
+    /// the process lock, so other threads can still use the lock meanwhile
+    #[test]
+    fn reader_process_lock() {
+        // Stand in for another version of env-lock holding the process lock
+        let process = env_lock_shared::lock();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let handle = thread::spawn(move || {
+            let token = ENV_LOCK.acquire_read(Location::caller());
+            sender.send(()).unwrap();
+            drop(token);
+        });
+        let start = Instant::now();
+        // This would hang if the reader held the state while blocked
+        while !ENV_LOCK.state().reader_process_pending
+            && start.elapsed() < Duration::from_secs(1)
+        {
+            thread::yield_now();
+        }
+        drop(process);
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        handle.join().unwrap();
+    }
+}
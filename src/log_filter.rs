@@ -0,0 +1,95 @@
+//! Reload a [tracing_subscriber] filter whenever a guard modifies `RUST_LOG`.
+//! A subscriber's [EnvFilter] is normally built once from `RUST_LOG` at
+//! startup, so changing the variable in a test has no effect on logging. If
+//! you register a [reload handle](reload::Handle) for the filter with
+//! [register_reload_handle], the filter will be rebuilt from the environment
+//! each time a guard sets or restores `RUST_LOG`, while the environment is
+//! locked.
+//!
+//! Requires the `tracing-subscriber` feature.
+//!
+//! ```
+//! use tracing_subscriber::{
+//!     layer::SubscriberExt, reload, EnvFilter, Registry,
+//! };
+//!
+//! let (filter, handle) = reload::Layer::new(EnvFilter::from_default_env());
+//! let subscriber = Registry::default().with(filter);
+//! env_lock::log_filter::register_reload_handle(handle);
+//!
+//! tracing::subscriber::with_default(subscriber, || {
+//!     let _guard = env_lock::lock_env([("RUST_LOG", Some("debug"))]);
+//!     assert!(tracing::enabled!(tracing::Level::DEBUG));
+//! });
+//! ```
+
+use crate::sys;
+use std::sync::Mutex;
+use tracing_subscriber::{reload, EnvFilter};
+
+/// Variable that filters are built from
+const VARIABLE: &str = "RUST_LOG";
+
+/// Callbacks to rebuild each registered filter
+static RELOADERS: Mutex<Vec<Reloader>> = Mutex::new(Vec::new());
+
+type Reloader = Box<dyn Fn() + Send>;
+
+/// Register a handle to a reloadable [EnvFilter]. Whenever a guard sets or
+/// restores `RUST_LOG`, the filter will be replaced with a new one built from
+/// the environment via [EnvFilter::from_default_env]. Multiple handles can be
+/// registered, e.g. for different subscribers.
+pub fn register_reload_handle<S: 'static>(
+    handle: reload::Handle<EnvFilter, S>,
+) {
+    let reloader = move || {
+        // If the subscriber is gone, there's nothing to reload
+        let _ = handle.reload(EnvFilter::from_default_env());
+    };
+    RELOADERS
+        .lock()
+        .unwrap_or_else(|error| error.into_inner())
+        .push(Box::new(reloader));
+}
+
+/// Rebuild all registered filters if any of the given variables affect them.
+/// Called by guards after applying and restoring variables.
+pub(crate) fn variables_changed<'a>(
+    mut variables: impl Iterator<Item = &'a str>,
+) {
+    if variables.any(|variable| sys::same_name(variable, VARIABLE)) {
+        for reloader in
+            &*RELOADERS.lock().unwrap_or_else(|error| error.into_inner())
+        {
+            reloader();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lock_env;
+    use tracing::Level;
+    use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+    /// Filter is rebuilt when the guard is applied and restored
+    #[test]
+    fn reload_filter() {
+        let (filter, handle) = reload::Layer::new(EnvFilter::new("info"));
+        let subscriber = Registry::default().with(filter);
+        register_reload_handle(handle);
+
+        tracing::subscriber::with_default(subscriber, || {
+            assert!(!tracing::enabled!(Level::DEBUG));
+            let guard = lock_env([(VARIABLE, Some("debug"))]);
+            assert!(tracing::enabled!(Level::DEBUG));
+            drop(guard);
+
+            // Every guard that touches the variable rebuilds the filter
+            let guard = lock_env([(VARIABLE, Some("warn"))]);
+            assert!(!tracing::enabled!(Level::INFO));
+            drop(guard);
+        });
+    }
+}
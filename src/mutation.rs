@@ -0,0 +1,64 @@
+use crate::EnvChange;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// Current [MutationCheck], stored as its discriminant
+static CHECK: AtomicU8 = AtomicU8::new(MutationCheck::Off as u8);
+
+/// What a guard does on drop if a variable it manages was modified without
+/// going through the guard, e.g. by code calling [std::env::set_var]
+/// directly. Such changes are normally hidden, because the guard restores
+/// the variable anyway. Set with [set_mutation_check]. To check at a specific
+/// point instead, use
+/// [EnvGuard::external_mutations](crate::EnvGuard::external_mutations).
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MutationCheck {
+    /// Don't check
+    #[default]
+    Off,
+    /// Print each mutation to stderr
+    Warn,
+    /// Panic if there are any mutations, unless the thread is already
+    /// panicking. The environment is still restored first.
+    Panic,
+}
+
+/// Set the [MutationCheck] for every guard dropped after this call
+///
+/// ```should_panic
+/// use env_lock::MutationCheck;
+/// use std::env;
+///
+/// env_lock::set_mutation_check(MutationCheck::Panic);
+/// let var = "ENV_LOCK_MUTATION_VARIABLE";
+/// let guard = env_lock::lock_env([(var, Some("1"))]);
+/// env::set_var(var, "2"); // Bypasses the guard!
+/// drop(guard); // Panics
+/// ```
+pub fn set_mutation_check(check: MutationCheck) {
+    CHECK.store(check as u8, Ordering::Release);
+}
+
+/// Get the current [MutationCheck]
+pub(crate) fn check() -> MutationCheck {
+    match CHECK.load(Ordering::Acquire) {
+        check if check == MutationCheck::Warn as u8 => MutationCheck::Warn,
+        check if check == MutationCheck::Panic as u8 => MutationCheck::Panic,
+        _ => MutationCheck::Off,
+    }
+}
+
+/// Describe a list of mutations for a report
+pub(crate) fn describe(mutations: &[EnvChange]) -> String {
+    let mut description = String::from(
+        "Variables managed by an env-lock guard were modified without going \
+        through the guard:",
+    );
+    for mutation in mutations {
+        description.push_str(&format!(
+            "\n  {}: {:?} -> {:?}",
+            mutation.name, mutation.before, mutation.after
+        ));
+    }
+    description
+}
@@ -0,0 +1,108 @@
+use crate::{set_or_remove, sys, EnvGuard};
+use std::ffi::OsStr;
+
+/// Lock the environment, get a variable, and release the lock immediately.
+/// Unlike [std::env::var], this waits for any guard that's modifying the
+/// environment. Returns `None` if the variable is unset or isn't valid
+/// UTF-8. If the current thread already holds the lock, it's reentered.
+///
+/// ```
+/// let _home = env_lock::var("HOME");
+/// ```
+#[track_caller]
+pub fn var(variable: &str) -> Option<String> {
+    let _guard = EnvGuard::acquire();
+    sys::var(variable).ok()
+}
+
+/// Lock the environment, set a variable, and release the lock immediately.
+/// The change is *not* restored, so it persists for the rest of the process.
+///
+/// ```
+/// env_lock::set_var("ENV_LOCK_ONESHOT_SET", "hello!");
+/// assert_eq!(env_lock::var("ENV_LOCK_ONESHOT_SET").unwrap(), "hello!");
+/// ```
+#[track_caller]
+pub fn set_var(variable: &str, value: impl AsRef<OsStr>) {
+    let _guard = EnvGuard::acquire();
+    set_or_remove(variable, Some(value));
+}
+
+/// Lock the environment, remove a variable, and release the lock
+/// immediately. The change is *not* restored, so it persists for the rest of
+/// the process.
+#[track_caller]
+pub fn remove_var(variable: &str) {
+    let _guard = EnvGuard::acquire();
+    set_or_remove(variable, None::<&str>);
+}
+
+/// Lock the environment, then replace a variable's value with the result of
+/// a function of its current value (`None` if unset), as one atomic step.
+/// Returning `None` removes the variable. The lock is released immediately
+/// after, and the change is *not* restored.
+///
+/// ```
+/// let var = "ENV_LOCK_ONESHOT_COUNTER";
+/// for _ in 0..3 {
+///     env_lock::update_var(var, |value| {
+///         let count: u32 = value.map_or(0, |value| value.parse().unwrap());
+///         Some((count + 1).to_string())
+///     });
+/// }
+/// assert_eq!(env_lock::var(var).unwrap(), "3");
+/// ```
+#[track_caller]
+pub fn update_var(
+    variable: &str,
+    f: impl FnOnce(Option<String>) -> Option<String>,
+) {
+    let _guard = EnvGuard::acquire();
+    let value = f(sys::var(variable).ok());
+    set_or_remove(variable, value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lock_env, set_lock_mode, LockMode};
+    use std::thread;
+
+    /// Concurrent updates don't lose any increments
+    #[test]
+    fn update_var() {
+        let var = "ENV_LOCK_TEST_VARIABLE_ONESHOT";
+        set_lock_mode(LockMode::Always);
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                thread::spawn(move || {
+                    for _ in 0..25 {
+                        super::update_var(var, |value| {
+                            let count: u32 =
+                                value.map_or(0, |value| value.parse().unwrap());
+                            Some((count + 1).to_string())
+                        });
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(super::var(var).as_deref(), Some("100"));
+        remove_var(var);
+        assert_eq!(super::var(var), None);
+    }
+
+    /// Accessors reenter a lock held by the current thread
+    #[test]
+    fn reentrant() {
+        let var = "ENV_LOCK_TEST_VARIABLE_ONESHOT_REENTRANT";
+        let guard = lock_env([(var, Some("locked"))]);
+        assert_eq!(super::var(var).as_deref(), Some("locked"));
+        set_var(var, "changed");
+        assert_eq!(super::var(var).as_deref(), Some("changed"));
+        drop(guard);
+        assert_eq!(super::var(var), None);
+    }
+}
@@ -0,0 +1,152 @@
+use crate::{expand_names, sys, IntoEnvValue, VariableNames};
+use std::{
+    borrow::Cow,
+    ffi::{OsStr, OsString},
+    process::Command,
+    slice,
+};
+
+/// A set of variables that's only ever applied to subprocesses, never to the
+/// current process. Building an overlay doesn't lock or modify the
+/// environment, so it's free of the soundness and contention problems of
+/// mutating process-global state. Use this for tests whose subject is an
+/// external binary. Build one directly, or from an [EnvLock](crate::EnvLock)
+/// or [Preset](crate::presets::Preset) with their `overlay` methods.
+///
+/// ```no_run
+/// use env_lock::EnvOverlay;
+/// use std::env;
+///
+/// let overlay = EnvOverlay::new([
+///     ("ENV_LOCK_OVERLAY_MODE", Some("test")),
+///     ("ENV_LOCK_OVERLAY_PROXY", None),
+/// ]);
+/// let output = overlay.command("env").output().unwrap();
+/// let stdout = String::from_utf8(output.stdout).unwrap();
+/// assert!(stdout.contains("ENV_LOCK_OVERLAY_MODE=test"));
+/// // The current process is untouched
+/// assert!(env::var("ENV_LOCK_OVERLAY_MODE").is_err());
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EnvOverlay<'a> {
+    /// Each variable appears at most once
+    variables: Vec<(Cow<'a, str>, Option<OsString>)>,
+}
+
+impl<'a> EnvOverlay<'a> {
+    /// Create an overlay from a list of variables, in the same format as
+    /// [lock_env](crate::lock_env). Later values for a variable replace
+    /// earlier ones.
+    pub fn new(
+        variables: impl IntoIterator<
+            Item = (impl VariableNames<'a>, Option<impl IntoEnvValue>),
+        >,
+    ) -> Self {
+        Self::from_variables(expand_names(variables))
+    }
+
+    /// Create an overlay from owned variables, keeping the last value for
+    /// each variable
+    pub(crate) fn from_variables(
+        variables: impl IntoIterator<
+            Item = (Cow<'a, str>, Option<impl Into<OsString>>),
+        >,
+    ) -> Self {
+        let mut overlay = Self::default();
+        for (variable, value) in variables {
+            let value = value.map(Into::into);
+            if let Some((_, existing)) = overlay
+                .variables
+                .iter_mut()
+                .find(|(name, _)| sys::same_name(name, &variable))
+            {
+                *existing = value;
+            } else {
+                overlay.variables.push((variable, value));
+            }
+        }
+        overlay
+    }
+
+    /// Set each variable on a command, and remove each variable without a
+    /// value, so the subprocess sees the parent environment plus this overlay
+    pub fn apply<'c>(&self, command: &'c mut Command) -> &'c mut Command {
+        for (variable, value) in &self.variables {
+            match value {
+                Some(value) => command.env(variable.as_ref(), value),
+                None => command.env_remove(variable.as_ref()),
+            };
+        }
+        command
+    }
+
+    /// Create a command for a program, with this overlay applied
+    pub fn command(&self, program: impl AsRef<OsStr>) -> Command {
+        let mut command = Command::new(program);
+        self.apply(&mut command);
+        command
+    }
+}
+
+impl<'a, 'b> IntoIterator for &'b EnvOverlay<'a> {
+    type Item = (&'b str, Option<&'b OsStr>);
+    type IntoIter = OverlayIter<'a, 'b>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        OverlayIter(self.variables.iter())
+    }
+}
+
+/// Iterator over the variables in an [EnvOverlay]
+pub struct OverlayIter<'a, 'b>(
+    slice::Iter<'b, (Cow<'a, str>, Option<OsString>)>,
+);
+
+impl<'a, 'b> Iterator for OverlayIter<'a, 'b> {
+    type Item = (&'b str, Option<&'b OsStr>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0
+            .next()
+            .map(|(variable, value)| (variable.as_ref(), value.as_deref()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{presets::Preset, EnvLock};
+    use std::env;
+
+    /// Variables are applied to the command, and never to this process
+    #[test]
+    fn apply() {
+        let var1 = "ENV_LOCK_TEST_VARIABLE_OVERLAY_1";
+        let var2 = "ENV_LOCK_TEST_VARIABLE_OVERLAY_2";
+        let overlay = EnvLock::new()
+            .set(var1, "first")
+            .set(var1, "second")
+            .unset(var2)
+            .overlay();
+        assert_eq!(
+            overlay.into_iter().collect::<Vec<_>>(),
+            [(var1, Some(OsStr::new("second"))), (var2, None)]
+        );
+
+        let command = overlay.command("env");
+        assert_eq!(
+            command.get_envs().collect::<Vec<_>>(),
+            [
+                (OsStr::new(var1), Some(OsStr::new("second"))),
+                (OsStr::new(var2), None)
+            ]
+        );
+        assert!(env::var(var1).is_err());
+
+        let overlay = Preset::new().set(var1, "preset").overlay();
+        assert_eq!(
+            overlay.into_iter().collect::<Vec<_>>(),
+            [(var1, Some(OsStr::new("preset")))]
+        );
+    }
+}
@@ -0,0 +1,1401 @@
+//! Parsers for loading variables from environment scripts and files. Each
+//! parser returns a [Preset], which can be locked or combined with other
+//! variables.
+//!
+//! ```
+//! use std::env;
+//!
+//! let preset = env_lock::parse::shell(r#"
+//!     ## Comments are ignored
+//!     export GREETING="hello world"
+//!     unset LANGUAGE
+//! "#)
+//! .unwrap();
+//! let _guard = preset.lock();
+//! assert_eq!(env::var("GREETING").unwrap(), "hello world");
+//! assert!(env::var("LANGUAGE").is_err());
+//! ```
+
+use crate::presets::Preset;
+use std::{
+    error::Error,
+    fmt::{self, Display},
+    iter::Peekable,
+    str::Chars,
+};
+
+/// An error encountered while parsing variables
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    /// Line number where the error occurred, starting at 1
+    pub line: usize,
+    /// Description of the problem
+    pub message: String,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Error on line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for ParseError {}
+
+/// Parse a POSIX shell snippet consisting of variable assignments, in the form
+/// of `export NAME=value` (the `export` is optional). `unset NAME` removes a
+/// variable, and `export NAME` without a value is ignored. Values follow shell
+/// quoting rules: single quotes are literal, double quotes allow `\` escapes
+/// for `$`, `` ` ``, `"`, and `\`, and unquoted values end at whitespace.
+/// Quoted values may span multiple lines. `#` starts a comment.
+///
+/// Shell expansions (`$VAR`, `$(cmd)`, etc.) are not supported, and produce
+/// an error rather than silently being left as literal text.
+pub fn shell(input: &str) -> Result<Preset, ParseError> {
+    let mut parser = ShellParser {
+        chars: input.chars().peekable(),
+        line: 1,
+    };
+    let mut preset = Preset::new();
+    loop {
+        parser.skip_blank();
+        let Some(word) = parser.word() else {
+            // Anything other than a word is either EOF or garbage
+            return match parser.chars.peek().copied() {
+                None => Ok(preset),
+                Some(c) => Err(parser.error(format!("Unexpected `{c}`"))),
+            };
+        };
+
+        match word.as_str() {
+            "unset" => {
+                parser.skip_inline_whitespace();
+                let name = parser.name()?;
+                preset = preset.unset(name);
+            }
+            _ => {
+                let is_export = word == "export";
+                let name = if is_export {
+                    parser.skip_inline_whitespace();
+                    parser.name()?
+                } else {
+                    validate_name(&word).map_err(|m| parser.error(m))?;
+                    word
+                };
+                if parser.chars.next_if_eq(&'=').is_some() {
+                    let value = parser.value()?;
+                    preset = preset.set(name, value);
+                } else if !is_export {
+                    return Err(parser.error(format!(
+                        "Expected `=` after variable name `{name}`"
+                    )));
+                }
+            }
+        }
+        parser.end_of_statement()?;
+    }
+}
+
+/// Character-level parser for [shell]
+struct ShellParser<'a> {
+    chars: Peekable<Chars<'a>>,
+    /// Current line number, for errors
+    line: usize,
+}
+
+impl<'a> ShellParser<'a> {
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next();
+        if c == Some('\n') {
+            self.line += 1;
+        }
+        c
+    }
+
+    /// Skip whitespace, newlines, and comments
+    fn skip_blank(&mut self) {
+        loop {
+            match self.chars.peek() {
+                Some(c) if c.is_whitespace() => {
+                    self.next();
+                }
+                Some('#') => self.skip_comment(),
+                _ => break,
+            }
+        }
+    }
+
+    fn skip_inline_whitespace(&mut self) {
+        while self.chars.next_if(|c| *c == ' ' || *c == '\t').is_some() {}
+    }
+
+    /// Skip to the end of the line, but not past it
+    fn skip_comment(&mut self) {
+        while self.chars.next_if(|c| *c != '\n').is_some() {}
+    }
+
+    /// Parse a word of identifier characters. Return `None` if there are none
+    fn word(&mut self) -> Option<String> {
+        let mut word = String::new();
+        while let Some(c) = self
+            .chars
+            .next_if(|c| c.is_ascii_alphanumeric() || *c == '_')
+        {
+            word.push(c);
+        }
+        (!word.is_empty()).then_some(word)
+    }
+
+    /// Parse a variable name
+    fn name(&mut self) -> Result<String, ParseError> {
+        let name = self
+            .word()
+            .ok_or_else(|| self.error("Expected variable name"))?;
+        validate_name(&name).map_err(|message| self.error(message))?;
+        Ok(name)
+    }
+
+    /// Parse a value, which may consist of several quoted and unquoted
+    /// segments
+    fn value(&mut self) -> Result<String, ParseError> {
+        let mut value = String::new();
+        while let Some(&c) = self.chars.peek() {
+            match c {
+                ' ' | '\t' | '\n' | '\r' | ';' => break,
+                '\'' => {
+                    self.next();
+                    self.single_quoted(&mut value)?;
+                }
+                '"' => {
+                    self.next();
+                    self.double_quoted(&mut value)?;
+                }
+                '\\' => {
+                    self.next();
+                    match self.next() {
+                        // Line continuation
+                        Some('\n') => {}
+                        Some(c) => value.push(c),
+                        None => {
+                            return Err(self.error("Unexpected end of input"))
+                        }
+                    }
+                }
+                '$' | '`' => return Err(self.expansion_error()),
+                _ => {
+                    self.next();
+                    value.push(c);
+                }
+            }
+        }
+        Ok(value)
+    }
+
+    fn single_quoted(&mut self, value: &mut String) -> Result<(), ParseError> {
+        loop {
+            match self.next() {
+                Some('\'') => return Ok(()),
+                Some(c) => value.push(c),
+                None => return Err(self.error("Unterminated single quote")),
+            }
+        }
+    }
+
+    fn double_quoted(&mut self, value: &mut String) -> Result<(), ParseError> {
+        loop {
+            match self.next() {
+                Some('"') => return Ok(()),
+                Some('\\') => match self.next() {
+                    Some(c @ ('$' | '`' | '"' | '\\')) => value.push(c),
+                    Some('\n') => {}
+                    Some(c) => {
+                        value.push('\\');
+                        value.push(c);
+                    }
+                    None => return Err(self.error("Unterminated double quote")),
+                },
+                Some('$' | '`') => return Err(self.expansion_error()),
+                Some(c) => value.push(c),
+                None => return Err(self.error("Unterminated double quote")),
+            }
+        }
+    }
+
+    /// After a statement, only a comment, `;`, end of line, or another
+    /// assignment (`A=1 B=2`) is allowed
+    fn end_of_statement(&mut self) -> Result<(), ParseError> {
+        self.skip_inline_whitespace();
+        match self.chars.peek().copied() {
+            None | Some('\n' | '\r') => Ok(()),
+            Some(c) if c.is_ascii_alphabetic() || c == '_' => Ok(()),
+            Some(';') => {
+                self.next();
+                Ok(())
+            }
+            Some('#') => {
+                self.skip_comment();
+                Ok(())
+            }
+            Some(c) => Err(self.error(format!("Unexpected `{c}`"))),
+        }
+    }
+
+    fn expansion_error(&self) -> ParseError {
+        self.error("Shell expansions are not supported; escape `$` and `` ` ``")
+    }
+
+    fn error(&self, message: impl Into<String>) -> ParseError {
+        ParseError {
+            line: self.line,
+            message: message.into(),
+        }
+    }
+}
+
+/// Parse a Windows batch script consisting of `set NAME=value` statements.
+/// As in `cmd.exe`, the value is everything after the first `=` up to the end
+/// of the line, and an empty value removes the variable. The quoted form
+/// `set "NAME=value"` excludes anything after the closing quote. Outside
+/// quotes, `^` escapes the following character. `rem` and `::` comments,
+/// `@echo off`, and `setlocal`/`endlocal` are ignored.
+///
+/// Expansions (`%VAR%`, `!VAR!`) and the `/a` and `/p` flags are not
+/// supported, and produce an error.
+pub fn batch(input: &str) -> Result<Preset, ParseError> {
+    let mut preset = Preset::new();
+    for (index, line) in input.lines().enumerate() {
+        let error = |message: &str| ParseError {
+            line: index + 1,
+            message: message.to_owned(),
+        };
+        let line = line.trim_start().trim_start_matches('@');
+        let (command, rest) = split_word(line);
+        let command = command.to_ascii_lowercase();
+        match command.as_str() {
+            "" | "rem" | "setlocal" | "endlocal" => continue,
+            "echo" if rest.trim().eq_ignore_ascii_case("off") => continue,
+            _ if command.starts_with("::") => continue,
+            "set" => {}
+            _ => {
+                return Err(error(&format!("Unsupported command `{command}`")))
+            }
+        }
+
+        let assignment = if let Some(quoted) = rest.strip_prefix('"') {
+            let end = quoted
+                .rfind('"')
+                .ok_or_else(|| error("Unterminated double quote"))?;
+            quoted[..end].to_owned()
+        } else if rest.starts_with('/') {
+            return Err(error("`set` flags are not supported"));
+        } else {
+            unescape_caret(rest)
+        };
+        if assignment.contains(['%', '!']) {
+            return Err(error("Variable expansions are not supported"));
+        }
+        let (name, value) = assignment
+            .split_once('=')
+            .ok_or_else(|| error("Expected `=` after variable name"))?;
+        if name.is_empty() {
+            return Err(error("Expected variable name"));
+        }
+        preset = if value.is_empty() {
+            preset.unset(name.to_owned())
+        } else {
+            preset.set(name.to_owned(), value)
+        };
+    }
+    Ok(preset)
+}
+
+/// Parse a PowerShell script consisting of `$env:NAME = "value"` assignments.
+/// Single-quoted values are literal, with `''` as an escaped quote.
+/// Double-quoted values support backtick escapes such as `` `n `` and `` `" ``.
+/// Assigning `$null` or an empty string removes the variable, as does
+/// `Remove-Item Env:NAME`. `#` starts a comment.
+///
+/// Expressions and `$` expansions within values are not supported, and produce
+/// an error.
+pub fn powershell(input: &str) -> Result<Preset, ParseError> {
+    let mut preset = Preset::new();
+    for (index, line) in input.lines().enumerate() {
+        let error = |message: &str| ParseError {
+            line: index + 1,
+            message: message.to_owned(),
+        };
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (command, rest) = split_word(line);
+        if command.eq_ignore_ascii_case("Remove-Item") {
+            let (path, rest) = split_word(rest);
+            let name = strip_prefix_ignore_case(path, "Env:")
+                .map(|name| name.trim_start_matches('\\'))
+                .filter(|name| !name.is_empty())
+                .ok_or_else(|| error("Expected `Env:NAME`"))?;
+            end_of_powershell_statement(rest).map_err(error)?;
+            preset = preset.unset(name.to_owned());
+            continue;
+        }
+
+        let (target, value) = line
+            .split_once('=')
+            .ok_or_else(|| error("Expected `$env:NAME = value`"))?;
+        let name = strip_prefix_ignore_case(target.trim_end(), "$env:")
+            .filter(|name| !name.is_empty())
+            .ok_or_else(|| error("Expected `$env:NAME = value`"))?;
+        let value = value.trim_start();
+        let (value, rest) =
+            if let Some(rest) = strip_prefix_ignore_case(value, "$null") {
+                (None, rest)
+            } else if let Some(quoted) = value.strip_prefix('\'') {
+                let (value, rest) = powershell_single_quoted(quoted)
+                    .ok_or_else(|| error("Unterminated single quote"))?;
+                (Some(value), rest)
+            } else if let Some(quoted) = value.strip_prefix('"') {
+                let (value, rest) = powershell_double_quoted(quoted)
+                    .map_err(error)?
+                    .ok_or_else(|| error("Unterminated double quote"))?;
+                (Some(value), rest)
+            } else {
+                return Err(error("Expected quoted string or `$null`"));
+            };
+        end_of_powershell_statement(rest).map_err(error)?;
+        preset = match value.filter(|value| !value.is_empty()) {
+            Some(value) => preset.set(name.to_owned(), value),
+            None => preset.unset(name.to_owned()),
+        };
+    }
+    Ok(preset)
+}
+
+/// Parse a file using the same rules as `docker run --env-file`. Each line is
+/// `NAME=value`, where the value is taken verbatim: there is no quoting,
+/// escaping, or interpolation, and trailing whitespace is kept. Leading
+/// whitespace and lines starting with `#` are ignored. A line with only a
+/// name passes the variable through from the host, so it's left unchanged.
+pub fn docker(input: &str) -> Result<Preset, ParseError> {
+    let mut preset = Preset::new();
+    let input = input.strip_prefix('\u{feff}').unwrap_or(input);
+    for (index, line) in input.lines().enumerate() {
+        let error = |message: String| ParseError {
+            line: index + 1,
+            message,
+        };
+        let line = line.trim_start();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (name, value) = match line.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (line, None),
+        };
+        if name.is_empty() {
+            return Err(error("Expected variable name".into()));
+        }
+        if name.contains(char::is_whitespace) {
+            return Err(error(format!(
+                "Variable name `{name}` contains whitespace"
+            )));
+        }
+        if let Some(value) = value {
+            preset = preset.set(name.to_owned(), value);
+        }
+    }
+    Ok(preset)
+}
+
+/// Parse a file using the same rules as systemd's `EnvironmentFile=`. Each
+/// line is `NAME=value`, and lines starting with `#` or `;` are comments.
+/// Whitespace around the name and value is trimmed. A value that starts with a
+/// single or double quote is quoted until the matching quote; double quotes
+/// allow `\` escapes for `"`, `\`, `` ` ``, and `$`. In unquoted values, `\`
+/// escapes any character. A `\` at the end of a line continues the value on
+/// the next line. Like systemd, lines without an `=` are ignored.
+pub fn systemd(input: &str) -> Result<Preset, ParseError> {
+    /// Parser states, named after systemd's own parser
+    #[derive(Clone, Copy, PartialEq)]
+    enum State {
+        PreKey,
+        Key,
+        PreValue,
+        Value,
+        SingleQuoteValue,
+        DoubleQuoteValue,
+        Comment,
+    }
+
+    let mut preset = Preset::new();
+    let mut state = State::PreKey;
+    let mut line = 1;
+    let mut name = String::new();
+    let mut value = String::new();
+    // Length of the value before any trailing unescaped whitespace
+    let mut value_end = 0;
+    let mut chars = input.chars().peekable();
+    let error = |line, message: &str| ParseError {
+        line,
+        message: message.to_owned(),
+    };
+
+    loop {
+        let c = chars.next();
+        if c == Some('\n') {
+            line += 1;
+        }
+        let newline = matches!(c, Some('\n' | '\r') | None);
+        match state {
+            State::PreKey => match c {
+                Some('#' | ';') => state = State::Comment,
+                Some(c) if !c.is_whitespace() => {
+                    name.push(c);
+                    state = State::Key;
+                }
+                _ => {}
+            },
+            State::Key => match c {
+                Some('=') => {
+                    let trimmed = name.trim_end();
+                    if trimmed.is_empty()
+                        || trimmed.contains(char::is_whitespace)
+                    {
+                        return Err(error(line, "Invalid variable name"));
+                    }
+                    name.truncate(trimmed.len());
+                    state = State::PreValue;
+                }
+                // No `=`, so ignore the line
+                _ if newline => {
+                    name.clear();
+                    state = State::PreKey;
+                }
+                Some(c) => name.push(c),
+                None => unreachable!(),
+            },
+            State::PreValue | State::Value => match c {
+                Some('\'') if state == State::PreValue => {
+                    state = State::SingleQuoteValue;
+                }
+                Some('"') if state == State::PreValue => {
+                    state = State::DoubleQuoteValue;
+                }
+                Some('\\') => match chars.next() {
+                    // Line continuation
+                    Some('\n') => line += 1,
+                    Some(c) => {
+                        value.push(c);
+                        value_end = value.len();
+                        state = State::Value;
+                    }
+                    None => {}
+                },
+                _ if newline => {
+                    value.truncate(value_end);
+                    preset = preset.set(
+                        std::mem::take(&mut name),
+                        std::mem::take(&mut value),
+                    );
+                    value_end = 0;
+                    state = State::PreKey;
+                }
+                Some(c) if c.is_whitespace() => {
+                    if state == State::Value {
+                        value.push(c);
+                    }
+                }
+                Some(c) => {
+                    value.push(c);
+                    value_end = value.len();
+                    state = State::Value;
+                }
+                None => unreachable!(),
+            },
+            State::SingleQuoteValue => match c {
+                Some('\'') => {
+                    value_end = value.len();
+                    state = State::PreValue;
+                }
+                Some(c) => value.push(c),
+                None => return Err(error(line, "Unterminated single quote")),
+            },
+            State::DoubleQuoteValue => match c {
+                Some('"') => {
+                    value_end = value.len();
+                    state = State::PreValue;
+                }
+                Some('\\') => match chars.next() {
+                    Some('\n') => line += 1,
+                    Some(c @ ('"' | '\\' | '`' | '$')) => value.push(c),
+                    Some(c) => {
+                        value.push('\\');
+                        value.push(c);
+                    }
+                    None => {
+                        return Err(error(line, "Unterminated double quote"))
+                    }
+                },
+                Some(c) => value.push(c),
+                None => return Err(error(line, "Unterminated double quote")),
+            },
+            State::Comment => {
+                if c == Some('\\') {
+                    // Comments can be continued too
+                    if chars.next() == Some('\n') {
+                        line += 1;
+                    }
+                } else if newline {
+                    state = State::PreKey;
+                }
+            }
+        }
+        if c.is_none() {
+            return Ok(preset);
+        }
+    }
+}
+
+/// Parse a dotenv file, as used by the `dotenv` family of libraries. Each
+/// line is `NAME=value`, optionally prefixed with `export`. Blank lines and
+/// lines starting with `#` are ignored. Whitespace around the name and value
+/// is trimmed. Single-quoted values are literal, and double-quoted values
+/// allow the escapes `\n`, `\r`, `\t`, `\"`, and `\\`. Quoted values may
+/// span multiple lines. In unquoted values, a `#` preceded by whitespace
+/// starts a comment.
+///
+/// References to other variables (`${VAR}`) are *not* expanded, and are kept
+/// as literal text.
+pub fn dotenv(input: &str) -> Result<Preset, ParseError> {
+    let mut preset = Preset::new();
+    let input = input.strip_prefix('\u{feff}').unwrap_or(input);
+    let mut lines = input.lines().enumerate();
+    while let Some((index, line)) = lines.next() {
+        let error = |message: String| ParseError {
+            line: index + 1,
+            message,
+        };
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line
+            .strip_prefix("export")
+            .filter(|rest| rest.starts_with([' ', '\t']))
+            .unwrap_or(line);
+        let (name, rest) = line.split_once('=').ok_or_else(|| {
+            error(format!(
+                "Expected `=` after variable name `{}`",
+                line.trim()
+            ))
+        })?;
+        let name = name.trim();
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(error(format!("Invalid variable name `{name}`")));
+        }
+        validate_name(name).map_err(error)?;
+
+        let rest = rest.trim_start();
+        let value = match rest.chars().next() {
+            Some(quote @ ('"' | '\'')) => {
+                let (value, rest) =
+                    dotenv_quoted(quote, &rest[1..], &mut lines)
+                        .map_err(error)?;
+                let rest = rest.trim_start();
+                if !(rest.is_empty() || rest.starts_with('#')) {
+                    return Err(error(format!(
+                        "Unexpected `{rest}` after quoted value"
+                    )));
+                }
+                value
+            }
+            _ => {
+                // A comment must be preceded by whitespace, so `a#b` is kept
+                let end = rest
+                    .match_indices('#')
+                    .map(|(i, _)| i)
+                    .find(|i| rest[..*i].ends_with([' ', '\t']))
+                    .unwrap_or(rest.len());
+                rest[..end].trim_end().to_owned()
+            }
+        };
+        preset = preset.set(name.to_owned(), value);
+    }
+    Ok(preset)
+}
+
+/// Parse the rest of a quoted dotenv value, which may continue onto
+/// subsequent lines. Return the value and whatever follows the closing quote.
+fn dotenv_quoted<'a>(
+    quote: char,
+    mut text: &'a str,
+    lines: &mut impl Iterator<Item = (usize, &'a str)>,
+) -> Result<(String, &'a str), String> {
+    let mut value = String::new();
+    loop {
+        let mut chars = text.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                _ if c == quote => return Ok((value, &text[i + 1..])),
+                '\\' if quote == '"' => match chars.next() {
+                    Some((_, 'n')) => value.push('\n'),
+                    Some((_, 'r')) => value.push('\r'),
+                    Some((_, 't')) => value.push('\t'),
+                    Some((_, c @ ('"' | '\\'))) => value.push(c),
+                    Some((_, c)) => {
+                        value.push('\\');
+                        value.push(c);
+                    }
+                    None => value.push('\\'),
+                },
+                _ => value.push(c),
+            }
+        }
+        let kind = if quote == '"' { "double" } else { "single" };
+        let (_, line) = lines
+            .next()
+            .ok_or_else(|| format!("Unterminated {kind} quote"))?;
+        value.push('\n');
+        text = line;
+    }
+}
+
+/// Parse a JSON object whose values are strings, numbers, booleans, or
+/// `null`. Strings are used as-is, numbers and booleans are converted to their
+/// JSON text, and `null` removes the variable. Nested objects and arrays are
+/// not supported, and produce an error.
+///
+/// ```
+/// let preset = env_lock::parse::json(r#"{"PORT": 8080, "PROXY": null}"#)
+///     .unwrap();
+/// let _guard = preset.lock();
+/// ```
+pub fn json(input: &str) -> Result<Preset, ParseError> {
+    let mut parser = DocumentParser::new(input);
+    let mut preset = Preset::new();
+    parser.skip_whitespace();
+    parser.expect("{")?;
+    parser.skip_whitespace();
+    if !parser.eat("}") {
+        loop {
+            parser.skip_whitespace();
+            parser.expect("\"")?;
+            let name = parser.json_string()?;
+            if name.is_empty() || name.contains(['=', '\0']) {
+                return Err(
+                    parser.error(format!("Invalid variable name `{name}`"))
+                );
+            }
+            parser.skip_whitespace();
+            parser.expect(":")?;
+            parser.skip_whitespace();
+            preset = match parser.json_value()? {
+                Some(value) => preset.set(name, value),
+                None => preset.unset(name),
+            };
+            parser.skip_whitespace();
+            if parser.eat("}") {
+                break;
+            }
+            parser.expect(",")?;
+        }
+    }
+    parser.skip_whitespace();
+    parser.end()?;
+    Ok(preset)
+}
+
+/// Parse a TOML document consisting of top-level `NAME = value` pairs. Basic,
+/// literal, and multi-line strings are supported, and other scalars (numbers,
+/// booleans, and dates) are converted to their TOML text, with any `_`
+/// separators removed. TOML has no null, so variables can't be removed.
+/// Tables, dotted keys, and arrays are not supported, and produce an error.
+///
+/// ```
+/// let preset = env_lock::parse::toml(
+///     r#"
+///     HOST = "localhost" # comment
+///     PORT = 8_080
+///     "#,
+/// )
+/// .unwrap();
+/// let _guard = preset.lock();
+/// ```
+pub fn toml(input: &str) -> Result<Preset, ParseError> {
+    let mut parser = DocumentParser::new(input);
+    let mut preset = Preset::new();
+    loop {
+        // Skip blank lines and comments
+        parser.skip_whitespace();
+        match parser.peek() {
+            None => return Ok(preset),
+            Some('#') => {
+                parser.skip_comment();
+                continue;
+            }
+            Some('[') => return Err(parser.error("Tables are not supported")),
+            Some(_) => {}
+        }
+
+        let name = if parser.eat("\"") {
+            parser.toml_basic_string()?
+        } else if parser.eat("'") {
+            parser.toml_literal_string()?
+        } else {
+            parser
+                .take_while(|c| {
+                    c.is_ascii_alphanumeric() || c == '_' || c == '-'
+                })
+                .to_owned()
+        };
+        if name.is_empty() || name.contains(['=', '\0']) {
+            return Err(parser.error(format!("Invalid variable name `{name}`")));
+        }
+        parser.skip_inline_whitespace();
+        if parser.peek() == Some('.') {
+            return Err(parser.error("Dotted keys are not supported"));
+        }
+        parser.expect("=")?;
+        parser.skip_inline_whitespace();
+        let value = parser.toml_value()?;
+        preset = preset.set(name, value);
+
+        // Only a comment can follow a value on the same line
+        parser.skip_inline_whitespace();
+        if parser.peek() == Some('#') {
+            parser.skip_comment();
+        }
+        if !(parser.eat("\n") || parser.eat("\r\n")) {
+            parser.end()?;
+        }
+    }
+}
+
+/// Character-level parser for [json] and [toml]
+struct DocumentParser<'a> {
+    rest: &'a str,
+    /// Current line number, for errors
+    line: usize,
+}
+
+impl<'a> DocumentParser<'a> {
+    fn new(input: &'a str) -> Self {
+        Self {
+            rest: input.strip_prefix('\u{feff}').unwrap_or(input),
+            line: 1,
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest.chars().next()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.rest = &self.rest[c.len_utf8()..];
+        if c == '\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+
+    /// Consume the given text if it's next, and return whether it was
+    fn eat(&mut self, text: &str) -> bool {
+        match self.rest.strip_prefix(text) {
+            Some(rest) => {
+                self.line += text.matches('\n').count();
+                self.rest = rest;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn expect(&mut self, text: &str) -> Result<(), ParseError> {
+        if self.eat(text) {
+            Ok(())
+        } else {
+            Err(self.unexpected(&format!("`{text}`")))
+        }
+    }
+
+    /// Expect the end of input
+    fn end(&self) -> Result<(), ParseError> {
+        match self.peek() {
+            None => Ok(()),
+            Some(_) => Err(self.unexpected("end of input")),
+        }
+    }
+
+    fn take_while(&mut self, predicate: impl Fn(char) -> bool) -> &'a str {
+        let end = self.rest.find(|c| !predicate(c)).unwrap_or(self.rest.len());
+        let (taken, rest) = self.rest.split_at(end);
+        self.line += taken.matches('\n').count();
+        self.rest = rest;
+        taken
+    }
+
+    fn skip_whitespace(&mut self) {
+        self.take_while(char::is_whitespace);
+    }
+
+    fn skip_inline_whitespace(&mut self) {
+        self.take_while(|c| c == ' ' || c == '\t');
+    }
+
+    /// Skip to the end of the line, but not past it
+    fn skip_comment(&mut self) {
+        self.take_while(|c| c != '\n');
+    }
+
+    /// Parse a JSON value, returning `None` for `null`
+    fn json_value(&mut self) -> Result<Option<String>, ParseError> {
+        match self.peek() {
+            Some('"') => {
+                self.next();
+                self.json_string().map(Some)
+            }
+            Some('{' | '[') => {
+                Err(self.error("Nested objects and arrays are not supported"))
+            }
+            _ => {
+                let token = self.take_while(|c| {
+                    c.is_ascii_alphanumeric() || matches!(c, '-' | '+' | '.')
+                });
+                match token {
+                    "null" => Ok(None),
+                    "true" | "false" => Ok(Some(token.to_owned())),
+                    _ if token.parse::<f64>().is_ok()
+                        && token.starts_with(|c: char| {
+                            c == '-' || c.is_ascii_digit()
+                        }) =>
+                    {
+                        Ok(Some(token.to_owned()))
+                    }
+                    "" => Err(self.unexpected("value")),
+                    _ => Err(self.error(format!("Invalid value `{token}`"))),
+                }
+            }
+        }
+    }
+
+    /// Parse the rest of a JSON string, after the opening quote
+    fn json_string(&mut self) -> Result<String, ParseError> {
+        let mut value = String::new();
+        loop {
+            if matches!(self.peek(), Some('\n') | None) {
+                return Err(self.error("Unterminated string"));
+            }
+            match self.next() {
+                Some('"') => return Ok(value),
+                Some('\\') => match self.next() {
+                    Some(c @ ('"' | '\\' | '/')) => value.push(c),
+                    Some('b') => value.push('\u{8}'),
+                    Some('f') => value.push('\u{c}'),
+                    Some('n') => value.push('\n'),
+                    Some('r') => value.push('\r'),
+                    Some('t') => value.push('\t'),
+                    Some('u') => value.push(self.json_unicode()?),
+                    _ => return Err(self.error("Invalid escape sequence")),
+                },
+                Some(c) => value.push(c),
+                None => unreachable!(),
+            }
+        }
+    }
+
+    /// Parse a `\u` escape, which may be the first half of a surrogate pair
+    fn json_unicode(&mut self) -> Result<char, ParseError> {
+        let high = self.hex(4)?;
+        let code = if (0xd800..0xdc00).contains(&high) {
+            self.expect("\\u")?;
+            let low = self.hex(4)?;
+            if !(0xdc00..0xe000).contains(&low) {
+                return Err(self.error("Invalid surrogate pair"));
+            }
+            0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)
+        } else {
+            high
+        };
+        char::from_u32(code).ok_or_else(|| self.error("Invalid unicode escape"))
+    }
+
+    /// Parse a TOML value as a string
+    fn toml_value(&mut self) -> Result<String, ParseError> {
+        if self.eat("\"\"\"") {
+            self.toml_multiline_string(true)
+        } else if self.eat("'''") {
+            self.toml_multiline_string(false)
+        } else if self.eat("\"") {
+            self.toml_basic_string()
+        } else if self.eat("'") {
+            self.toml_literal_string()
+        } else if matches!(self.peek(), Some('[' | '{')) {
+            Err(self.error("Arrays and inline tables are not supported"))
+        } else {
+            let token = self.take_while(|c| {
+                c.is_ascii_alphanumeric()
+                    || matches!(c, '-' | '+' | '.' | ':' | '_')
+            });
+            if token.is_empty() {
+                Err(self.unexpected("value"))
+            } else {
+                Ok(token.replace('_', ""))
+            }
+        }
+    }
+
+    /// Parse the rest of a single-line basic string, after the opening quote
+    fn toml_basic_string(&mut self) -> Result<String, ParseError> {
+        let mut value = String::new();
+        loop {
+            if matches!(self.peek(), Some('\n') | None) {
+                return Err(self.error("Unterminated string"));
+            }
+            match self.next() {
+                Some('"') => return Ok(value),
+                Some('\\') => value.push(self.toml_escape()?),
+                Some(c) => value.push(c),
+                None => unreachable!(),
+            }
+        }
+    }
+
+    /// Parse the rest of a single-line literal string, after the opening
+    /// quote
+    fn toml_literal_string(&mut self) -> Result<String, ParseError> {
+        let value = self.take_while(|c| c != '\'' && c != '\n').to_owned();
+        if self.eat("'") {
+            Ok(value)
+        } else {
+            Err(self.error("Unterminated string"))
+        }
+    }
+
+    /// Parse the rest of a multi-line string, after the opening delimiter.
+    /// Basic strings allow escapes, and literal strings don't.
+    fn toml_multiline_string(
+        &mut self,
+        basic: bool,
+    ) -> Result<String, ParseError> {
+        let delimiter = if basic { "\"\"\"" } else { "'''" };
+        // A newline immediately after the delimiter is trimmed
+        let _ = self.eat("\n") || self.eat("\r\n");
+        let mut value = String::new();
+        loop {
+            if self.eat(delimiter) {
+                return Ok(value);
+            }
+            match self.next() {
+                Some('\\') if basic => {
+                    // A backslash at the end of a line trims all whitespace
+                    // up to the next non-whitespace character
+                    let trailing = self.rest.trim_start_matches([' ', '\t']);
+                    if trailing.starts_with(['\n', '\r']) {
+                        self.skip_whitespace();
+                    } else {
+                        value.push(self.toml_escape()?);
+                    }
+                }
+                Some(c) => value.push(c),
+                None => return Err(self.error("Unterminated string")),
+            }
+        }
+    }
+
+    /// Parse the rest of an escape sequence in a basic string, after the `\`
+    fn toml_escape(&mut self) -> Result<char, ParseError> {
+        match self.next() {
+            Some(c @ ('"' | '\\')) => Ok(c),
+            Some('b') => Ok('\u{8}'),
+            Some('f') => Ok('\u{c}'),
+            Some('n') => Ok('\n'),
+            Some('r') => Ok('\r'),
+            Some('t') => Ok('\t'),
+            Some('u') => self.toml_unicode(4),
+            Some('U') => self.toml_unicode(8),
+            _ => Err(self.error("Invalid escape sequence")),
+        }
+    }
+
+    fn toml_unicode(&mut self, digits: usize) -> Result<char, ParseError> {
+        let code = self.hex(digits)?;
+        char::from_u32(code).ok_or_else(|| self.error("Invalid unicode escape"))
+    }
+
+    /// Parse a fixed number of hex digits
+    fn hex(&mut self, digits: usize) -> Result<u32, ParseError> {
+        let hex = self
+            .rest
+            .get(..digits)
+            .filter(|hex| hex.chars().all(|c| c.is_ascii_hexdigit()))
+            .ok_or_else(|| self.error("Invalid unicode escape"))?;
+        self.rest = &self.rest[digits..];
+        Ok(u32::from_str_radix(hex, 16).expect("Digits are hex"))
+    }
+
+    fn unexpected(&self, expected: &str) -> ParseError {
+        match self.peek() {
+            Some(c) => self.error(format!("Expected {expected}, found `{c}`")),
+            None => {
+                self.error(format!("Expected {expected}, found end of input"))
+            }
+        }
+    }
+
+    fn error(&self, message: impl Into<String>) -> ParseError {
+        ParseError {
+            line: self.line,
+            message: message.into(),
+        }
+    }
+}
+
+/// Split off the first whitespace-delimited word of a line
+fn split_word(line: &str) -> (&str, &str) {
+    let line = line.trim_start();
+    let end = line.find(char::is_whitespace).unwrap_or(line.len());
+    (&line[..end], line[end..].trim_start())
+}
+
+fn strip_prefix_ignore_case<'a>(s: &'a str, prefix: &str) -> Option<&'a str> {
+    let head = s.get(..prefix.len())?;
+    head.eq_ignore_ascii_case(prefix)
+        .then(|| &s[prefix.len()..])
+}
+
+/// Remove `^` escapes from an unquoted batch value
+fn unescape_caret(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c == '^' {
+            unescaped.extend(chars.next());
+        } else {
+            unescaped.push(c);
+        }
+    }
+    unescaped
+}
+
+/// Parse the remainder of a single-quoted PowerShell string. Return the value
+/// and the rest of the line, or `None` if the string isn't terminated
+fn powershell_single_quoted(input: &str) -> Option<(String, &str)> {
+    let mut value = String::new();
+    let mut chars = input.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        // A doubled quote is an escaped quote
+        if c == '\'' && chars.next_if(|(_, c)| *c == '\'').is_none() {
+            return Some((value, &input[i + 1..]));
+        }
+        value.push(c);
+    }
+    None
+}
+
+/// Parse the remainder of a double-quoted PowerShell string. Return the value
+/// and the rest of the line, or `None` if the string isn't terminated
+fn powershell_double_quoted(
+    input: &str,
+) -> Result<Option<(String, &str)>, &'static str> {
+    let mut value = String::new();
+    let mut chars = input.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => {
+                if chars.next_if(|(_, c)| *c == '"').is_none() {
+                    return Ok(Some((value, &input[i + 1..])));
+                }
+                value.push('"');
+            }
+            '`' => {
+                let Some((_, escaped)) = chars.next() else {
+                    return Ok(None);
+                };
+                value.push(match escaped {
+                    '0' => '\0',
+                    'a' => '\x07',
+                    'b' => '\x08',
+                    'e' => '\x1b',
+                    'f' => '\x0c',
+                    'n' => '\n',
+                    'r' => '\r',
+                    't' => '\t',
+                    'v' => '\x0b',
+                    c => c,
+                });
+            }
+            '$' => return Err("Variable expansions are not supported"),
+            c => value.push(c),
+        }
+    }
+    Ok(None)
+}
+
+/// After a PowerShell statement, only a comment or `;` is allowed
+fn end_of_powershell_statement(rest: &str) -> Result<(), &'static str> {
+    let rest = rest.trim();
+    let rest = rest.strip_prefix(';').unwrap_or(rest).trim_start();
+    if rest.is_empty() || rest.starts_with('#') {
+        Ok(())
+    } else {
+        Err("Unexpected input after statement")
+    }
+}
+
+/// Check that a variable name is a valid identifier
+fn validate_name(name: &str) -> Result<(), String> {
+    if name.starts_with(|c: char| c.is_ascii_digit()) {
+        Err(format!("Invalid variable name `{name}`"))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variables(
+        result: Result<Preset, ParseError>,
+    ) -> Vec<(String, Option<String>)> {
+        result
+            .unwrap()
+            .into_iter()
+            .map(|(name, value)| (name.to_owned(), value.map(String::from)))
+            .collect()
+    }
+
+    fn var(name: &str, value: Option<&str>) -> (String, Option<String>) {
+        (name.to_owned(), value.map(String::from))
+    }
+
+    /// Quoting, comments, and statement types
+    #[test]
+    fn shell_valid() {
+        let input = r#"
+# Comment
+export A=plain # trailing comment
+B='single $quoted'; export C="double \"quoted\" \$ \n"
+D="multi
+line" E=unquoted\ escape
+export F
+unset G
+H=
+"#;
+        assert_eq!(
+            variables(shell(input)),
+            [
+                var("A", Some("plain")),
+                var("B", Some("single $quoted")),
+                var("C", Some("double \"quoted\" $ \\n")),
+                var("D", Some("multi\nline")),
+                var("E", Some("unquoted escape")),
+                var("G", None),
+                var("H", Some("")),
+            ]
+        );
+    }
+
+    /// Errors include the line number
+    #[test]
+    fn shell_invalid() {
+        let error = |input| shell(input).unwrap_err();
+        assert_eq!(error("A=1\nB=$HOME").line, 2);
+        assert_eq!(error("A=\"unterminated").line, 1);
+        assert_eq!(error("1A=1").line, 1);
+        assert_eq!(error("A 1").line, 1);
+        assert_eq!(error("export =1").line, 1);
+    }
+
+    /// Batch `set` statements, with and without quotes
+    #[test]
+    fn batch_valid() {
+        let input = r#"
+@echo off
+setlocal
+rem Comment
+:: Another comment
+set A=plain value
+SET "B=quoted" trailing
+set C=caret^&escape
+set D=
+"#;
+        assert_eq!(
+            variables(batch(input)),
+            [
+                var("A", Some("plain value")),
+                var("B", Some("quoted")),
+                var("C", Some("caret&escape")),
+                var("D", None),
+            ]
+        );
+        assert_eq!(batch("set A=%PATH%").unwrap_err().line, 1);
+        assert_eq!(batch("\necho hi").unwrap_err().line, 2);
+    }
+
+    /// PowerShell `$env:` assignments and removals
+    #[test]
+    fn powershell_valid() {
+        let input = r#"
+# Comment
+$env:A = 'single ''quoted'''
+$ENV:B="double `"quoted`"`n"; # trailing comment
+$env:C = $null
+$env:D = ''
+Remove-Item Env:\E
+"#;
+        assert_eq!(
+            variables(powershell(input)),
+            [
+                var("A", Some("single 'quoted'")),
+                var("B", Some("double \"quoted\"\n")),
+                var("C", None),
+                var("D", None),
+                var("E", None),
+            ]
+        );
+        assert_eq!(powershell("$env:A = \"$HOME\"").unwrap_err().line, 1);
+        assert_eq!(powershell("\n$env:A = 'a' b").unwrap_err().line, 2);
+    }
+
+    /// Docker values are verbatim
+    #[test]
+    fn docker_valid() {
+        let input = "\u{feff}# Comment\n  A=\"quoted\" \nB=a=$B\nC\nD=\n";
+        assert_eq!(
+            variables(docker(input)),
+            [
+                var("A", Some("\"quoted\" ")),
+                var("B", Some("a=$B")),
+                var("D", Some("")),
+            ]
+        );
+        assert_eq!(docker("A=1\nB C=1").unwrap_err().line, 2);
+        assert_eq!(docker("=1").unwrap_err().line, 1);
+    }
+
+    /// systemd quoting and continuation rules
+    #[test]
+    fn systemd_valid() {
+        let input = r#"
+# Comment
+; Another comment
+A = unquoted  value  
+B="double \"quoted\" \n"
+C='single \' "quoted"  'segments'
+D=continued \
+line
+E=not # a comment
+ignored line
+F=
+"#;
+        assert_eq!(
+            variables(systemd(input)),
+            [
+                var("A", Some("unquoted  value")),
+                var("B", Some("double \"quoted\" \\n")),
+                var("C", Some("single \\quotedsegments")),
+                var("D", Some("continued line")),
+                var("E", Some("not # a comment")),
+                var("F", Some("")),
+            ]
+        );
+        assert_eq!(systemd("A=1\nB=\"unterminated").unwrap_err().line, 2);
+    }
+
+    /// dotenv quoting, comments, and multiline values
+    #[test]
+    fn dotenv_valid() {
+        let input = r#"
+# Comment
+A = unquoted value # comment
+export B="double \"quoted\"\n" # comment
+C='single \n ${C}'
+D="multi
+line"
+E=no#comment
+F=
+exported=1
+"#;
+        assert_eq!(
+            variables(dotenv(input)),
+            [
+                var("A", Some("unquoted value")),
+                var("B", Some("double \"quoted\"\n")),
+                var("C", Some("single \\n ${C}")),
+                var("D", Some("multi\nline")),
+                var("E", Some("no#comment")),
+                var("F", Some("")),
+                var("exported", Some("1")),
+            ]
+        );
+        assert_eq!(dotenv("A=1\nB").unwrap_err().line, 2);
+        assert_eq!(dotenv("A=1\nB=\"unterminated\n").unwrap_err().line, 2);
+        assert_eq!(dotenv("A='a' b").unwrap_err().line, 1);
+        assert_eq!(dotenv("A B=1").unwrap_err().line, 1);
+    }
+
+    /// JSON scalars are converted to strings, and null removes
+    #[test]
+    fn json_valid() {
+        let input = r#"
+{
+    "A": "escaped \"\u00e9\ud83d\ude00\n",
+    "B": -1.5e3,
+    "C": true,
+    "D": null
+}
+"#;
+        assert_eq!(
+            variables(json(input)),
+            [
+                var("A", Some("escaped \"\u{e9}\u{1f600}\n")),
+                var("B", Some("-1.5e3")),
+                var("C", Some("true")),
+                var("D", None),
+            ]
+        );
+        assert_eq!(variables(json(" {} ")), []);
+        assert_eq!(json("{\n\"A\": {}}").unwrap_err().line, 2);
+        assert_eq!(json("{\"A\": 1,\n}").unwrap_err().line, 2);
+        assert_eq!(json("{\"A\": nope}").unwrap_err().line, 1);
+        assert_eq!(json("{} {}").unwrap_err().line, 1);
+        assert_eq!(json("[]").unwrap_err().line, 1);
+    }
+
+    /// TOML strings and scalars
+    #[test]
+    fn toml_valid() {
+        let input = r#"
+# Comment
+A = "basic \"\u00e9\"" # comment
+"B" = 'literal \n'
+C = """
+multi \
+    line"""
+D = '''
+raw \n'''
+E = 1_000
+F = false
+G = 1979-05-27T07:32:00Z
+"#;
+        assert_eq!(
+            variables(toml(input)),
+            [
+                var("A", Some("basic \"\u{e9}\"")),
+                var("B", Some("literal \\n")),
+                var("C", Some("multi line")),
+                var("D", Some("raw \\n")),
+                var("E", Some("1000")),
+                var("F", Some("false")),
+                var("G", Some("1979-05-27T07:32:00Z")),
+            ]
+        );
+        assert_eq!(toml("A = 1\n[table]").unwrap_err().line, 2);
+        assert_eq!(toml("A.B = 1").unwrap_err().line, 1);
+        assert_eq!(toml("A = [1]").unwrap_err().line, 1);
+        assert_eq!(toml("A = 1 B = 2").unwrap_err().line, 1);
+        assert_eq!(toml("A = 1\nB = \"unterminated\n").unwrap_err().line, 2);
+    }
+}
@@ -0,0 +1,14 @@
+//! Common imports for using env-lock. This contains the core locking API and
+//! all [extension traits](crate::ext).
+//!
+//! ```
+//! use env_lock::prelude::*;
+//! ```
+
+pub use crate::{
+    ext::{CommandEnvExt, Fixture, FixtureExt, LockExt},
+    lock_env, with_env, EnvGuard, EnvLock, EnvOverlay, OwnedEnvGuard,
+    RestorePolicy, TempVar,
+};
+#[cfg(feature = "async")]
+pub use crate::{lock_env_async, with_env_async};
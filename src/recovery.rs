@@ -0,0 +1,107 @@
+use crate::{baseline, lock::ENV_LOCK, set_or_remove, RestorePolicy};
+use std::{
+    ffi::{OsStr, OsString},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, MutexGuard,
+    },
+};
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+/// Every live guard, in the order they were created
+static GUARDS: Mutex<Vec<GuardRecord>> = Mutex::new(Vec::new());
+
+/// What's needed to restore a live guard's variables without the guard
+struct GuardRecord {
+    id: u64,
+    /// Each managed variable, its value from before the lock, and its policy
+    variables: Vec<(String, Option<OsString>, RestorePolicy)>,
+}
+
+/// Restore every variable modified by a live guard, and release exclusive
+/// access to the environment, regardless of who holds it. This recovers from
+/// guards that were leaked (e.g. with [std::mem::forget]) or held by a thread
+/// that was aborted, which would otherwise leave the environment modified and
+/// block every later test waiting for the lock. Call it from a test
+/// harness's teardown hook, once no test is running. Return the number of
+/// guards that were restored.
+///
+/// Guards are restored from newest to oldest, so each variable ends up with
+/// its value from before the first guard. Once restored, a guard no longer
+/// restores anything when it's dropped. Guards from
+/// [lock_env_vars](crate::lock_env_vars) are restored, but their locks on
+/// individual variables are not released. Variables created inside a
+/// [lock_env_sandbox](crate::lock_env_sandbox) are not removed.
+///
+/// This is a last resort: any test still using a guard will see its
+/// variables change underneath it, and lose exclusive access.
+///
+/// ```
+/// use std::{env, mem};
+///
+/// let var = "ENV_LOCK_RECOVERY_VARIABLE";
+/// mem::forget(env_lock::lock_env([(var, Some("leaked"))]));
+/// assert_eq!(env_lock::force_restore_all(), 1);
+/// assert!(env::var(var).is_err());
+/// // The lock is available again
+/// let _guard = env_lock::lock_env([(var, Some("1"))]);
+/// ```
+pub fn force_restore_all() -> usize {
+    let records = std::mem::take(&mut *lock_guards());
+    for record in records.iter().rev() {
+        for (name, previous, policy) in &record.variables {
+            match policy {
+                RestorePolicy::Restore => {
+                    set_or_remove(name, previous.as_deref())
+                }
+                RestorePolicy::Keep => {}
+                RestorePolicy::RemoveOnDrop => {
+                    set_or_remove(name, None::<&str>)
+                }
+            }
+        }
+    }
+    baseline::reset();
+    ENV_LOCK.force_release();
+    records.len()
+}
+
+/// Start tracking a new guard
+pub(crate) fn register() -> u64 {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    lock_guards().push(GuardRecord {
+        id,
+        variables: Vec::new(),
+    });
+    id
+}
+
+/// Replace the list of variables managed by a guard
+pub(crate) fn update<'a>(
+    id: u64,
+    variables: impl Iterator<Item = (&'a str, Option<&'a OsStr>, RestorePolicy)>,
+) {
+    if let Some(record) =
+        lock_guards().iter_mut().find(|record| record.id == id)
+    {
+        record.variables = variables
+            .map(|(name, previous, policy)| {
+                (name.to_owned(), previous.map(OsStr::to_owned), policy)
+            })
+            .collect();
+    }
+}
+
+/// Stop tracking a guard. Return `false` if it was already restored by
+/// [force_restore_all], in which case the guard shouldn't restore anything.
+pub(crate) fn unregister(id: u64) -> bool {
+    let mut guards = lock_guards();
+    let len = guards.len();
+    guards.retain(|record| record.id != id);
+    guards.len() < len
+}
+
+fn lock_guards() -> MutexGuard<'static, Vec<GuardRecord>> {
+    // Records are only replaced whole, so they can't be left inconsistent
+    GUARDS.lock().unwrap_or_else(|error| error.into_inner())
+}
@@ -0,0 +1,98 @@
+use crate::lock::{VariablesToken, RESOURCE_LOCKS};
+use std::{collections::BTreeSet, fmt, panic::Location};
+
+/// Lock a named resource, blocking until no other thread holds it. Use this
+/// to serialize tests on any shared global other than the environment, such
+/// as a fixed TCP port, a shared temp file, or a singleton. The resource is
+/// released when the guard is dropped, even if the holder panics.
+///
+/// Resource locks are independent of the environment lock and of each other:
+/// holding one doesn't block [lock_env](crate::lock_env) or a resource with a
+/// different name. Unlike the environment lock, they're always taken,
+/// regardless of the [LockMode](crate::LockMode).
+///
+/// ```
+/// let guard = env_lock::lock_resource("port:8080");
+/// // Any other test that locks this resource waits until the guard is dropped
+/// assert_eq!(guard.names().collect::<Vec<_>>(), ["port:8080"]);
+/// ```
+///
+/// ## Panics
+/// Panics if the current thread already holds the resource, because it would
+/// wait forever.
+#[track_caller]
+pub fn lock_resource(name: impl Into<String>) -> ResourceGuard {
+    lock_resources([name])
+}
+
+/// Lock several named resources at once, blocking until none of them are
+/// held. Acquiring them together means two tests locking the same resources
+/// in a different order can't deadlock. See [lock_resource].
+///
+/// ## Panics
+/// Panics if the current thread already holds any of the resources, because
+/// it would wait forever.
+#[track_caller]
+pub fn lock_resources(
+    names: impl IntoIterator<Item = impl Into<String>>,
+) -> ResourceGuard {
+    let names: BTreeSet<String> = names.into_iter().map(Into::into).collect();
+    let token = RESOURCE_LOCKS.acquire(names, Location::caller());
+    ResourceGuard { token }
+}
+
+/// A guard on one or more named resources, from [lock_resource]. The
+/// resources are released when this is dropped.
+pub struct ResourceGuard {
+    token: VariablesToken,
+}
+
+impl ResourceGuard {
+    /// Get the names of the locked resources, in sorted order
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.token.names().map(String::as_str)
+    }
+}
+
+impl fmt::Debug for ResourceGuard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ResourceGuard")
+            .field("names", &self.names().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::mpsc, thread, time::Duration};
+
+    /// A resource blocks other threads until it's released, but resources
+    /// with other names don't
+    #[test]
+    fn lock_resource() {
+        let name = "ENV_LOCK_TEST_RESOURCE";
+        let guard = super::lock_resource(name);
+        assert_eq!(guard.names().collect::<Vec<_>>(), [name]);
+
+        let _other = super::lock_resource("ENV_LOCK_TEST_RESOURCE_OTHER");
+        let (sender, receiver) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            let guard = lock_resources([name, "ENV_LOCK_TEST_RESOURCE_2"]);
+            sender.send(()).unwrap();
+            drop(guard);
+        });
+        assert!(receiver.recv_timeout(Duration::from_millis(50)).is_err());
+        drop(guard);
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        handle.join().unwrap();
+    }
+
+    /// Locking a resource twice on one thread panics instead of hanging
+    #[test]
+    #[should_panic(expected = "Deadlock")]
+    fn deadlock() {
+        let _guard = super::lock_resource("ENV_LOCK_TEST_RESOURCE_DEADLOCK");
+        let _guard = super::lock_resource("ENV_LOCK_TEST_RESOURCE_DEADLOCK");
+    }
+}
@@ -0,0 +1,123 @@
+//! Lock the environment from [rstest](https://docs.rs/rstest) fixtures.
+//! [EnvFixture] owns its variable names, so it can be returned from a fixture
+//! function regardless of where the names came from, including fixture
+//! arguments that are overridden per test with `#[with(...)]`:
+//!
+//! ```ignore
+//! use env_lock::rstest::EnvFixture;
+//! use rstest::{fixture, rstest};
+//!
+//! #[fixture]
+//! fn locked_env(
+//!     #[default(&[])] variables: &[(&str, Option<&str>)],
+//! ) -> EnvFixture {
+//!     EnvFixture::new(variables)
+//! }
+//!
+//! #[rstest]
+//! fn connect(#[with(&[("DATABASE_PORT", Some("5432"))])] locked_env: EnvFixture) {
+//!     let port: u16 = locked_env.parse("DATABASE_PORT");
+//!     assert_eq!(port, 5432);
+//! }
+//! ```
+//!
+//! Fixtures are plain functions, so the same works without rstest:
+//!
+//! ```
+//! use env_lock::rstest::EnvFixture;
+//!
+//! fn locked_env(variables: &[(&str, Option<&str>)]) -> EnvFixture {
+//!     EnvFixture::new(variables)
+//! }
+//!
+//! let env = locked_env(&[("ENV_LOCK_RSTEST_PORT", Some("5432"))]);
+//! assert_eq!(env.parse::<u16>("ENV_LOCK_RSTEST_PORT"), 5432);
+//! ```
+//!
+//! Requires the `rstest` feature.
+
+use crate::{lock_env, sys, EnvGuard, OwnedEnvGuard};
+use std::{fmt::Debug, str::FromStr};
+
+/// A guard for use as an rstest fixture. It holds the environment lock until
+/// it's dropped at the end of the test. See the [module docs](self).
+pub struct EnvFixture {
+    guard: OwnedEnvGuard,
+}
+
+impl EnvFixture {
+    /// Lock the environment and apply each variable. The names are copied, so
+    /// the fixture doesn't borrow from its arguments. See [lock_env].
+    #[track_caller]
+    pub fn new(variables: &[(&str, Option<&str>)]) -> Self {
+        let variables = variables
+            .iter()
+            .map(|(variable, value)| ((*variable).to_owned(), *value));
+        Self {
+            guard: lock_env(variables),
+        }
+    }
+
+    /// Get the current value of a variable, or `None` if it's unset or not
+    /// valid unicode
+    pub fn var(&self, variable: &str) -> Option<String> {
+        sys::var(variable).ok()
+    }
+
+    /// Parse the current value of a variable
+    ///
+    /// ## Panics
+    /// Panics if the variable is unset or can't be parsed as `T`
+    #[track_caller]
+    pub fn parse<T>(&self, variable: &str) -> T
+    where
+        T: FromStr,
+        T::Err: Debug,
+    {
+        let value = self
+            .var(variable)
+            .unwrap_or_else(|| panic!("Variable `{variable}` is not set"));
+        value.parse().unwrap_or_else(|error| {
+            panic!("Error parsing variable `{variable}`: {error:?}")
+        })
+    }
+
+    /// Get the underlying guard, to modify the environment further within the
+    /// test
+    pub fn guard(&mut self) -> &mut OwnedEnvGuard {
+        &mut self.guard
+    }
+}
+
+/// Convert any guard into a fixture, e.g. one built with
+/// [EnvLock](crate::EnvLock) or a [Preset](crate::presets::Preset)
+impl<'a> From<EnvGuard<'a>> for EnvFixture {
+    fn from(guard: EnvGuard<'a>) -> Self {
+        Self {
+            guard: guard.into_owned(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::EnvLock;
+    use std::env;
+
+    /// Fixture names don't need to outlive the fixture
+    #[test]
+    fn fixture() {
+        let var = String::from("ENV_LOCK_TEST_VARIABLE_RSTEST");
+        let mut fixture = EnvFixture::new(&[(var.as_str(), Some("3"))]);
+        assert_eq!(fixture.parse::<u32>(&var), 3);
+        fixture.guard().swap(&var, Some("4"));
+        assert_eq!(fixture.var(&var).as_deref(), Some("4"));
+        drop(fixture);
+        assert!(env::var(&var).is_err());
+
+        let name = format!("{var}_BUILDER");
+        let fixture: EnvFixture = EnvLock::new().set(&*name, "1").lock().into();
+        assert_eq!(fixture.var(&name).as_deref(), Some("1"));
+    }
+}
@@ -0,0 +1,213 @@
+//! Declare the variables a test expects, and check them all at once. An
+//! [EnvSchema] lists required and optional variables along with
+//! [Validator]s for their values, and reports every violation together
+//! instead of failing on the first one.
+//!
+//! ```
+//! use env_lock::{schema::EnvSchema, source::Validator};
+//!
+//! let schema = EnvSchema::new()
+//!     .required("ENV_LOCK_SCHEMA_HOST", [])
+//!     .optional("ENV_LOCK_SCHEMA_PORT", [Validator::parse::<u16>()]);
+//!
+//! let guard = env_lock::lock_env([
+//!     ("ENV_LOCK_SCHEMA_HOST", Some("localhost")),
+//!     ("ENV_LOCK_SCHEMA_PORT", Some("8080")),
+//! ]);
+//! schema.assert_satisfied_locked(&guard);
+//! ```
+
+use crate::{inventory, source::Validator, sys, EnvGuard};
+use std::{
+    error::Error,
+    fmt::{self, Display},
+};
+
+/// A declaration of expected variables. See the [module docs](self).
+#[derive(Debug, Default)]
+pub struct EnvSchema {
+    entries: Vec<Entry>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    variable: String,
+    required: bool,
+    validators: Vec<Validator>,
+}
+
+impl EnvSchema {
+    /// Create an empty schema
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Declare a variable that must be set, and whose value must pass every
+    /// validator
+    pub fn required(
+        self,
+        variable: impl Into<String>,
+        validators: impl IntoIterator<Item = Validator>,
+    ) -> Self {
+        self.entry(variable.into(), true, validators)
+    }
+
+    /// Declare a variable that may be unset, but if it is set, its value must
+    /// pass every validator
+    pub fn optional(
+        self,
+        variable: impl Into<String>,
+        validators: impl IntoIterator<Item = Validator>,
+    ) -> Self {
+        self.entry(variable.into(), false, validators)
+    }
+
+    /// Lock the environment and check it against the schema
+    #[track_caller]
+    pub fn check(&self) -> Result<(), SchemaReport> {
+        let guard = EnvGuard::acquire();
+        self.check_locked(&guard)
+    }
+
+    /// Check the environment against the schema, using a guard that's already
+    /// held
+    pub fn check_locked(&self, _guard: &EnvGuard) -> Result<(), SchemaReport> {
+        let mut violations = Vec::new();
+        for entry in &self.entries {
+            inventory::record_read(&entry.variable);
+            let mut violation = |message| {
+                violations.push(Violation {
+                    variable: entry.variable.clone(),
+                    message,
+                })
+            };
+            match sys::var(&entry.variable) {
+                Ok(value) => {
+                    for validator in &entry.validators {
+                        if let Err(message) = validator.check(&value) {
+                            violation(message);
+                        }
+                    }
+                }
+                Err(_) if entry.required => {
+                    violation("Required but not set".into())
+                }
+                Err(_) => {}
+            }
+        }
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(SchemaReport { violations })
+        }
+    }
+
+    /// Lock the environment and check it against the schema
+    ///
+    /// ## Panics
+    /// Panics with a report of every violation if the schema isn't satisfied
+    #[track_caller]
+    pub fn assert_satisfied(&self) {
+        if let Err(report) = self.check() {
+            panic!("{report}");
+        }
+    }
+
+    /// Check the environment against the schema, using a guard that's already
+    /// held
+    ///
+    /// ## Panics
+    /// Panics with a report of every violation if the schema isn't satisfied
+    #[track_caller]
+    pub fn assert_satisfied_locked(&self, guard: &EnvGuard) {
+        if let Err(report) = self.check_locked(guard) {
+            panic!("{report}");
+        }
+    }
+
+    fn entry(
+        mut self,
+        variable: String,
+        required: bool,
+        validators: impl IntoIterator<Item = Validator>,
+    ) -> Self {
+        self.entries.push(Entry {
+            variable,
+            required,
+            validators: validators.into_iter().collect(),
+        });
+        self
+    }
+}
+
+/// Every way in which the environment violates an [EnvSchema]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SchemaReport {
+    /// Violations, in the order the variables were declared
+    pub violations: Vec<Violation>,
+}
+
+/// A single problem with a variable
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Violation {
+    /// Variable with the problem
+    pub variable: String,
+    /// Description of the problem
+    pub message: String,
+}
+
+impl Display for SchemaReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Environment does not satisfy schema ({} violations):",
+            self.violations.len()
+        )?;
+        for violation in &self.violations {
+            writeln!(f, "  {}: {}", violation.variable, violation.message)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for SchemaReport {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lock_env;
+
+    /// All violations are reported together
+    #[test]
+    fn report_violations() {
+        let var1 = "ENV_LOCK_TEST_VARIABLE_SCHEMA_1";
+        let var2 = "ENV_LOCK_TEST_VARIABLE_SCHEMA_2";
+        let var3 = "ENV_LOCK_TEST_VARIABLE_SCHEMA_3";
+        let schema = EnvSchema::new()
+            .required(var1, [])
+            .optional(var2, [])
+            .optional(
+                var3,
+                [
+                    Validator::parse::<u16>(),
+                    Validator::new("short", |value| value.len() < 2),
+                ],
+            );
+
+        let guard = lock_env([(var1, None), (var2, None), (var3, Some("80"))]);
+        let report = schema.check_locked(&guard).unwrap_err();
+        assert_eq!(
+            report.violations,
+            [
+                Violation {
+                    variable: var1.into(),
+                    message: "Required but not set".into()
+                },
+                Violation {
+                    variable: var3.into(),
+                    message: "Value `80` must be short".into()
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,203 @@
+//! A whole environment state that can be captured, generated, and applied.
+//!
+//! With the `proptest` feature, [EnvSnapshot] implements
+//! [Arbitrary](proptest::arbitrary::Arbitrary), so property tests can explore
+//! environment states. Shrinking leaves variables untouched and simplifies
+//! values, to find the minimal environment that causes a failure. Lock each
+//! generated snapshot within the test case, so it's applied and reverted under
+//! the lock:
+//!
+//! ```
+//! # #[cfg(feature = "proptest")]
+//! # {
+//! use env_lock::EnvSnapshot;
+//! use proptest::prelude::*;
+//!
+//! proptest! {
+//!     #[test]
+//!     fn config_loads(
+//!         snapshot in any_with::<EnvSnapshot>(
+//!             vec!["APP_PORT".into(), "APP_HOST".into()],
+//!         ),
+//!     ) {
+//!         let _guard = snapshot.lock();
+//!         // Load config...
+//!     }
+//! }
+//! # }
+//! ```
+
+use crate::{inventory, sys, EnvGuard};
+use std::{borrow::Cow, collections::BTreeMap};
+
+/// A set of variables and their values, where `None` means the variable is
+/// unset. Variables not in the snapshot are left alone when it's applied.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EnvSnapshot {
+    variables: BTreeMap<String, Option<String>>,
+}
+
+impl EnvSnapshot {
+    /// Create an empty snapshot
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Capture the current values of the given variables. This doesn't lock
+    /// the environment, so it should be called while holding a guard if other
+    /// tests may be modifying these variables.
+    pub fn capture<'a>(variables: impl IntoIterator<Item = &'a str>) -> Self {
+        variables
+            .into_iter()
+            .map(|variable| {
+                inventory::record_read(variable);
+                (variable, sys::var(variable).ok())
+            })
+            .collect()
+    }
+
+    /// Capture every variable in the current environment. Variables whose
+    /// name or value isn't valid UTF-8 are skipped.
+    pub fn capture_all() -> Self {
+        sys::vars_os()
+            .filter_map(|(name, value)| {
+                Some((
+                    name.into_string().ok()?,
+                    Some(value.into_string().ok()?),
+                ))
+            })
+            .collect()
+    }
+
+    /// Get a variable's value. The outer `Option` is `None` if the variable
+    /// isn't in the snapshot; the inner is `None` if the variable is unset.
+    /// On Windows, the name is case-insensitive.
+    pub fn get(&self, variable: &str) -> Option<Option<&str>> {
+        self.variables
+            .get(variable)
+            .or_else(|| {
+                self.variables
+                    .iter()
+                    .find(|(name, _)| sys::same_name(name, variable))
+                    .map(|(_, value)| value)
+            })
+            .map(Option::as_deref)
+    }
+
+    /// Iterate over the variables in the snapshot, sorted by name
+    pub fn iter(&self) -> impl Iterator<Item = (&str, Option<&str>)> {
+        self.variables
+            .iter()
+            .map(|(variable, value)| (variable.as_str(), value.as_deref()))
+    }
+
+    /// Lock the environment and apply this snapshot. See
+    /// [lock_env](crate::lock_env).
+    #[track_caller]
+    pub fn lock(&self) -> EnvGuard<'static> {
+        EnvGuard::new(self.variables.iter().map(|(variable, value)| {
+            (Cow::Owned(variable.clone()), value.as_deref())
+        }))
+    }
+}
+
+impl<K: Into<String>, V: Into<String>> FromIterator<(K, Option<V>)>
+    for EnvSnapshot
+{
+    fn from_iter<T: IntoIterator<Item = (K, Option<V>)>>(iter: T) -> Self {
+        Self {
+            variables: iter
+                .into_iter()
+                .map(|(variable, value)| {
+                    (variable.into(), value.map(Into::into))
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(feature = "proptest")]
+mod arbitrary {
+    use super::*;
+    use proptest::{
+        arbitrary::Arbitrary,
+        collection, option,
+        strategy::{BoxedStrategy, Strategy},
+    };
+
+    /// Generate snapshots. The parameters are the variable names to choose
+    /// from; each one is either left out, unset, or set to a random value. If
+    /// no names are given, names are random too, which can clobber variables
+    /// such as `PATH` for the duration of the test case.
+    impl Arbitrary for EnvSnapshot {
+        type Parameters = Vec<String>;
+        type Strategy = BoxedStrategy<Self>;
+
+        fn arbitrary_with(variables: Self::Parameters) -> Self::Strategy {
+            // Values can't contain NUL, so stick to printable characters
+            let value = || option::of("\\PC{0,16}");
+            if variables.is_empty() {
+                collection::btree_map("[A-Z][A-Z0-9_]{0,7}", value(), 0..8)
+                    .prop_map(|variables| EnvSnapshot { variables })
+                    .boxed()
+            } else {
+                variables
+                    .into_iter()
+                    .map(|variable| {
+                        option::of(value())
+                            .prop_map(move |value| (variable.clone(), value))
+                    })
+                    .collect::<Vec<_>>()
+                    .prop_map(|variables| {
+                        variables
+                            .into_iter()
+                            .filter_map(|(variable, value)| {
+                                Some((variable, value?))
+                            })
+                            .collect()
+                    })
+                    .boxed()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    /// Capture a snapshot, then apply it to restore a previous state
+    #[test]
+    fn capture_and_lock() {
+        let var1 = "ENV_LOCK_TEST_VARIABLE_SNAPSHOT_1";
+        let var2 = "ENV_LOCK_TEST_VARIABLE_SNAPSHOT_2";
+        let guard = crate::lock_env([(var1, Some("1")), (var2, None)]);
+        let snapshot = EnvSnapshot::capture([var1, var2]);
+        drop(guard);
+
+        assert_eq!(snapshot.get(var1), Some(Some("1")));
+        assert_eq!(snapshot.get(var2), Some(None));
+        let _guard = snapshot.lock();
+        assert_eq!(env::var(var1).unwrap(), "1");
+        assert!(env::var(var2).is_err());
+    }
+
+    #[cfg(feature = "proptest")]
+    proptest::proptest! {
+        /// Generated snapshots only use the given names, and apply cleanly
+        #[test]
+        fn arbitrary_snapshot(
+            snapshot in proptest::arbitrary::any_with::<EnvSnapshot>(vec![
+                "ENV_LOCK_TEST_VARIABLE_PROPTEST_1".into(),
+                "ENV_LOCK_TEST_VARIABLE_PROPTEST_2".into(),
+            ]),
+        ) {
+            let _guard = snapshot.lock();
+            for (variable, value) in snapshot.iter() {
+                assert!(variable.starts_with("ENV_LOCK_TEST_VARIABLE_PROPTEST"));
+                assert_eq!(env::var(variable).ok().as_deref(), value);
+            }
+        }
+    }
+}
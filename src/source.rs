@@ -0,0 +1,660 @@
+//! Produce variable values at lock time. A [ValueSource] is evaluated after
+//! the environment is locked, so values that depend on shared state (files,
+//! secret stores, etc.) can't change between being read and being applied.
+//! Implement [ValueSource] to plug in your own sources, such as a vault
+//! client or keychain. Any closure returning a [SourceResult] is also a
+//! source.
+//!
+//! ```
+//! use env_lock::source::{self, Literal};
+//! use std::env;
+//!
+//! let user = || Ok(Some("ferris".to_owned()));
+//! let _guard = source::lock_env(&[
+//!     ("ENV_LOCK_SOURCE_MODE", &Literal::set("test")),
+//!     ("ENV_LOCK_SOURCE_USER", &user),
+//! ])
+//! .unwrap();
+//! assert_eq!(env::var("ENV_LOCK_SOURCE_MODE").unwrap(), "test");
+//! assert_eq!(env::var("ENV_LOCK_SOURCE_USER").unwrap(), "ferris");
+//! ```
+
+use crate::EnvGuard;
+use std::{
+    any,
+    borrow::Cow,
+    collections::hash_map::RandomState,
+    error::Error,
+    ffi::OsString,
+    fmt::{self, Debug, Display},
+    fs,
+    hash::{BuildHasher, Hasher},
+    net::{Ipv4Addr, TcpListener},
+    path::PathBuf,
+    process::Command,
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Result of evaluating a [ValueSource]. `None` removes the variable.
+pub type SourceResult = Result<Option<String>, Box<dyn Error + Send + Sync>>;
+
+/// Something that produces a variable's value at lock time
+pub trait ValueSource {
+    /// Produce a value. This is called while the environment is locked, so it
+    /// must not try to acquire the lock itself.
+    fn value(&self) -> SourceResult;
+
+    /// Check this source's value with a [Validator] before it's applied. If
+    /// the check fails, locking fails with an error naming the variable.
+    /// Multiple validators can be chained.
+    ///
+    /// ```
+    /// use env_lock::source::{self, Literal, ValueSource, Validator};
+    ///
+    /// let port = Literal::set("http").validate(Validator::parse::<u16>());
+    /// let error = source::lock_env(&[("PORT", &port)]).err().unwrap();
+    /// assert_eq!(
+    ///     error.to_string(),
+    ///     "Error producing value for `PORT`: Value `http` must be a valid u16",
+    /// );
+    /// ```
+    fn validate(self, validator: Validator) -> Validated<Self>
+    where
+        Self: Sized,
+    {
+        Validated {
+            source: self,
+            validators: vec![validator],
+        }
+    }
+}
+
+impl<F: Fn() -> SourceResult> ValueSource for F {
+    fn value(&self) -> SourceResult {
+        self()
+    }
+}
+
+/// A fixed value
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Literal(pub Option<String>);
+
+impl Literal {
+    /// Set the variable to a value
+    pub fn set(value: impl Into<String>) -> Self {
+        Self(Some(value.into()))
+    }
+
+    /// Remove the variable
+    pub fn unset() -> Self {
+        Self(None)
+    }
+}
+
+impl ValueSource for Literal {
+    fn value(&self) -> SourceResult {
+        Ok(self.0.clone())
+    }
+}
+
+/// The contents of a file, with a single trailing newline removed. This is
+/// how secrets are typically mounted, e.g. with Docker or Kubernetes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct File(pub PathBuf);
+
+impl ValueSource for File {
+    fn value(&self) -> SourceResult {
+        let mut contents = fs::read_to_string(&self.0)?;
+        if contents.ends_with('\n') {
+            contents.pop();
+            if contents.ends_with('\r') {
+                contents.pop();
+            }
+        }
+        Ok(Some(contents))
+    }
+}
+
+/// The trimmed stdout of a command, e.g. `git rev-parse HEAD` or a token
+/// helper. The command runs once each time the environment is locked, and
+/// inherits the environment as it is before any variables are applied. A
+/// non-zero exit status is an error.
+///
+/// ```no_run
+/// use env_lock::source::{self, CommandOutput};
+///
+/// let commit = CommandOutput::new("git").args(["rev-parse", "HEAD"]);
+/// let _guard = source::lock_env(&[("GIT_COMMIT", &commit)]);
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CommandOutput {
+    program: OsString,
+    args: Vec<OsString>,
+    current_dir: Option<PathBuf>,
+}
+
+impl CommandOutput {
+    /// Run a program, which is looked up on the `PATH`
+    pub fn new(program: impl Into<OsString>) -> Self {
+        Self {
+            program: program.into(),
+            args: Vec::new(),
+            current_dir: None,
+        }
+    }
+
+    /// Add an argument
+    pub fn arg(mut self, arg: impl Into<OsString>) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    /// Add multiple arguments
+    pub fn args(
+        mut self,
+        args: impl IntoIterator<Item = impl Into<OsString>>,
+    ) -> Self {
+        self.args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    /// Run the command in a different directory
+    pub fn current_dir(mut self, directory: impl Into<PathBuf>) -> Self {
+        self.current_dir = Some(directory.into());
+        self
+    }
+}
+
+impl ValueSource for CommandOutput {
+    fn value(&self) -> SourceResult {
+        let mut command = Command::new(&self.program);
+        command.args(&self.args);
+        if let Some(directory) = &self.current_dir {
+            command.current_dir(directory);
+        }
+        let output = command.output()?;
+        if !output.status.success() {
+            return Err(format!(
+                "`{}` failed with {}: {}",
+                self.program.to_string_lossy(),
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+            .into());
+        }
+        Ok(Some(String::from_utf8(output.stdout)?.trim().to_owned()))
+    }
+}
+
+/// A check on a value, applied to a [ValueSource] with
+/// [validate](ValueSource::validate). Validators only check values that are
+/// set; a source that removes its variable always passes.
+pub struct Validator {
+    /// What a valid value is, e.g. "a valid u16"
+    description: String,
+    check: Box<dyn Fn(&str) -> bool + Send + Sync>,
+}
+
+impl Validator {
+    /// Create a validator from a custom check. The description says what a
+    /// valid value looks like, and is included in the error.
+    pub fn new(
+        description: impl Into<String>,
+        check: impl Fn(&str) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            description: description.into(),
+            check: Box::new(check),
+        }
+    }
+
+    /// Require the value to be parseable as `T`
+    pub fn parse<T: FromStr>() -> Self {
+        // Strip the module path from the type name
+        let type_name = any::type_name::<T>();
+        let type_name = type_name.rsplit("::").next().unwrap_or(type_name);
+        Self::new(format!("a valid {type_name}"), |value| {
+            value.parse::<T>().is_ok()
+        })
+    }
+
+    /// Require the value to match a regular expression. Requires the `regex`
+    /// feature.
+    #[cfg(feature = "regex")]
+    pub fn regex(pattern: &str) -> Result<Self, regex::Error> {
+        let regex = regex::Regex::new(pattern)?;
+        Ok(Self::new(format!("match `{pattern}`"), move |value| {
+            regex.is_match(value)
+        }))
+    }
+}
+
+impl Validator {
+    /// Check a value, returning an error message if it's invalid
+    pub(crate) fn check(&self, value: &str) -> Result<(), String> {
+        if (self.check)(value) {
+            Ok(())
+        } else {
+            Err(format!("Value `{value}` must be {}", self.description))
+        }
+    }
+}
+
+impl Debug for Validator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Validator")
+            .field("description", &self.description)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A [ValueSource] whose value is checked by one or more [Validator]s. Created
+/// by [ValueSource::validate].
+#[derive(Debug)]
+pub struct Validated<S> {
+    source: S,
+    validators: Vec<Validator>,
+}
+
+impl<S> Validated<S> {
+    /// Add another validator. All validators must pass.
+    pub fn validate(mut self, validator: Validator) -> Self {
+        self.validators.push(validator);
+        self
+    }
+}
+
+impl<S: ValueSource> ValueSource for Validated<S> {
+    fn value(&self) -> SourceResult {
+        let value = self.source.value()?;
+        if let Some(value) = &value {
+            for validator in &self.validators {
+                validator.check(value)?;
+            }
+        }
+        Ok(value)
+    }
+}
+
+/// A random alphanumeric token of the given length, different on every lock.
+/// This is *not* cryptographically secure.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RandomToken(pub usize);
+
+impl ValueSource for RandomToken {
+    fn value(&self) -> SourceResult {
+        const ALPHABET: &[u8] =
+            b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+        let token = (0..self.0)
+            .map(|_| {
+                let index = random_u64() % ALPHABET.len() as u64;
+                char::from(ALPHABET[index as usize])
+            })
+            .collect();
+        Ok(Some(token))
+    }
+}
+
+/// A random (version 4) UUID in hyphenated form, different on every lock
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Uuid;
+
+impl ValueSource for Uuid {
+    fn value(&self) -> SourceResult {
+        let mut value =
+            (u128::from(random_u64()) << 64) | u128::from(random_u64());
+        value = (value & !(0xf << 76)) | (0x4 << 76); // Version 4
+        value = (value & !(0x3 << 62)) | (0x2 << 62); // RFC 4122 variant
+        let hex = format!("{value:032x}");
+        Ok(Some(format!(
+            "{}-{}-{}-{}-{}",
+            &hex[..8],
+            &hex[8..12],
+            &hex[12..16],
+            &hex[16..20],
+            &hex[20..]
+        )))
+    }
+}
+
+/// A TCP port on localhost that was free at lock time. The port is found by
+/// binding to port 0 and releasing it immediately, so it's possible (though
+/// unlikely) for another process to take it before the test binds it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FreePort;
+
+impl ValueSource for FreePort {
+    fn value(&self) -> SourceResult {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        Ok(Some(listener.local_addr()?.port().to_string()))
+    }
+}
+
+/// A timestamp, either fixed or relative to the time of locking, for
+/// variables such as `SOURCE_DATE_EPOCH` or an application's "now" override.
+/// Defaults to [TimestampFormat::EpochSeconds].
+///
+/// ```
+/// use env_lock::source::{self, Timestamp, TimestampFormat};
+/// use std::{
+///     env,
+///     time::{Duration, UNIX_EPOCH},
+/// };
+///
+/// let time = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+/// let _guard = source::lock_env(&[
+///     ("SOURCE_DATE_EPOCH", &Timestamp::fixed(time)),
+///     (
+///         "APP_NOW",
+///         &Timestamp::fixed(time).format(TimestampFormat::Rfc3339),
+///     ),
+/// ])
+/// .unwrap();
+/// assert_eq!(env::var("SOURCE_DATE_EPOCH").unwrap(), "1700000000");
+/// assert_eq!(env::var("APP_NOW").unwrap(), "2023-11-14T22:13:20Z");
+/// ```
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Timestamp {
+    time: TimestampTime,
+    format: TimestampFormat,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum TimestampTime {
+    Fixed(SystemTime),
+    FromNow(Duration),
+    BeforeNow(Duration),
+}
+
+/// How a [Timestamp] is formatted
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum TimestampFormat {
+    /// Whole seconds since the Unix epoch, e.g. `1700000000`
+    #[default]
+    EpochSeconds,
+    /// Milliseconds since the Unix epoch, e.g. `1700000000000`
+    EpochMillis,
+    /// RFC 3339 in UTC, with whole seconds, e.g. `2023-11-14T22:13:20Z`
+    Rfc3339,
+}
+
+impl Timestamp {
+    /// Always the given time
+    pub fn fixed(time: SystemTime) -> Self {
+        Self::new(TimestampTime::Fixed(time))
+    }
+
+    /// The given duration after the time of locking
+    pub fn from_now(offset: Duration) -> Self {
+        Self::new(TimestampTime::FromNow(offset))
+    }
+
+    /// The given duration before the time of locking
+    pub fn before_now(offset: Duration) -> Self {
+        Self::new(TimestampTime::BeforeNow(offset))
+    }
+
+    /// Set the output format
+    pub fn format(self, format: TimestampFormat) -> Self {
+        Self { format, ..self }
+    }
+
+    fn new(time: TimestampTime) -> Self {
+        Self {
+            time,
+            format: TimestampFormat::default(),
+        }
+    }
+}
+
+impl ValueSource for Timestamp {
+    fn value(&self) -> SourceResult {
+        let time = match self.time {
+            TimestampTime::Fixed(time) => Some(time),
+            TimestampTime::FromNow(offset) => {
+                SystemTime::now().checked_add(offset)
+            }
+            TimestampTime::BeforeNow(offset) => {
+                SystemTime::now().checked_sub(offset)
+            }
+        }
+        .ok_or("Timestamp is out of range")?;
+        // Signed, so times before the epoch work too
+        let millis = match time.duration_since(UNIX_EPOCH) {
+            Ok(duration) => duration.as_millis() as i128,
+            Err(error) => -(error.duration().as_millis() as i128),
+        };
+        let seconds = millis.div_euclid(1000);
+
+        let value = match self.format {
+            TimestampFormat::EpochSeconds => seconds.to_string(),
+            TimestampFormat::EpochMillis => millis.to_string(),
+            TimestampFormat::Rfc3339 => {
+                let (year, month, day) =
+                    civil_from_days(seconds.div_euclid(86400));
+                let time = seconds.rem_euclid(86400);
+                format!(
+                    "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+                    time / 3600,
+                    time % 3600 / 60,
+                    time % 60
+                )
+            }
+        };
+        Ok(Some(value))
+    }
+}
+
+/// Convert days since the Unix epoch to a (year, month, day) date. See
+/// <http://howardhinnant.github.io/date_algorithms.html#civil_from_days>
+fn civil_from_days(days: i128) -> (i128, i128, i128) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524
+        - day_of_era / 146_096)
+        / 365;
+    let day_of_year =
+        day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153; // March is 0
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i128::from(month <= 2);
+    (year, month, day)
+}
+
+/// Get a random number, without a dependency on a random number generator
+fn random_u64() -> u64 {
+    // RandomState is seeded randomly per instance
+    RandomState::new().build_hasher().finish()
+}
+
+/// An error produced by a [ValueSource]
+#[derive(Debug)]
+pub struct SourceError {
+    /// Variable whose value couldn't be produced
+    pub variable: String,
+    /// Error from the source
+    pub error: Box<dyn Error + Send + Sync>,
+}
+
+impl Display for SourceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Error producing value for `{}`: {}",
+            self.variable, self.error
+        )
+    }
+}
+
+impl Error for SourceError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&*self.error)
+    }
+}
+
+/// Lock the environment, then evaluate each source and apply its value. If
+/// any source fails, the environment is left unmodified and an error is
+/// returned. Otherwise, this behaves like [crate::lock_env].
+#[track_caller]
+pub fn lock_env<'a>(
+    variables: &[(&'a str, &dyn ValueSource)],
+) -> Result<EnvGuard<'a>, SourceError> {
+    let mut guard = EnvGuard::acquire();
+    let values = variables
+        .iter()
+        .map(|(variable, source)| {
+            let value = source.value().map_err(|error| SourceError {
+                variable: (*variable).to_owned(),
+                error,
+            })?;
+            Ok((Cow::Borrowed(*variable), value))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    guard.apply(values);
+    Ok(guard)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, io};
+
+    /// A failed source leaves the environment untouched
+    #[test]
+    fn source_error() {
+        let var1 = "ENV_LOCK_TEST_VARIABLE_SOURCE_1";
+        let var2 = "ENV_LOCK_TEST_VARIABLE_SOURCE_2";
+        let fail = || -> SourceResult {
+            Err(io::Error::new(io::ErrorKind::Other, "nope").into())
+        };
+
+        let error = lock_env(&[(var1, &Literal::set("hello")), (var2, &fail)])
+            .err()
+            .unwrap();
+        assert_eq!(error.variable, var2);
+        assert!(env::var(var1).is_err());
+    }
+
+    /// Command output is trimmed, and failure is an error
+    #[test]
+    fn command_output() {
+        let var = "ENV_LOCK_TEST_VARIABLE_COMMAND";
+        let cargo = CommandOutput::new(env::var("CARGO").unwrap());
+
+        let guard =
+            lock_env(&[(var, &cargo.clone().arg("--version"))]).unwrap();
+        let version = env::var(var).unwrap();
+        assert!(version.starts_with("cargo "));
+        assert_eq!(version, version.trim());
+        drop(guard);
+
+        assert!(lock_env(&[(var, &cargo.arg("--not-a-flag"))]).is_err());
+    }
+
+    /// Random values have the right form, and differ between locks
+    #[test]
+    fn random() {
+        let token = "ENV_LOCK_TEST_VARIABLE_TOKEN";
+        let uuid = "ENV_LOCK_TEST_VARIABLE_UUID";
+        let port = "ENV_LOCK_TEST_VARIABLE_PORT";
+        let sources: &[(&str, &dyn ValueSource)] =
+            &[(token, &RandomToken(16)), (uuid, &Uuid), (port, &FreePort)];
+
+        let guard = lock_env(sources).unwrap();
+        let values = [token, uuid, port].map(|var| env::var(var).unwrap());
+        drop(guard);
+
+        let [token_value, uuid_value, port_value] = &values;
+        assert_eq!(token_value.len(), 16);
+        assert!(token_value.chars().all(|c| c.is_ascii_alphanumeric()));
+        assert_eq!(uuid_value.len(), 36);
+        assert_eq!(&uuid_value[14..15], "4");
+        assert_ne!(port_value.parse::<u16>().unwrap(), 0);
+
+        let _guard = lock_env(sources).unwrap();
+        assert_ne!(&env::var(token).unwrap(), token_value);
+        assert_ne!(&env::var(uuid).unwrap(), uuid_value);
+    }
+
+    /// Timestamps are formatted correctly, including before the epoch
+    #[test]
+    fn timestamp() {
+        let format = |time: Timestamp, format| {
+            time.format(format).value().unwrap().unwrap()
+        };
+        let leap_day = UNIX_EPOCH + Duration::from_millis(951_782_400_500);
+        let before_epoch = UNIX_EPOCH - Duration::from_millis(500);
+
+        assert_eq!(
+            format(Timestamp::fixed(leap_day), TimestampFormat::EpochSeconds),
+            "951782400"
+        );
+        assert_eq!(
+            format(Timestamp::fixed(leap_day), TimestampFormat::EpochMillis),
+            "951782400500"
+        );
+        assert_eq!(
+            format(Timestamp::fixed(leap_day), TimestampFormat::Rfc3339),
+            "2000-02-29T00:00:00Z"
+        );
+        assert_eq!(
+            format(
+                Timestamp::fixed(before_epoch),
+                TimestampFormat::EpochSeconds
+            ),
+            "-1"
+        );
+        assert_eq!(
+            format(Timestamp::fixed(before_epoch), TimestampFormat::Rfc3339),
+            "1969-12-31T23:59:59Z"
+        );
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let hour = Duration::from_secs(3600);
+        let from_now: u64 =
+            format(Timestamp::from_now(hour), TimestampFormat::EpochSeconds)
+                .parse()
+                .unwrap();
+        assert!((now + 3600..now + 3610).contains(&from_now));
+    }
+
+    /// Every validator must pass, and only set values are checked
+    #[test]
+    fn validate() {
+        let var = "ENV_LOCK_TEST_VARIABLE_VALIDATE";
+        let even = || Validator::new("even", |value| value.len() % 2 == 0);
+
+        let valid = Literal::set("80").validate(Validator::parse::<u16>());
+        let _ = lock_env(&[(var, &valid.validate(even()))]).unwrap();
+        let _ = lock_env(&[(var, &Literal::unset().validate(even()))]).unwrap();
+
+        let invalid = Literal::set("800").validate(even());
+        let error = lock_env(&[(var, &invalid)]).err().unwrap();
+        assert_eq!(error.variable, var);
+        assert_eq!(error.error.to_string(), "Value `800` must be even");
+    }
+
+    /// Regex validation
+    #[cfg(feature = "regex")]
+    #[test]
+    fn validate_regex() {
+        let var = "ENV_LOCK_TEST_VARIABLE_VALIDATE_REGEX";
+        let validator = || Validator::regex("^[a-z]+$").unwrap();
+        let _ = lock_env(&[(var, &Literal::set("abc").validate(validator()))])
+            .unwrap();
+        assert!(
+            lock_env(&[(var, &Literal::set("ABC").validate(validator()))])
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,85 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+static ACQUISITIONS: AtomicU64 = AtomicU64::new(0);
+/// All durations are in nanoseconds
+static TOTAL_WAIT: AtomicU64 = AtomicU64::new(0);
+static MAX_WAIT: AtomicU64 = AtomicU64::new(0);
+static TOTAL_HOLD: AtomicU64 = AtomicU64::new(0);
+static MAX_HOLD: AtomicU64 = AtomicU64::new(0);
+
+/// Usage statistics for the environment lock, from [stats]. Only guards that
+/// actually acquire the lock are counted, so nothing is recorded while
+/// locking is disabled by the [LockMode](crate::LockMode). Reentrant guards
+/// are counted separately from the guard they reenter.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct LockStats {
+    /// Number of guards created
+    pub acquisitions: u64,
+    /// Total time guards spent waiting for the lock
+    pub total_wait: Duration,
+    /// Longest time any guard spent waiting for the lock
+    pub max_wait: Duration,
+    /// Total time guards held the lock, counting only dropped guards
+    pub total_hold: Duration,
+    /// Longest time any dropped guard held the lock
+    pub max_hold: Duration,
+}
+
+/// Get usage statistics for the environment lock, since the start of the
+/// process or the last call to [reset_stats]. Use this to measure how much
+/// time a suite loses to serialization on the lock.
+///
+/// ```
+/// let _guard = env_lock::lock_env([("ENV_LOCK_STATS", Some("1"))]);
+/// let stats = env_lock::stats();
+/// assert!(stats.acquisitions > 0);
+/// assert!(stats.max_wait <= stats.total_wait);
+/// ```
+pub fn stats() -> LockStats {
+    let duration = |counter: &AtomicU64| {
+        Duration::from_nanos(counter.load(Ordering::Relaxed))
+    };
+    LockStats {
+        acquisitions: ACQUISITIONS.load(Ordering::Relaxed),
+        total_wait: duration(&TOTAL_WAIT),
+        max_wait: duration(&MAX_WAIT),
+        total_hold: duration(&TOTAL_HOLD),
+        max_hold: duration(&MAX_HOLD),
+    }
+}
+
+/// Reset all [stats] to zero. Guards that are currently held will still be
+/// counted when they're dropped.
+pub fn reset_stats() {
+    for counter in [
+        &ACQUISITIONS,
+        &TOTAL_WAIT,
+        &MAX_WAIT,
+        &TOTAL_HOLD,
+        &MAX_HOLD,
+    ] {
+        counter.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Record a guard acquiring the lock
+pub(crate) fn record_acquire(wait: Duration) {
+    let wait = nanos(wait);
+    ACQUISITIONS.fetch_add(1, Ordering::Relaxed);
+    TOTAL_WAIT.fetch_add(wait, Ordering::Relaxed);
+    MAX_WAIT.fetch_max(wait, Ordering::Relaxed);
+}
+
+/// Record a guard releasing the lock
+pub(crate) fn record_release(hold: Duration) {
+    let hold = nanos(hold);
+    TOTAL_HOLD.fetch_add(hold, Ordering::Relaxed);
+    MAX_HOLD.fetch_max(hold, Ordering::Relaxed);
+}
+
+fn nanos(duration: Duration) -> u64 {
+    duration.as_nanos().try_into().unwrap_or(u64::MAX)
+}
@@ -0,0 +1,172 @@
+use crate::sys;
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    panic::{self, AssertUnwindSafe},
+    thread,
+};
+
+/// Pool of variables that stress scenarios modify. The pool is small so that
+/// threads frequently touch the same variables as each other
+const VARIABLES: [&str; 6] = [
+    "ENV_LOCK_STRESS_0",
+    "ENV_LOCK_STRESS_1",
+    "ENV_LOCK_STRESS_2",
+    "ENV_LOCK_STRESS_3",
+    "ENV_LOCK_STRESS_4",
+    "ENV_LOCK_STRESS_5",
+];
+
+/// A list of variables for a stress scenario to apply. Each is either a value
+/// to set or `None` to remove the variable.
+pub type ScenarioVariables<'a> = [(&'static str, Option<&'a str>)];
+
+/// Hammer an environment lock from many threads at once to verify that it
+/// provides exclusive access and restores the environment correctly. This can
+/// be used to validate wrappers built on top of [lock_env](crate::lock_env),
+/// or alternative locking schemes entirely.
+///
+/// `scenario` is called with a random list of variables to apply, and must
+/// return a guard that holds the lock and restores the variables when
+/// dropped. Each of `threads` threads calls the scenario `iterations` times.
+/// While each guard is held, the environment is checked to make sure no other
+/// thread modified it, and some guards are dropped by a panic instead of
+/// normally. Once all threads are done, the environment is checked against a
+/// snapshot taken before starting.
+///
+/// Panics if any of the checks fail. Because this spawns its own threads, the
+/// lock must not be disabled by the [LockMode](crate::LockMode). If your test
+/// harness runs on a single thread, set [LockMode::Always](crate::LockMode)
+/// first.
+///
+/// ```
+/// env_lock::stress(4, 20, |variables| {
+///     env_lock::lock_env(variables.iter().copied())
+/// });
+/// ```
+pub fn stress<G>(
+    threads: usize,
+    iterations: usize,
+    scenario: impl Fn(&ScenarioVariables) -> G + Sync,
+) {
+    let snapshot = {
+        let _guard = scenario(&[]);
+        take_snapshot()
+    };
+
+    thread::scope(|scope| {
+        let handles: Vec<_> = (0..threads)
+            .map(|_| {
+                let scenario = &scenario;
+                scope.spawn(move || {
+                    let mut rng = Rng::new();
+                    for _ in 0..iterations {
+                        run_iteration(&mut rng, scenario);
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            if let Err(error) = handle.join() {
+                panic::resume_unwind(error);
+            }
+        }
+    });
+
+    let _guard = scenario(&[]);
+    assert_eq!(
+        take_snapshot(),
+        snapshot,
+        "Environment was not restored to its initial state"
+    );
+}
+
+/// Apply a single random scenario and check that it holds for the lifetime of
+/// the guard
+fn run_iteration<G>(
+    rng: &mut Rng,
+    scenario: &impl Fn(&ScenarioVariables) -> G,
+) {
+    let values: Vec<String> = (0..VARIABLES.len())
+        .map(|_| rng.next().to_string())
+        .collect();
+    // Pick a random subset of the pool, each either set or removed
+    let mut variables: Vec<(&'static str, Option<&str>)> = Vec::new();
+    for (&variable, value) in VARIABLES.iter().zip(&values) {
+        if rng.chance(2) {
+            variables.push((variable, (!rng.chance(3)).then_some(value)));
+        }
+    }
+    let should_panic = rng.chance(10);
+
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        let _guard = scenario(&variables);
+        check_variables(&variables);
+        thread::yield_now();
+        check_variables(&variables);
+        if should_panic {
+            panic!("Intentional panic from stress scenario");
+        }
+    }));
+    // Intentional panics are fine, but failed checks have to propagate
+    if let Err(error) = result {
+        if !should_panic {
+            panic::resume_unwind(error);
+        }
+    }
+}
+
+/// Assert that every variable has its expected value
+fn check_variables(variables: &ScenarioVariables) {
+    for (variable, expected) in variables {
+        assert_eq!(
+            sys::var(variable).ok().as_deref(),
+            *expected,
+            "Variable {variable} was modified while the lock was held"
+        );
+    }
+}
+
+/// Get the current value of every variable in the pool
+fn take_snapshot() -> Vec<Option<String>> {
+    VARIABLES
+        .iter()
+        .map(|variable| sys::var(variable).ok())
+        .collect()
+}
+
+/// Minimal xorshift PRNG, so we don't need a dependency for randomness. This
+/// only needs to shuffle scenarios around, not be any good.
+struct Rng(u64);
+
+impl Rng {
+    fn new() -> Self {
+        // RandomState is seeded randomly per instance
+        let seed = RandomState::new().build_hasher().finish();
+        Self(seed | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Return true with a probability of 1 in `n`
+    fn chance(&mut self, n: u64) -> bool {
+        self.next() % n == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lock_env;
+
+    /// Run the stress test against env-lock's own locking
+    #[test]
+    fn stress_lock_env() {
+        stress(8, 100, |variables| lock_env(variables.iter().copied()));
+    }
+}
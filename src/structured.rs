@@ -0,0 +1,362 @@
+use crate::{lock_env, sys, EnvGuard};
+use std::{
+    env::VarError,
+    error::Error,
+    fmt::{self, Display},
+    str::FromStr,
+};
+
+/// A struct whose fields can be set as environment variables, one variable
+/// per field. Derive it with `#[derive(ToEnv)]` (requires the `macros`
+/// feature), then lock the environment with [lock_env_serialize]. Fields are
+/// formatted with [Display](std::fmt::Display), and `Option` fields that are
+/// `None` are removed.
+///
+/// ```
+/// use env_lock::{EnvNaming, ToEnv};
+/// use std::env;
+///
+/// struct Settings {
+///     host: String,
+///     port: u16,
+/// }
+///
+/// impl ToEnv for Settings {
+///     fn to_env(&self) -> Vec<(&'static str, Option<String>)> {
+///         vec![
+///             ("host", Some(self.host.clone())),
+///             ("port", Some(self.port.to_string())),
+///         ]
+///     }
+/// }
+///
+/// let settings = Settings { host: "localhost".into(), port: 8080 };
+/// let _guard = env_lock::lock_env_serialize(
+///     &settings,
+///     &EnvNaming::new().prefix("ENV_LOCK_TO_ENV"),
+/// );
+/// assert_eq!(env::var("ENV_LOCK_TO_ENV_HOST").unwrap(), "localhost");
+/// assert_eq!(env::var("ENV_LOCK_TO_ENV_PORT").unwrap(), "8080");
+/// ```
+pub trait ToEnv {
+    /// Get each field's name and value, or `None` to remove the variable.
+    /// Names are converted to variable names by [EnvNaming].
+    fn to_env(&self) -> Vec<(&'static str, Option<String>)>;
+}
+
+/// How struct field names are converted to variable names, for
+/// [lock_env_serialize]. By default, a field `log_level` becomes
+/// `LOG_LEVEL`. With a prefix `APP`, it becomes `APP_LOG_LEVEL`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EnvNaming {
+    prefix: Option<String>,
+    case: NameCase,
+}
+
+/// Case of field names within variable names. See [EnvNaming].
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum NameCase {
+    /// `log_level` becomes `LOG_LEVEL`
+    #[default]
+    Upper,
+    /// `log_level` stays `log_level`
+    Lower,
+}
+
+impl EnvNaming {
+    /// Convert field names to uppercase, with no prefix
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Put a prefix before each field name, joined with `_`. The prefix is
+    /// used as given, regardless of the [NameCase].
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = Some(prefix.into());
+        self
+    }
+
+    /// Set the case of field names
+    pub fn case(mut self, case: NameCase) -> Self {
+        self.case = case;
+        self
+    }
+
+    /// Get the variable name for a field
+    pub fn name(&self, field: &str) -> String {
+        let field = match self.case {
+            NameCase::Upper => field.to_uppercase(),
+            NameCase::Lower => field.to_lowercase(),
+        };
+        match &self.prefix {
+            Some(prefix) => format!("{prefix}_{field}"),
+            None => field,
+        }
+    }
+}
+
+/// Lock the environment, then set one variable for each field of a struct.
+/// Variable names are derived from field names by `naming`, so they can't
+/// drift from the struct when a field is renamed. See [ToEnv].
+#[track_caller]
+pub fn lock_env_serialize(
+    value: &impl ToEnv,
+    naming: &EnvNaming,
+) -> EnvGuard<'static> {
+    lock_env(
+        value
+            .to_env()
+            .into_iter()
+            .map(|(field, value)| (naming.name(field), value)),
+    )
+}
+
+/// A struct that can be read from environment variables, one variable per
+/// field. Derive it with `#[derive(FromEnv)]` (requires the `macros`
+/// feature), then read it with [EnvGuard::deserialize]. Fields are parsed with
+/// [FromStr], and `Option` fields are `None` if their variable is unset.
+///
+/// ```
+/// use env_lock::{EnvFields, EnvNaming, FromEnv, FromEnvError};
+///
+/// #[derive(Debug, PartialEq)]
+/// struct Settings {
+///     port: u16,
+///     proxy: Option<String>,
+/// }
+///
+/// impl FromEnv for Settings {
+///     fn from_env(fields: &EnvFields<'_>) -> Result<Self, FromEnvError> {
+///         Ok(Self {
+///             port: fields.required("port")?,
+///             proxy: fields.optional("proxy")?,
+///         })
+///     }
+/// }
+///
+/// let guard = env_lock::lock_env([("ENV_LOCK_FROM_ENV_PORT", Some("8080"))]);
+/// let settings: Settings = guard
+///     .deserialize(&EnvNaming::new().prefix("ENV_LOCK_FROM_ENV"))
+///     .unwrap();
+/// assert_eq!(settings, Settings { port: 8080, proxy: None });
+/// ```
+pub trait FromEnv: Sized {
+    /// Read each field from the environment
+    fn from_env(fields: &EnvFields<'_>) -> Result<Self, FromEnvError>;
+}
+
+/// Access to the variable for each field of a [FromEnv] struct, while the
+/// environment is locked. Variable names are derived from field names by
+/// [EnvNaming].
+#[derive(Debug)]
+pub struct EnvFields<'a> {
+    naming: &'a EnvNaming,
+}
+
+impl<'a> EnvFields<'a> {
+    /// Get and parse the variable for a field, returning an error if it's
+    /// unset
+    pub fn required<T>(&self, field: &str) -> Result<T, FromEnvError>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        let variable = self.naming.name(field);
+        self.optional(field)?
+            .ok_or(FromEnvError::Missing { variable })
+    }
+
+    /// Get and parse the variable for a field, returning `None` if it's unset
+    pub fn optional<T>(&self, field: &str) -> Result<Option<T>, FromEnvError>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        let variable = self.naming.name(field);
+        let value = match sys::var(&variable) {
+            Ok(value) => value,
+            Err(VarError::NotPresent) => return Ok(None),
+            Err(error @ VarError::NotUnicode(_)) => {
+                return Err(FromEnvError::Invalid {
+                    variable,
+                    message: error.to_string(),
+                })
+            }
+        };
+        value
+            .parse()
+            .map(Some)
+            .map_err(|error: T::Err| FromEnvError::Invalid {
+                variable,
+                message: format!("{value:?}: {error}"),
+            })
+    }
+}
+
+/// An error reading a [FromEnv] struct from the environment
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FromEnvError {
+    /// The variable for a required field is unset
+    Missing {
+        /// Name of the variable
+        variable: String,
+    },
+    /// The variable for a field couldn't be parsed
+    Invalid {
+        /// Name of the variable
+        variable: String,
+        /// The value and why it couldn't be parsed
+        message: String,
+    },
+}
+
+impl Display for FromEnvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Missing { variable } => {
+                write!(f, "Variable `{variable}` is not set")
+            }
+            Self::Invalid { variable, message } => {
+                write!(f, "Invalid value for `{variable}`: {message}")
+            }
+        }
+    }
+}
+
+impl Error for FromEnvError {}
+
+/// Read a [FromEnv] struct from the environment. The caller should hold the
+/// lock.
+pub(crate) fn deserialize<T: FromEnv>(
+    naming: &EnvNaming,
+) -> Result<T, FromEnvError> {
+    T::from_env(&EnvFields { naming })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Field names are converted with the prefix and case
+    #[test]
+    fn naming() {
+        assert_eq!(EnvNaming::new().name("log_level"), "LOG_LEVEL");
+        assert_eq!(
+            EnvNaming::new().prefix("APP").name("log_level"),
+            "APP_LOG_LEVEL"
+        );
+        assert_eq!(
+            EnvNaming::new()
+                .prefix("app")
+                .case(NameCase::Lower)
+                .name("log_level"),
+            "app_log_level"
+        );
+    }
+
+    /// Fields are parsed, and missing or invalid values are errors
+    #[test]
+    fn from_env() {
+        #[derive(Debug, PartialEq)]
+        struct Settings {
+            port: u16,
+            host: Option<String>,
+        }
+
+        impl FromEnv for Settings {
+            fn from_env(fields: &EnvFields<'_>) -> Result<Self, FromEnvError> {
+                Ok(Self {
+                    port: fields.required("port")?,
+                    host: fields.optional("host")?,
+                })
+            }
+        }
+
+        let prefix = "ENV_LOCK_TEST_VARIABLE_DESERIALIZE";
+        let naming = EnvNaming::new().prefix(prefix);
+        let port = format!("{prefix}_PORT");
+        let mut guard = lock_env([(port.as_str(), None::<&str>)]);
+        assert_eq!(
+            guard.deserialize::<Settings>(&naming),
+            Err(FromEnvError::Missing {
+                variable: port.clone()
+            })
+        );
+        guard.set(&port, "http");
+        assert!(matches!(
+            guard.deserialize::<Settings>(&naming),
+            Err(FromEnvError::Invalid { variable, .. }) if variable == port
+        ));
+        guard.set(&port, "80");
+        assert_eq!(
+            guard.deserialize::<Settings>(&naming),
+            Ok(Settings {
+                port: 80,
+                host: None
+            })
+        );
+    }
+
+    /// Derived impls set each field, remove `None` fields, and strip `r#` from
+    /// field names
+    #[cfg(feature = "macros")]
+    #[test]
+    fn derive() {
+        use std::env;
+
+        #[derive(crate::ToEnv)]
+        struct Settings<'a> {
+            host: &'a str,
+            port: u16,
+            r#type: Option<String>,
+        }
+
+        let prefix = "ENV_LOCK_TEST_VARIABLE_SERIALIZE";
+        let kind = format!("{prefix}_TYPE");
+        env::set_var(&kind, "stale");
+        let settings = Settings {
+            host: "localhost",
+            port: 8080,
+            r#type: None,
+        };
+        let guard =
+            lock_env_serialize(&settings, &EnvNaming::new().prefix(prefix));
+        assert_eq!(env::var(format!("{prefix}_HOST")).unwrap(), "localhost");
+        assert_eq!(env::var(format!("{prefix}_PORT")).unwrap(), "8080");
+        assert!(env::var(&kind).is_err());
+        drop(guard);
+        assert_eq!(env::var(&kind).unwrap(), "stale");
+        env::remove_var(&kind);
+    }
+
+    /// Derived impls read back what was set
+    #[cfg(feature = "macros")]
+    #[test]
+    fn derive_round_trip() {
+        #[derive(Debug, PartialEq, crate::ToEnv, crate::FromEnv)]
+        struct Settings {
+            host: String,
+            port: u16,
+            r#type: Option<String>,
+        }
+
+        let naming = EnvNaming::new()
+            .prefix("env_lock_test_variable_round_trip")
+            .case(NameCase::Lower);
+        for settings in [
+            Settings {
+                host: "localhost".into(),
+                port: 8080,
+                r#type: Some("http".into()),
+            },
+            Settings {
+                host: "example.com".into(),
+                port: 443,
+                r#type: None,
+            },
+        ] {
+            let guard = lock_env_serialize(&settings, &naming);
+            assert_eq!(guard.deserialize::<Settings>(&naming), Ok(settings));
+        }
+    }
+}
@@ -0,0 +1,199 @@
+//! The only place this crate mutates the process environment. Every
+//! modification made by a guard goes through here, so the soundness argument
+//! only needs to be made once. This is also home to the crate's only other
+//! FFI call, for file locking.
+//!
+//! Reads go through here too, so targets without a process environment can
+//! substitute their own. On `wasm32-unknown-unknown`, the standard library
+//! can't set variables and never finds any, so an in-memory map stands in
+//! for the environment. It starts empty, and only code reading through this
+//! crate (e.g. [var](crate::var) or [EnvGuard::get](crate::EnvGuard::get))
+//! sees it. WASI has a real environment, so it's used as normal.
+//!
+//! ## Safety
+//! Modifying the environment is only sound if no other thread reads or writes
+//! it at the same time, because the underlying libc functions (`setenv`,
+//! `getenv`, etc.) aren't thread-safe on most platforms. The standard library
+//! already synchronizes its own accessors, such as [std::env::var], with an
+//! internal lock, so Rust code can't race with these calls. The remaining
+//! risk is code that reads the environment through libc *without* that lock:
+//! C libraries calling `getenv`, `getaddrinfo`, `localtime`, etc. on another
+//! thread. That's exactly the access the global environment lock is meant to
+//! serialize: a caller that holds a guard has exclusive access to the
+//! environment for as long as the guard lives, so every test that touches the
+//! environment (from Rust *or* FFI) must do so while holding a guard.
+//!
+//! ## Edition 2024
+//! In Rust 2024, [std::env::set_var] and [std::env::remove_var] are `unsafe`.
+//! Once this crate migrates, these functions will wrap the calls in `unsafe`
+//! blocks citing the argument above, and this module will be the only one
+//! that allows `unsafe_code`. The rest of the crate, and its public API, stay
+//! safe.
+
+#[cfg(all(target_family = "wasm", target_os = "unknown"))]
+use shim as imp;
+#[cfg(not(all(target_family = "wasm", target_os = "unknown")))]
+use std::env as imp;
+use std::{
+    borrow::Cow,
+    env::VarError,
+    ffi::{OsStr, OsString},
+};
+#[cfg(all(unix, feature = "file-lock"))]
+use std::{fs::File, io, os::unix::io::AsRawFd};
+
+/// Set a variable. Only call this while holding the environment lock, or
+/// while the lock is disabled by the [LockMode](crate::LockMode) because
+/// tests run on a single thread.
+pub(crate) fn set_var(variable: &str, value: &OsStr) {
+    imp::set_var(variable, value);
+}
+
+/// Remove a variable. The same requirements as [set_var] apply.
+pub(crate) fn remove_var(variable: &str) {
+    imp::remove_var(variable);
+}
+
+/// Get the value of a variable, or `None` if it's unset
+pub(crate) fn var_os(variable: &str) -> Option<OsString> {
+    imp::var_os(variable)
+}
+
+/// Get the value of a variable as a string. See [std::env::var].
+pub(crate) fn var(variable: &str) -> Result<String, VarError> {
+    imp::var(variable)
+}
+
+/// Get every variable and its value. On Windows, this skips the hidden
+/// variables that track each drive's working directory (e.g. `=C:`). They
+/// can't be set or removed by name, so a guard can't manage them.
+pub(crate) fn vars_os() -> impl Iterator<Item = (OsString, OsString)> {
+    imp::vars_os().filter(|(name, _)| !is_hidden(name))
+}
+
+/// Is this one of the hidden variables on Windows, whose names start with
+/// `=`? These are never hidden on other platforms.
+fn is_hidden(name: &OsStr) -> bool {
+    cfg!(windows) && name.to_string_lossy().starts_with('=')
+}
+
+/// Do two names refer to the same variable? Names are case-insensitive on
+/// Windows, and case-sensitive everywhere else.
+pub(crate) fn same_name(a: &str, b: &str) -> bool {
+    if cfg!(windows) {
+        a.eq_ignore_ascii_case(b) || a.to_uppercase() == b.to_uppercase()
+    } else {
+        a == b
+    }
+}
+
+/// Are two characters of a name the same? Like [same_name], this is
+/// case-insensitive on Windows.
+pub(crate) fn same_char(a: char, b: char) -> bool {
+    if cfg!(windows) {
+        a.eq_ignore_ascii_case(&b) || a.to_uppercase().eq(b.to_uppercase())
+    } else {
+        a == b
+    }
+}
+
+/// Normalize a name for use as a map key, so names that refer to the same
+/// variable get the same key. On Windows, this is the uppercase name.
+pub(crate) fn name_key(name: &str) -> Cow<'_, str> {
+    if cfg!(windows) {
+        Cow::Owned(name.to_uppercase())
+    } else {
+        Cow::Borrowed(name)
+    }
+}
+
+/// Block until an advisory lock on a file is available, then take it. The
+/// lock is released when the file is closed.
+#[cfg(all(unix, feature = "file-lock"))]
+#[allow(unsafe_code)]
+pub(crate) fn flock(file: &File, exclusive: bool) -> io::Result<()> {
+    let operation = if exclusive {
+        libc::LOCK_EX
+    } else {
+        libc::LOCK_SH
+    };
+    loop {
+        // SAFETY: The descriptor is valid as long as the file is open, and
+        // flock doesn't touch any memory
+        let result = unsafe { libc::flock(file.as_raw_fd(), operation) };
+        if result == 0 {
+            return Ok(());
+        }
+        let error = io::Error::last_os_error();
+        // Retry if interrupted by a signal
+        if error.kind() != io::ErrorKind::Interrupted {
+            return Err(error);
+        }
+    }
+}
+
+/// In-memory stand-in for the environment, on targets that don't have one.
+/// Mirrors the functions we use from `std::env`.
+#[cfg(all(target_family = "wasm", target_os = "unknown"))]
+mod shim {
+    use std::{
+        collections::BTreeMap,
+        env::VarError,
+        ffi::{OsStr, OsString},
+        sync::{Mutex, MutexGuard},
+    };
+
+    static VARIABLES: Mutex<BTreeMap<OsString, OsString>> =
+        Mutex::new(BTreeMap::new());
+
+    pub fn set_var(variable: &str, value: &OsStr) {
+        variables().insert(variable.into(), value.to_owned());
+    }
+
+    pub fn remove_var(variable: &str) {
+        variables().remove(OsStr::new(variable));
+    }
+
+    pub fn var_os(variable: &str) -> Option<OsString> {
+        variables().get(OsStr::new(variable)).cloned()
+    }
+
+    pub fn var(variable: &str) -> Result<String, VarError> {
+        var_os(variable)
+            .ok_or(VarError::NotPresent)?
+            .into_string()
+            .map_err(VarError::NotUnicode)
+    }
+
+    pub fn vars_os() -> impl Iterator<Item = (OsString, OsString)> {
+        variables().clone().into_iter()
+    }
+
+    fn variables() -> MutexGuard<'static, BTreeMap<OsString, OsString>> {
+        // Each operation is a single map operation, so it can't be left
+        // inconsistent
+        VARIABLES.lock().unwrap_or_else(|error| error.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Names are only case-insensitive on Windows
+    #[test]
+    fn same_name() {
+        assert!(super::same_name("PATH", "PATH"));
+        assert!(!super::same_name("PATH", "PATHS"));
+        assert_eq!(super::same_name("Path", "PATH"), cfg!(windows));
+        assert_eq!(same_char('a', 'A'), cfg!(windows));
+        assert_eq!(name_key("Path") == name_key("PATH"), cfg!(windows));
+    }
+
+    /// Drive working directories are only hidden on Windows
+    #[test]
+    fn is_hidden() {
+        assert_eq!(super::is_hidden(OsStr::new("=C:")), cfg!(windows));
+        assert!(!super::is_hidden(OsStr::new("PATH")));
+    }
+}
@@ -0,0 +1,90 @@
+use crate::{lock_env, EnvGuard, IntoEnvValue};
+use std::{borrow::Cow, ffi::OsString};
+
+/// A guard for a single variable, for the common case where a test only
+/// needs to override one. Like [lock_env], this locks the environment until
+/// it's dropped, then restores the variable.
+///
+/// ```
+/// use env_lock::TempVar;
+/// use std::env;
+///
+/// let var = TempVar::set("ENV_LOCK_TEMP_VAR", "hello!");
+/// assert_eq!(env::var("ENV_LOCK_TEMP_VAR").unwrap(), "hello!");
+/// drop(var);
+///
+/// let _var = TempVar::unset("PATH");
+/// assert!(env::var("PATH").is_err());
+/// ```
+pub struct TempVar {
+    name: Cow<'static, str>,
+    guard: EnvGuard<'static>,
+}
+
+impl TempVar {
+    /// Lock the environment and set a variable. Accepts any value that
+    /// [lock_env] does.
+    ///
+    /// ## Panics
+    /// Panics if the name or value is invalid. See [lock_env].
+    #[track_caller]
+    pub fn set(
+        name: impl Into<Cow<'static, str>>,
+        value: impl IntoEnvValue,
+    ) -> Self {
+        Self::new(name.into(), Some(value.into_env_value()))
+    }
+
+    /// Lock the environment and remove a variable
+    ///
+    /// ## Panics
+    /// Panics if the name is invalid. See [lock_env].
+    #[track_caller]
+    pub fn unset(name: impl Into<Cow<'static, str>>) -> Self {
+        Self::new(name.into(), None)
+    }
+
+    #[track_caller]
+    fn new(name: Cow<'static, str>, value: Option<OsString>) -> Self {
+        let guard = lock_env([(name.clone(), value)]);
+        Self { name, guard }
+    }
+
+    /// Get the variable's name
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Convert to a regular guard, e.g. to set more variables with
+    /// [EnvGuard::set]
+    pub fn into_guard(self) -> EnvGuard<'static> {
+        self.guard
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    /// The variable is set or removed, then restored on drop
+    #[test]
+    fn temp_var() {
+        let name = "ENV_LOCK_TEST_VARIABLE_TEMP";
+        let var = TempVar::set(name, 3);
+        assert_eq!(var.name(), name);
+        assert_eq!(env::var(name).unwrap(), "3");
+        drop(var);
+        assert!(env::var(name).is_err());
+
+        let outer = TempVar::set(name.to_owned(), "outer");
+        let inner = TempVar::unset(name);
+        assert!(env::var(name).is_err());
+        drop(inner);
+        let mut guard = outer.into_guard();
+        assert_eq!(env::var(name).unwrap(), "outer");
+        guard.set(name, "guard");
+        drop(guard);
+        assert!(env::var(name).is_err());
+    }
+}
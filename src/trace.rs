@@ -0,0 +1,159 @@
+//! Emit [tracing] events whenever the lock is acquired or released, and
+//! whenever a guard modifies a variable, to correlate slow tests and
+//! environment mutations in your test telemetry. All events are emitted at
+//! the `DEBUG` level with the target `env_lock`. Variables restored when a
+//! guard is dropped are modified within a `restore` span.
+//!
+//! Variable values are redacted by default, because the environment often
+//! contains secrets. Use [set_show_values] to include them.
+//!
+//! Requires the `tracing` feature.
+//!
+//! ```
+//! env_lock::trace::set_show_values(true);
+//! let _guard = env_lock::lock_env([("ENV_LOCK_TRACE", Some("traced"))]);
+//! ```
+
+use std::{
+    ffi::OsStr,
+    fmt::{self, Display},
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+use tracing::span::EnteredSpan;
+
+/// Target for all spans and events
+const TARGET: &str = "env_lock";
+
+/// Should values be included in events?
+static SHOW_VALUES: AtomicBool = AtomicBool::new(false);
+
+/// Include variable values in events, instead of redacting them. Only enable
+/// this if your environment doesn't contain secrets, or your telemetry is
+/// private.
+pub fn set_show_values(show: bool) {
+    SHOW_VALUES.store(show, Ordering::Relaxed);
+}
+
+/// Record a guard acquiring the lock
+pub(crate) fn acquired(wait: Duration) {
+    tracing::debug!(target: TARGET, ?wait, "Acquired environment lock");
+}
+
+/// Record a guard releasing the lock
+pub(crate) fn released(held: Duration) {
+    tracing::debug!(target: TARGET, ?held, "Released environment lock");
+}
+
+/// Record a variable being set or removed
+pub(crate) fn variable_set(variable: &str, value: Option<&OsStr>) {
+    match value {
+        Some(value) => tracing::debug!(
+            target: TARGET,
+            variable,
+            value = %Value(value),
+            "Set environment variable"
+        ),
+        None => {
+            tracing::debug!(
+                target: TARGET,
+                variable,
+                "Removed environment variable"
+            )
+        }
+    }
+}
+
+/// Enter a span for restoring a guard's variables
+pub(crate) fn restore_span() -> EnteredSpan {
+    tracing::debug_span!(target: TARGET, "restore").entered()
+}
+
+/// A variable value, displayed only if enabled by [set_show_values]
+struct Value<'a>(&'a OsStr);
+
+impl<'a> Display for Value<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if SHOW_VALUES.load(Ordering::Relaxed) {
+            write!(f, "{}", self.0.to_string_lossy())
+        } else {
+            write!(f, "<redacted>")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lock_env, set_lock_mode, LockMode};
+    use std::sync::{Arc, Mutex};
+    use tracing::{
+        field::{Field, Visit},
+        span, Event, Metadata, Subscriber,
+    };
+
+    /// Subscriber that records the fields of every event
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    impl Subscriber for Recorder {
+        fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+            metadata.target() == TARGET
+        }
+
+        fn new_span(&self, _: &span::Attributes<'_>) -> span::Id {
+            span::Id::from_u64(1)
+        }
+
+        fn record(&self, _: &span::Id, _: &span::Record<'_>) {}
+
+        fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+
+        fn event(&self, event: &Event<'_>) {
+            let mut fields = String::new();
+            event.record(&mut FieldVisitor(&mut fields));
+            self.0.lock().unwrap().push(fields);
+        }
+
+        fn enter(&self, _: &span::Id) {}
+
+        fn exit(&self, _: &span::Id) {}
+    }
+
+    struct FieldVisitor<'a>(&'a mut String);
+
+    impl<'a> Visit for FieldVisitor<'a> {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            if field.name() != "wait" && field.name() != "held" {
+                self.0.push_str(&format!("{}={value:?} ", field.name()));
+            }
+        }
+    }
+
+    /// Acquisition, modification, and release are recorded, with values
+    /// redacted
+    #[test]
+    fn events() {
+        let var = "ENV_LOCK_TEST_VARIABLE_TRACE";
+        set_lock_mode(LockMode::Always);
+        let recorder = Recorder::default();
+        tracing::subscriber::with_default(recorder.clone(), || {
+            let _guard = lock_env([(var, Some("secret"))]);
+        });
+        let events = recorder.0.lock().unwrap().clone();
+        assert_eq!(
+            events,
+            [
+                "message=Acquired environment lock ".to_owned(),
+                format!(
+                    "message=Set environment variable variable=\"{var}\" \
+                    value=<redacted> "
+                ),
+                format!(
+                    "message=Removed environment variable variable=\"{var}\" "
+                ),
+                "message=Released environment lock ".to_owned(),
+            ]
+        );
+    }
+}
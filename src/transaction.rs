@@ -0,0 +1,240 @@
+use crate::{source::Validator, sys, validate, EnvGuard};
+use std::{
+    borrow::Cow,
+    error::Error,
+    ffi::OsStr,
+    fmt::{self, Display},
+};
+
+/// A set of changes that are staged, then checked and applied together. On
+/// [commit](Self::commit), the environment is locked, then every staged value
+/// is interpolated and validated. Only if every check passes are the changes
+/// applied, so a bad value never leaves the environment partially modified.
+///
+/// Values can reference other variables as `${NAME}`. References are resolved
+/// at commit time, while the environment is locked, using the value staged
+/// earlier in the same transaction if there is one, otherwise the current
+/// value. Unset variables expand to an empty string.
+///
+/// ```
+/// use env_lock::{source::Validator, EnvTransaction};
+/// use std::env;
+///
+/// let guard = EnvTransaction::new()
+///     .set("ENV_LOCK_TRANSACTION_HOST", "localhost")
+///     .set("ENV_LOCK_TRANSACTION_PORT", "8080")
+///     .validate("ENV_LOCK_TRANSACTION_PORT", Validator::parse::<u16>())
+///     .set(
+///         "ENV_LOCK_TRANSACTION_URL",
+///         "http://${ENV_LOCK_TRANSACTION_HOST}:${ENV_LOCK_TRANSACTION_PORT}",
+///     )
+///     .commit()
+///     .unwrap();
+/// assert_eq!(
+///     env::var("ENV_LOCK_TRANSACTION_URL").unwrap(),
+///     "http://localhost:8080"
+/// );
+/// ```
+#[derive(Debug, Default)]
+pub struct EnvTransaction<'a> {
+    changes: Vec<Change<'a>>,
+    validators: Vec<(Cow<'a, str>, Validator)>,
+}
+
+#[derive(Debug)]
+struct Change<'a> {
+    variable: Cow<'a, str>,
+    /// Uninterpolated value, or `None` to remove
+    value: Option<String>,
+}
+
+impl<'a> EnvTransaction<'a> {
+    /// Create an empty transaction
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stage a variable to be set. The value may reference other variables
+    /// as `${NAME}`.
+    pub fn set(
+        mut self,
+        variable: impl Into<Cow<'a, str>>,
+        value: impl Into<String>,
+    ) -> Self {
+        self.changes.push(Change {
+            variable: variable.into(),
+            value: Some(value.into()),
+        });
+        self
+    }
+
+    /// Stage a variable to be removed
+    pub fn remove(mut self, variable: impl Into<Cow<'a, str>>) -> Self {
+        self.changes.push(Change {
+            variable: variable.into(),
+            value: None,
+        });
+        self
+    }
+
+    /// Check a staged variable's final value before anything is applied.
+    /// Like [ValueSource::validate](crate::source::ValueSource::validate),
+    /// only set values are checked.
+    pub fn validate(
+        mut self,
+        variable: impl Into<Cow<'a, str>>,
+        validator: Validator,
+    ) -> Self {
+        self.validators.push((variable.into(), validator));
+        self
+    }
+
+    /// Lock the environment, then interpolate and validate every staged
+    /// value. If everything is valid, apply the changes and return a guard
+    /// that restores them. Otherwise, return the first error, leaving the
+    /// environment unmodified.
+    #[track_caller]
+    pub fn commit(self) -> Result<EnvGuard<'a>, TransactionError> {
+        let mut guard = EnvGuard::acquire();
+        let mut resolved: Vec<(Cow<'a, str>, Option<String>)> =
+            Vec::with_capacity(self.changes.len());
+        for change in self.changes {
+            let error = |message: String| TransactionError {
+                variable: change.variable.to_string(),
+                message,
+            };
+            let value = change
+                .value
+                .as_deref()
+                .map(|value| interpolate(value, &resolved))
+                .transpose()
+                .map_err(error)?;
+            validate::check(&change.variable, value.as_deref().map(OsStr::new))
+                .map_err(|check_error| error(check_error.to_string()))?;
+            resolved.push((change.variable, value));
+        }
+
+        for (variable, validator) in &self.validators {
+            // Check the last staged value for the variable
+            let value = resolved
+                .iter()
+                .rev()
+                .find(|(name, _)| sys::same_name(name, variable))
+                .ok_or_else(|| TransactionError {
+                    variable: variable.to_string(),
+                    message: "Validated but not staged".into(),
+                })?;
+            if let Some(value) = &value.1 {
+                validator.check(value).map_err(|message| TransactionError {
+                    variable: variable.to_string(),
+                    message,
+                })?;
+            }
+        }
+
+        guard.apply(resolved);
+        Ok(guard)
+    }
+
+    /// Discard the staged changes without touching the environment. This is
+    /// equivalent to dropping the transaction.
+    pub fn rollback(self) {}
+}
+
+/// Replace each `${NAME}` in a value with the value staged so far, or the
+/// current value
+fn interpolate(
+    value: &str,
+    staged: &[(Cow<'_, str>, Option<String>)],
+) -> Result<String, String> {
+    let mut output = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        output.push_str(&rest[..start]);
+        let reference = &rest[start + 2..];
+        let end = reference
+            .find('}')
+            .ok_or_else(|| format!("Unclosed `${{` in value `{value}`"))?;
+        let name = &reference[..end];
+        let referenced =
+            match staged.iter().rev().find(|(n, _)| sys::same_name(n, name)) {
+                Some((_, value)) => value.clone(),
+                None => sys::var(name).ok(),
+            };
+        output.push_str(referenced.as_deref().unwrap_or_default());
+        rest = &reference[end + 1..];
+    }
+    output.push_str(rest);
+    Ok(output)
+}
+
+/// A staged change that couldn't be applied by [EnvTransaction::commit]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TransactionError {
+    /// Variable with the problem
+    pub variable: String,
+    /// Description of the problem
+    pub message: String,
+}
+
+impl Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Error applying `{}`: {}", self.variable, self.message)
+    }
+}
+
+impl Error for TransactionError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    /// Staged values are interpolated and applied together
+    #[test]
+    fn commit() {
+        let var1 = "ENV_LOCK_TEST_VARIABLE_TRANSACTION_1";
+        let var2 = "ENV_LOCK_TEST_VARIABLE_TRANSACTION_2";
+        let var3 = "ENV_LOCK_TEST_VARIABLE_TRANSACTION_3";
+        let guard = EnvTransaction::new()
+            .set(var1, "a")
+            .set(var2, format!("${{{var1}}}-${{{var3}}}-$"))
+            .remove(var3)
+            .commit()
+            .unwrap();
+        assert_eq!(env::var(var1).unwrap(), "a");
+        assert_eq!(env::var(var2).unwrap(), "a--$");
+        assert!(env::var(var3).is_err());
+        drop(guard);
+        assert!(env::var(var1).is_err());
+    }
+
+    /// A failed check leaves the environment untouched
+    #[test]
+    fn commit_error() {
+        let var1 = "ENV_LOCK_TEST_VARIABLE_TRANSACTION_ERROR_1";
+        let var2 = "ENV_LOCK_TEST_VARIABLE_TRANSACTION_ERROR_2";
+        let transaction =
+            || EnvTransaction::new().set(var1, "1").set(var2, "x");
+
+        let error = transaction()
+            .validate(var2, Validator::parse::<u8>())
+            .commit()
+            .err()
+            .unwrap();
+        assert_eq!(
+            error,
+            TransactionError {
+                variable: var2.into(),
+                message: "Value `x` must be a valid u8".into()
+            }
+        );
+        assert!(env::var(var1).is_err());
+
+        let error = transaction().set("BAD=NAME", "").commit().err().unwrap();
+        assert_eq!(error.variable, "BAD=NAME");
+        let error = transaction().set(var2, "${").commit().err().unwrap();
+        assert_eq!(error.message, "Unclosed `${` in value `${`");
+        assert!(env::var(var1).is_err());
+    }
+}
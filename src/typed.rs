@@ -0,0 +1,140 @@
+use crate::{sys, EnvGuard};
+use std::{
+    fmt::{self, Debug, Display},
+    marker::PhantomData,
+    str::FromStr,
+};
+
+/// A variable name paired with the type of its value. Declare each variable
+/// once as a `static`, then set it and read it back through the key, so
+/// values of the wrong type are rejected at compile time.
+///
+/// ```
+/// use env_lock::EnvVar;
+///
+/// static TIMEOUT: EnvVar<u64> = EnvVar::new("ENV_LOCK_TYPED_TIMEOUT");
+/// static VERBOSE: EnvVar<bool> = EnvVar::new("ENV_LOCK_TYPED_VERBOSE");
+///
+/// let guard = env_lock::lock_env([TIMEOUT.value(30), VERBOSE.unset()]);
+/// assert_eq!(guard.get(&TIMEOUT), Some(30));
+/// assert_eq!(guard.get(&VERBOSE), None);
+/// ```
+///
+/// ```compile_fail
+/// # use env_lock::EnvVar;
+/// static TIMEOUT: EnvVar<u64> = EnvVar::new("ENV_LOCK_TYPED_TIMEOUT");
+/// let _guard = env_lock::lock_env([TIMEOUT.value("thirty")]);
+/// ```
+pub struct EnvVar<T> {
+    name: &'static str,
+    /// `fn() -> T` so the key is `Send` and `Sync` regardless of `T`
+    _type: PhantomData<fn() -> T>,
+}
+
+impl<T> EnvVar<T> {
+    /// Create a key for a variable
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            _type: PhantomData,
+        }
+    }
+
+    /// Get the variable's name
+    pub const fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Get an entry that sets the variable to a value, for
+    /// [lock_env](crate::lock_env) or [EnvOverlay::new](crate::EnvOverlay::new)
+    pub fn value(&self, value: T) -> (&'static str, Option<String>)
+    where
+        T: Display,
+    {
+        (self.name, Some(value.to_string()))
+    }
+
+    /// Get an entry that removes the variable, for
+    /// [lock_env](crate::lock_env) or [EnvOverlay::new](crate::EnvOverlay::new)
+    pub fn unset(&self) -> (&'static str, Option<String>) {
+        (self.name, None)
+    }
+
+    /// Lock the environment and set the variable to a value. Shorthand for
+    /// `lock_env([var.value(value)])`.
+    #[track_caller]
+    pub fn lock(&self, value: T) -> EnvGuard<'static>
+    where
+        T: Display,
+    {
+        crate::lock_env([self.value(value)])
+    }
+}
+
+impl<T> Clone for EnvVar<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for EnvVar<T> {}
+
+impl<T> Debug for EnvVar<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("EnvVar").field(&self.name).finish()
+    }
+}
+
+/// Use a key wherever a name is expected, e.g. in
+/// [assert_env_eq](crate::assert_env_eq)
+impl<T> AsRef<str> for EnvVar<T> {
+    fn as_ref(&self) -> &str {
+        self.name
+    }
+}
+
+impl<T> Display for EnvVar<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+/// Parse the current value of a variable. The caller should hold the lock.
+#[track_caller]
+pub(crate) fn parse<T>(var: &EnvVar<T>) -> Option<T>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    let value = sys::var(var.name).ok()?;
+    match value.parse() {
+        Ok(value) => Some(value),
+        Err(error) => panic!(
+            "Error parsing variable `{}` from value `{value}`: {error}",
+            var.name
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, panic};
+
+    static PORT: EnvVar<u16> = EnvVar::new("ENV_LOCK_TEST_VARIABLE_TYPED");
+
+    /// Values are parsed under the lock, and invalid values panic
+    #[test]
+    fn get() {
+        let mut guard = PORT.lock(8080);
+        assert_eq!(env::var(PORT.name()).unwrap(), "8080");
+        assert_eq!(guard.get(&PORT), Some(8080));
+        guard.set(PORT.name(), "http");
+        let result = panic::catch_unwind(|| parse(&PORT));
+        assert!(result.is_err());
+        guard.remove(PORT.name());
+        assert_eq!(guard.get(&PORT), None);
+        drop(guard);
+        assert!(env::var(PORT.name()).is_err());
+    }
+}
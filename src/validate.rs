@@ -0,0 +1,97 @@
+use std::{
+    error::Error,
+    ffi::OsStr,
+    fmt::{self, Display},
+};
+
+/// A variable name or value that can't be stored in the environment. Setting
+/// one with [std::env::set_var] would panic, or be rejected by the platform.
+/// Returned by [try_lock_env_checked](crate::try_lock_env_checked).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EnvError {
+    /// The name is empty
+    EmptyName,
+    /// The name contains `=` or NUL
+    InvalidName {
+        /// The offending name
+        variable: String,
+    },
+    /// The value contains NUL
+    InvalidValue {
+        /// Name of the variable being set
+        variable: String,
+    },
+}
+
+impl Display for EnvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::EmptyName => write!(f, "Variable name cannot be empty"),
+            Self::InvalidName { variable } => write!(
+                f,
+                "Variable name {variable:?} cannot contain `=` or NUL"
+            ),
+            Self::InvalidValue { variable } => {
+                write!(f, "Value for `{variable}` cannot contain NUL")
+            }
+        }
+    }
+}
+
+impl Error for EnvError {}
+
+/// Check that a variable can be set to a value (or removed, if `None`)
+pub(crate) fn check(
+    variable: &str,
+    value: Option<&OsStr>,
+) -> Result<(), EnvError> {
+    if variable.is_empty() {
+        Err(EnvError::EmptyName)
+    } else if variable.contains(['=', '\0']) {
+        Err(EnvError::InvalidName {
+            variable: variable.to_owned(),
+        })
+    } else if value.is_some_and(|value| value.to_string_lossy().contains('\0'))
+    {
+        Err(EnvError::InvalidValue {
+            variable: variable.to_owned(),
+        })
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::try_lock_env_checked;
+    use std::env;
+
+    /// Invalid entries are rejected, and earlier entries are restored
+    #[test]
+    fn invalid() {
+        let var = "ENV_LOCK_TEST_VARIABLE_CHECKED";
+        for (variables, expected) in [
+            ([(var, Some("1")), ("", Some("2"))], EnvError::EmptyName),
+            (
+                [(var, Some("1")), ("A=B", Some("2"))],
+                EnvError::InvalidName {
+                    variable: "A=B".into(),
+                },
+            ),
+            (
+                [(var, Some("1")), (var, Some("a\0b"))],
+                EnvError::InvalidValue {
+                    variable: var.into(),
+                },
+            ),
+        ] {
+            assert_eq!(try_lock_env_checked(variables).err(), Some(expected));
+            assert!(env::var(var).is_err());
+        }
+
+        let guard = try_lock_env_checked([(var, Some("1"))]).unwrap();
+        assert_eq!(env::var(var).unwrap(), "1");
+        drop(guard);
+    }
+}
@@ -0,0 +1,108 @@
+use crate::{lock::ENV_LOCK, LockHolder};
+use std::{
+    sync::{Arc, Mutex, MutexGuard, Once},
+    thread,
+    time::Duration,
+};
+
+/// Callback for [set_hold_warning]
+type Callback = Arc<dyn Fn(&LockHolder, Duration) + Send + Sync>;
+
+/// The threshold and callback, if enabled
+static WARNING: Mutex<Option<(Duration, Callback)>> = Mutex::new(None);
+static SPAWN: Once = Once::new();
+
+/// Shortest and longest time between checks of the lock
+const MIN_INTERVAL: Duration = Duration::from_millis(10);
+const MAX_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Call a function whenever a guard has held exclusive access to the
+/// environment for longer than a threshold. Each hold is reported once, with
+/// the holder's location and how long it's been held so far. This surfaces
+/// the tests that serialize the rest of the suite, without instrumenting
+/// every test. To fail a specific test instead, use
+/// [EnvGuard::assert_released_within](crate::EnvGuard::assert_released_within).
+///
+/// The lock is checked from a background thread, so the callback is called
+/// from that thread while the guard is still held. Calling this again
+/// replaces the previous threshold and callback.
+///
+/// ```
+/// use std::time::Duration;
+///
+/// env_lock::set_hold_warning(Duration::from_secs(10), |holder, held| {
+///     eprintln!("Environment lock held for {held:?} by {holder}");
+/// });
+/// ```
+pub fn set_hold_warning(
+    threshold: Duration,
+    callback: impl Fn(&LockHolder, Duration) + Send + Sync + 'static,
+) {
+    *warning() = Some((threshold, Arc::new(callback)));
+    SPAWN.call_once(|| {
+        thread::Builder::new()
+            .name("env-lock-watchdog".into())
+            .spawn(watch)
+            .expect("Error spawning env-lock watchdog thread");
+    });
+}
+
+/// Stop reporting guards held past the threshold. See [set_hold_warning].
+pub fn clear_hold_warning() {
+    *warning() = None;
+}
+
+/// Check the lock periodically, forever
+fn watch() {
+    // ID of the last hold that was reported
+    let mut reported: Option<u64> = None;
+    loop {
+        let warning = warning().clone();
+        let interval = match warning {
+            Some((threshold, callback)) => {
+                if let Some((id, holder, since)) = ENV_LOCK.exclusive_holder() {
+                    let held = since.elapsed();
+                    if held > threshold && reported != Some(id) {
+                        reported = Some(id);
+                        callback(&holder, held);
+                    }
+                }
+                (threshold / 4).clamp(MIN_INTERVAL, MAX_INTERVAL)
+            }
+            None => MAX_INTERVAL,
+        };
+        thread::sleep(interval);
+    }
+}
+
+fn warning() -> MutexGuard<'static, Option<(Duration, Callback)>> {
+    // The setting is only replaced whole, so it can't be left inconsistent
+    WARNING.lock().unwrap_or_else(|error| error.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lock_env, set_lock_mode, LockMode};
+    use std::sync::mpsc;
+
+    /// A long hold is reported once, with its location
+    #[test]
+    fn hold_warning() {
+        set_lock_mode(LockMode::Always);
+        let (sender, receiver) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        let guard = lock_env([("ENV_LOCK_TEST_VARIABLE_WATCHDOG", Some("1"))]);
+        let line = line!() - 1;
+        set_hold_warning(Duration::from_millis(50), move |holder, held| {
+            let _ = sender.lock().unwrap().send((holder.clone(), held));
+        });
+        let (holder, held) =
+            receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        clear_hold_warning();
+        assert_eq!(holder.location.line(), line);
+        assert!(held >= Duration::from_millis(50));
+        drop(guard);
+        assert!(receiver.try_recv().is_err());
+    }
+}
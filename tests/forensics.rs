@@ -0,0 +1,43 @@
+//! Tests for [env_lock::forensics]. These run in their own process, because
+//! the panic hook can't be uninstalled, and would otherwise write a report
+//! for every test that panics on purpose while holding a guard.
+
+use env_lock::{forensics, lock_env};
+use std::{env, fs, process, thread};
+
+/// Panicking while holding a guard writes a report
+#[test]
+fn write_report_on_panic() {
+    let var = "ENV_LOCK_TEST_VARIABLE_FORENSICS";
+    let directory =
+        env::temp_dir().join(format!("env-lock-forensics-{}", process::id()));
+    forensics::set_output_directory(&directory);
+    forensics::install_panic_hook();
+
+    thread::Builder::new()
+        .name("forensics_test".into())
+        .spawn(move || {
+            let _guard = lock_env([(var, Some("applied"))]);
+            panic!("Oh no!");
+        })
+        .unwrap()
+        .join()
+        .unwrap_err();
+
+    let report = fs::read_dir(&directory)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| {
+            path.file_name()
+                .unwrap()
+                .to_string_lossy()
+                .starts_with("forensics_test")
+        })
+        .unwrap();
+    let report = fs::read_to_string(report).unwrap();
+    fs::remove_dir_all(&directory).unwrap();
+    assert!(report.contains("Oh no!"));
+    assert!(report.contains(&format!(
+        "    {var}\n      Before lock: None\n      Current: Some(\"applied\")"
+    )));
+}
@@ -0,0 +1,55 @@
+//! Tests for [force_restore_all]. These run in their own process, because it
+//! restores every live guard, including those of unrelated tests.
+
+use env_lock::{
+    force_restore_all, lock_env, lock_resource, set_lock_mode, LockMode,
+    RestorePolicy,
+};
+use std::{env, mem, sync::mpsc, thread, time::Duration};
+
+/// Held by each test, so one test doesn't restore the other's guards
+const RESOURCE: &str = "force_restore_all";
+
+/// Leaked guards are restored, and the lock is released
+#[test]
+fn force_restore() {
+    let _resource = lock_resource(RESOURCE);
+    let var1 = "ENV_LOCK_TEST_VARIABLE_RECOVERY_1";
+    let var2 = "ENV_LOCK_TEST_VARIABLE_RECOVERY_2";
+    set_lock_mode(LockMode::Always);
+    let mut guard = lock_env([(var1, Some("1"))]);
+    guard.set_restore_policy(var2, RestorePolicy::RemoveOnDrop);
+    guard.set(var2, "2");
+    mem::forget(guard);
+
+    // Other threads are blocked until recovery
+    let (sender, receiver) = mpsc::channel();
+    let handle = thread::spawn(move || {
+        let guard = lock_env([(var1, Some("other"))]);
+        sender.send(()).unwrap();
+        drop(guard);
+    });
+    assert!(receiver.recv_timeout(Duration::from_millis(50)).is_err());
+
+    assert_eq!(force_restore_all(), 1);
+    receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    handle.join().unwrap();
+    assert!(env::var(var1).is_err());
+    assert!(env::var(var2).is_err());
+}
+
+/// A guard restored by force doesn't restore again when it's dropped
+#[test]
+fn drop_after_restore() {
+    let _resource = lock_resource(RESOURCE);
+    let var = "ENV_LOCK_TEST_VARIABLE_RECOVERY_DROP";
+    set_lock_mode(LockMode::Always);
+    let guard = lock_env([(var, Some("1"))]);
+    assert_eq!(force_restore_all(), 1);
+    assert!(env::var(var).is_err());
+    let new = lock_env([(var, Some("2"))]);
+    drop(guard);
+    assert_eq!(env::var(var).unwrap(), "2");
+    drop(new);
+    assert!(env::var(var).is_err());
+}
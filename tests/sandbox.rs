@@ -0,0 +1,29 @@
+//! Tests that clear the entire environment. These run in their own process,
+//! because other tests may set variables without holding the lock, and the
+//! sandbox would remove them.
+
+use std::env;
+
+/// The sandbox clears everything except kept variables, and restores the
+/// entire environment on drop
+#[test]
+fn lock_env_sandbox() {
+    let var1 = "ENV_LOCK_TEST_VARIABLE_SANDBOX_1";
+    let var2 = "ENV_LOCK_TEST_VARIABLE_SANDBOX_2";
+    let var3 = "ENV_LOCK_TEST_VARIABLE_SANDBOX_3";
+    env::set_var(var1, "existing");
+    env::set_var(var2, "kept");
+
+    let guard = env_lock::lock_env_sandbox(&[var2], [(var3, Some("3"))]);
+    assert!(env::var(var1).is_err());
+    assert_eq!(env::var(var2).unwrap(), "kept");
+    assert_eq!(env::var(var3).unwrap(), "3");
+    env::set_var(var2, "modified");
+    env::set_var("ENV_LOCK_TEST_VARIABLE_SANDBOX_CREATED", "1");
+    drop(guard);
+
+    assert_eq!(env::var(var1).unwrap(), "existing");
+    assert_eq!(env::var(var2).unwrap(), "kept");
+    assert!(env::var(var3).is_err());
+    assert!(env::var("ENV_LOCK_TEST_VARIABLE_SANDBOX_CREATED").is_err());
+}